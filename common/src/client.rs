@@ -0,0 +1,1039 @@
+//! The device's side of the protocol, shared by the firmware and tools that act like a device.
+//!
+//! A [`Client`] talks to the server over any [`AbstractSocket`] and receives updates into a [`Storage`], so that
+//! each platform only has to open the connection and keep the messages.
+
+use core::{fmt, future::Future, ops::DerefMut, str::Utf8Error, time::Duration};
+
+use crate::{
+    consts::{FOOTER_BUFFER_SIZE, IMAGE_BUFFER_SIZE, PAGED_TEXT_BUFFER_SIZE},
+    protocols::pico::{
+        self, auth_mac, payload_checksum,
+        serialization::{AbstractSocket, Transmission},
+        AuthChallenge, AuthResult, Capabilities, ClientCommand, DeviceErrorKind, DeviceSecret, DeviceStatus, Hello,
+        HelloResult, RequestUpdateResult, Update, UpdateKind, KEEP_ALIVE_INTERVAL_SEC, PROTOCOL_VERSION,
+    },
+    rle,
+    types::{DeviceID, FirmwareVersion, MessageID, PairingCode},
+};
+
+/// How long we wait for the server before we consider the connection dead.
+pub const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+/// While we wait for updates, we consider the connection dead if the server sends no keep-alive for this long.
+pub const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(2 * KEEP_ALIVE_INTERVAL_SEC);
+/// How often we ask the server again for an update whose payload was corrupted.
+pub const MAX_TRANSFER_RETRIES: u8 = 2;
+/// Compressed images are read from the socket in chunks of this size and unpacked right away.
+const COMPRESSED_CHUNK_SIZE: usize = 256;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The connection broke or the server stopped answering.
+    Socket(pico::Error),
+    /// The server sent something that the protocol does not allow.
+    Protocol(pico::Error),
+    Encoding(Utf8Error),
+    /// The server speaks the protocol versions from `min` to `max` but not ours.
+    UnsupportedProtocol {
+        min: u16,
+        max: u16,
+    },
+    /// The server did not accept our device secret.
+    AuthenticationDenied,
+    /// The payload of an update did not match its checksum, also when we asked for it again.
+    Checksum,
+    Decompression(rle::DecodeError),
+    /// The server stopped sending keep-alives while we waited for updates.
+    KeepAliveTimeout,
+}
+
+impl From<pico::Error> for Error {
+    fn from(value: pico::Error) -> Self {
+        match value {
+            pico::Error::Eof | pico::Error::Timeout | pico::Error::Socket => Self::Socket(value),
+            pico::Error::Length { .. }
+            | pico::Error::Oversize { .. }
+            | pico::Error::Encode(_)
+            | pico::Error::Decode(_) => Self::Protocol(value),
+        }
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(value: Utf8Error) -> Self {
+        Self::Encoding(value)
+    }
+}
+
+impl From<rle::DecodeError> for Error {
+    fn from(value: rle::DecodeError) -> Self {
+        Self::Decompression(value)
+    }
+}
+
+#[cfg(feature = "use-std")]
+impl std::error::Error for Error {}
+
+impl Error {
+    pub fn fmt<W: fmt::Write>(&self, f: &mut W) -> fmt::Result {
+        match self {
+            Error::Socket(e) | Error::Protocol(e) => e.fmt(f),
+            Error::Encoding(_) => write!(f, "UTF-8 encoding error."),
+            Error::UnsupportedProtocol { min, max } => {
+                write!(
+                    f,
+                    "Server speaks protocol {min} to {max} but we speak {PROTOCOL_VERSION}."
+                )
+            }
+            Error::AuthenticationDenied => write!(f, "Server rejected the device secret."),
+            Error::Checksum => write!(f, "Message was corrupted during transfer."),
+            Error::Decompression(e) => write!(f, "{e}"),
+            Error::KeepAliveTimeout => write!(f, "Server stopped responding."),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Error::fmt(self, f)
+    }
+}
+
+impl From<&Error> for DeviceErrorKind {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::Socket(_) => DeviceErrorKind::Socket,
+            Error::Protocol(_) => DeviceErrorKind::Protocol,
+            Error::Encoding(_) => DeviceErrorKind::Encoding,
+            Error::UnsupportedProtocol { .. } => DeviceErrorKind::UnsupportedProtocol,
+            Error::AuthenticationDenied => DeviceErrorKind::AuthenticationDenied,
+            Error::Checksum => DeviceErrorKind::Checksum,
+            Error::Decompression(_) => DeviceErrorKind::Decompression,
+            Error::KeepAliveTimeout => DeviceErrorKind::KeepAliveTimeout,
+        }
+    }
+}
+
+/// The timer of the platform, with which we give up on a server that stopped talking.
+#[allow(async_fn_in_trait)]
+pub trait Timer {
+    /// The output of `future`, or `None` if it did not finish within `timeout`.
+    async fn with_timeout<F: Future>(&self, timeout: Duration, future: F) -> Option<F::Output>;
+}
+
+/// Where a device keeps the messages it received.
+#[allow(async_fn_in_trait)]
+pub trait Storage {
+    type Messages: MessageStore;
+    type Guard<'a>: DerefMut<Target = Self::Messages>
+    where
+        Self: 'a;
+
+    /// Access the messages to store an update. The firmware shares them with the display, so we only hold the guard
+    /// while an update arrives and not while we wait for the server.
+    async fn lock(&mut self) -> Self::Guard<'_>;
+
+    /// The server does not know the device and wants it to show `code`, so that a user can claim it. Called with
+    /// `None` once the server hands out messages.
+    fn set_pairing_code(&mut self, _code: Option<PairingCode>) {}
+
+    /// The message of `update` was stored and is shown from now on.
+    fn stored(&mut self, _update: &Update) {}
+
+    /// The message `id` was recalled and is no longer shown.
+    fn recalled(&mut self, _id: MessageID) {}
+}
+
+/// The messages a device shows, see `pico/src/messagebuf.rs`.
+pub trait MessageStore {
+    type ImageSlot: ImageSlot;
+
+    /// Show the text of `update`, which was received completely. The pages of a [`UpdateKind::PagedText`] are
+    /// separated by [`crate::consts::PAGE_SEPARATOR`], the style of a [`UpdateKind::StyledText`] is part of `update`.
+    fn add_text(&mut self, update: &Update, text: &str, footer: &str);
+
+    /// A slot that is not shown, into which the next image is received. A failed transfer leaves the shown messages
+    /// alone, since the image is only shown after [`ImageSlot::finish`] and [`Self::commit_image`].
+    fn image_slot(&mut self) -> &mut Self::ImageSlot;
+
+    /// Show the image that was received into the slot from [`Self::image_slot`].
+    fn commit_image(&mut self);
+
+    /// Stop showing the message `id`.
+    fn recall(&mut self, id: MessageID);
+}
+
+pub trait ImageSlot {
+    fn image(&mut self) -> &mut [u8; IMAGE_BUFFER_SIZE];
+
+    /// Remember which update the image belongs to, once all of it was received.
+    fn finish(&mut self, update: &Update, footer: &str);
+}
+
+/// Who the device is and what it can do.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub device_id: DeviceID,
+    pub secret: DeviceSecret,
+    pub firmware_version: FirmwareVersion,
+    /// The optional protocol features the device supports.
+    pub capabilities: Capabilities,
+}
+
+/// What a device remembers between connections.
+#[derive(Debug, Default)]
+pub struct SyncState {
+    /// The latest update we received, so that the server continues after it on the next connection.
+    pub last_message_id: Option<MessageID>,
+    /// Transfers that arrived corrupted since boot, which the device reports in its [`DeviceStatus`].
+    pub checksum_failures: u32,
+    /// Corrupted transfers of the current update. We ask for it again with the same cursor.
+    failed_transfers: u8,
+}
+
+impl SyncState {
+    pub const fn new() -> Self {
+        Self {
+            last_message_id: None,
+            checksum_failures: 0,
+            failed_transfers: 0,
+        }
+    }
+
+    fn advance(&mut self, id: MessageID) {
+        self.last_message_id = Some(self.last_message_id.map_or(id, |last| last.max(id)));
+    }
+}
+
+pub struct Client<S, T> {
+    socket: S,
+    timer: T,
+    device_id: DeviceID,
+    /// The capabilities the server agreed to use on this connection.
+    capabilities: Capabilities,
+}
+
+impl<S: AbstractSocket, T: Timer> Client<S, T> {
+    /// Say hello on a new connection to the server and authenticate.
+    pub async fn connect(socket: S, timer: T, identity: &Identity) -> Result<Self, Error> {
+        let mut client = Self {
+            socket,
+            timer,
+            device_id: identity.device_id,
+            capabilities: Capabilities::NONE,
+        };
+        client.capabilities = client.hello(identity).await?;
+        client.authenticate(&identity.secret).await?;
+        Ok(client)
+    }
+
+    /// Tell the server who we are and learn which capabilities it will use.
+    async fn hello(&mut self, identity: &Identity) -> Result<Capabilities, Error> {
+        self.send(ClientCommand::Hello(Hello {
+            device_id: identity.device_id,
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: identity.firmware_version,
+            capabilities: identity.capabilities,
+        }))
+        .await?;
+
+        let mut reply_buf = [0u8; HelloResult::BUFFER_SIZE];
+        let result = with_timeout(
+            &self.timer,
+            SOCKET_TIMEOUT,
+            HelloResult::receive(&mut reply_buf, &mut self.socket),
+        )
+        .await?;
+        log::info!("HelloResult {result:?}");
+        match result {
+            HelloResult::Accepted(capabilities) => Ok(capabilities),
+            HelloResult::UnsupportedProtocol { min, max } => Err(Error::UnsupportedProtocol { min, max }),
+        }
+    }
+
+    /// Prove to the server that we hold the secret of our device ID, or tell it our secret if it does not know it yet.
+    async fn authenticate(&mut self, secret: &DeviceSecret) -> Result<(), Error> {
+        let mut challenge_buf = [0u8; AuthChallenge::BUFFER_SIZE];
+        let challenge = with_timeout(
+            &self.timer,
+            SOCKET_TIMEOUT,
+            AuthChallenge::receive(&mut challenge_buf, &mut self.socket),
+        )
+        .await?;
+        let command = match challenge {
+            AuthChallenge::Challenge(nonce) => ClientCommand::Authenticate(auth_mac(secret, self.device_id, &nonce)),
+            AuthChallenge::Enroll => {
+                log::info!("Server does not know our secret yet, enrolling.");
+                ClientCommand::Enroll(*secret)
+            }
+        };
+        self.send(command).await?;
+
+        let mut reply_buf = [0u8; AuthResult::BUFFER_SIZE];
+        let result = with_timeout(
+            &self.timer,
+            SOCKET_TIMEOUT,
+            AuthResult::receive(&mut reply_buf, &mut self.socket),
+        )
+        .await?;
+        match result {
+            AuthResult::Authenticated => Ok(()),
+            AuthResult::Denied => Err(Error::AuthenticationDenied),
+        }
+    }
+
+    /// The capabilities the server agreed to use on this connection.
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// End the session and return the connection, e.g. to close it.
+    pub fn into_socket(self) -> S {
+        self.socket
+    }
+
+    /// Tell the server how we are doing. Does nothing if the server did not agree to [`Capabilities::STATUS`].
+    pub async fn report_status(&mut self, status: DeviceStatus) -> Result<(), Error> {
+        if !self.capabilities.contains(Capabilities::STATUS) {
+            return Ok(());
+        }
+        self.send(ClientCommand::Status(status)).await
+    }
+
+    /// Fetch updates until we are up to date and store them in `storage`.
+    ///
+    /// If the server pushes updates to us, we then keep waiting for more and only return once the connection ends.
+    pub async fn sync<St: Storage>(&mut self, storage: &mut St, state: &mut SyncState) -> Result<(), Error> {
+        // Once we are up to date, we keep the connection open if the server pushes new updates to us.
+        let mut waiting = false;
+        loop {
+            let result = if waiting {
+                log::info!("Waiting for updates");
+                self.wait_for_update(state.last_message_id).await?
+            } else {
+                log::info!("Checking for updates");
+                self.request_update(state.last_message_id).await?
+            };
+            match result {
+                RequestUpdateResult::NoUpdate => {
+                    storage.set_pairing_code(None);
+                    if self.capabilities.contains(Capabilities::PUSH) {
+                        waiting = true;
+                        continue;
+                    }
+                    log::info!("No updates for now.");
+                    return Ok(());
+                }
+                // Only sent while we wait, and `wait_for_update` already skips them.
+                RequestUpdateResult::KeepAlive => {}
+                RequestUpdateResult::Unclaimed(pairing_code) => {
+                    log::info!("Device is not registered yet, claim it with /claim {pairing_code}");
+                    storage.set_pairing_code(Some(pairing_code));
+                    return Ok(());
+                }
+                RequestUpdateResult::Recall(recall) => {
+                    log::info!("Recalling message {}.", recall.message_id.0);
+                    storage.lock().await.recall(recall.message_id);
+                    storage.recalled(recall.message_id);
+                    state.advance(recall.id);
+                }
+                RequestUpdateResult::Update(update) => match self.receive_update(&update, storage).await {
+                    Ok(()) => {
+                        storage.set_pairing_code(None);
+                        storage.stored(&update);
+                        state.advance(update.id);
+                        state.failed_transfers = 0;
+                        self.report_delivery(ClientCommand::Ack(update.id)).await?;
+                    }
+                    Err(Error::Checksum) => {
+                        state.checksum_failures += 1;
+                        log::warn!(
+                            "Checksum of message {} does not match, {} corrupted transfers since boot.",
+                            update.id.0,
+                            state.checksum_failures
+                        );
+                        // The whole update was read, so the connection is still usable.
+                        self.report_delivery(ClientCommand::Nack(update.id)).await?;
+                        if state.failed_transfers >= MAX_TRANSFER_RETRIES {
+                            state.failed_transfers = 0;
+                            return Err(Error::Checksum);
+                        }
+                        state.failed_transfers += 1;
+                        log::warn!("Message {} was corrupted, requesting it again.", update.id.0);
+                    }
+                    Err(e) => {
+                        state.failed_transfers = 0;
+                        return Err(e);
+                    }
+                },
+            }
+        }
+    }
+
+    async fn request_update(&mut self, after: Option<MessageID>) -> Result<RequestUpdateResult, Error> {
+        self.send(ClientCommand::RequestUpdate(self.device_id, after)).await?;
+        self.receive_update_result(SOCKET_TIMEOUT).await
+    }
+
+    /// Like [`Self::request_update`], but the server only answers once there is an update.
+    async fn wait_for_update(&mut self, after: Option<MessageID>) -> Result<RequestUpdateResult, Error> {
+        self.send(ClientCommand::WaitForUpdate(self.device_id, after)).await?;
+        loop {
+            let result = self
+                .receive_update_result(KEEP_ALIVE_TIMEOUT)
+                .await
+                .map_err(|e| match e {
+                    Error::Socket(pico::Error::Timeout) => Error::KeepAliveTimeout,
+                    e => e,
+                })?;
+            if result != RequestUpdateResult::KeepAlive {
+                return Ok(result);
+            }
+        }
+    }
+
+    async fn receive_update_result(&mut self, timeout: Duration) -> Result<RequestUpdateResult, Error> {
+        let mut reply_buf = [0u8; RequestUpdateResult::BUFFER_SIZE];
+        let result = with_timeout(
+            &self.timer,
+            timeout,
+            RequestUpdateResult::receive(&mut reply_buf, &mut self.socket),
+        )
+        .await?;
+        log::info!("CheckUpdateResult {result:?}");
+        result.check_valid().map_err(Error::Protocol)?;
+        Ok(result)
+    }
+
+    /// Tell the server whether we stored a message. Servers that do not track deliveries would not understand it.
+    async fn report_delivery(&mut self, command: ClientCommand) -> Result<(), Error> {
+        if !self.capabilities.contains(Capabilities::DELIVERY_ACK) {
+            return Ok(());
+        }
+        self.send(command).await
+    }
+
+    /// Receive the payload and footer of `update` and store the message if they match its checksum.
+    async fn receive_update<St: Storage>(&mut self, update: &Update, storage: &mut St) -> Result<(), Error> {
+        let mut footer_buf = [0u8; FOOTER_BUFFER_SIZE];
+        let footer_buf = &mut footer_buf[..update.footer_len as usize];
+
+        match update.kind {
+            UpdateKind::Text(_) | UpdateKind::PagedText(_) | UpdateKind::StyledText(..) => {
+                let mut text_buf = [0u8; PAGED_TEXT_BUFFER_SIZE];
+                let text_buf = &mut text_buf[..update.kind.size()];
+                self.read_exact(text_buf).await?;
+                self.read_exact(footer_buf).await?;
+                verify_checksum(update, text_buf, footer_buf)?;
+                let text = core::str::from_utf8(text_buf)?;
+                let footer = core::str::from_utf8(footer_buf)?;
+                log::info!("Received text update: {text}");
+                storage.lock().await.add_text(update, text, footer);
+            }
+            UpdateKind::Image | UpdateKind::CompressedImage(_) => {
+                log::info!("Received an image update. Acquiring the message buffer.");
+                let mut messages = storage.lock().await;
+                let slot = messages.image_slot();
+                if let UpdateKind::CompressedImage(_) = update.kind {
+                    self.receive_compressed_payload(update, slot.image()).await?;
+                } else {
+                    self.read_exact(slot.image()).await?;
+                }
+                self.read_exact(footer_buf).await?;
+                verify_checksum(update, slot.image(), footer_buf)?;
+                slot.finish(update, core::str::from_utf8(footer_buf)?);
+                messages.commit_image();
+            }
+        }
+        Ok(())
+    }
+
+    /// Unpack the compressed image of `update` into `image` while it arrives.
+    async fn receive_compressed_payload(&mut self, update: &Update, image: &mut [u8]) -> Result<(), Error> {
+        let mut chunk_buf = [0u8; COMPRESSED_CHUNK_SIZE];
+        let mut decoder = rle::Decoder::new();
+        let mut remaining = update.kind.size();
+
+        while remaining > 0 {
+            let chunk = &mut chunk_buf[..remaining.min(COMPRESSED_CHUNK_SIZE)];
+            self.read_exact(chunk).await?;
+            decoder.decode(chunk, image)?;
+            remaining -= chunk.len();
+        }
+        Ok(decoder.finish(image)?)
+    }
+
+    async fn send(&mut self, command: ClientCommand) -> Result<(), Error> {
+        let mut command_buf = [0u8; ClientCommand::BUFFER_SIZE];
+        with_timeout(
+            &self.timer,
+            SOCKET_TIMEOUT,
+            command.send(&mut command_buf, &mut self.socket),
+        )
+        .await
+    }
+
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        with_timeout(&self.timer, SOCKET_TIMEOUT, self.socket.read_exact(buf)).await
+    }
+}
+
+async fn with_timeout<T: Timer, R>(
+    timer: &T,
+    timeout: Duration,
+    future: impl Future<Output = Result<R, pico::Error>>,
+) -> Result<R, Error> {
+    match timer.with_timeout(timeout, future).await {
+        Some(result) => Ok(result?),
+        None => Err(Error::Socket(pico::Error::Timeout)),
+    }
+}
+
+/// Check the payload and footer we received for `update`. The message is not shown if they were corrupted.
+fn verify_checksum(update: &Update, payload: &[u8], footer: &[u8]) -> Result<(), Error> {
+    if payload_checksum(payload, footer) == update.checksum {
+        Ok(())
+    } else {
+        Err(Error::Checksum)
+    }
+}
+
+#[cfg(all(test, feature = "use-std"))]
+mod tests {
+    use std::{collections::VecDeque, future::Future};
+
+    use super::*;
+    use crate::{
+        consts::{MAX_PAGES, PAGE_SEPARATOR, TEXT_BUFFER_SIZE},
+        protocols::pico::{AuthNonce, Recall, DEVICE_SECRET_LEN},
+        testing::block_on,
+    };
+
+    const DEVICE_ID: DeviceID = DeviceID(0x1234);
+    const NONCE: AuthNonce = [3; 16];
+    const CAPABILITIES: Capabilities = Capabilities::FOOTER
+        .union(Capabilities::IMAGE_COMPRESSION)
+        .union(Capabilities::DELIVERY_ACK);
+
+    fn identity() -> Identity {
+        Identity {
+            device_id: DEVICE_ID,
+            secret: [7; DEVICE_SECRET_LEN],
+            firmware_version: FirmwareVersion {
+                major: 1,
+                minor: 2,
+                patch: 3,
+            },
+            capabilities: CAPABILITIES.union(Capabilities::PUSH),
+        }
+    }
+
+    /// A server that gives the answers of its script, no matter what the client sends, and records what it sent.
+    #[derive(Default)]
+    struct ScriptedServer {
+        script: VecDeque<u8>,
+        received: VecDeque<u8>,
+    }
+
+    impl AbstractSocket for ScriptedServer {
+        async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), pico::Error> {
+            self.script.read_exact(buf).await
+        }
+
+        async fn write_all(&mut self, buf: &[u8]) -> Result<(), pico::Error> {
+            self.received.write_all(buf).await
+        }
+    }
+
+    impl ScriptedServer {
+        /// Accepts the client with `capabilities` and its answer to the challenge.
+        fn accepting(capabilities: Capabilities) -> Self {
+            let mut server = Self::default();
+            server
+                .reply(HelloResult::Accepted(capabilities))
+                .reply(AuthChallenge::Challenge(NONCE))
+                .reply(AuthResult::Authenticated);
+            server
+        }
+
+        fn reply<T: Transmission>(&mut self, value: T) -> &mut Self {
+            block_on(value.send_alloc(&mut self.script)).unwrap();
+            self
+        }
+
+        fn update(&mut self, id: u32, kind: UpdateKind, payload: &[u8], stored: &[u8], footer: &str) -> &mut Self {
+            self.reply(RequestUpdateResult::Update(Update {
+                lifetime_sec: 60,
+                id: MessageID(id),
+                kind,
+                footer_len: footer.len() as u8,
+                checksum: payload_checksum(stored, footer.as_bytes()),
+                urgent: false,
+            }));
+            self.script.extend(payload);
+            self.script.extend(footer.as_bytes());
+            self
+        }
+
+        fn text(&mut self, id: u32, text: &str, footer: &str) -> &mut Self {
+            let kind = UpdateKind::Text(text.len() as u8);
+            self.update(id, kind, text.as_bytes(), text.as_bytes(), footer)
+        }
+
+        /// A text that got corrupted on the way.
+        fn corrupted_text(&mut self, id: u32, text: &str) -> &mut Self {
+            let kind = UpdateKind::Text(text.len() as u8);
+            self.update(id, kind, text.as_bytes(), b"something else", "")
+        }
+
+        /// The commands that the client sent after it authenticated.
+        fn commands(&mut self) -> Vec<ClientCommand> {
+            let mut commands = Vec::new();
+            while let Ok(command) = block_on(ClientCommand::receive_alloc(&mut self.received)) {
+                if !matches!(command, ClientCommand::Hello(_) | ClientCommand::Authenticate(_)) {
+                    commands.push(command);
+                }
+            }
+            commands
+        }
+    }
+
+    /// Times out the waits that take `expire`, as if the server said nothing for that long.
+    struct TestTimer {
+        expire: Option<Duration>,
+    }
+
+    impl Timer for TestTimer {
+        async fn with_timeout<F: Future>(&self, timeout: Duration, future: F) -> Option<F::Output> {
+            if self.expire == Some(timeout) {
+                return None;
+            }
+            Some(future.await)
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    enum Shown {
+        Text { id: u32, text: String, footer: String },
+        Image { id: u32, image: Vec<u8>, footer: String },
+        Recalled(u32),
+    }
+
+    struct TestImage {
+        image: Box<[u8; IMAGE_BUFFER_SIZE]>,
+        finished: Option<(MessageID, String)>,
+    }
+
+    impl ImageSlot for TestImage {
+        fn image(&mut self) -> &mut [u8; IMAGE_BUFFER_SIZE] {
+            &mut self.image
+        }
+
+        fn finish(&mut self, update: &Update, footer: &str) {
+            self.finished = Some((update.id, footer.to_string()));
+        }
+    }
+
+    struct TestStorage {
+        shown: Vec<Shown>,
+        slot: TestImage,
+        pairing_code: Option<PairingCode>,
+        stored: Vec<MessageID>,
+    }
+
+    impl TestStorage {
+        fn new() -> Self {
+            Self {
+                shown: Vec::new(),
+                slot: TestImage {
+                    image: Box::new([0; IMAGE_BUFFER_SIZE]),
+                    finished: None,
+                },
+                pairing_code: None,
+                stored: Vec::new(),
+            }
+        }
+    }
+
+    impl Storage for TestStorage {
+        type Messages = Self;
+        type Guard<'a> = &'a mut Self;
+
+        async fn lock(&mut self) -> &mut Self {
+            self
+        }
+
+        fn set_pairing_code(&mut self, code: Option<PairingCode>) {
+            self.pairing_code = code;
+        }
+
+        fn stored(&mut self, update: &Update) {
+            self.stored.push(update.id);
+        }
+    }
+
+    impl MessageStore for TestStorage {
+        type ImageSlot = TestImage;
+
+        fn add_text(&mut self, update: &Update, text: &str, footer: &str) {
+            self.shown.push(Shown::Text {
+                id: update.id.0,
+                text: text.to_string(),
+                footer: footer.to_string(),
+            });
+        }
+
+        fn image_slot(&mut self) -> &mut TestImage {
+            self.slot.finished = None;
+            &mut self.slot
+        }
+
+        fn commit_image(&mut self) {
+            let (id, footer) = self
+                .slot
+                .finished
+                .take()
+                .expect("image was committed before it was finished");
+            self.shown.push(Shown::Image {
+                id: id.0,
+                image: self.slot.image.to_vec(),
+                footer,
+            });
+        }
+
+        fn recall(&mut self, id: MessageID) {
+            self.shown.push(Shown::Recalled(id.0));
+        }
+    }
+
+    fn connect(server: &mut ScriptedServer) -> Result<Client<&mut ScriptedServer, TestTimer>, Error> {
+        let timer = TestTimer { expire: None };
+        block_on(Client::connect(server, timer, &identity()))
+    }
+
+    /// Connect and sync once, returning what was shown and how the sync ended.
+    fn sync(server: &mut ScriptedServer, state: &mut SyncState) -> (Vec<Shown>, Result<(), Error>) {
+        let mut storage = TestStorage::new();
+        let mut client = connect(server).unwrap();
+        let result = block_on(client.sync(&mut storage, state));
+        (storage.shown, result)
+    }
+
+    fn text(id: u32, text: &str, footer: &str) -> Shown {
+        Shown::Text {
+            id,
+            text: text.to_string(),
+            footer: footer.to_string(),
+        }
+    }
+
+    fn test_image() -> Vec<u8> {
+        (0..IMAGE_BUFFER_SIZE).map(|i| (i / 1000) as u8).collect()
+    }
+
+    #[test]
+    fn says_hello_and_answers_the_challenge() {
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        assert_eq!(connect(&mut server).unwrap().capabilities(), CAPABILITIES);
+
+        let identity = identity();
+        let hello = block_on(ClientCommand::receive_alloc(&mut server.received)).unwrap();
+        assert_eq!(
+            hello,
+            ClientCommand::Hello(Hello {
+                device_id: DEVICE_ID,
+                protocol_version: PROTOCOL_VERSION,
+                firmware_version: identity.firmware_version,
+                capabilities: identity.capabilities,
+            })
+        );
+        let answer = block_on(ClientCommand::receive_alloc(&mut server.received)).unwrap();
+        assert_eq!(
+            answer,
+            ClientCommand::Authenticate(auth_mac(&identity.secret, DEVICE_ID, &NONCE))
+        );
+    }
+
+    #[test]
+    fn enrolls_if_the_server_does_not_know_the_secret() {
+        let mut server = ScriptedServer::default();
+        server
+            .reply(HelloResult::Accepted(CAPABILITIES))
+            .reply(AuthChallenge::Enroll)
+            .reply(AuthResult::Authenticated);
+        connect(&mut server).unwrap();
+
+        assert_eq!(server.commands(), [ClientCommand::Enroll(identity().secret)]);
+    }
+
+    #[test]
+    fn refusals_of_the_server_end_the_connection() {
+        let mut server = ScriptedServer::default();
+        server.reply(HelloResult::UnsupportedProtocol { min: 1, max: 2 });
+        assert!(matches!(
+            connect(&mut server),
+            Err(Error::UnsupportedProtocol { min: 1, max: 2 })
+        ));
+
+        let mut server = ScriptedServer::default();
+        server
+            .reply(HelloResult::Accepted(CAPABILITIES))
+            .reply(AuthChallenge::Challenge(NONCE))
+            .reply(AuthResult::Denied);
+        assert!(matches!(connect(&mut server), Err(Error::AuthenticationDenied)));
+    }
+
+    #[test]
+    fn status_is_only_reported_if_the_server_wants_it() {
+        let status = DeviceStatus {
+            uptime_sec: 10,
+            rssi: None,
+            free_text_slots: 1,
+            free_image_slots: 2,
+            checksum_failures: 0,
+            last_error: None,
+        };
+        for (capabilities, expected) in [
+            (CAPABILITIES, vec![]),
+            (CAPABILITIES | Capabilities::STATUS, vec![ClientCommand::Status(status)]),
+        ] {
+            let mut server = ScriptedServer::accepting(capabilities);
+            let mut client = connect(&mut server).unwrap();
+            block_on(client.report_status(status)).unwrap();
+            assert_eq!(server.commands(), expected);
+        }
+    }
+
+    #[test]
+    fn syncs_texts_images_and_recalls_in_order() {
+        let image = test_image();
+        let compressed = rle::encode(&image);
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        server
+            .text(1, "first", "Alice")
+            .update(
+                2,
+                UpdateKind::CompressedImage(compressed.len() as u16),
+                &compressed,
+                &image,
+                "",
+            )
+            .update(3, UpdateKind::Image, &image, &image, "Bob")
+            .reply(RequestUpdateResult::Recall(Recall {
+                id: MessageID(4),
+                message_id: MessageID(1),
+            }))
+            .reply(RequestUpdateResult::NoUpdate);
+
+        let mut state = SyncState::new();
+        let (shown, result) = sync(&mut server, &mut state);
+
+        result.unwrap();
+        assert_eq!(
+            shown,
+            [
+                text(1, "first", "Alice"),
+                Shown::Image {
+                    id: 2,
+                    image: image.clone(),
+                    footer: String::new(),
+                },
+                Shown::Image {
+                    id: 3,
+                    image,
+                    footer: "Bob".to_string(),
+                },
+                Shown::Recalled(1),
+            ]
+        );
+        assert_eq!(state.last_message_id, Some(MessageID(4)));
+        let request = |after: Option<u32>| ClientCommand::RequestUpdate(DEVICE_ID, after.map(MessageID));
+        assert_eq!(
+            server.commands(),
+            [
+                request(None),
+                ClientCommand::Ack(MessageID(1)),
+                request(Some(1)),
+                ClientCommand::Ack(MessageID(2)),
+                request(Some(2)),
+                ClientCommand::Ack(MessageID(3)),
+                request(Some(3)),
+                request(Some(4)),
+            ]
+        );
+    }
+
+    #[test]
+    fn paged_text_is_stored_with_all_its_pages() {
+        let page = "x".repeat(TEXT_BUFFER_SIZE);
+        let pages = [page.as_str(); MAX_PAGES].join(&PAGE_SEPARATOR.to_string());
+        assert_eq!(pages.len(), PAGED_TEXT_BUFFER_SIZE);
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        server
+            .update(
+                1,
+                UpdateKind::PagedText(pages.len() as u16),
+                pages.as_bytes(),
+                pages.as_bytes(),
+                "",
+            )
+            .reply(RequestUpdateResult::NoUpdate);
+
+        let (shown, result) = sync(&mut server, &mut SyncState::new());
+
+        result.unwrap();
+        assert_eq!(shown, [text(1, &pages, "")]);
+    }
+
+    #[test]
+    fn sync_continues_after_the_last_message() {
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        server.reply(RequestUpdateResult::NoUpdate);
+        let mut state = SyncState::new();
+        state.last_message_id = Some(MessageID(7));
+
+        let (shown, result) = sync(&mut server, &mut state);
+
+        result.unwrap();
+        assert_eq!(shown, []);
+        assert_eq!(
+            server.commands(),
+            [ClientCommand::RequestUpdate(DEVICE_ID, Some(MessageID(7)))]
+        );
+    }
+
+    #[test]
+    fn corrupted_update_is_requested_again() {
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        server
+            .corrupted_text(1, "hello")
+            .text(1, "hello", "")
+            .reply(RequestUpdateResult::NoUpdate);
+
+        let mut state = SyncState::new();
+        let (shown, result) = sync(&mut server, &mut state);
+
+        result.unwrap();
+        assert_eq!(shown, [text(1, "hello", "")]);
+        assert_eq!(state.checksum_failures, 1);
+        assert_eq!(
+            server.commands(),
+            [
+                ClientCommand::RequestUpdate(DEVICE_ID, None),
+                ClientCommand::Nack(MessageID(1)),
+                ClientCommand::RequestUpdate(DEVICE_ID, None),
+                ClientCommand::Ack(MessageID(1)),
+                ClientCommand::RequestUpdate(DEVICE_ID, Some(MessageID(1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_corruption_ends_the_sync() {
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        for _ in 0..=MAX_TRANSFER_RETRIES {
+            server.corrupted_text(1, "hello");
+        }
+
+        let mut state = SyncState::new();
+        let (shown, result) = sync(&mut server, &mut state);
+
+        assert!(matches!(result, Err(Error::Checksum)));
+        assert_eq!(shown, []);
+        assert_eq!(state.checksum_failures, MAX_TRANSFER_RETRIES as u32 + 1);
+        assert_eq!(state.last_message_id, None);
+
+        // The next connection gets all retries again.
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        server
+            .corrupted_text(1, "hello")
+            .text(1, "hello", "")
+            .reply(RequestUpdateResult::NoUpdate);
+        let (shown, result) = sync(&mut server, &mut state);
+        result.unwrap();
+        assert_eq!(shown, [text(1, "hello", "")]);
+    }
+
+    #[test]
+    fn invalid_update_is_a_protocol_error() {
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        let footer = "x".repeat(FOOTER_BUFFER_SIZE + 1);
+        server.text(1, "hello", &footer);
+
+        let (shown, result) = sync(&mut server, &mut SyncState::new());
+
+        assert!(matches!(result, Err(Error::Protocol(pico::Error::Length { .. }))));
+        assert_eq!(shown, []);
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_encoding_error() {
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        server.update(1, UpdateKind::Text(2), &[0xc3, 0x28], &[0xc3, 0x28], "");
+
+        let (shown, result) = sync(&mut server, &mut SyncState::new());
+
+        assert!(matches!(result, Err(Error::Encoding(_))));
+        assert_eq!(shown, []);
+    }
+
+    #[test]
+    fn unclaimed_device_shows_its_pairing_code_until_it_gets_messages() {
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        server.reply(RequestUpdateResult::Unclaimed(PairingCode(123456)));
+        let mut storage = TestStorage::new();
+        let mut state = SyncState::new();
+        let mut client = connect(&mut server).unwrap();
+        block_on(client.sync(&mut storage, &mut state)).unwrap();
+        assert_eq!(storage.pairing_code, Some(PairingCode(123456)));
+
+        let mut server = ScriptedServer::accepting(CAPABILITIES);
+        server.reply(RequestUpdateResult::NoUpdate);
+        let mut client = connect(&mut server).unwrap();
+        block_on(client.sync(&mut storage, &mut state)).unwrap();
+        assert_eq!(storage.pairing_code, None);
+    }
+
+    #[test]
+    fn push_waits_for_updates_on_the_same_connection() {
+        let mut server = ScriptedServer::accepting(CAPABILITIES | Capabilities::PUSH);
+        server
+            .reply(RequestUpdateResult::NoUpdate)
+            .reply(RequestUpdateResult::KeepAlive)
+            .reply(RequestUpdateResult::KeepAlive)
+            .text(1, "pushed", "");
+        let mut storage = TestStorage::new();
+        let mut state = SyncState::new();
+        let mut client = connect(&mut server).unwrap();
+
+        // We only stop waiting once the connection ends.
+        let result = block_on(client.sync(&mut storage, &mut state));
+
+        assert!(matches!(result, Err(Error::Socket(pico::Error::Eof))));
+        assert_eq!(storage.shown, [text(1, "pushed", "")]);
+        assert_eq!(storage.stored, [MessageID(1)]);
+        assert_eq!(
+            server.commands(),
+            [
+                ClientCommand::RequestUpdate(DEVICE_ID, None),
+                ClientCommand::WaitForUpdate(DEVICE_ID, None),
+                ClientCommand::Ack(MessageID(1)),
+                ClientCommand::WaitForUpdate(DEVICE_ID, Some(MessageID(1))),
+            ]
+        );
+    }
+
+    #[test]
+    fn silent_server_while_waiting_is_a_keep_alive_timeout() {
+        let mut server = ScriptedServer::accepting(CAPABILITIES | Capabilities::PUSH);
+        server.reply(RequestUpdateResult::NoUpdate);
+        let timer = TestTimer {
+            expire: Some(KEEP_ALIVE_TIMEOUT),
+        };
+        let mut client = block_on(Client::connect(&mut server, timer, &identity())).unwrap();
+
+        let result = block_on(client.sync(&mut TestStorage::new(), &mut SyncState::new()));
+
+        assert!(matches!(result, Err(Error::KeepAliveTimeout)));
+    }
+}
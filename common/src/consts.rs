@@ -6,6 +6,18 @@ pub const TEXT_LINES: usize = 8;
 pub const TEXT_COLUMNS: usize = 17;
 pub const TEXT_LENGTH: TextLength = TEXT_LINES as u8 * TEXT_COLUMNS as u8;
 pub const TEXT_BUFFER_SIZE: usize = TEXT_LENGTH as usize;
+/// Texts that do not fit on the display are split into at most this many pages.
+pub const MAX_PAGES: usize = 4;
+/// The last line of a page shows which page it is, so the text of a page has one line less.
+pub const PAGE_LINES: usize = TEXT_LINES - 1;
+/// Separates the pages of a `PagedText` update.
+pub const PAGE_SEPARATOR: char = '\x0C';
+/// Each page fits into a text buffer, with a separator between two pages.
+pub const PAGED_TEXT_BUFFER_SIZE: usize = MAX_PAGES * TEXT_BUFFER_SIZE + MAX_PAGES - 1;
+/// The footer of a message, e.g. its author, takes one line of text.
+pub const FOOTER_LENGTH: usize = TEXT_COLUMNS;
+/// Footers are UTF-8, so we leave room for some multi-byte characters.
+pub const FOOTER_BUFFER_SIZE: usize = 2 * FOOTER_LENGTH;
 
 pub const IMAGE_WIDTH: usize = 160;
 pub const IMAGE_HEIGHT: usize = 128;
@@ -1,5 +1,10 @@
 #![cfg_attr(not(feature = "use-std"), no_std)]
 
+#[cfg(feature = "client")]
+pub mod client;
 pub mod consts;
 pub mod protocols;
+pub mod rle;
+#[cfg(all(test, feature = "use-std"))]
+mod testing;
 pub mod types;
@@ -1,33 +1,70 @@
-use core::fmt;
+use core::{fmt, ops::BitOr};
 
+use hmac::{Hmac, Mac};
 use postcard::{self, experimental::max_size::MaxSize};
 use serde::{Deserialize, Serialize};
-
-use crate::consts::{IMAGE_BUFFER_SIZE, TEXT_BUFFER_SIZE};
-use crate::types::{DeviceID, MessageID, TextLength};
+use sha2::Sha256;
+
+use crate::{
+    consts::{
+        FOOTER_BUFFER_SIZE, IMAGE_BUFFER_SIZE, PAGED_TEXT_BUFFER_SIZE, TEXT_BUFFER_SIZE, TEXT_COLUMNS, TEXT_LINES,
+    },
+    types::{DeviceID, FirmwareVersion, MessageID, PairingCode, TextLength},
+};
+
+/// Version of the protocol between devices and the server.
+/// Increase it whenever a change would break devices or servers that still speak the previous version.
+/// Optional features that do not break older peers should be negotiated through [`Capabilities`] instead.
+pub const PROTOCOL_VERSION: u16 = 5;
+
+/// While a device waits for updates with [`ClientCommand::WaitForUpdate`], the server sends
+/// [`RequestUpdateResult::KeepAlive`] at this interval so that both sides notice a dead connection.
+pub const KEEP_ALIVE_INTERVAL_SEC: u64 = 30;
+
+/// Length of the secret with which a device proves its identity.
+pub const DEVICE_SECRET_LEN: usize = 32;
+/// Length of the random challenge the server sends to a device.
+pub const AUTH_NONCE_LEN: usize = 16;
+
+pub type DeviceSecret = [u8; DEVICE_SECRET_LEN];
+pub type AuthNonce = [u8; AUTH_NONCE_LEN];
+/// HMAC-SHA256 of the device ID and the nonce, keyed with the device secret. See [`auth_mac`].
+pub type AuthMac = [u8; 32];
 
 #[derive(Debug)]
 pub enum Error {
-    Length { val: usize, max: usize },
-    Postcard(postcard::Error),
-
+    /// A value in a message is longer than the protocol allows.
+    Length {
+        val: usize,
+        max: usize,
+    },
+    /// A frame announced more data than the message it carries can have.
+    Oversize {
+        len: usize,
+        max: usize,
+    },
+    Encode(postcard::Error),
+    /// The data of a frame is not a valid message.
+    Decode(postcard::Error),
+    /// The peer closed the connection.
+    Eof,
+    /// The socket gave up waiting for data.
+    Timeout,
     Socket,
 }
 
 #[cfg(feature = "use-std")]
 impl std::error::Error for Error {}
 
-impl From<postcard::Error> for Error {
-    fn from(value: postcard::Error) -> Self {
-        Self::Postcard(value)
-    }
-}
-
 impl Error {
     pub fn fmt<W: fmt::Write>(&self, f: &mut W) -> fmt::Result {
         match self {
             Error::Length { val, max } => write!(f, "Length is {val} but max is {max}."),
-            Error::Postcard(error) => write!(f, "Serialization error: {}", error),
+            Error::Oversize { len, max } => write!(f, "Frame of {len} bytes is larger than {max} bytes."),
+            Error::Encode(error) => write!(f, "Serialization error: {}", error),
+            Error::Decode(error) => write!(f, "Deserialization error: {}", error),
+            Error::Eof => write!(f, "Connection closed"),
+            Error::Timeout => write!(f, "Socket timed out"),
             Error::Socket => write!(f, "Socket error"),
         }
     }
@@ -39,57 +76,384 @@ impl fmt::Display for Error {
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, MaxSize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub enum UpdateKind {
     Image,
     Text(TextLength),
+    /// An image compressed with [`crate::rle`], followed by this many bytes. Only sent to devices with
+    /// [`Capabilities::IMAGE_COMPRESSION`].
+    CompressedImage(u16),
+    /// A text that does not fit on the display, split into pages by [`crate::consts::PAGE_SEPARATOR`] and followed
+    /// by this many bytes. Only sent to devices with [`Capabilities::PAGING`].
+    PagedText(u16),
+    /// A text that is drawn in `TextStyle` instead of black on white, followed by this many bytes.
+    /// Only sent to devices with [`Capabilities::STYLED_TEXT`].
+    StyledText(TextStyle, TextLength),
 }
 
 impl UpdateKind {
+    /// The number of payload bytes that follow the update.
     pub fn size(&self) -> usize {
         match *self {
             UpdateKind::Image => IMAGE_BUFFER_SIZE,
             UpdateKind::Text(len) => len as usize,
+            UpdateKind::CompressedImage(len) => len as usize,
+            UpdateKind::PagedText(len) => len as usize,
+            UpdateKind::StyledText(_, len) => len as usize,
+        }
+    }
+}
+
+/// How a [`UpdateKind::StyledText`] is drawn. Styles apply to the whole text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct TextStyle {
+    pub foreground: Color,
+    pub background: Color,
+    pub alignment: TextAlignment,
+    pub font: FontSize,
+    /// The first line is a heading in a bold font.
+    pub heading: bool,
+}
+
+impl Default for TextStyle {
+    /// Like texts without a style: black on white, centered, in the normal font.
+    fn default() -> Self {
+        Self {
+            foreground: Color::BLACK,
+            background: Color::WHITE,
+            alignment: TextAlignment::Center,
+            font: FontSize::Normal,
+            heading: false,
+        }
+    }
+}
+
+/// A color as the display shows it, in RGB565.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct Color(pub u16);
+
+impl Color {
+    pub const BLACK: Self = Self(0x0000);
+    pub const WHITE: Self = Self(0xffff);
+    pub const GRAY: Self = Self(0x8410);
+    pub const RED: Self = Self(0xf800);
+    pub const ORANGE: Self = Self(0xfd20);
+    pub const YELLOW: Self = Self(0xffe0);
+    pub const GREEN: Self = Self(0x07e0);
+    pub const CYAN: Self = Self(0x07ff);
+    pub const BLUE: Self = Self(0x001f);
+    pub const MAGENTA: Self = Self(0xf81f);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum TextAlignment {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum FontSize {
+    Small,
+    Normal,
+    Large,
+}
+
+impl FontSize {
+    /// How many characters fit on a line of the display.
+    pub const fn columns(self) -> usize {
+        match self {
+            FontSize::Small => 25,
+            FontSize::Normal => TEXT_COLUMNS,
+            FontSize::Large => 15,
+        }
+    }
+
+    /// How many lines fit on the display.
+    pub const fn lines(self) -> usize {
+        match self {
+            FontSize::Small => 9,
+            FontSize::Normal => TEXT_LINES,
+            FontSize::Large => 6,
         }
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, MaxSize)]
+const _: () = assert!(FOOTER_BUFFER_SIZE <= u8::MAX as usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub struct Update {
     pub lifetime_sec: u32,
     pub id: MessageID,
     pub kind: UpdateKind,
+    /// Length of the UTF-8 footer that is sent after the payload, e.g. the name of the author. Zero for no footer.
+    pub footer_len: u8,
+    /// [`payload_checksum`] of the payload and the footer, so that the device can drop corrupted transfers.
+    pub checksum: u32,
+    /// The device should interrupt the other messages and show this one until someone acknowledges it on the device.
+    /// Only set for devices with [`Capabilities::PRIORITY`].
+    pub urgent: bool,
+}
+
+/// CRC32 of the payload, as the device stores it, followed by the footer.
+pub fn payload_checksum(payload: &[u8], footer: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(payload);
+    hasher.update(footer);
+    hasher.finalize()
+}
+
+/// Tells the device to drop a message it may have stored already.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct Recall {
+    /// Recalls are ordered together with updates, so the device uses this ID as a cursor just like [`Update::id`].
+    pub id: MessageID,
+    pub message_id: MessageID,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, MaxSize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub enum RequestUpdateResult {
     NoUpdate,
     Update(Update),
+    Recall(Recall),
+    /// The device is not registered yet. It should show the code so that a user can claim it.
+    Unclaimed(PairingCode),
+    /// There is still no update, the device should keep waiting. Only sent after [`ClientCommand::WaitForUpdate`].
+    KeepAlive,
+}
+
+/// Optional features of the protocol. The server only uses the ones that the device announced in its [`Hello`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+#[serde(transparent)]
+#[repr(transparent)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Self = Self(0);
+    /// The device shows the footer that follows the payload of an [`Update`].
+    pub const FOOTER: Self = Self(1 << 0);
+    /// The device can decompress images.
+    pub const IMAGE_COMPRESSION: Self = Self(1 << 1);
+    /// The device shows texts that do not fit on the display page by page, see [`UpdateKind::PagedText`].
+    pub const PAGING: Self = Self(1 << 2);
+    /// The device shows urgent messages before all others.
+    pub const PRIORITY: Self = Self(1 << 3);
+    /// The device acknowledges each [`Update`] with [`ClientCommand::Ack`] or [`ClientCommand::Nack`].
+    pub const DELIVERY_ACK: Self = Self(1 << 4);
+    /// The device keeps the connection open with [`ClientCommand::WaitForUpdate`], so that updates are pushed to it.
+    pub const PUSH: Self = Self(1 << 5);
+    /// The device reports its [`DeviceStatus`] after authenticating.
+    pub const STATUS: Self = Self(1 << 6);
+    /// The device draws texts in the colors, alignment and font of a [`UpdateKind::StyledText`].
+    pub const STYLED_TEXT: Self = Self(1 << 7);
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// The capabilities that are in both sets.
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// The capabilities that are in either set. Same as `|`, but usable in constants.
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        self.union(rhs)
+    }
+}
+
+/// The first command of a device on each connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct Hello {
+    pub device_id: DeviceID,
+    pub protocol_version: u16,
+    pub firmware_version: FirmwareVersion,
+    pub capabilities: Capabilities,
+}
+
+/// Reply to a [`Hello`].
+///
+/// Devices must be able to read this reply whatever protocol version the server speaks,
+/// so existing variants must never change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum HelloResult {
+    /// The server uses only these capabilities, which the device and the server both support.
+    Accepted(Capabilities),
+    /// The server does not speak the device's protocol version. It supports the versions from `min` to `max`.
+    UnsupportedProtocol { min: u16, max: u16 },
+}
+
+/// Sent by the server right after it accepted a [`Hello`]. The device must answer before it may request updates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum AuthChallenge {
+    /// Prove that you know your secret by answering with [`ClientCommand::Authenticate`] and the [`auth_mac`] of this nonce.
+    Challenge(AuthNonce),
+    /// The server does not know the device's secret yet. Answer with [`ClientCommand::Enroll`] so that it can
+    /// verify the device on later connections.
+    Enroll,
+}
+
+/// The kinds of errors a device runs into. It reports the most recent one in its [`DeviceStatus`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum DeviceErrorKind {
+    WifiConnect,
+    WifiConfiguration,
+    DeviceSecretConfiguration,
+    StaticData,
+    ServerConnect,
+    Socket,
+    Encoding,
+    Protocol,
+    UnsupportedProtocol,
+    AuthenticationDenied,
+    Checksum,
+    Decompression,
+    KeepAliveTimeout,
+}
+
+impl fmt::Display for DeviceErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = match self {
+            DeviceErrorKind::WifiConnect => "cannot connect to WiFi",
+            DeviceErrorKind::WifiConfiguration => "WiFi not configured",
+            DeviceErrorKind::DeviceSecretConfiguration => "device secret not configured",
+            DeviceErrorKind::StaticData => "cannot read static data",
+            DeviceErrorKind::ServerConnect => "cannot connect to server",
+            DeviceErrorKind::Socket => "socket error",
+            DeviceErrorKind::Encoding => "invalid UTF-8 from server",
+            DeviceErrorKind::Protocol => "invalid message from server",
+            DeviceErrorKind::UnsupportedProtocol => "unsupported protocol version",
+            DeviceErrorKind::AuthenticationDenied => "authentication denied",
+            DeviceErrorKind::Checksum => "corrupted transfer",
+            DeviceErrorKind::Decompression => "invalid compressed image",
+            DeviceErrorKind::KeepAliveTimeout => "server stopped responding",
+        };
+        f.write_str(text)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct DeviceError {
+    pub kind: DeviceErrorKind,
+    /// The uptime of the device when the error happened.
+    pub uptime_sec: u32,
+}
+
+/// How a device is doing. Sent with [`ClientCommand::Status`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub struct DeviceStatus {
+    pub uptime_sec: u32,
+    /// Signal strength of the WiFi in dBm. `None` if the device could not measure it.
+    pub rssi: Option<i16>,
+    /// How many more texts the device can show before it replaces the oldest one.
+    pub free_text_slots: u8,
+    /// How many more images the device can show before it replaces the oldest one.
+    pub free_image_slots: u8,
+    /// Transfers that arrived corrupted since boot.
+    pub checksum_failures: u32,
+    pub last_error: Option<DeviceError>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, MaxSize)]
+/// Reply to [`ClientCommand::Authenticate`] and [`ClientCommand::Enroll`]. The server closes the connection after a denial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
+pub enum AuthResult {
+    Authenticated,
+    Denied,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, MaxSize)]
 pub enum ClientCommand {
     RequestUpdate(DeviceID, Option<MessageID>),
+    /// Must be sent before any other command.
+    Hello(Hello),
+    /// Answer to [`AuthChallenge::Challenge`].
+    Authenticate(AuthMac),
+    /// Answer to [`AuthChallenge::Enroll`].
+    Enroll(DeviceSecret),
+    /// The device stored the message and shows it.
+    Ack(MessageID),
+    /// The device dropped the message because it arrived corrupted. It requests it again afterwards.
+    Nack(MessageID),
+    /// Like [`ClientCommand::RequestUpdate`], but instead of [`RequestUpdateResult::NoUpdate`] the server
+    /// answers with the next update as soon as there is one, sending keep-alives in the meantime.
+    WaitForUpdate(DeviceID, Option<MessageID>),
+    /// Report how the device is doing. Sent after authenticating if the server agreed to [`Capabilities::STATUS`].
+    Status(DeviceStatus),
+}
+
+fn auth_hmac(secret: &DeviceSecret, device_id: DeviceID, nonce: &AuthNonce) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&device_id.0.to_le_bytes());
+    mac.update(nonce);
+    mac
+}
+
+/// The answer of the device `device_id` to the challenge `nonce`.
+pub fn auth_mac(secret: &DeviceSecret, device_id: DeviceID, nonce: &AuthNonce) -> AuthMac {
+    auth_hmac(secret, device_id, nonce).finalize().into_bytes().into()
+}
+
+/// Check the answer of a device in constant time.
+pub fn verify_auth_mac(secret: &DeviceSecret, device_id: DeviceID, nonce: &AuthNonce, mac: &AuthMac) -> bool {
+    auth_hmac(secret, device_id, nonce).verify_slice(mac).is_ok()
 }
 
 impl RequestUpdateResult {
     pub fn check_valid(&self) -> Result<(), Error> {
         match self {
-            RequestUpdateResult::NoUpdate => Ok(()),
-            RequestUpdateResult::Update(message_update) => match message_update.kind {
-                UpdateKind::Image => Ok(()),
-                UpdateKind::Text(size) => {
-                    let size = size as usize;
-                    if size > TEXT_BUFFER_SIZE {
-                        Err(Error::Length {
-                            val: size,
-                            max: TEXT_BUFFER_SIZE,
-                        })
-                    } else {
-                        Ok(())
+            RequestUpdateResult::NoUpdate
+            | RequestUpdateResult::Recall(_)
+            | RequestUpdateResult::Unclaimed(_)
+            | RequestUpdateResult::KeepAlive => Ok(()),
+            RequestUpdateResult::Update(message_update) => {
+                let footer_len = message_update.footer_len as usize;
+                if footer_len > FOOTER_BUFFER_SIZE {
+                    return Err(Error::Length {
+                        val: footer_len,
+                        max: FOOTER_BUFFER_SIZE,
+                    });
+                }
+
+                match message_update.kind {
+                    UpdateKind::Image => Ok(()),
+                    // We only compress images if that makes them smaller.
+                    UpdateKind::CompressedImage(size) if size as usize >= IMAGE_BUFFER_SIZE => Err(Error::Length {
+                        val: size as usize,
+                        max: IMAGE_BUFFER_SIZE,
+                    }),
+                    UpdateKind::CompressedImage(_) => Ok(()),
+                    UpdateKind::Text(size) | UpdateKind::StyledText(_, size) => {
+                        let size = size as usize;
+                        if size > TEXT_BUFFER_SIZE {
+                            Err(Error::Length {
+                                val: size,
+                                max: TEXT_BUFFER_SIZE,
+                            })
+                        } else {
+                            Ok(())
+                        }
+                    }
+                    UpdateKind::PagedText(size) => {
+                        let size = size as usize;
+                        if size > PAGED_TEXT_BUFFER_SIZE {
+                            Err(Error::Length {
+                                val: size,
+                                max: PAGED_TEXT_BUFFER_SIZE,
+                            })
+                        } else {
+                            Ok(())
+                        }
                     }
                 }
-            },
+            }
         }
     }
 }
@@ -101,19 +465,36 @@ pub mod serialization {
 
     type Length = u16;
 
-    // TODO should be removable
+    /// The first bytes of every frame.
+    pub const FRAME_MAGIC: [u8; 2] = *b"RM";
+
+    /// A connection over which messages are sent, so that the protocol works the same on devices, the server and in tests.
     #[allow(async_fn_in_trait)]
-    trait AbstractSocket {
+    pub trait AbstractSocket {
         async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error>;
         async fn write_all(&mut self, buf: &[u8]) -> Result<(), Error>;
     }
 
+    /// Lets a caller keep the socket, e.g. to look at it after a [`crate::client::Client`] is done with it.
+    impl<S: AbstractSocket> AbstractSocket for &mut S {
+        async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+            (**self).read_exact(buf).await
+        }
+
+        async fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+            (**self).write_all(buf).await
+        }
+    }
+
     #[cfg(all(feature = "embedded-io-async", feature = "embassy-net"))]
     impl AbstractSocket for embassy_net::tcp::TcpSocket<'_> {
         async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
             embedded_io_async::Read::read_exact(self, buf)
                 .await
-                .map_err(|_| Error::Socket)
+                .map_err(|e| match e {
+                    embedded_io_async::ReadExactError::UnexpectedEof => Error::Eof,
+                    embedded_io_async::ReadExactError::Other(_) => Error::Socket,
+                })
         }
 
         async fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
@@ -123,40 +504,69 @@ pub mod serialization {
         }
     }
 
+    #[cfg(feature = "use-std")]
+    fn io_error(e: std::io::Error) -> Error {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::Eof,
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => Error::Timeout,
+            _ => Error::Socket,
+        }
+    }
+
     #[cfg(feature = "use-std")]
     impl AbstractSocket for std::net::TcpStream {
         async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
-            std::io::Read::read_exact(self, buf).map_err(|_| Error::Socket)?;
-            Ok(())
+            std::io::Read::read_exact(self, buf).map_err(io_error)
         }
 
         async fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
-            std::io::Write::write_all(self, buf).map_err(|_| Error::Socket)
+            std::io::Write::write_all(self, buf).map_err(io_error)
         }
     }
 
+    #[cfg(feature = "tokio")]
+    async fn tokio_read_exact<S: tokio::io::AsyncRead + Unpin>(socket: &mut S, buf: &mut [u8]) -> Result<(), Error> {
+        tokio::io::AsyncReadExt::read_exact(socket, buf)
+            .await
+            .map_err(io_error)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn tokio_write_all<S: tokio::io::AsyncWrite + Unpin>(socket: &mut S, buf: &[u8]) -> Result<(), Error> {
+        tokio::io::AsyncWriteExt::write_all(socket, buf).await.map_err(io_error)
+    }
+
     #[cfg(feature = "tokio")]
     impl AbstractSocket for tokio::net::TcpStream {
         async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
-            tokio::io::AsyncReadExt::read_exact(self, buf)
-                .await
-                .map_err(|_| Error::Socket)?;
-            Ok(())
+            tokio_read_exact(self, buf).await
         }
 
         async fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
-            tokio::io::AsyncWriteExt::write_all(self, buf)
-                .await
-                .map_err(|_| Error::Socket)
+            tokio_write_all(self, buf).await
+        }
+    }
+
+    /// An in-memory connection, see [`tokio::io::duplex`]. Lets tests run a device and the server against each other.
+    #[cfg(feature = "tokio")]
+    impl AbstractSocket for tokio::io::DuplexStream {
+        async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+            tokio_read_exact(self, buf).await
+        }
+
+        async fn write_all(&mut self, buf: &[u8]) -> Result<(), Error> {
+            tokio_write_all(self, buf).await
         }
     }
 
-    /// Serialize values with a length prefix.
-    /// +-------------+----------------+
-    /// | Length: u16 | data: u8       |
-    /// +-------------+----------------+
+    /// Serialize values into frames with a fixed little-endian header.
+    /// +-------------+----------------+----------+
+    /// | magic: "RM" | length: u16 LE | data: u8 |
+    /// +-------------+----------------+----------+
+    /// The [`FRAME_MAGIC`] marks the start of a frame, so that a receiver can find the next frame after a bad one.
     trait SerDe: Serialize + DeserializeOwned + MaxSize {
-        const DATA_START: usize = size_of::<Length>();
+        const DATA_START: usize = FRAME_MAGIC.len() + size_of::<Length>();
         const SERIALIZED_SIZE: usize = Self::DATA_START + Self::POSTCARD_MAX_SIZE;
         // Statically check that the POSTCARD_MAX_SIZE constant can be encoded in the length field of our messages.
         const _ASSERT_LENGTH_REPRESENTABLE: () = assert!(Self::POSTCARD_MAX_SIZE <= Length::MAX as usize);
@@ -166,22 +576,24 @@ pub mod serialization {
             // TODO There is an unstable option for complex generic const expressions but I'd wait until it's stabilized https://github.com/rust-lang/rust/issues/76560
             debug_assert!(buf.len() == Self::SERIALIZED_SIZE);
 
-            let result = postcard::to_slice(self, &mut buf[Self::DATA_START..])?;
+            let result = postcard::to_slice(self, &mut buf[Self::DATA_START..]).map_err(Error::Encode)?;
             let data_len = result.len();
             let total_len = Self::DATA_START + data_len;
-            let length_bytes = (data_len as Length).to_ne_bytes();
-            buf[..Self::DATA_START].copy_from_slice(&length_bytes);
+            buf[..FRAME_MAGIC.len()].copy_from_slice(&FRAME_MAGIC);
+            buf[FRAME_MAGIC.len()..Self::DATA_START].copy_from_slice(&(data_len as Length).to_le_bytes());
             Ok(&mut buf[..total_len])
         }
 
         fn from_bytes(buf: &[u8]) -> Result<Self, Error> {
-            let result = postcard::from_bytes(buf)?;
-            Ok(result)
+            postcard::from_bytes(buf).map_err(Error::Decode)
         }
     }
 
     impl SerDe for ClientCommand {}
     impl SerDe for RequestUpdateResult {}
+    impl SerDe for HelloResult {}
+    impl SerDe for AuthChallenge {}
+    impl SerDe for AuthResult {}
 
     #[allow(async_fn_in_trait, private_bounds)]
     pub trait Transmission: SerDe {
@@ -208,23 +620,308 @@ pub mod serialization {
             Self::receive(&mut buf, socket).await
         }
 
+        /// Receive the next frame. Bytes before its magic are skipped, e.g. the rest of a frame that failed before.
         async fn receive<S: AbstractSocket>(buf: &mut [u8], socket: &mut S) -> Result<Self, Error> {
             assert!(buf.len() == Self::BUFFER_SIZE);
 
-            socket.read_exact(&mut buf[..Self::DATA_START]).await?;
-            let data_len = Length::from_ne_bytes([buf[0], buf[1]]) as usize;
-            if Self::DATA_START + data_len > Self::BUFFER_SIZE {
-                return Err(Error::Length {
-                    val: data_len,
+            let mut magic = [0u8; FRAME_MAGIC.len()];
+            socket.read_exact(&mut magic).await?;
+            while magic != FRAME_MAGIC {
+                magic.copy_within(1.., 0);
+                socket.read_exact(&mut magic[FRAME_MAGIC.len() - 1..]).await?;
+            }
+
+            let mut length = [0u8; size_of::<Length>()];
+            socket.read_exact(&mut length).await?;
+            let data_len = Length::from_le_bytes(length) as usize;
+            // We do not skip the announced data since the length may be garbage. The next receive finds the next magic.
+            if data_len > Self::POSTCARD_MAX_SIZE {
+                return Err(Error::Oversize {
+                    len: data_len,
                     max: Self::POSTCARD_MAX_SIZE,
                 });
             }
             let data_buf = &mut buf[Self::DATA_START..(Self::DATA_START + data_len)];
             socket.read_exact(data_buf).await?;
-            Self::from_bytes(&data_buf)
+            Self::from_bytes(data_buf)
         }
     }
 
     impl Transmission for ClientCommand {}
     impl Transmission for RequestUpdateResult {}
+    impl Transmission for HelloResult {}
+    impl Transmission for AuthChallenge {}
+    impl Transmission for AuthResult {}
+
+    #[cfg(all(test, feature = "use-std"))]
+    mod tests {
+        use std::collections::VecDeque;
+
+        use proptest::prelude::*;
+
+        use super::*;
+        use crate::testing::block_on;
+
+        fn round_trip<T: Transmission + PartialEq + fmt::Debug>(value: T) -> Result<(), TestCaseError> {
+            let mut socket = VecDeque::new();
+            block_on(value.send_alloc(&mut socket)).unwrap();
+            let received = block_on(T::receive_alloc(&mut socket)).unwrap();
+            prop_assert_eq!(received, value);
+            prop_assert!(socket.is_empty());
+            Ok(())
+        }
+
+        fn frame(data: &[u8]) -> Vec<u8> {
+            let mut frame = FRAME_MAGIC.to_vec();
+            frame.extend_from_slice(&(data.len() as Length).to_le_bytes());
+            frame.extend_from_slice(data);
+            frame
+        }
+
+        fn message_id() -> impl Strategy<Value = MessageID> {
+            any::<u32>().prop_map(MessageID)
+        }
+
+        fn device_id() -> impl Strategy<Value = DeviceID> {
+            any::<u32>().prop_map(DeviceID)
+        }
+
+        fn capabilities() -> impl Strategy<Value = Capabilities> {
+            any::<u32>().prop_map(Capabilities)
+        }
+
+        fn device_error_kind() -> impl Strategy<Value = DeviceErrorKind> {
+            prop_oneof![
+                Just(DeviceErrorKind::WifiConnect),
+                Just(DeviceErrorKind::WifiConfiguration),
+                Just(DeviceErrorKind::DeviceSecretConfiguration),
+                Just(DeviceErrorKind::StaticData),
+                Just(DeviceErrorKind::ServerConnect),
+                Just(DeviceErrorKind::Socket),
+                Just(DeviceErrorKind::Encoding),
+                Just(DeviceErrorKind::Protocol),
+                Just(DeviceErrorKind::UnsupportedProtocol),
+                Just(DeviceErrorKind::AuthenticationDenied),
+                Just(DeviceErrorKind::Checksum),
+                Just(DeviceErrorKind::Decompression),
+                Just(DeviceErrorKind::KeepAliveTimeout),
+            ]
+        }
+
+        fn device_status() -> impl Strategy<Value = DeviceStatus> {
+            let last_error =
+                (device_error_kind(), any::<u32>()).prop_map(|(kind, uptime_sec)| DeviceError { kind, uptime_sec });
+            (
+                any::<u32>(),
+                any::<Option<i16>>(),
+                any::<u8>(),
+                any::<u8>(),
+                any::<u32>(),
+                proptest::option::of(last_error),
+            )
+                .prop_map(
+                    |(uptime_sec, rssi, free_text_slots, free_image_slots, checksum_failures, last_error)| {
+                        DeviceStatus {
+                            uptime_sec,
+                            rssi,
+                            free_text_slots,
+                            free_image_slots,
+                            checksum_failures,
+                            last_error,
+                        }
+                    },
+                )
+        }
+
+        fn client_command() -> impl Strategy<Value = ClientCommand> {
+            let hello = (device_id(), any::<u16>(), any::<(u16, u16, u16)>(), capabilities()).prop_map(
+                |(device_id, protocol_version, (major, minor, patch), capabilities)| Hello {
+                    device_id,
+                    protocol_version,
+                    firmware_version: FirmwareVersion { major, minor, patch },
+                    capabilities,
+                },
+            );
+            prop_oneof![
+                (device_id(), proptest::option::of(message_id()))
+                    .prop_map(|(id, after)| ClientCommand::RequestUpdate(id, after)),
+                hello.prop_map(ClientCommand::Hello),
+                any::<AuthMac>().prop_map(ClientCommand::Authenticate),
+                any::<DeviceSecret>().prop_map(ClientCommand::Enroll),
+                message_id().prop_map(ClientCommand::Ack),
+                message_id().prop_map(ClientCommand::Nack),
+                (device_id(), proptest::option::of(message_id()))
+                    .prop_map(|(id, after)| ClientCommand::WaitForUpdate(id, after)),
+                device_status().prop_map(ClientCommand::Status),
+            ]
+        }
+
+        fn text_style() -> impl Strategy<Value = TextStyle> {
+            let alignment = prop_oneof![
+                Just(TextAlignment::Left),
+                Just(TextAlignment::Center),
+                Just(TextAlignment::Right)
+            ];
+            let font = prop_oneof![Just(FontSize::Small), Just(FontSize::Normal), Just(FontSize::Large)];
+            (any::<(u16, u16)>(), alignment, font, any::<bool>()).prop_map(
+                |((foreground, background), alignment, font, heading)| TextStyle {
+                    foreground: Color(foreground),
+                    background: Color(background),
+                    alignment,
+                    font,
+                    heading,
+                },
+            )
+        }
+
+        fn request_update_result() -> impl Strategy<Value = RequestUpdateResult> {
+            let kind = prop_oneof![
+                Just(UpdateKind::Image),
+                any::<TextLength>().prop_map(UpdateKind::Text),
+                any::<u16>().prop_map(UpdateKind::CompressedImage),
+                any::<u16>().prop_map(UpdateKind::PagedText),
+                (text_style(), any::<TextLength>()).prop_map(|(style, len)| UpdateKind::StyledText(style, len)),
+            ];
+            let update = (
+                any::<u32>(),
+                message_id(),
+                kind,
+                any::<u8>(),
+                any::<u32>(),
+                any::<bool>(),
+            )
+                .prop_map(|(lifetime_sec, id, kind, footer_len, checksum, urgent)| Update {
+                    lifetime_sec,
+                    id,
+                    kind,
+                    footer_len,
+                    checksum,
+                    urgent,
+                });
+            let recall = (message_id(), message_id()).prop_map(|(id, message_id)| Recall { id, message_id });
+            prop_oneof![
+                Just(RequestUpdateResult::NoUpdate),
+                update.prop_map(RequestUpdateResult::Update),
+                recall.prop_map(RequestUpdateResult::Recall),
+                any::<u32>().prop_map(|code| RequestUpdateResult::Unclaimed(PairingCode(code))),
+                Just(RequestUpdateResult::KeepAlive),
+            ]
+        }
+
+        fn hello_result() -> impl Strategy<Value = HelloResult> {
+            prop_oneof![
+                capabilities().prop_map(HelloResult::Accepted),
+                any::<(u16, u16)>().prop_map(|(min, max)| HelloResult::UnsupportedProtocol { min, max }),
+            ]
+        }
+
+        fn auth_challenge() -> impl Strategy<Value = AuthChallenge> {
+            prop_oneof![
+                any::<AuthNonce>().prop_map(AuthChallenge::Challenge),
+                Just(AuthChallenge::Enroll)
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn client_commands_round_trip(command in client_command()) {
+                round_trip(command)?;
+            }
+
+            #[test]
+            fn update_results_round_trip(result in request_update_result()) {
+                round_trip(result)?;
+            }
+
+            #[test]
+            fn hello_results_round_trip(result in hello_result()) {
+                round_trip(result)?;
+            }
+
+            #[test]
+            fn auth_challenges_round_trip(challenge in auth_challenge()) {
+                round_trip(challenge)?;
+            }
+
+            #[test]
+            fn auth_results_round_trip(result in prop_oneof![Just(AuthResult::Authenticated), Just(AuthResult::Denied)]) {
+                round_trip(result)?;
+            }
+
+            #[test]
+            fn header_is_little_endian(command in client_command()) {
+                let mut buf = vec![0u8; ClientCommand::BUFFER_SIZE];
+                let frame = command.to_bytes(&mut buf).unwrap();
+                prop_assert_eq!(&frame[..2], &FRAME_MAGIC);
+                let data_len = frame.len() - ClientCommand::DATA_START;
+                prop_assert_eq!(&frame[2..4], &(data_len as Length).to_le_bytes());
+            }
+
+            #[test]
+            fn leading_garbage_is_skipped(
+                garbage in proptest::collection::vec(any::<u8>().prop_filter("no magic in the garbage", |b| *b != FRAME_MAGIC[0]), 0..64),
+                half_magic: bool,
+                command in client_command(),
+            ) {
+                let mut socket = VecDeque::new();
+                socket.extend(&garbage);
+                // A garbage byte that looks like the start of the magic must not hide the real one.
+                if half_magic {
+                    socket.push_back(FRAME_MAGIC[0]);
+                }
+                block_on(command.send_alloc(&mut socket)).unwrap();
+                let received = block_on(ClientCommand::receive_alloc(&mut socket)).unwrap();
+                prop_assert_eq!(received, command);
+            }
+
+            #[test]
+            fn truncated_frames_are_eof(command in client_command(), cut in 1usize..) {
+                let mut buf = vec![0u8; ClientCommand::BUFFER_SIZE];
+                let frame = command.to_bytes(&mut buf).unwrap();
+                let cut = cut % frame.len();
+                let mut socket = VecDeque::new();
+                socket.extend(&frame[..cut]);
+                prop_assert!(matches!(block_on(ClientCommand::receive_alloc(&mut socket)), Err(Error::Eof)));
+            }
+
+            #[test]
+            fn recovers_after_bad_frames(data in proptest::collection::vec(any::<u8>(), 0..=ClientCommand::POSTCARD_MAX_SIZE), command in client_command()) {
+                let mut socket = VecDeque::new();
+                let bad = frame(&data);
+                socket.extend(&bad);
+                block_on(command.send_alloc(&mut socket)).unwrap();
+
+                // Random data may happen to decode, but then it must be a complete message and the next frame is intact.
+                let _ = block_on(ClientCommand::receive_alloc(&mut socket));
+                let received = block_on(ClientCommand::receive_alloc(&mut socket));
+                prop_assert!(matches!(received, Ok(received) if received == command), "{received:?}");
+            }
+        }
+
+        #[test]
+        fn oversize_frame_is_rejected_and_skipped() {
+            let mut socket = VecDeque::new();
+            let len = ClientCommand::POSTCARD_MAX_SIZE + 1;
+            socket.extend(FRAME_MAGIC);
+            socket.extend((len as Length).to_le_bytes());
+            let command = ClientCommand::Ack(MessageID(7));
+            block_on(command.send_alloc(&mut socket)).unwrap();
+
+            let result = block_on(ClientCommand::receive_alloc(&mut socket));
+            assert!(
+                matches!(result, Err(Error::Oversize { len: l, .. }) if l == len),
+                "{result:?}"
+            );
+            assert_eq!(block_on(ClientCommand::receive_alloc(&mut socket)).unwrap(), command);
+        }
+
+        #[test]
+        fn invalid_data_is_decode_error() {
+            let mut socket = VecDeque::new();
+            // There is no command with this discriminant.
+            socket.extend(frame(&[0x7f]));
+            let result = block_on(ClientCommand::receive_alloc(&mut socket));
+            assert!(matches!(result, Err(Error::Decode(_))), "{result:?}");
+        }
+    }
 }
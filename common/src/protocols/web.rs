@@ -1,11 +1,20 @@
 use serde::{Deserialize, Serialize};
 
-use crate::types::{DeviceID, MessageID};
+use crate::{
+    protocols::pico::DeviceStatus,
+    types::{DeviceID, FirmwareVersion, MessageID, PairingCode, RecurringID},
+};
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct MessageMeta {
     pub receiver_id: DeviceID,
     pub duration: chrono::Duration,
+    /// Devices show urgent messages before all others until someone acknowledges them.
+    #[serde(default)]
+    pub urgent: bool,
+    /// Devices only get the message at this time. Its duration counts from then on.
+    #[serde(default)]
+    pub not_before: Option<chrono::DateTime<chrono::Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,3 +34,163 @@ pub struct NewImageMessage {
 pub struct NewMessageCreated {
     pub id: MessageID,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageKind {
+    Text,
+    Image,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageSender {
+    Web,
+    Telegram,
+}
+
+/// Metadata of a message. Only text messages include their content.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageInfo {
+    pub id: MessageID,
+    pub receiver_id: DeviceID,
+    pub sender: MessageSender,
+    /// The user that sent the message, e.g. `telegram:1234`.
+    pub author: Option<String>,
+    /// Display name of the author, if we know it.
+    pub author_name: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    pub kind: MessageKind,
+    pub text: Option<String>,
+    pub urgent: bool,
+    /// When the message is shown at the earliest, if it was scheduled.
+    pub not_before: Option<chrono::DateTime<chrono::Utc>>,
+    /// Whether the message still waits for its `not_before` time.
+    pub scheduled: bool,
+    /// When the receiver acknowledged that it stored and shows the message. `None` if it did not yet.
+    pub delivered_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Transfers of the message that the receiver reported as corrupted.
+    pub failed_deliveries: u32,
+}
+
+/// One page of the message history, newest messages first.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MessageHistory {
+    pub messages: Vec<MessageInfo>,
+    /// Pass this as `before` to get the next page. `None` if there are no older messages.
+    pub next_cursor: Option<MessageID>,
+}
+
+/// When a recurring message is due, in the local time of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "every", rename_all = "lowercase")]
+pub enum Recurrence {
+    Day {
+        time: chrono::NaiveTime,
+    },
+    Week {
+        weekday: chrono::Weekday,
+        time: chrono::NaiveTime,
+    },
+}
+
+/// A text that is sent to a device each time it is due, e.g. as a reminder of a weekly chore.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewRecurringMessage {
+    pub receiver_id: DeviceID,
+    pub text: String,
+    pub recurrence: Recurrence,
+    /// How long each message is shown.
+    pub duration: chrono::Duration,
+    #[serde(default)]
+    pub urgent: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecurringMessageInfo {
+    pub id: RecurringID,
+    pub receiver_id: DeviceID,
+    pub text: String,
+    pub recurrence: Recurrence,
+    pub duration: chrono::Duration,
+    pub urgent: bool,
+    /// The user that created the recurring message, e.g. `telegram:1234`.
+    pub author: Option<String>,
+    /// When the next message is sent.
+    pub next_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub id: DeviceID,
+    pub name: String,
+    /// The user that claimed the device, e.g. `telegram:1234`. Devices registered by the admin have no owner.
+    pub owner: Option<String>,
+    /// Whether the device shows the author of a message in a footer.
+    pub show_author: bool,
+    /// The firmware version the device reported when it last connected.
+    pub firmware: Option<FirmwareVersion>,
+    /// Whether the device runs an older firmware than the latest one.
+    pub outdated: bool,
+}
+
+/// Whether a device is online and healthy.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceStatusInfo {
+    pub id: DeviceID,
+    /// Whether the device currently keeps a connection open to wait for updates.
+    pub connected: bool,
+    /// When the device last reported its status.
+    pub last_seen: Option<chrono::DateTime<chrono::Utc>>,
+    pub firmware: Option<FirmwareVersion>,
+    pub outdated: bool,
+    /// The latest status the device reported. Unknown for devices whose firmware does not report it.
+    pub status: Option<DeviceStatus>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewDevice {
+    pub id: DeviceID,
+    pub name: String,
+}
+
+/// Changes to a device. Fields that are `None` stay unchanged.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UpdateDevice {
+    pub name: Option<String>,
+    pub show_author: Option<bool>,
+}
+
+/// Claim an unregistered device by the pairing code shown on its display.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ClaimDevice {
+    pub code: PairingCode,
+    pub name: String,
+}
+
+/// What a user may do with a device. Each right includes the ones before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceRight {
+    /// Send messages to the device.
+    Send,
+    /// Change the device's settings, remove any message from it and grant rights to other users.
+    Manage,
+}
+
+/// A right on a device that was granted to a user, in addition to its owner.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GrantInfo {
+    /// The user that was granted the right, e.g. `telegram:1234`.
+    pub user: String,
+    /// Display name of the user, if we know it.
+    pub user_name: Option<String>,
+    pub right: DeviceRight,
+}
+
+/// Grant a right on a device to a user, replacing the right they had before.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SetGrant {
+    pub right: DeviceRight,
+}
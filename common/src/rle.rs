@@ -0,0 +1,270 @@
+//! Run-length encoding of RGB565 images, a variant of PackBits on pixels instead of bytes.
+//!
+//! The encoded data is a sequence of runs, each starting with a control byte `c`:
+//! +-----------+--------------------------------------------------+
+//! | c < 0x80  | `c + 1` literal pixels follow (2 bytes each)     |
+//! | c >= 0x80 | one pixel follows, repeated `c - 0x80 + 2` times |
+//! +-----------+--------------------------------------------------+
+//!
+//! Flat areas shrink a lot, while noisy images grow by at most one byte per 128 pixels.
+//! The [`Decoder`] works on chunks of any size, so devices can unpack an image as it arrives without buffering it.
+
+use core::fmt;
+
+const PIXEL_SIZE: usize = 2;
+#[cfg(feature = "use-std")]
+const MAX_LITERAL: usize = 128;
+const MIN_REPEAT: usize = 2;
+#[cfg(feature = "use-std")]
+const MAX_REPEAT: usize = 129;
+const REPEAT_FLAG: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The runs produce more pixels than fit into the output.
+    Overflow,
+    /// The data ended before the output was filled or in the middle of a run.
+    Truncated,
+}
+
+#[cfg(feature = "use-std")]
+impl std::error::Error for DecodeError {}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Overflow => write!(f, "Compressed image is larger than the image buffer."),
+            DecodeError::Truncated => write!(f, "Compressed image ended early."),
+        }
+    }
+}
+
+/// Compress RGB565 `pixels`, whose length must be even.
+#[cfg(feature = "use-std")]
+pub fn encode(pixels: &[u8]) -> Vec<u8> {
+    assert!(
+        pixels.len().is_multiple_of(PIXEL_SIZE),
+        "pixels must be whole RGB565 values"
+    );
+    let pixels: Vec<&[u8]> = pixels.chunks_exact(PIXEL_SIZE).collect();
+    let mut encoded = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+
+    let flush_literals = |encoded: &mut Vec<u8>, literals: &[&[u8]]| {
+        for chunk in literals.chunks(MAX_LITERAL) {
+            encoded.push((chunk.len() - 1) as u8);
+            chunk.iter().for_each(|pixel| encoded.extend_from_slice(pixel));
+        }
+    };
+
+    while i < pixels.len() {
+        let run = pixels[i..]
+            .iter()
+            .take(MAX_REPEAT)
+            .take_while(|pixel| **pixel == pixels[i])
+            .count();
+        if run >= MIN_REPEAT {
+            flush_literals(&mut encoded, &pixels[literal_start..i]);
+            encoded.push(REPEAT_FLAG | (run - MIN_REPEAT) as u8);
+            encoded.extend_from_slice(pixels[i]);
+            i += run;
+            literal_start = i;
+        } else {
+            i += 1;
+        }
+    }
+    flush_literals(&mut encoded, &pixels[literal_start..]);
+    encoded
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    /// Expecting the control byte of the next run.
+    Control,
+    /// Copying the remaining bytes of literal pixels.
+    Literal { remaining: usize },
+    /// Waiting for the pixel of a repeat run. `low` is its first byte if we got it already.
+    Repeat { count: usize, low: Option<u8> },
+}
+
+/// Streaming decoder that unpacks chunks of encoded data into one output buffer.
+#[derive(Debug)]
+pub struct Decoder {
+    state: State,
+    /// Bytes of the output written so far.
+    written: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Control,
+            written: 0,
+        }
+    }
+
+    /// Decode the next chunk of encoded data into `output`, after what the previous chunks produced.
+    /// `output` must be the same buffer for all chunks.
+    pub fn decode(&mut self, input: &[u8], output: &mut [u8]) -> Result<(), DecodeError> {
+        let mut input = input.iter().copied();
+        loop {
+            match self.state {
+                State::Control => {
+                    let Some(control) = input.next() else {
+                        return Ok(());
+                    };
+                    self.state = if control & REPEAT_FLAG == 0 {
+                        State::Literal {
+                            remaining: (control as usize + 1) * PIXEL_SIZE,
+                        }
+                    } else {
+                        State::Repeat {
+                            count: (control & !REPEAT_FLAG) as usize + MIN_REPEAT,
+                            low: None,
+                        }
+                    };
+                }
+                State::Literal { remaining: 0 } => self.state = State::Control,
+                State::Literal { remaining } => {
+                    let Some(byte) = input.next() else {
+                        return Ok(());
+                    };
+                    *output.get_mut(self.written).ok_or(DecodeError::Overflow)? = byte;
+                    self.written += 1;
+                    self.state = State::Literal {
+                        remaining: remaining - 1,
+                    };
+                }
+                State::Repeat { count, low: None } => {
+                    let Some(byte) = input.next() else {
+                        return Ok(());
+                    };
+                    self.state = State::Repeat { count, low: Some(byte) };
+                }
+                State::Repeat { count, low: Some(low) } => {
+                    let Some(high) = input.next() else {
+                        return Ok(());
+                    };
+                    let end = self.written + count * PIXEL_SIZE;
+                    let run = output.get_mut(self.written..end).ok_or(DecodeError::Overflow)?;
+                    for pixel in run.chunks_exact_mut(PIXEL_SIZE) {
+                        pixel.copy_from_slice(&[low, high]);
+                    }
+                    self.written = end;
+                    self.state = State::Control;
+                }
+            }
+        }
+    }
+
+    /// Check that the data filled all of `output` and did not stop in the middle of a run.
+    pub fn finish(self, output: &[u8]) -> Result<(), DecodeError> {
+        match self.state {
+            State::Control | State::Literal { remaining: 0 } if self.written == output.len() => Ok(()),
+            _ => Err(DecodeError::Truncated),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "use-std"))]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Decode `encoded` in chunks that end at `splits`, into a buffer of `len` bytes.
+    fn decode_split(encoded: &[u8], splits: &[usize], len: usize) -> Result<Vec<u8>, DecodeError> {
+        let mut output = vec![0; len];
+        let mut decoder = Decoder::new();
+        let mut start = 0;
+        for &end in splits.iter().chain([&encoded.len()]) {
+            let end = end.clamp(start, encoded.len());
+            decoder.decode(&encoded[start..end], &mut output)?;
+            start = end;
+        }
+        decoder.finish(&output)?;
+        Ok(output)
+    }
+
+    fn decode(encoded: &[u8], len: usize) -> Result<Vec<u8>, DecodeError> {
+        decode_split(encoded, &[], len)
+    }
+
+    /// Pixels from a few colors, so that there are runs of all lengths between the literals.
+    fn pixels() -> impl Strategy<Value = Vec<u8>> {
+        proptest::collection::vec((0u8..3, 1usize..300), 0..20).prop_map(|runs| {
+            runs.into_iter()
+                .flat_map(|(color, len)| (0..len).map(move |i| if color == 0 { [i as u8, 0] } else { [color, color] }))
+                .flatten()
+                .collect()
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(pixels in pixels()) {
+            let encoded = encode(&pixels);
+            prop_assert_eq!(decode(&encoded, pixels.len()), Ok(pixels));
+        }
+
+        #[test]
+        fn round_trip_in_chunks(pixels in pixels(), mut splits in proptest::collection::vec(any::<usize>(), 0..16)) {
+            let encoded = encode(&pixels);
+            splits.iter_mut().for_each(|split| *split %= encoded.len() + 1);
+            splits.sort();
+            prop_assert_eq!(decode_split(&encoded, &splits, pixels.len()), Ok(pixels));
+        }
+    }
+
+    #[test]
+    fn runs_are_split_at_their_maximum_length() {
+        let literals: Vec<u8> = (0..MAX_LITERAL as u16 + 1).flat_map(u16::to_le_bytes).collect();
+        let encoded = encode(&literals);
+        assert_eq!(encoded[0], (MAX_LITERAL - 1) as u8);
+        assert_eq!(encoded[1 + MAX_LITERAL * PIXEL_SIZE], 0);
+        assert_eq!(encoded.len(), 2 + literals.len());
+
+        let repeats = [0xab, 0xcd].repeat(MAX_REPEAT + MIN_REPEAT);
+        assert_eq!(encode(&repeats), [0xff, 0xab, 0xcd, REPEAT_FLAG, 0xab, 0xcd]);
+        assert_eq!(decode(&encode(&repeats), repeats.len()), Ok(repeats));
+    }
+
+    #[test]
+    fn every_split_of_a_run_decodes() {
+        let pixels = [[1, 2].repeat(5), vec![3, 4, 5, 6], [7, 8].repeat(3)].concat();
+        let encoded = encode(&pixels);
+        for split in 0..=encoded.len() {
+            assert_eq!(
+                decode_split(&encoded, &[split], pixels.len()),
+                Ok(pixels.clone()),
+                "split at {split}"
+            );
+        }
+    }
+
+    #[test]
+    fn runs_beyond_the_output_overflow() {
+        // Two literal pixels and a repeat of three into room for two pixels.
+        assert_eq!(decode(&[1, 1, 2, 3, 4], 2), Err(DecodeError::Overflow));
+        assert_eq!(decode(&[REPEAT_FLAG | 1, 1, 2], 4), Err(DecodeError::Overflow));
+    }
+
+    #[test]
+    fn data_ending_early_is_truncated() {
+        // Too few pixels for the output.
+        assert_eq!(decode(&[REPEAT_FLAG, 1, 2], 6), Err(DecodeError::Truncated));
+        // In the middle of a literal run.
+        assert_eq!(decode(&[1, 1, 2, 3], 4), Err(DecodeError::Truncated));
+        // In the middle of the pixel of a repeat run.
+        assert_eq!(decode(&[0, 1, 2, REPEAT_FLAG, 3], 2), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[], 2), Err(DecodeError::Truncated));
+        assert_eq!(decode(&[], 0), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,39 @@
+//! Fixtures for the tests of the protocol and of the client, which talk over sockets in memory.
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+use crate::protocols::pico::{self, serialization::AbstractSocket};
+
+/// A connection to ourselves. Reads return what was written before and fail with [`pico::Error::Eof`] once there
+/// is not enough data left, like a socket whose peer closed it.
+impl AbstractSocket for VecDeque<u8> {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), pico::Error> {
+        if self.len() < buf.len() {
+            self.clear();
+            return Err(pico::Error::Eof);
+        }
+        let len = buf.len();
+        for (byte, data) in buf.iter_mut().zip(self.drain(..len)) {
+            *byte = data;
+        }
+        Ok(())
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> Result<(), pico::Error> {
+        self.extend(buf);
+        Ok(())
+    }
+}
+
+/// Sockets in memory never make us wait, so every future is done after the first poll.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("sockets in memory never block"),
+    }
+}
@@ -21,6 +21,24 @@ pub struct DeviceID(pub u32);
 
 pub struct MessageID(pub u32);
 
+/// Identifies a recurring message, of which the server sends a new message each time it is due.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+#[repr(transparent)]
+pub struct RecurringID(pub u32);
+
+/// Short code that a device shows on its display so that a user can claim it.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "postcard", derive(MaxSize))]
+#[serde(transparent)]
+#[repr(transparent)]
+pub struct PairingCode(pub u32);
+
+impl PairingCode {
+    pub const DIGITS: usize = 6;
+    pub const MAX: u32 = 10u32.pow(Self::DIGITS as u32) - 1;
+}
+
 impl FromStr for DeviceID {
     type Err = ParseIntError;
 
@@ -39,12 +57,100 @@ impl FromStr for MessageID {
     }
 }
 
+impl FromStr for RecurringID {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        u32::from_str(s).map(RecurringID)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidPairingCode;
+
+impl fmt::Display for InvalidPairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A pairing code consists of {} digits.", PairingCode::DIGITS)
+    }
+}
+
+#[cfg(feature = "use-std")]
+impl std::error::Error for InvalidPairingCode {}
+
+impl FromStr for PairingCode {
+    type Err = InvalidPairingCode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != Self::DIGITS || !s.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(InvalidPairingCode);
+        }
+        u32::from_str(s).map(PairingCode).map_err(|_| InvalidPairingCode)
+    }
+}
+
+impl fmt::Display for PairingCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:0width$}", self.0, width = Self::DIGITS)
+    }
+}
+
+/// Semantic version of the firmware running on a device.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "postcard", derive(MaxSize))]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidFirmwareVersion;
+
+impl fmt::Display for InvalidFirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "A firmware version has the form <major>.<minor>.<patch>.")
+    }
+}
+
+#[cfg(feature = "use-std")]
+impl std::error::Error for InvalidFirmwareVersion {}
+
+impl FromStr for FirmwareVersion {
+    type Err = InvalidFirmwareVersion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('.').map(u16::from_str);
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => Ok(Self { major, minor, patch }),
+            _ => Err(InvalidFirmwareVersion),
+        }
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
 impl fmt::Display for DeviceID {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "0x{:08x}", self)
     }
 }
 
+impl fmt::Display for MessageID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for RecurringID {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl fmt::UpperHex for DeviceID {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         fmt::UpperHex::fmt(&self.0, f)
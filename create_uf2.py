@@ -1,6 +1,10 @@
+import secrets
 import struct 
 
 device_id = 0xcafebabe
+# The secret with which the device authenticates to the server. The server learns it on the first connection,
+# so flash a freshly generated one on every device.
+device_secret = secrets.token_bytes(32)
 ssid = 'Buffalo-G-1338'.encode('utf-8')
 password = 'mysecretpw2'.encode('utf-8')
 
@@ -54,7 +58,8 @@ def gen_wifi():
         
 def gen_dev():
     device_data = struct.pack('<I', device_id)
-    device_data += (256 - 4) * b'\0'
+    device_data += device_secret
+    device_data += (256 - 4 - len(device_secret)) * b'\0'
     
     file = b''
     file += gen_block(device_base_address, 0, device_data)
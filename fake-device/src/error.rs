@@ -0,0 +1,21 @@
+use common::{client, protocols::pico::DeviceErrorKind};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The errors the firmware handles as soft errors, see `pico/src/error.rs`.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Can't connect to server: {0}")]
+    ServerConnect(std::io::Error),
+    #[error(transparent)]
+    Client(#[from] client::Error),
+}
+
+impl From<&Error> for DeviceErrorKind {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::ServerConnect(_) => DeviceErrorKind::ServerConnect,
+            Error::Client(e) => DeviceErrorKind::from(e),
+        }
+    }
+}
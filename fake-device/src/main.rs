@@ -0,0 +1,201 @@
+//! A device that runs on a normal computer instead of a Pico W. It talks to the server like the firmware does and
+//! saves what the display would show as PNG files, so that the server can be tested end to end without hardware.
+
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::{anyhow, Context};
+use common::{
+    client::{Identity, SyncState},
+    protocols::pico::{Capabilities, DeviceError, DeviceErrorKind, DeviceStatus},
+    types::{DeviceID, FirmwareVersion},
+};
+use tokio::time::{sleep, Instant};
+
+use crate::{
+    error::{Error, Result},
+    messagebuf::Messages,
+    output::SavedMessages,
+};
+
+mod error;
+// Shared with the firmware, which uses the parts that we do not need.
+#[allow(dead_code)]
+#[path = "../../pico/src/messagebuf.rs"]
+mod messagebuf;
+mod output;
+#[allow(dead_code)]
+#[path = "../../pico/src/render.rs"]
+mod render;
+mod socket;
+
+const DEFAULT_SERVER: &str = "127.0.0.1:1338";
+/// The same interval as the firmware.
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+const SERVER_CONNECT_ERROR_WAIT: Duration = Duration::from_secs(2);
+
+const USAGE: &str = "\
+Usage: rpi-messages-fake-device <DEVICE_ID> [OPTIONS]
+
+Acts like a display with the hexadecimal DEVICE_ID and saves the messages it receives as PNG files.
+
+Options:
+  --interval <SECONDS>  Wait this long between checks for updates [default: 60]
+  --server <ADDRESS>    Address of the server's device port [default: 127.0.0.1:1338]
+  --output <DIR>        Where to save the messages and the device secret [default: fake-device-<DEVICE_ID>]";
+
+struct Args {
+    device_id: DeviceID,
+    poll_interval: Duration,
+    server: String,
+    output: PathBuf,
+}
+
+impl Args {
+    fn parse() -> anyhow::Result<Self> {
+        let mut args = std::env::args().skip(1);
+        let mut device_id = None;
+        let mut poll_interval = DEFAULT_POLL_INTERVAL;
+        let mut server = DEFAULT_SERVER.to_string();
+        let mut output = None;
+
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| anyhow!("{arg} needs a value"));
+            match arg.as_str() {
+                "--interval" => {
+                    let seconds = value()?.parse().context("--interval must be a number of seconds")?;
+                    poll_interval = Duration::from_secs(seconds);
+                }
+                "--server" => server = value()?,
+                "--output" => output = Some(PathBuf::from(value()?)),
+                "-h" | "--help" => {
+                    println!("{USAGE}");
+                    std::process::exit(0);
+                }
+                _ if device_id.is_none() && !arg.starts_with('-') => {
+                    device_id = Some(arg.parse().with_context(|| format!("invalid device ID {arg}"))?);
+                }
+                _ => return Err(anyhow!("unexpected argument {arg}")),
+            }
+        }
+
+        let device_id: DeviceID = device_id.ok_or_else(|| anyhow!("missing device ID"))?;
+        Ok(Self {
+            device_id,
+            poll_interval,
+            server,
+            output: output.unwrap_or_else(|| PathBuf::from(format!("fake-device-{:08x}", device_id.0))),
+        })
+    }
+}
+
+/// The same capabilities as the firmware.
+const CAPABILITIES: Capabilities = Capabilities::FOOTER
+    .union(Capabilities::IMAGE_COMPRESSION)
+    .union(Capabilities::PAGING)
+    .union(Capabilities::PRIORITY)
+    .union(Capabilities::DELIVERY_ACK)
+    .union(Capabilities::PUSH)
+    .union(Capabilities::STATUS)
+    .union(Capabilities::STYLED_TEXT);
+
+struct FakeDevice {
+    args: Args,
+    identity: Identity,
+    messages: SavedMessages,
+    sync_state: SyncState,
+    booted_at: Instant,
+    last_error: Option<DeviceError>,
+}
+
+impl FakeDevice {
+    fn uptime_sec(&self) -> u32 {
+        self.booted_at.elapsed().as_secs() as u32
+    }
+
+    fn status(&self) -> DeviceStatus {
+        let messages = &self.messages.messages;
+        DeviceStatus {
+            uptime_sec: self.uptime_sec(),
+            // We have no WiFi to measure.
+            rssi: None,
+            free_text_slots: messages.free_text_slots() as u8,
+            free_image_slots: messages.free_image_slots() as u8,
+            checksum_failures: self.sync_state.checksum_failures,
+            last_error: self.last_error,
+        }
+    }
+
+    fn handle_error(&mut self, e: Error) {
+        self.last_error = Some(DeviceError {
+            kind: DeviceErrorKind::from(&e),
+            uptime_sec: self.uptime_sec(),
+        });
+        log::error!("Handling error: {e}");
+    }
+
+    /// Connect to the server and fetch updates until we are up to date, like the `fetch_data` task of the firmware.
+    /// Returns once the connection ended.
+    async fn sync(&mut self) -> Result<()> {
+        log::info!("Creating new connection.");
+        let mut client = socket::connect(&self.args.server, &self.identity, self.status()).await?;
+        client.sync(&mut self.messages, &mut self.sync_state).await?;
+        Ok(())
+    }
+}
+
+/// The version from our Cargo.toml, which we report to the server.
+fn firmware_version() -> FirmwareVersion {
+    env!("CARGO_PKG_VERSION")
+        .parse()
+        .expect("package version is a valid firmware version")
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let args = match Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{e:#}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    std::fs::create_dir_all(&args.output).with_context(|| format!("creating {} failed", args.output.display()))?;
+    let secret = output::load_or_create_secret(&args.output)?;
+    log::info!(
+        "Booting device with ID: 0x{:08x}, saving messages to {}",
+        args.device_id.0,
+        args.output.display()
+    );
+
+    let mut device = FakeDevice {
+        identity: Identity {
+            device_id: args.device_id,
+            secret,
+            firmware_version: firmware_version(),
+            capabilities: CAPABILITIES,
+        },
+        messages: SavedMessages {
+            messages: Box::new(Messages::new()),
+            dir: args.output.clone(),
+        },
+        args,
+        sync_state: SyncState::new(),
+        booted_at: Instant::now(),
+        last_error: None,
+    };
+    loop {
+        match device.sync().await {
+            Ok(()) => sleep(device.args.poll_interval).await,
+            Err(e @ Error::ServerConnect(_)) => {
+                device.handle_error(e);
+                sleep(SERVER_CONNECT_ERROR_WAIT).await;
+            }
+            Err(e) => {
+                device.handle_error(e);
+                sleep(device.args.poll_interval).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Files of the fake device: its secret and what its display would show.
+
+use std::{
+    convert::Infallible,
+    fs, io, iter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use common::{
+    client::Storage,
+    consts::{IMAGE_HEIGHT, IMAGE_WIDTH, MAX_PAGES},
+    protocols::pico::{DeviceSecret, Update, DEVICE_SECRET_LEN},
+    types::{MessageID, PairingCode},
+};
+use embedded_graphics::{
+    pixelcolor::{Rgb565, Rgb888},
+    prelude::*,
+};
+use image::{Rgb, RgbImage};
+
+use crate::{
+    messagebuf::{DisplayMessage, DisplayMessageData, Messages, TextData},
+    render::{self, DisplayOptions},
+};
+
+const SECRET_FILE: &str = "device-secret";
+
+/// The secret of the device, stored in `dir` as hex. A new secret is created on the first run, which the server
+/// trusts once the device enrolls.
+pub fn load_or_create_secret(dir: &Path) -> anyhow::Result<DeviceSecret> {
+    let path = dir.join(SECRET_FILE);
+    match fs::read_to_string(&path) {
+        Ok(hex) => parse_secret(hex.trim()).with_context(|| format!("invalid secret in {}", path.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let secret: DeviceSecret = rand::random();
+            let hex: String = secret.iter().map(|byte| format!("{byte:02x}")).collect();
+            fs::write(&path, hex).with_context(|| format!("writing {} failed", path.display()))?;
+            log::info!("Created a new device secret in {}.", path.display());
+            Ok(secret)
+        }
+        Err(e) => Err(e).with_context(|| format!("reading {} failed", path.display())),
+    }
+}
+
+fn parse_secret(hex: &str) -> anyhow::Result<DeviceSecret> {
+    if hex.len() != 2 * DEVICE_SECRET_LEN || !hex.is_ascii() {
+        return Err(anyhow!("expected {} hex digits", 2 * DEVICE_SECRET_LEN));
+    }
+    let mut secret = [0; DEVICE_SECRET_LEN];
+    for (byte, digits) in secret.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        // Cannot fail since the string is ASCII.
+        let digits = std::str::from_utf8(digits).unwrap();
+        *byte = u8::from_str_radix(digits, 16)?;
+    }
+    Ok(secret)
+}
+
+fn message_path(dir: &Path, id: MessageID) -> PathBuf {
+    dir.join(format!("message-{}.png", id.0))
+}
+
+/// Where page `index` of a text with several pages is saved, counting from 1 like the display does.
+fn page_path(dir: &Path, id: MessageID, index: usize) -> PathBuf {
+    dir.join(format!("message-{}-{index}.png", id.0))
+}
+
+/// Draw the message `id` like the display would and save it in `dir`, one picture per page if it has several.
+pub fn save_message(dir: &Path, messages: &Messages, id: MessageID) -> anyhow::Result<()> {
+    let message = messages
+        .texts
+        .iter()
+        .map(DisplayMessage::from)
+        .chain(messages.images.iter().map(DisplayMessage::from))
+        .find(|message| message.meta.id == id && message.meta.is_active())
+        .ok_or_else(|| anyhow!("message {} is not stored", id.0))?;
+
+    // We have no button to acknowledge urgent messages, so they look like the firmware shows them at first.
+    let options = if message.meta.urgent {
+        DisplayOptions::PriorityMessage
+    } else {
+        DisplayOptions::NormalMessage
+    };
+    let count = message.page_count();
+    for page in 0..count {
+        let mut display = Framebuffer::new();
+        // Drawing into memory cannot fail.
+        match message.data {
+            DisplayMessageData::Text(data) if count > 1 => {
+                render::draw_page(&mut display, data.page(page), message.footer, page, count, options).ok();
+            }
+            DisplayMessageData::Text(TextData {
+                text,
+                style: Some(style),
+            }) => {
+                render::draw_styled_text(&mut display, text, message.footer, style, options).ok();
+            }
+            DisplayMessageData::Text(data) => {
+                render::draw_text(&mut display, &data.text, message.footer, options).ok();
+            }
+            DisplayMessageData::Image(data) => {
+                render::draw_image(&mut display, &data.image, message.footer, options).ok();
+            }
+        }
+
+        let path = if count > 1 {
+            page_path(dir, id, page + 1)
+        } else {
+            message_path(dir, id)
+        };
+        display
+            .to_image()
+            .save(&path)
+            .with_context(|| format!("writing {} failed", path.display()))?;
+        log::info!("Saved message {} to {}.", id.0, path.display());
+    }
+    Ok(())
+}
+
+/// Delete the pictures of a recalled message.
+pub fn remove_message(dir: &Path, id: MessageID) -> anyhow::Result<()> {
+    let pages = (1..=MAX_PAGES).map(|index| page_path(dir, id, index));
+    for path in iter::once(message_path(dir, id)).chain(pages) {
+        match fs::remove_file(&path) {
+            Ok(()) => {}
+            // We may have never received the message, or it has fewer pages.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("deleting {} failed", path.display())),
+        }
+    }
+    Ok(())
+}
+
+/// The messages the display would show, which are saved in `dir` whenever they change.
+pub struct SavedMessages {
+    pub messages: Box<Messages>,
+    pub dir: PathBuf,
+}
+
+impl Storage for SavedMessages {
+    type Messages = Messages;
+    type Guard<'a> = &'a mut Messages;
+
+    async fn lock(&mut self) -> &mut Messages {
+        &mut self.messages
+    }
+
+    fn set_pairing_code(&mut self, code: Option<PairingCode>) {
+        if let Some(code) = code {
+            log::warn!("Device is not registered yet. Claim it with /claim {code}");
+        }
+    }
+
+    fn stored(&mut self, update: &Update) {
+        if let Err(e) = save_message(&self.dir, &self.messages, update.id) {
+            log::error!("{e:#}");
+        }
+    }
+
+    fn recalled(&mut self, id: MessageID) {
+        if let Err(e) = remove_message(&self.dir, id) {
+            log::error!("{e:#}");
+        }
+    }
+}
+
+/// Pixels of the display, so that we can draw the same way the firmware does.
+struct Framebuffer {
+    pixels: Vec<Rgb565>,
+}
+
+impl Framebuffer {
+    fn new() -> Self {
+        Self {
+            pixels: vec![Rgb565::BLACK; IMAGE_WIDTH * IMAGE_HEIGHT],
+        }
+    }
+
+    fn to_image(&self) -> RgbImage {
+        RgbImage::from_fn(IMAGE_WIDTH as u32, IMAGE_HEIGHT as u32, |x, y| {
+            let color = Rgb888::from(self.pixels[y as usize * IMAGE_WIDTH + x as usize]);
+            Rgb([color.r(), color.g(), color.b()])
+        })
+    }
+}
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        Size::new(IMAGE_WIDTH as u32, IMAGE_HEIGHT as u32)
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I: IntoIterator<Item = Pixel<Rgb565>>>(&mut self, pixels: I) -> Result<(), Infallible> {
+        for Pixel(point, color) in pixels {
+            // Text may overdraw the margins, the display just clips it.
+            if let (Ok(x @ 0..IMAGE_WIDTH), Ok(y @ 0..IMAGE_HEIGHT)) =
+                (usize::try_from(point.x), usize::try_from(point.y))
+            {
+                self.pixels[y * IMAGE_WIDTH + x] = color;
+            }
+        }
+        Ok(())
+    }
+}
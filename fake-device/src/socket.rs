@@ -0,0 +1,35 @@
+//! Connecting to the server, like `fetch_data::connect` in the firmware. The protocol itself is implemented by
+//! [`common::client`].
+
+use std::future::Future;
+
+use common::{
+    client::{self, Client, Identity, Timer},
+    protocols::pico::{Capabilities, DeviceStatus},
+};
+use tokio::{net::TcpStream, time::timeout};
+
+use crate::error::{Error, Result};
+
+pub struct TokioTimer;
+
+impl Timer for TokioTimer {
+    async fn with_timeout<F: Future>(&self, wait: std::time::Duration, future: F) -> Option<F::Output> {
+        timeout(wait, future).await.ok()
+    }
+}
+
+/// Connect to `server` and authenticate. `status` is reported if the server wants it.
+pub async fn connect(server: &str, identity: &Identity, status: DeviceStatus) -> Result<Client<TcpStream, TokioTimer>> {
+    log::info!("Connecting to server: {server}");
+    let socket = timeout(client::SOCKET_TIMEOUT, TcpStream::connect(server))
+        .await
+        .map_err(|e| Error::ServerConnect(e.into()))?
+        .map_err(Error::ServerConnect)?;
+
+    let mut client = Client::connect(socket, TokioTimer, identity).await?;
+    if client.capabilities().contains(Capabilities::STATUS) {
+        client.report_status(status).await?;
+    }
+    Ok(client)
+}
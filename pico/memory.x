@@ -18,7 +18,8 @@ SECTIONS {
 
 SECTIONS {
     .device_info : {
-        KEEP(*(.device_info.*))
-        *(.device_info.id);
+        /* Explicit order, since the UF2 files write the ID first and the secret right after it. */
+        KEEP(*(.device_info.id))
+        KEEP(*(.device_info.secret))
     } > DEVICE_INFO
 } INSERT AFTER .text;
\ No newline at end of file
@@ -0,0 +1,26 @@
+//! Counters about the health of the device, which help to tell why a display misbehaves.
+
+use core::cell::Cell;
+
+use common::protocols::pico::{DeviceError, DeviceErrorKind};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+/// The most recent error, which we report to the server.
+static LAST_ERROR: Mutex<CriticalSectionRawMutex, Cell<Option<DeviceError>>> = Mutex::new(Cell::new(None));
+
+pub fn record_error(kind: DeviceErrorKind) {
+    let error = DeviceError {
+        kind,
+        uptime_sec: uptime_sec(),
+    };
+    LAST_ERROR.lock(|last_error| last_error.set(Some(error)));
+}
+
+pub fn last_error() -> Option<DeviceError> {
+    LAST_ERROR.lock(Cell::get)
+}
+
+pub fn uptime_sec() -> u32 {
+    Instant::now().as_secs() as u32
+}
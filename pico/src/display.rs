@@ -1,46 +1,13 @@
-use common::consts::{IMAGE_HEIGHT, IMAGE_WIDTH, TEXT_COLUMNS, TEXT_LINES};
+use common::protocols::pico::TextStyle;
 use embassy_rp::{
     gpio::Output,
     spi::{Blocking, Spi},
 };
 use embassy_time::Delay;
-use embedded_graphics::{
-    draw_target::DrawTarget,
-    image::{Image, ImageRaw, ImageRawBE},
-    mono_font::{self, ascii::FONT_9X15, MonoTextStyle},
-    pixelcolor::Rgb565,
-    prelude::*,
-    primitives::Rectangle,
-};
 use embedded_hal_bus::spi::ExclusiveDevice;
-use embedded_text::{
-    alignment::{HorizontalAlignment, VerticalAlignment},
-    style::{HeightMode, TextBoxStyle, TextBoxStyleBuilder, VerticalOverdraw},
-    TextBox,
-};
-
-use crate::error::HardError;
-
-const MESSAGE_FONT: mono_font::MonoFont = FONT_9X15;
-const MESSAGE_TEXT_COLOR: Rgb565 = Rgb565::BLACK;
-const MESSAGE_BG_COLOR: Rgb565 = Rgb565::WHITE;
-pub const PRIO_MESSAGE_BG_COLOR: Rgb565 = Rgb565::RED;
-pub const MESSAGE_TEXT_STYLE: MonoTextStyle<'_, Rgb565> = MonoTextStyle::new(&MESSAGE_FONT, MESSAGE_TEXT_COLOR);
 
-const MARGIN_LEFT: u32 = 4;
-const MARGIN_RIGHT: u32 = 3;
-const MARGIN_TOP: u32 = 4;
-const MARGIN_BOTTOM: u32 = 4;
-
-/// With these margins we are able to fit TEXT_LINES * TEXT_COLUMNS characters on one screen.
-const _ASSERT_WIDTH_FITS: () = assert!(
-    IMAGE_WIDTH
-        == MARGIN_LEFT as usize + TEXT_COLUMNS * MESSAGE_FONT.character_size.width as usize + MARGIN_RIGHT as usize
-);
-const _ASSERT_HEIGHT_FITS: () = assert!(
-    IMAGE_HEIGHT
-        == MARGIN_TOP as usize + TEXT_LINES * MESSAGE_FONT.character_size.height as usize + MARGIN_BOTTOM as usize
-);
+pub use crate::render::DisplayOptions;
+use crate::{error::HardError, render};
 
 pub type DisplaySPI = embassy_rp::peripherals::SPI1;
 type Device = st7735_lcd::ST7735<
@@ -55,62 +22,45 @@ pub struct ST7735 {
     bl: Output<'static>,
 }
 
-#[derive(Clone, Copy)]
-pub enum DisplayOptions {
-    PriorityMessage,
-    NormalMessage,
-}
-
-impl DisplayOptions {
-    fn clear_style(self) -> Rgb565 {
-        match self {
-            DisplayOptions::PriorityMessage => PRIO_MESSAGE_BG_COLOR,
-            DisplayOptions::NormalMessage => MESSAGE_BG_COLOR,
-        }
-    }
-
-    fn textbox_style(self) -> TextBoxStyle {
-        match self {
-            DisplayOptions::PriorityMessage => TextBoxStyleBuilder::new()
-                .height_mode(HeightMode::Exact(VerticalOverdraw::Visible))
-                .alignment(HorizontalAlignment::Left)
-                .vertical_alignment(VerticalAlignment::Top)
-                .build(),
-            DisplayOptions::NormalMessage => TextBoxStyleBuilder::new()
-                .height_mode(HeightMode::Exact(VerticalOverdraw::Visible))
-                .alignment(HorizontalAlignment::Center)
-                .vertical_alignment(VerticalAlignment::Middle)
-                .build(),
-        }
-    }
-}
-
 impl ST7735 {
     pub fn new(dev: Device, bl: Output<'static>) -> Self {
         Self { dev, bl }
     }
 
     pub fn string_formatted(&mut self, text: &str, options: DisplayOptions) -> Result<(), HardError> {
-        // Margins are not symmetric in the 9x15 font size, so at the bottom and right side there is one pixel less space (+1 in Size::new).
-        let bounds = Rectangle::new(
-            Point::new(MARGIN_LEFT as i32, MARGIN_TOP as i32),
-            Size::new(IMAGE_WIDTH as u32 - MARGIN_RIGHT, IMAGE_HEIGHT as u32 - MARGIN_BOTTOM),
-        );
+        self.string_with_footer(text, "", options)
+    }
+
+    /// Like `string_formatted` but shows `footer` in the last line, unless it is empty.
+    pub fn string_with_footer(&mut self, text: &str, footer: &str, options: DisplayOptions) -> Result<(), HardError> {
+        render::draw_text(&mut self.dev, text, footer, options).map_err(|()| HardError::Display)
+    }
 
-        // Create the text box and apply styling options.
-        let text_box = TextBox::with_textbox_style(text, bounds, MESSAGE_TEXT_STYLE, options.textbox_style());
+    /// Like `string_with_footer` for the page `index` of a text with `count` pages, which the last line also shows.
+    pub fn page_with_footer(
+        &mut self,
+        text: &str,
+        footer: &str,
+        index: usize,
+        count: usize,
+        options: DisplayOptions,
+    ) -> Result<(), HardError> {
+        render::draw_page(&mut self.dev, text, footer, index, count, options).map_err(|()| HardError::Display)
+    }
 
-        // Draw the text box.
-        self.dev.clear(options.clear_style()).map_err(|()| HardError::Display)?;
-        text_box.draw(&mut self.dev).map_err(|()| HardError::Display)?;
-        Ok(())
+    /// Like `string_with_footer`, but in the colors, alignment and font of `style`.
+    pub fn styled_string_with_footer(
+        &mut self,
+        text: &str,
+        footer: &str,
+        style: &TextStyle,
+        options: DisplayOptions,
+    ) -> Result<(), HardError> {
+        render::draw_styled_text(&mut self.dev, text, footer, style, options).map_err(|()| HardError::Display)
     }
 
-    pub fn draw_image(&mut self, data: &[u8]) -> Result<(), HardError> {
-        let raw: ImageRawBE<Rgb565> = ImageRaw::new(data, IMAGE_WIDTH as u32);
-        Image::new(&raw, Point::zero())
-            .draw(&mut self.dev)
-            .map_err(|()| HardError::Display)?;
-        Ok(())
+    /// Draw the image and show `footer` over its bottom, unless it is empty.
+    pub fn draw_image(&mut self, data: &[u8], footer: &str, options: DisplayOptions) -> Result<(), HardError> {
+        render::draw_image(&mut self.dev, data, footer, options).map_err(|()| HardError::Display)
     }
 }
@@ -1,62 +1,50 @@
-use core::{
-    fmt::{self, Debug},
-    str::Utf8Error,
-};
+use core::fmt::{self, Debug};
 
-use common::consts::TEXT_BUFFER_SIZE;
+use common::{client, consts::TEXT_BUFFER_SIZE, protocols::pico::DeviceErrorKind};
 use derive_more::From;
 use embassy_net::tcp::ConnectError;
 use heapless::String;
 
-use crate::{messagebuf::TextData, PRIO_MESSAGE_SIGNAL};
+use crate::{diagnostics, messagebuf::TextData, PRIO_MESSAGE_SIGNAL};
 
 pub type Result<T> = core::result::Result<T, SoftError>;
 
-#[allow(unused)]
-#[derive(Debug, From)]
-pub enum ServerMessageError {
-    Encoding(Utf8Error),
-    Protocol(common::protocols::pico::Error),
-}
-
 #[allow(unused)]
 #[derive(Debug, From)]
 pub enum SoftError {
     WifiConnect(cyw43::ControlError),
     WifiConfiguration,
+    DeviceSecretConfiguration,
     ServerConnect(ConnectError),
-    Socket,
-    ServerMessage(ServerMessageError),
+    /// Talking to the server failed, see [`client::Client`].
+    Server(client::Error),
     StaticDataError,
 }
 
-impl From<common::protocols::pico::Error> for SoftError {
-    fn from(value: common::protocols::pico::Error) -> Self {
-        Self::ServerMessage(ServerMessageError::Protocol(value))
-    }
-}
-
-impl ServerMessageError {
-    fn fmt<W: fmt::Write>(&self, f: &mut W) -> fmt::Result {
-        match self {
-            Self::Encoding(_) => write!(f, "UTF-8 encoding error."),
-            Self::Protocol(e) => e.fmt(f),
-        }
-    }
-}
-
 impl SoftError {
     fn fmt<W: fmt::Write>(&self, f: &mut W) -> fmt::Result {
         match self {
             SoftError::WifiConnect(_) => write!(f, "Cannot connect to Wifi. Please check Wifi settings."),
             SoftError::ServerConnect(_) => write!(f, "Can't connect to server. Please check Wifi connection."),
-            SoftError::Socket => write!(f, "Internal socket error."),
-            SoftError::ServerMessage(e) => e.fmt(f),
+            SoftError::Server(e) => match e {
+                client::Error::Socket(_) => write!(f, "Internal socket error."),
+                client::Error::UnsupportedProtocol { .. } => {
+                    e.fmt(f)?;
+                    write!(f, " Please update.")
+                }
+                client::Error::AuthenticationDenied => {
+                    write!(f, "Server rejected the device secret. Please flash device uf2.")
+                }
+                e => e.fmt(f),
+            },
             SoftError::StaticDataError => write!(
                 f,
                 "Cannot read static data from flash memory. Please re-flash static data uf2."
             ),
             SoftError::WifiConfiguration => write!(f, "Wifi settings are not configured yet. Please flash uf2."),
+            SoftError::DeviceSecretConfiguration => {
+                write!(f, "Device secret is not configured yet. Please flash device uf2.")
+            }
         }
     }
 
@@ -72,11 +60,25 @@ impl SoftError {
             text.push_str(ERROR_TOO_LONG).unwrap();
         }
 
-        TextData { text }
+        TextData { text, style: None }
+    }
+}
+
+impl From<&SoftError> for DeviceErrorKind {
+    fn from(e: &SoftError) -> Self {
+        match e {
+            SoftError::WifiConnect(_) => DeviceErrorKind::WifiConnect,
+            SoftError::WifiConfiguration => DeviceErrorKind::WifiConfiguration,
+            SoftError::DeviceSecretConfiguration => DeviceErrorKind::DeviceSecretConfiguration,
+            SoftError::ServerConnect(_) => DeviceErrorKind::ServerConnect,
+            SoftError::StaticDataError => DeviceErrorKind::StaticData,
+            SoftError::Server(e) => DeviceErrorKind::from(e),
+        }
     }
 }
 
 pub fn handle_soft_error(e: SoftError) {
+    diagnostics::record_error(DeviceErrorKind::from(&e));
     let msg = e.to_display_string();
     log::error!("Handling error: {}", msg.text);
     PRIO_MESSAGE_SIGNAL.signal(msg);
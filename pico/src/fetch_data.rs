@@ -1,25 +1,59 @@
-//! Definition of the protocol used to communicate messages between server and client.
+//! Connecting to the server. The protocol itself is implemented by [`common::client`], which we give the connection
+//! and the global [`MESSAGES`].
+
+use core::future::Future;
 
 use common::{
+    client::{self, Client, Identity, Storage, Timer},
     consts::IMAGE_BUFFER_SIZE,
-    protocols::pico::{serialization::Transmission, ClientCommand, RequestUpdateResult, Update, UpdateKind},
-    types::MessageID,
+    protocols::pico::{self, serialization::AbstractSocket, Capabilities, DeviceStatus, Update},
+    types::{FirmwareVersion, PairingCode},
 };
-use cyw43::Control;
+use cyw43::{Control, ScanOptions};
 use embassy_net::tcp::TcpSocket;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::MutexGuard};
 use embassy_time::Duration;
-use embedded_io_async::Read;
+use heapless::String;
 
 use crate::{
-    error::{ServerMessageError, SoftError},
+    diagnostics,
+    error::SoftError,
     messagebuf::Messages,
-    static_data::{device_id, server_endpoint},
-    Result, MESSAGES,
+    static_data::{device_id, device_secret, server_endpoint, wifi_ssid},
+    Result, MESSAGES, PAIRING_CODE, URGENT_MESSAGE_SIGNAL,
 };
 
 // a.d. TODO we could treat all of the consts like in the static_data module to make it configurable.
-const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(client::SOCKET_TIMEOUT.as_secs());
 const TX_BUFFER_SIZE: usize = 256;
+/// The optional protocol features this firmware supports.
+const CAPABILITIES: Capabilities = Capabilities::FOOTER
+    .union(Capabilities::IMAGE_COMPRESSION)
+    .union(Capabilities::PAGING)
+    .union(Capabilities::PRIORITY)
+    .union(Capabilities::DELIVERY_ACK)
+    .union(Capabilities::PUSH)
+    .union(Capabilities::STATUS)
+    .union(Capabilities::STYLED_TEXT);
+
+/// The version from our Cargo.toml, which we report to the server.
+const FIRMWARE_VERSION: FirmwareVersion = FirmwareVersion {
+    major: parse_version_part(env!("CARGO_PKG_VERSION_MAJOR")),
+    minor: parse_version_part(env!("CARGO_PKG_VERSION_MINOR")),
+    patch: parse_version_part(env!("CARGO_PKG_VERSION_PATCH")),
+};
+
+const fn parse_version_part(s: &str) -> u16 {
+    let bytes = s.as_bytes();
+    let mut value = 0u16;
+    let mut i = 0;
+    while i < bytes.len() {
+        assert!(bytes[i].is_ascii_digit(), "version parts must be numbers");
+        value = value * 10 + (bytes[i] - b'0') as u16;
+        i += 1;
+    }
+    value
+}
 
 /// a.d. TODO document
 mod internal {
@@ -52,123 +86,130 @@ mod internal {
 
 pub use internal::Token;
 
-pub struct Socket<'a> {
+/// A connection to the server. It holds the [`Token`] since it uses the static socket buffers.
+pub struct Connection<'a> {
     #[allow(unused)]
     state: &'a mut Token,
     socket: TcpSocket<'static>,
 }
 
-impl<'a> Socket<'a> {
-    pub async fn new(
-        state: &'a mut Token,
-        stack: embassy_net::Stack<'static>,
-        control: &mut Control<'static>,
-    ) -> Result<Self> {
-        static mut RX_BUFFER: [u8; IMAGE_BUFFER_SIZE] = [0; IMAGE_BUFFER_SIZE];
-        static mut TX_BUFFER: [u8; TX_BUFFER_SIZE] = [0; TX_BUFFER_SIZE];
-
-        // SAFETY - TODO
-        let mut socket = unsafe {
-            #[allow(static_mut_refs)]
-            TcpSocket::new(stack, &mut RX_BUFFER, &mut TX_BUFFER)
-        };
-        socket.set_timeout(Some(SOCKET_TIMEOUT));
-
-        // TODO what does setting the gpio here do?
-        control.gpio_set(1, false).await;
-        let server_endpoint = server_endpoint();
-        log::info!("Connecting to server: {}", server_endpoint);
-        let connected = socket
-            .connect(server_endpoint)
-            .await
-            .map_err(|e| SoftError::ServerConnect(e));
-        control.gpio_set(0, true).await;
-
-        connected.and(Ok(Self { state, socket }))
-    }
-
+impl Connection<'_> {
     pub async fn close(mut self) {
         self.socket.close();
         self.socket.flush().await.ok();
     }
+}
 
-    pub async fn request_update(&mut self, after: Option<MessageID>) -> Result<RequestUpdateResult> {
-        let command = ClientCommand::RequestUpdate(device_id(), after);
+impl AbstractSocket for Connection<'_> {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> core::result::Result<(), pico::Error> {
+        self.socket.read_exact(buf).await
+    }
 
-        let mut command_buf = [0u8; ClientCommand::BUFFER_SIZE];
-        command.send(&mut command_buf, &mut self.socket).await?;
+    async fn write_all(&mut self, buf: &[u8]) -> core::result::Result<(), pico::Error> {
+        self.socket.write_all(buf).await
+    }
+}
 
-        let mut reply_buf = [0u8; RequestUpdateResult::BUFFER_SIZE];
-        let result = RequestUpdateResult::receive(&mut reply_buf, &mut self.socket).await?;
-        let valid = result
-            .check_valid()
-            .map_err(|e| SoftError::ServerMessage(ServerMessageError::Protocol(e)));
+/// Connect to the server, authenticate and report our status if the server wants it.
+pub async fn connect<'a>(
+    state: &'a mut Token,
+    stack: embassy_net::Stack<'static>,
+    control: &mut Control<'static>,
+    checksum_failures: u32,
+) -> Result<Client<Connection<'a>, EmbassyTimer>> {
+    static mut RX_BUFFER: [u8; IMAGE_BUFFER_SIZE] = [0; IMAGE_BUFFER_SIZE];
+    static mut TX_BUFFER: [u8; TX_BUFFER_SIZE] = [0; TX_BUFFER_SIZE];
+
+    let identity = Identity {
+        device_id: device_id(),
+        secret: device_secret().ok_or(SoftError::DeviceSecretConfiguration)?,
+        firmware_version: FIRMWARE_VERSION,
+        capabilities: CAPABILITIES,
+    };
+
+    // SAFETY - TODO
+    let mut socket = unsafe {
+        #[allow(static_mut_refs)]
+        TcpSocket::new(stack, &mut RX_BUFFER, &mut TX_BUFFER)
+    };
+    socket.set_timeout(Some(SOCKET_TIMEOUT));
+
+    // TODO what does setting the gpio here do?
+    control.gpio_set(1, false).await;
+    let server_endpoint = server_endpoint();
+    log::info!("Connecting to server: {}", server_endpoint);
+    let connected = socket
+        .connect(server_endpoint)
+        .await
+        .map_err(|e| SoftError::ServerConnect(e));
+    control.gpio_set(0, true).await;
+    connected?;
+
+    let mut client = Client::connect(Connection { state, socket }, EmbassyTimer, &identity).await?;
+    if client.capabilities().contains(Capabilities::STATUS) {
+        let rssi = wifi_rssi(control).await;
+        client.report_status(status(rssi, checksum_failures).await).await?;
+    }
+    Ok(client)
+}
 
-        log::info!("CheckUpdateResult {result:?}");
-        valid.and(Ok(result))
+/// How we are doing, which we tell the server.
+async fn status(rssi: Option<i16>, checksum_failures: u32) -> DeviceStatus {
+    let (free_text_slots, free_image_slots) = {
+        let messages = MESSAGES.lock().await;
+        (messages.free_text_slots(), messages.free_image_slots())
+    };
+    DeviceStatus {
+        uptime_sec: diagnostics::uptime_sec(),
+        rssi,
+        free_text_slots: free_text_slots as u8,
+        free_image_slots: free_image_slots as u8,
+        checksum_failures,
+        last_error: diagnostics::last_error(),
     }
+}
 
-    pub async fn receive_payload(&mut self, update: &Update, payload_buf: &mut [u8]) -> Result<()> {
-        assert!(
-            payload_buf.len() == update.kind.size(),
-            "Payload buf length is {} <> {}, for update kind {:?}",
-            payload_buf.len(),
-            update.kind.size(),
-            update.kind
-        );
-
-        self.socket
-            .read_exact(payload_buf)
-            .await
-            .map_err(|_| SoftError::Socket)?;
-        Ok(())
+/// Signal strength of our WiFi in dBm. The driver does not expose it for the current connection, so we scan for it.
+async fn wifi_rssi(control: &mut Control<'static>) -> Option<i16> {
+    let mut options = ScanOptions::default();
+    options.ssid = Some(String::try_from(wifi_ssid()?).ok()?);
+    let mut scanner = control.scan(options).await;
+    let mut rssi = None;
+    // The scan can find several access points of our WiFi, we are most likely connected to the strongest.
+    while let Some(bss) = scanner.next().await {
+        rssi = rssi.max(Some(bss.rssi));
     }
+    rssi
+}
 
-    pub async fn handle_update(&mut self, update: Update) -> Result<()> {
-        log::info!("Received an update. Acquiring mutex to change message buffer.");
-        let mut guard = MESSAGES.lock().await;
-        let messages: &mut Messages = &mut guard;
-
-        match update.kind {
-            UpdateKind::Text(text_len) => {
-                log::info!("Requesting text update.");
-                let message = messages.next_available_text();
-                message.update_meta(&update);
-
-                // SAFETY - We read the bytes from the network into message.data.text.
-                // If that fails (in which case the buffer could be half-filled) or if the buffer does not contain valid UTF-8 in the end, we clear the string.
-                // We are holding the message lock so no one else can access the the unsafe buffer contents while this future may be paused.
-                unsafe {
-                    let message_buf = message.data.text.as_mut_vec();
-                    // a.d. TODO cannot use this since elements were not initialized
-                    // how about creating the slice directly
-                    // but calling read with maybeuninit data is potentially UB so we should just initialize all strings in the beginning. Then we can also use set_len.
-                    message_buf.set_len(text_len as usize);
-                    if let Err(e) = self.receive_payload(&update, message_buf).await {
-                        message_buf.clear();
-                        return Err(e);
-                    }
-
-                    match core::str::from_utf8(message_buf) {
-                        Ok(text) => {
-                            log::info!("Received text update: {}", text);
-                        }
-                        Err(e) => {
-                            message_buf.clear();
-                            return Err(SoftError::ServerMessage(ServerMessageError::Encoding(e)));
-                        }
-                    }
-                }
-            }
-            UpdateKind::Image => {
-                log::info!("Requesting image update.");
-                let message = messages.next_available_image();
-                message.update_meta(&update);
-                let payload_buf = message.data.image.as_mut();
-                self.receive_payload(&update, payload_buf).await?;
-            }
-        };
+pub struct EmbassyTimer;
+
+impl Timer for EmbassyTimer {
+    async fn with_timeout<F: Future>(&self, timeout: core::time::Duration, future: F) -> Option<F::Output> {
+        let timeout = Duration::from_micros(timeout.as_micros() as u64);
+        embassy_time::with_timeout(timeout, future).await.ok()
+    }
+}
 
-        Ok(())
+/// The global [`MESSAGES`], which the display tasks show while we wait for updates.
+pub struct SharedMessages;
+
+impl Storage for SharedMessages {
+    type Messages = Messages;
+    type Guard<'a> = MutexGuard<'static, CriticalSectionRawMutex, Messages>;
+
+    async fn lock(&mut self) -> Self::Guard<'_> {
+        log::info!("Acquiring mutex to change message buffer.");
+        MESSAGES.lock().await
+    }
+
+    fn set_pairing_code(&mut self, code: Option<PairingCode>) {
+        PAIRING_CODE.lock(|pairing_code| pairing_code.set(code));
+    }
+
+    fn stored(&mut self, update: &Update) {
+        if update.urgent {
+            URGENT_MESSAGE_SIGNAL.signal(());
+        }
     }
 }
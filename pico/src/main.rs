@@ -7,12 +7,13 @@
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
 
-use core::{cmp, future::pending};
+use core::{cell::Cell, future::pending};
 
 use assign_resources::assign_resources;
 use common::{
+    client::SyncState,
     consts::{IMAGE_HEIGHT, IMAGE_WIDTH},
-    protocols::pico::RequestUpdateResult,
+    types::PairingCode,
 };
 use cortex_m_rt::entry;
 use cyw43::JoinOptions;
@@ -29,8 +30,12 @@ use embassy_rp::{
     spi::{self, Blocking, Spi},
     usb::{self, Driver},
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex, signal::Signal};
-use embassy_time::{Delay, Duration, Instant, Timer};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Delay, Duration, Timer};
 use embedded_hal_bus::spi::ExclusiveDevice;
 use messagebuf::TextData;
 /// In deploy mode we just want to reboot the device.
@@ -48,22 +53,31 @@ use crate::{
     static_data::device_id,
 };
 
+mod diagnostics;
 mod display;
 mod error;
 mod fetch_data;
 mod messagebuf;
+mod render;
 mod static_data;
 
 const PRIO_MESSAGE_DISPLAY_DURATION: Duration = Duration::from_secs(3);
 const MESSAGE_DISPLAY_DURATION: Duration = Duration::from_secs(5);
 const MESSAGE_FETCH_INTERVAL: Duration = Duration::from_secs(60);
 const SERVER_CONNECT_ERROR_WAIT: Duration = Duration::from_secs(2);
+/// Presses of the acknowledge button within this time count as one.
+const BUTTON_DEBOUNCE: Duration = Duration::from_millis(200);
 
 // a.d. TODO can we drop down to a Noop mutex? depends on if we access messages from difference executors.
 /// Global variable to hold message data retrieved from server. No persistence across reboots.
 /// We need the async mutex because we want to do an async read call inside a critical section.
 static MESSAGES: Mutex<CriticalSectionRawMutex, Messages> = Mutex::new(Messages::new());
 static PRIO_MESSAGE_SIGNAL: Signal<CriticalSectionRawMutex, TextData> = Signal::new();
+/// Signaled when an urgent message arrived or was acknowledged, so that the display does not wait for the rotation.
+static URGENT_MESSAGE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// Set while the server does not know this device. The code is shown instead of "No messages" so that a user can claim it.
+static PAIRING_CODE: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<PairingCode>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
 
 static FW: &[u8; 230321] = include_bytes!("../cyw43-firmware/43439A0.bin");
 static CLM: &[u8; 4752] = include_bytes!("../cyw43-firmware/43439A0_clm.bin");
@@ -100,6 +114,14 @@ mod init {
             .expect("Spawning resetter task failed.")
     }
 
+    /// ----- Acknowledge button setup -----
+    pub(super) fn acknowledge(spawner: Spawner, r: AcknowledgeResources) {
+        let button = gpio::Input::new(r.pin, gpio::Pull::Up);
+        spawner
+            .spawn(main_tasks::acknowledge_urgent(button))
+            .expect("Spawning acknowledge_urgent task failed.")
+    }
+
     /// ----- USB logging setup -----
     pub(super) fn usb(spawner: SendSpawner, r: UsbLogResources) {
         let driver = Driver::new(r.usb, Irqs);
@@ -299,8 +321,18 @@ mod init {
 //// ---- Main tasks to implement the device features. ------------------------
 mod main_tasks {
 
+    use core::fmt::Write;
+
+    use common::consts::TEXT_BUFFER_SIZE;
+    use embassy_rp::gpio;
+    use heapless::String;
+
     use super::*;
-    use crate::{display::DisplayOptions, error::handle_hard_error, messagebuf::DisplayMessageData};
+    use crate::{
+        display::DisplayOptions,
+        error::handle_hard_error,
+        messagebuf::{DisplayMessage, DisplayMessageData, Rotation},
+    };
 
     /// This task connects to the configured server and periodically fetches new messages to update the global [`MESSAGES`] object.
     ///
@@ -312,14 +344,14 @@ mod main_tasks {
         stack: net::Stack<'static>,
         mut control: cyw43::Control<'static>,
     ) {
-        // We save the id of the latest message we received to send to the server for the next update check.
-        let mut last_message_id = None;
+        // We remember the latest update we received, so that the next connection continues after it.
+        let mut sync_state = SyncState::new();
 
         loop {
             log::info!("Creating new connection.");
-            let protocol = fetch_data::Socket::new(&mut state, stack, &mut control).await;
-            let mut protocol = match protocol {
-                Ok(protocol) => protocol,
+            let client = fetch_data::connect(&mut state, stack, &mut control, sync_state.checksum_failures).await;
+            let mut client = match client {
+                Ok(client) => client,
                 Err(e) => {
                     handle_soft_error(e);
                     Timer::after(SERVER_CONNECT_ERROR_WAIT).await;
@@ -327,31 +359,11 @@ mod main_tasks {
                 }
             };
 
-            // We loop as long as we successfully receive new message updates. Every other case exits the loop.
-            // a.d. TODO move somewhere else
-            let update_result = loop {
-                log::info!("Checking for updates");
-                match protocol.request_update(last_message_id).await {
-                    Err(e) => {
-                        break Err(e);
-                    }
-                    Ok(RequestUpdateResult::NoUpdate) => {
-                        log::info!("No updates for now. Sleeping.");
-                        break Ok(());
-                    }
-                    Ok(RequestUpdateResult::Update(update)) => match protocol.handle_update(update).await {
-                        Ok(()) => {
-                            last_message_id = Some(last_message_id.map_or(update.id, |last| cmp::max(last, update.id)));
-                        }
-                        Err(e) => break Err(e),
-                    },
-                }
-            };
-
-            protocol.close().await;
+            let update_result = client.sync(&mut fetch_data::SharedMessages, &mut sync_state).await;
+            client.into_socket().close().await;
 
             if let Err(e) = update_result {
-                handle_soft_error(e);
+                handle_soft_error(e.into());
             }
             Timer::after(MESSAGE_FETCH_INTERVAL).await;
         }
@@ -372,36 +384,94 @@ mod main_tasks {
         }
     }
 
+    /// Acknowledge the urgent messages when the button is pressed, so that they are shown like all others.
+    #[embassy_executor::task]
+    pub(super) async fn acknowledge_urgent(mut button: gpio::Input<'static>) -> ! {
+        loop {
+            button.wait_for_falling_edge().await;
+            log::info!("Acknowledge button pressed.");
+            if MESSAGES.lock().await.acknowledge_urgent() {
+                URGENT_MESSAGE_SIGNAL.signal(());
+            }
+            Timer::after(BUTTON_DEBOUNCE).await;
+        }
+    }
+
+    /// Show `message`, or its page `page` if it is a text with several pages.
+    async fn show_message(
+        display: &'static SharedDisplay,
+        message: &DisplayMessage<'_>,
+        page: usize,
+        options: DisplayOptions,
+    ) {
+        match message.data {
+            DisplayMessageData::Text(data) if message.page_count() > 1 => {
+                log::info!("Showing page {} of a text message: {}", page + 1, data.page(page));
+                let mut display = display.lock().await;
+                display
+                    .page_with_footer(data.page(page), message.footer, page, message.page_count(), options)
+                    .map_err(|e| handle_hard_error(e))
+                    .ok();
+            }
+            DisplayMessageData::Text(TextData {
+                text,
+                style: Some(style),
+            }) => {
+                log::info!("Showing a styled text message: {}", text.as_str());
+                let mut display = display.lock().await;
+                display
+                    .styled_string_with_footer(text, message.footer, style, options)
+                    .map_err(|e| handle_hard_error(e))
+                    .ok();
+            }
+            DisplayMessageData::Text(data) => {
+                log::info!("Showing a text message: {}", data.text.as_str());
+                let mut display = display.lock().await;
+                display
+                    .string_with_footer(&data.text, message.footer, options)
+                    .map_err(|e| handle_hard_error(e))
+                    .ok();
+            }
+            DisplayMessageData::Image(data) => {
+                log::info!("Showing an image message.");
+                let mut display = display.lock().await;
+                display
+                    .draw_image(&data.image, message.footer, options)
+                    .map_err(|e| handle_hard_error(e))
+                    .ok();
+            }
+        }
+    }
+
     /// Periodically get the next messages from the global [`MESSAGES`] object and display it.
     ///
     /// - [`display`]: a driver to interact with the display's ST7735 chip.
     #[embassy_executor::task]
     pub(super) async fn display_messages(display: &'static SharedDisplay) {
-        let mut last_message_time = Instant::MIN;
+        let mut rotation = Rotation::new();
+        let mut urgent_rotation = Rotation::new();
 
-        // Each time the loop is entered we display the next non-priority message and then wait for `MESSAGE_DISPLAY_DURATION`
+        // Each time the loop is entered we display the next urgent message, or the next non-priority message if there is none,
+        // and then wait for `MESSAGE_DISPLAY_DURATION` or until the urgent messages change.
         // Note that if a priority message arrives this will be interrupted (outside of the critical section of locking the display)
         loop {
             log::info!("Acquiring mutex for message buffer and for display.");
             let messages = MESSAGES.lock().await;
 
-            if let Some(next_message) = messages.next_display_message_generic(last_message_time) {
-                last_message_time = next_message.meta.updated_at;
-                match next_message.data {
-                    DisplayMessageData::Text(data) => {
-                        log::info!("Showing a text message: {}", data.text.as_str());
-                        let mut display = display.lock().await;
-                        display
-                            .string_formatted(&data.text, DisplayOptions::NormalMessage)
-                            .map_err(|e| handle_hard_error(e))
-                            .ok();
-                    }
-                    DisplayMessageData::Image(data) => {
-                        log::info!("Showing an image message.");
-                        let mut display = display.lock().await;
-                        display.draw_image(&data.image).map_err(|e| handle_hard_error(e)).ok();
-                    }
-                }
+            // Urgent messages take the place of the rotation until they are acknowledged.
+            if let Some((urgent_message, page)) = urgent_rotation.next_urgent(&messages) {
+                show_message(display, &urgent_message, page, DisplayOptions::PriorityMessage).await;
+            } else if let Some((next_message, page)) = rotation.next(&messages) {
+                show_message(display, &next_message, page, DisplayOptions::NormalMessage).await;
+            } else if let Some(code) = PAIRING_CODE.lock(|code| code.get()) {
+                let mut text: String<TEXT_BUFFER_SIZE> = String::new();
+                // The text is short enough to always fit into the buffer.
+                write!(text, "Claim me with\n/claim {code}").ok();
+                let mut display = display.lock().await;
+                display
+                    .string_formatted(&text, DisplayOptions::NormalMessage)
+                    .map_err(|e| handle_hard_error(e))
+                    .ok();
             } else {
                 let mut display = display.lock().await;
                 display
@@ -413,7 +483,13 @@ mod main_tasks {
             // Must drop this before waiting below so that we do not hold the locks for too long.
             drop(messages);
 
-            Timer::after(MESSAGE_DISPLAY_DURATION).await;
+            // New urgent messages and acknowledgements are shown right away.
+            if with_timeout(MESSAGE_DISPLAY_DURATION, URGENT_MESSAGE_SIGNAL.wait())
+                .await
+                .is_ok()
+            {
+                log::info!("Urgent messages changed.");
+            }
         }
     }
 }
@@ -447,6 +523,9 @@ assign_resources! {
     reset: ResetResources {
         pin: PIN_1,
     }
+    acknowledge: AcknowledgeResources {
+        pin: PIN_2,
+    }
     cyw43: Cyw43Resources {
         pwr: PIN_23,
         cs: PIN_25,
@@ -478,10 +557,12 @@ async fn init_normal_tasks(
     spawner: Spawner,
     protocol_token: fetch_data::Token,
     r_reset: ResetResources,
+    r_acknowledge: AcknowledgeResources,
     r_cyw43: Cyw43Resources,
     display: &'static SharedDisplay,
 ) {
     init::reset(spawner, r_reset);
+    init::acknowledge(spawner, r_acknowledge);
 
     spawner
         .spawn(main_tasks::display_messages(display))
@@ -513,7 +594,14 @@ fn main() -> ! {
     let thread_executor = EXECUTOR_NORMAL.init_with(Executor::new);
     thread_executor.run(|spawner| {
         spawner
-            .spawn(init_normal_tasks(spawner, protocol_token, r.reset, r.cyw43, display))
+            .spawn(init_normal_tasks(
+                spawner,
+                protocol_token,
+                r.reset,
+                r.acknowledge,
+                r.cyw43,
+                display,
+            ))
             .expect("Spawning init_system_tasks task failed.")
     });
 }
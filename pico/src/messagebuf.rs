@@ -1,22 +1,29 @@
 use core::borrow::Borrow;
 
 use common::{
-    consts::{IMAGE_BUFFER_SIZE, TEXT_BUFFER_SIZE},
-    protocols::pico::Update,
+    client::{ImageSlot, MessageStore},
+    consts::{FOOTER_BUFFER_SIZE, IMAGE_BUFFER_SIZE, PAGED_TEXT_BUFFER_SIZE, PAGE_SEPARATOR},
+    protocols::pico::{TextStyle, Update, UpdateKind},
+    types::MessageID,
 };
 use embassy_time::{Duration, Instant};
 use heapless::String;
 
-/// a.d. TODO we could also do paging for longer messages, since we already need to infer linebreaks anyways.
 const TEXT_MESSAGE_NUM: usize = 10;
 const IMAGE_MESSAGE_NUM: usize = 2;
+/// Each kind has one slot more than it shows. New messages are received into an inactive slot and only replace
+/// the oldest shown message once they are complete, so a failed transfer leaves the shown messages alone.
+const TEXT_SLOTS: usize = TEXT_MESSAGE_NUM + 1;
+const IMAGE_SLOTS: usize = IMAGE_MESSAGE_NUM + 1;
 
 pub trait MessageData: Borrow<[u8]> {}
 
 #[derive(Debug)]
-#[repr(transparent)]
 pub struct TextData {
-    pub text: String<TEXT_BUFFER_SIZE>,
+    /// Texts that do not fit on the display consist of pages that are separated by [`PAGE_SEPARATOR`].
+    pub text: String<PAGED_TEXT_BUFFER_SIZE>,
+    /// How the text is drawn, `None` for black on white.
+    pub style: Option<TextStyle>,
 }
 
 #[derive(Debug)]
@@ -35,7 +42,19 @@ impl MessageData for TextData {}
 impl TextData {
     const fn new() -> Self {
         // a.d. TODO taking a mutable reference to this results in an empty slice!
-        Self { text: String::new() }
+        Self {
+            text: String::new(),
+            style: None,
+        }
+    }
+
+    pub fn page_count(&self) -> usize {
+        self.text.split(PAGE_SEPARATOR).count()
+    }
+
+    /// The text of page `index`, counting from 0. Empty if there is no such page.
+    pub fn page(&self, index: usize) -> &str {
+        self.text.split(PAGE_SEPARATOR).nth(index).unwrap_or_default()
     }
 }
 
@@ -56,15 +75,20 @@ impl ImageData {
 
 #[derive(Clone, Copy)]
 pub struct MessageMeta {
+    pub id: MessageID,
     pub lifetime: Duration,
     pub updated_at: Instant,
+    /// Shown before all other messages until someone acknowledges it with the button.
+    pub urgent: bool,
 }
 
 impl MessageMeta {
     const fn new() -> Self {
         Self {
+            id: MessageID(0),
             lifetime: Duration::MIN,
             updated_at: Instant::MIN,
+            urgent: false,
         }
     }
 
@@ -79,12 +103,18 @@ impl MessageMeta {
 pub struct Message<T> {
     pub data: T,
     pub meta: MessageMeta,
+    /// Shown below the message, e.g. the name of its author. Empty for no footer.
+    pub footer: String<FOOTER_BUFFER_SIZE>,
 }
 
 impl<T> Message<T> {
+    /// Show the message that was received into this slot. Call [`Messages::commit_text`] or
+    /// [`MessageStore::commit_image`] afterwards to make room for the next one.
     pub fn update_meta(&mut self, update: &Update) {
+        self.meta.id = update.id;
         self.meta.updated_at = Instant::now();
         self.meta.lifetime = Duration::from_secs(update.lifetime_sec.into());
+        self.meta.urgent = update.urgent;
     }
 }
 
@@ -96,6 +126,7 @@ impl TextMessage {
         Self {
             data: TextData::new(),
             meta: MessageMeta::new(),
+            footer: String::new(),
         }
     }
 }
@@ -105,6 +136,7 @@ impl ImageMessage {
         Self {
             data: ImageData::new(),
             meta: MessageMeta::new(),
+            footer: String::new(),
         }
     }
 }
@@ -117,6 +149,17 @@ pub enum DisplayMessageData<'a> {
 pub struct DisplayMessage<'a> {
     pub data: DisplayMessageData<'a>,
     pub meta: MessageMeta,
+    pub footer: &'a str,
+}
+
+impl DisplayMessage<'_> {
+    /// How many pages the message is shown on, one after the other.
+    pub fn page_count(&self) -> usize {
+        match self.data {
+            DisplayMessageData::Text(data) => data.page_count(),
+            DisplayMessageData::Image(_) => 1,
+        }
+    }
 }
 
 impl<'a> From<&'a TextMessage> for DisplayMessage<'a> {
@@ -124,6 +167,7 @@ impl<'a> From<&'a TextMessage> for DisplayMessage<'a> {
         Self {
             data: DisplayMessageData::Text(&value.data),
             meta: value.meta,
+            footer: &value.footer,
         }
     }
 }
@@ -133,6 +177,7 @@ impl<'a> From<&'a ImageMessage> for DisplayMessage<'a> {
         Self {
             data: DisplayMessageData::Image(&value.data),
             meta: value.meta,
+            footer: &value.footer,
         }
     }
 }
@@ -142,8 +187,8 @@ impl<'a> From<&'a ImageMessage> for DisplayMessage<'a> {
 /// TODO I think internal pointers would be so nice here. Then I could keep the metadata inside one array and reference the buffers from there.
 /// We don't want to mix both types of messages in one array without indirection for the buffers because then all text messages would be the size of image messages.
 pub struct Messages {
-    pub texts: [Message<TextData>; TEXT_MESSAGE_NUM],
-    pub images: [Message<ImageData>; IMAGE_MESSAGE_NUM],
+    pub texts: [Message<TextData>; TEXT_SLOTS],
+    pub images: [Message<ImageData>; IMAGE_SLOTS],
 }
 
 impl Messages {
@@ -160,21 +205,42 @@ impl Messages {
                 TextMessage::new(),
                 TextMessage::new(),
                 TextMessage::new(),
+                TextMessage::new(),
             ],
-            images: [ImageMessage::new(), ImageMessage::new()],
+            images: [ImageMessage::new(), ImageMessage::new(), ImageMessage::new()],
         }
     }
 
-    /// Get a pointer to either a `TextMessage` or `ImageMessage` to display.
-    /// a.d. TODO fix
-    /// - `last_message`: the last message that was displayed. If `None`, it this function returns the oldest active message.
-    ///   If `Some(m)` it returns the oldest active message newer than `m`.
-    pub fn next_display_message_generic(&self, last_message_time: Instant) -> Option<DisplayMessage<'_>> {
+    /// Urgent messages are shown like all others from now on.
+    /// Returns false if there was no urgent message to acknowledge.
+    pub fn acknowledge_urgent(&mut self) -> bool {
+        let metas = self
+            .texts
+            .iter_mut()
+            .map(|text| &mut text.meta)
+            .chain(self.images.iter_mut().map(|image| &mut image.meta));
+
+        let mut acknowledged = false;
+        for meta in metas.filter(|meta| meta.urgent && meta.is_active()) {
+            meta.urgent = false;
+            acknowledged = true;
+        }
+        acknowledged
+    }
+
+    /// The oldest active message for which `filter` holds that is newer than `last_message_time`.
+    /// If there is none, the rotation wraps around to the oldest active message.
+    fn next_message_where(
+        &self,
+        last_message_time: Instant,
+        filter: impl Fn(&MessageMeta) -> bool,
+    ) -> Option<DisplayMessage<'_>> {
         let messages = self
             .texts
             .iter()
-            .map(|text| DisplayMessage::from(text))
-            .chain(self.images.iter().map(|image| DisplayMessage::from(image)));
+            .map(DisplayMessage::from)
+            .chain(self.images.iter().map(DisplayMessage::from))
+            .filter(|m| filter(&m.meta));
 
         let latest_message = messages
             .clone()
@@ -200,10 +266,38 @@ impl Messages {
         }
     }
 
+    /// The message `id` if it is still active and `filter` holds for it.
+    fn active_message_where(&self, id: MessageID, filter: impl Fn(&MessageMeta) -> bool) -> Option<DisplayMessage<'_>> {
+        self.texts
+            .iter()
+            .map(DisplayMessage::from)
+            .chain(self.images.iter().map(DisplayMessage::from))
+            .find(|m| m.meta.id == id && m.meta.is_active() && filter(&m.meta))
+    }
+
+    /// Stop showing the message `id`. Its slot becomes available for new messages.
+    /// Returns false if the message was not active anyways.
+    pub fn remove(&mut self, id: MessageID) -> bool {
+        let metas = self
+            .texts
+            .iter_mut()
+            .map(|text| &mut text.meta)
+            .chain(self.images.iter_mut().map(|image| &mut image.meta));
+
+        let mut removed = false;
+        for meta in metas.filter(|meta| meta.id == id && meta.is_active()) {
+            meta.lifetime = Duration::MIN;
+            removed = true;
+        }
+        removed
+    }
+
     pub fn next_available_text(&mut self) -> &mut Message<TextData> {
         log::debug!("nat: Retrieve next available text.");
         let message = Messages::next_available_message(&mut self.texts);
         message.data.text.clear();
+        message.data.style = None;
+        message.footer.clear();
         message
     }
 
@@ -211,12 +305,46 @@ impl Messages {
         log::debug!("nai: Retrieve next available image.");
         let message = Messages::next_available_message(&mut self.images);
         message.data.image.fill(0);
+        message.footer.clear();
         message
     }
 
+    /// Hide the oldest text if more texts are shown than we want, so that the next one has an inactive slot.
+    pub fn commit_text(&mut self) {
+        Messages::evict_oldest(&mut self.texts, TEXT_MESSAGE_NUM);
+    }
+
+    /// How many more texts we can show before the oldest one is replaced.
+    pub fn free_text_slots(&self) -> usize {
+        Messages::free_slots(&self.texts, TEXT_MESSAGE_NUM)
+    }
+
+    /// How many more images we can show before the oldest one is replaced.
+    pub fn free_image_slots(&self) -> usize {
+        Messages::free_slots(&self.images, IMAGE_MESSAGE_NUM)
+    }
+
+    fn free_slots<T>(messages: &[Message<T>], max_active: usize) -> usize {
+        let active = messages.iter().filter(|message| message.meta.is_active()).count();
+        max_active.saturating_sub(active)
+    }
+
+    fn evict_oldest<T>(messages: &mut [Message<T>], max_active: usize) {
+        while messages.iter().filter(|message| message.meta.is_active()).count() > max_active {
+            // PANIC: unwrap cannot fail since more than `max_active` messages are active.
+            let oldest = messages
+                .iter_mut()
+                .filter(|message| message.meta.is_active())
+                .min_by_key(|message| message.meta.updated_at)
+                .unwrap();
+            oldest.meta.lifetime = Duration::MIN;
+        }
+    }
+
     /// Returns a pointer to the next message that should be overwritten.
-    fn next_available_message<'a, T: MessageData>(messages: &'a mut [Message<T>]) -> &'a mut Message<T> {
-        assert!(messages.len() > 0);
+    /// Thanks to the extra slot this is an inactive message, unless a commit was missed.
+    fn next_available_message<T: MessageData>(messages: &mut [Message<T>]) -> &mut Message<T> {
+        assert!(!messages.is_empty());
 
         // We first check if there is any message that is currently not active, then we can just use that.
         // Otherwise we return the oldest active message, to be overwritten.
@@ -244,3 +372,91 @@ impl Messages {
         // }
     }
 }
+
+/// Where the display is in the rotation of messages. The pages of a long text are shown one after the other before
+/// the rotation moves on.
+pub struct Rotation {
+    last_message_time: Instant,
+    /// The message that was shown last and its page, if it has more than one.
+    page: Option<(MessageID, usize)>,
+}
+
+impl Rotation {
+    pub const fn new() -> Self {
+        Self {
+            last_message_time: Instant::MIN,
+            page: None,
+        }
+    }
+
+    /// The next message to show of all messages, together with the index of its page to show.
+    pub fn next<'a>(&mut self, messages: &'a Messages) -> Option<(DisplayMessage<'a>, usize)> {
+        self.next_where(messages, |_| true)
+    }
+
+    /// Like [`Self::next`], but only for the urgent messages that nobody acknowledged yet.
+    pub fn next_urgent<'a>(&mut self, messages: &'a Messages) -> Option<(DisplayMessage<'a>, usize)> {
+        self.next_where(messages, |meta| meta.urgent)
+    }
+
+    fn next_where<'a>(
+        &mut self,
+        messages: &'a Messages,
+        filter: impl Fn(&MessageMeta) -> bool,
+    ) -> Option<(DisplayMessage<'a>, usize)> {
+        if let Some((id, page)) = self.page {
+            if let Some(message) = messages.active_message_where(id, &filter) {
+                if page + 1 < message.page_count() {
+                    self.page = Some((id, page + 1));
+                    return Some((message, page + 1));
+                }
+            }
+        }
+
+        let message = messages.next_message_where(self.last_message_time, filter)?;
+        self.last_message_time = message.meta.updated_at;
+        self.page = (message.page_count() > 1).then_some((message.meta.id, 0));
+        Some((message, 0))
+    }
+}
+
+impl MessageStore for Messages {
+    type ImageSlot = ImageMessage;
+
+    fn add_text(&mut self, update: &Update, text: &str, footer: &str) {
+        let message = self.next_available_text();
+        // Cannot fail since `check_valid` ensures that the text and the footer fit into their buffers.
+        message.data.text.push_str(text).ok();
+        if let UpdateKind::StyledText(style, _) = update.kind {
+            message.data.style = Some(style);
+        }
+        message.footer.push_str(footer).ok();
+        message.update_meta(update);
+        self.commit_text();
+    }
+
+    fn image_slot(&mut self) -> &mut ImageMessage {
+        self.next_available_image()
+    }
+
+    /// Hide the oldest image if more images are shown than we want, so that the next one has an inactive slot.
+    fn commit_image(&mut self) {
+        Messages::evict_oldest(&mut self.images, IMAGE_MESSAGE_NUM);
+    }
+
+    fn recall(&mut self, id: MessageID) {
+        self.remove(id);
+    }
+}
+
+impl ImageSlot for ImageMessage {
+    fn image(&mut self) -> &mut [u8; IMAGE_BUFFER_SIZE] {
+        &mut self.data.image
+    }
+
+    fn finish(&mut self, update: &Update, footer: &str) {
+        // Cannot fail since `check_valid` ensures that the footer fits into its buffer.
+        self.footer.push_str(footer).ok();
+        self.update_meta(update);
+    }
+}
@@ -0,0 +1,317 @@
+//! Layout of messages on the display, independent of the display hardware so that host tools can draw them the same way.
+
+use core::fmt::Write;
+
+use common::{
+    consts::{IMAGE_HEIGHT, IMAGE_WIDTH, MAX_PAGES, TEXT_COLUMNS, TEXT_LINES},
+    protocols::pico::{FontSize, TextAlignment, TextStyle},
+};
+use embedded_graphics::{
+    draw_target::DrawTarget,
+    image::{Image, ImageRaw, ImageRawBE},
+    mono_font::{
+        self,
+        ascii::{FONT_10X20, FONT_6X13, FONT_6X13_BOLD, FONT_9X15, FONT_9X15_BOLD, FONT_9X18_BOLD},
+        MonoFont, MonoTextStyle,
+    },
+    pixelcolor::{raw::RawU16, Rgb565},
+    prelude::*,
+    primitives::{PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, StrokeAlignment},
+    text::{Alignment, Baseline, Text, TextStyleBuilder},
+};
+use embedded_text::{
+    alignment::{HorizontalAlignment, VerticalAlignment},
+    style::{HeightMode, TextBoxStyle, TextBoxStyleBuilder, VerticalOverdraw},
+    TextBox,
+};
+use heapless::String;
+
+const MESSAGE_FONT: mono_font::MonoFont = FONT_9X15;
+const MESSAGE_TEXT_COLOR: Rgb565 = Rgb565::BLACK;
+const MESSAGE_BG_COLOR: Rgb565 = Rgb565::WHITE;
+pub const PRIO_MESSAGE_BG_COLOR: Rgb565 = Rgb565::RED;
+/// Images and styled texts do not get the background of priority messages, so they get a frame in its color instead.
+const PRIO_IMAGE_FRAME_WIDTH: u32 = 4;
+pub const MESSAGE_TEXT_STYLE: MonoTextStyle<'_, Rgb565> = MonoTextStyle::new(&MESSAGE_FONT, MESSAGE_TEXT_COLOR);
+const FOOTER_TEXT_COLOR: Rgb565 = Rgb565::WHITE;
+const FOOTER_BG_COLOR: Rgb565 = Rgb565::BLACK;
+const FOOTER_TEXT_STYLE: MonoTextStyle<'_, Rgb565> = MonoTextStyle::new(&MESSAGE_FONT, FOOTER_TEXT_COLOR);
+
+const MARGIN_LEFT: u32 = 4;
+const MARGIN_RIGHT: u32 = 3;
+const MARGIN_TOP: u32 = 4;
+const MARGIN_BOTTOM: u32 = 4;
+
+/// The footer takes the place of the last line of text.
+const FOOTER_HEIGHT: u32 = MESSAGE_FONT.character_size.height;
+const FOOTER_TOP: i32 = (IMAGE_HEIGHT as u32 - MARGIN_BOTTOM - FOOTER_HEIGHT) as i32;
+/// Enough for "4/4", since texts have at most `MAX_PAGES` pages.
+const PAGE_INDICATOR_LEN: usize = 3;
+const _ASSERT_INDICATOR_FITS: () = assert!(MAX_PAGES < 10);
+
+/// With these margins we are able to fit TEXT_LINES * TEXT_COLUMNS characters on one screen.
+const _ASSERT_WIDTH_FITS: () = assert!(
+    IMAGE_WIDTH
+        == MARGIN_LEFT as usize + TEXT_COLUMNS * MESSAGE_FONT.character_size.width as usize + MARGIN_RIGHT as usize
+);
+const _ASSERT_HEIGHT_FITS: () = assert!(
+    IMAGE_HEIGHT
+        == MARGIN_TOP as usize + TEXT_LINES * MESSAGE_FONT.character_size.height as usize + MARGIN_BOTTOM as usize
+);
+
+/// Each font fits as many characters on the display as the server expects when it checks that a styled text fits.
+const _ASSERT_FONTS_FIT: () = {
+    let sizes = [FontSize::Small, FontSize::Normal, FontSize::Large];
+    let mut i = 0;
+    while i < sizes.len() {
+        let font = fonts(sizes[i]).0;
+        assert!(
+            sizes[i].columns() as u32 * font.character_size.width <= IMAGE_WIDTH as u32 - MARGIN_LEFT - MARGIN_RIGHT
+        );
+        assert!(
+            sizes[i].lines() as u32 * font.character_size.height <= IMAGE_HEIGHT as u32 - MARGIN_TOP - MARGIN_BOTTOM
+        );
+        i += 1;
+    }
+};
+
+#[derive(Clone, Copy)]
+pub enum DisplayOptions {
+    PriorityMessage,
+    NormalMessage,
+}
+
+impl DisplayOptions {
+    fn clear_style(self) -> Rgb565 {
+        match self {
+            DisplayOptions::PriorityMessage => PRIO_MESSAGE_BG_COLOR,
+            DisplayOptions::NormalMessage => MESSAGE_BG_COLOR,
+        }
+    }
+
+    fn textbox_style(self) -> TextBoxStyle {
+        match self {
+            DisplayOptions::PriorityMessage => TextBoxStyleBuilder::new()
+                .height_mode(HeightMode::Exact(VerticalOverdraw::Visible))
+                .alignment(HorizontalAlignment::Left)
+                .vertical_alignment(VerticalAlignment::Top)
+                .build(),
+            DisplayOptions::NormalMessage => TextBoxStyleBuilder::new()
+                .height_mode(HeightMode::Exact(VerticalOverdraw::Visible))
+                .alignment(HorizontalAlignment::Center)
+                .vertical_alignment(VerticalAlignment::Middle)
+                .build(),
+        }
+    }
+}
+
+/// Fill the display with `text` and show `footer` in the last line, unless it is empty.
+pub fn draw_text<D: DrawTarget<Color = Rgb565>>(
+    target: &mut D,
+    text: &str,
+    footer: &str,
+    options: DisplayOptions,
+) -> Result<(), D::Error> {
+    draw_text_box(target, text, !footer.is_empty(), options)?;
+    draw_footer(target, footer)
+}
+
+/// Fill the display with the page `index` of a text with `count` pages. The last line shows `footer` and which page
+/// this is, e.g. "2/3" for the page with index 1.
+pub fn draw_page<D: DrawTarget<Color = Rgb565>>(
+    target: &mut D,
+    text: &str,
+    footer: &str,
+    index: usize,
+    count: usize,
+    options: DisplayOptions,
+) -> Result<(), D::Error> {
+    draw_text_box(target, text, true, options)?;
+    draw_footer_bar(target)?;
+    draw_footer_text(target, footer)?;
+
+    let mut indicator: String<PAGE_INDICATOR_LEN> = String::new();
+    // Cannot fail since there are fewer than 10 pages.
+    write!(indicator, "{}/{count}", index + 1).ok();
+    let indicator = Text::with_text_style(
+        &indicator,
+        Point::new((IMAGE_WIDTH as u32 - MARGIN_RIGHT) as i32, FOOTER_TOP),
+        FOOTER_TEXT_STYLE,
+        TextStyleBuilder::new()
+            .alignment(Alignment::Right)
+            .baseline(Baseline::Top)
+            .build(),
+    );
+    // A long footer makes room for the indicator, with a gap of one character.
+    let gap = MESSAGE_FONT.character_size.width;
+    let bounds = indicator.bounding_box();
+    Rectangle::new(
+        bounds.top_left - Point::new(gap as i32, 0),
+        bounds.size + Size::new(gap, 0),
+    )
+    .into_styled(PrimitiveStyle::with_fill(FOOTER_BG_COLOR))
+    .draw(target)?;
+    indicator.draw(target)?;
+    Ok(())
+}
+
+/// Fill the display with `text` in `style` and show `footer` in the last line, unless it is empty.
+/// Styled texts keep their colors when they are urgent, so they get a frame like images instead.
+pub fn draw_styled_text<D: DrawTarget<Color = Rgb565>>(
+    target: &mut D,
+    text: &str,
+    footer: &str,
+    style: &TextStyle,
+    options: DisplayOptions,
+) -> Result<(), D::Error> {
+    let (font, bold_font) = fonts(style.font);
+    let foreground = Rgb565::from(RawU16::new(style.foreground.0));
+    let background = Rgb565::from(RawU16::new(style.background.0));
+    let (alignment, x) = match style.alignment {
+        TextAlignment::Left => (HorizontalAlignment::Left, MARGIN_LEFT),
+        TextAlignment::Center => (HorizontalAlignment::Center, IMAGE_WIDTH as u32 / 2),
+        TextAlignment::Right => (HorizontalAlignment::Right, IMAGE_WIDTH as u32 - MARGIN_RIGHT),
+    };
+    target.clear(background)?;
+
+    let mut top = MARGIN_TOP;
+    let mut body = text;
+    if style.heading {
+        let (heading, rest) = text.split_once('\n').unwrap_or((text, ""));
+        let heading_alignment = match alignment {
+            HorizontalAlignment::Left => Alignment::Left,
+            HorizontalAlignment::Right => Alignment::Right,
+            _ => Alignment::Center,
+        };
+        Text::with_text_style(
+            heading,
+            Point::new(x as i32, top as i32),
+            MonoTextStyle::new(bold_font, foreground),
+            TextStyleBuilder::new()
+                .alignment(heading_alignment)
+                .baseline(Baseline::Top)
+                .build(),
+        )
+        .draw(target)?;
+        top += font.character_size.height;
+        body = rest;
+    }
+
+    let footer_height = if footer.is_empty() { 0 } else { FOOTER_HEIGHT };
+    let bounds = Rectangle::new(
+        Point::new(MARGIN_LEFT as i32, top as i32),
+        Size::new(
+            IMAGE_WIDTH as u32 - MARGIN_LEFT - MARGIN_RIGHT,
+            IMAGE_HEIGHT as u32 - top - MARGIN_BOTTOM - footer_height,
+        ),
+    );
+    // Below a heading the text starts right away, like in a letter.
+    let vertical_alignment = if style.heading {
+        VerticalAlignment::Top
+    } else {
+        VerticalAlignment::Middle
+    };
+    let textbox_style = TextBoxStyleBuilder::new()
+        .height_mode(HeightMode::Exact(VerticalOverdraw::Visible))
+        .alignment(alignment)
+        .vertical_alignment(vertical_alignment)
+        .build();
+    TextBox::with_textbox_style(body, bounds, MonoTextStyle::new(font, foreground), textbox_style).draw(target)?;
+
+    if let DisplayOptions::PriorityMessage = options {
+        draw_priority_frame(target)?;
+    }
+    draw_footer(target, footer)
+}
+
+/// The regular and the bold font of `size`.
+const fn fonts(size: FontSize) -> (&'static MonoFont<'static>, &'static MonoFont<'static>) {
+    match size {
+        FontSize::Small => (&FONT_6X13, &FONT_6X13_BOLD),
+        FontSize::Normal => (&MESSAGE_FONT, &FONT_9X15_BOLD),
+        // There is no bold 10x20 font, the bold 9x18 font is the closest.
+        FontSize::Large => (&FONT_10X20, &FONT_9X18_BOLD),
+    }
+}
+
+/// Clear the display and draw `text`, above the last line if `footer` is shown there.
+fn draw_text_box<D: DrawTarget<Color = Rgb565>>(
+    target: &mut D,
+    text: &str,
+    footer: bool,
+    options: DisplayOptions,
+) -> Result<(), D::Error> {
+    let footer_height = if footer { FOOTER_HEIGHT } else { 0 };
+    // Margins are not symmetric in the 9x15 font size, so at the bottom and right side there is one pixel less space (+1 in Size::new).
+    let bounds = Rectangle::new(
+        Point::new(MARGIN_LEFT as i32, MARGIN_TOP as i32),
+        Size::new(
+            IMAGE_WIDTH as u32 - MARGIN_RIGHT,
+            IMAGE_HEIGHT as u32 - MARGIN_BOTTOM - footer_height,
+        ),
+    );
+
+    // Create the text box and apply styling options.
+    let text_box = TextBox::with_textbox_style(text, bounds, MESSAGE_TEXT_STYLE, options.textbox_style());
+
+    // Draw the text box.
+    target.clear(options.clear_style())?;
+    text_box.draw(target)?;
+    Ok(())
+}
+
+/// Draw the RGB565 image `data` and show `footer` over its bottom, unless it is empty.
+pub fn draw_image<D: DrawTarget<Color = Rgb565>>(
+    target: &mut D,
+    data: &[u8],
+    footer: &str,
+    options: DisplayOptions,
+) -> Result<(), D::Error> {
+    let raw: ImageRawBE<Rgb565> = ImageRaw::new(data, IMAGE_WIDTH as u32);
+    Image::new(&raw, Point::zero()).draw(target)?;
+    if let DisplayOptions::PriorityMessage = options {
+        draw_priority_frame(target)?;
+    }
+    draw_footer(target, footer)
+}
+
+fn draw_priority_frame<D: DrawTarget<Color = Rgb565>>(target: &mut D) -> Result<(), D::Error> {
+    Rectangle::new(Point::zero(), Size::new(IMAGE_WIDTH as u32, IMAGE_HEIGHT as u32))
+        .into_styled(
+            PrimitiveStyleBuilder::new()
+                .stroke_color(PRIO_MESSAGE_BG_COLOR)
+                .stroke_width(PRIO_IMAGE_FRAME_WIDTH)
+                .stroke_alignment(StrokeAlignment::Inside)
+                .build(),
+        )
+        .draw(target)
+}
+
+fn draw_footer<D: DrawTarget<Color = Rgb565>>(target: &mut D, footer: &str) -> Result<(), D::Error> {
+    if footer.is_empty() {
+        return Ok(());
+    }
+    draw_footer_bar(target)?;
+    draw_footer_text(target, footer)
+}
+
+fn draw_footer_bar<D: DrawTarget<Color = Rgb565>>(target: &mut D) -> Result<(), D::Error> {
+    Rectangle::new(
+        Point::new(0, FOOTER_TOP),
+        Size::new(IMAGE_WIDTH as u32, IMAGE_HEIGHT as u32 - FOOTER_TOP as u32),
+    )
+    .into_styled(PrimitiveStyle::with_fill(FOOTER_BG_COLOR))
+    .draw(target)?;
+    Ok(())
+}
+
+fn draw_footer_text<D: DrawTarget<Color = Rgb565>>(target: &mut D, footer: &str) -> Result<(), D::Error> {
+    Text::with_baseline(
+        footer,
+        Point::new(MARGIN_LEFT as i32, FOOTER_TOP),
+        FOOTER_TEXT_STYLE,
+        Baseline::Top,
+    )
+    .draw(target)?;
+    Ok(())
+}
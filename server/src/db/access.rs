@@ -0,0 +1,64 @@
+//! Who may do what with a device.
+//!
+//! The admin and the owner of a device may do everything with it, including removing it.
+//! Other users need a [`Grant`] to send messages to the device or to manage it.
+//! Devices without an owner, i.e. the ones registered by the admin, are only writable by the admin and through grants.
+
+use common::{protocols::web::DeviceRight, types::DeviceID};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    device::Device,
+    user::{Authorized, RawUser, User},
+    Db,
+};
+use crate::error::Result;
+
+/// The right of `user` on the device `device_id`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Grant {
+    pub device_id: DeviceID,
+    pub(crate) user: RawUser,
+    pub right: DeviceRight,
+}
+
+pub async fn is_admin(db: &dyn Db, user: &User<Authorized>) -> bool {
+    let admin = User::new_telegram(db.get_telegram_admin_id().await);
+    user.raw() == admin.raw()
+}
+
+/// Whether `user` may remove `device`, which is reserved to its owner and the admin.
+pub async fn is_owner(db: &dyn Db, user: &User<Authorized>, device: &Device) -> bool {
+    device.owner() == Some(user.raw()) || is_admin(db, user).await
+}
+
+/// The highest right `user` has on `device`, if any.
+pub async fn device_right(db: &dyn Db, user: &User<Authorized>, device: &Device) -> Result<Option<DeviceRight>> {
+    if is_owner(db, user, device).await {
+        return Ok(Some(DeviceRight::Manage));
+    }
+    let grants = db.get_user_grants(user.raw()).await?;
+    Ok(grants
+        .iter()
+        .find(|grant| grant.device_id == device.id())
+        .map(|grant| grant.right))
+}
+
+/// All devices on which `user` has at least `right`.
+pub async fn devices_with_right(db: &dyn Db, user: &User<Authorized>, right: DeviceRight) -> Result<Vec<Device>> {
+    let devices = db.get_devices().await?;
+    if is_admin(db, user).await {
+        return Ok(devices);
+    }
+
+    let grants = db.get_user_grants(user.raw()).await?;
+    Ok(devices
+        .into_iter()
+        .filter(|device| {
+            device.owner() == Some(user.raw())
+                || grants
+                    .iter()
+                    .any(|grant| grant.device_id == device.id() && grant.right >= right)
+        })
+        .collect())
+}
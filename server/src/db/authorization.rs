@@ -58,6 +58,11 @@ impl AuthRequest {
         }
     }
 
+    /// Reassemble an authorization request, e.g. when loading it from a database.
+    pub fn from_raw(id: Uuid, user_id: UserId, user_name: String) -> Self {
+        Self { id, user_id, user_name }
+    }
+
     pub fn id(&self) -> Uuid {
         self.id
     }
@@ -1,23 +1,187 @@
 use std::fmt;
 
-use common::types::DeviceID;
+use anyhow::anyhow;
+use chrono::{DateTime, TimeDelta, Utc};
+use common::{
+    protocols::pico::{DeviceSecret, DeviceStatus},
+    types::{DeviceID, FirmwareVersion, PairingCode},
+};
 use serde::{Deserialize, Serialize};
 
+use super::user::RawUser;
+use crate::error::Result;
+
+/// Names are shown on Telegram buttons, so they should be short.
+const MAX_DEVICE_NAME_LEN: usize = 32;
+/// How long an unregistered device can be claimed with its pairing code. Afterwards it gets a new code.
+pub const PAIRING_CODE_LIFETIME: TimeDelta = TimeDelta::hours(1);
+/// Unregistered devices that wait to be claimed at the same time. Keeps most of the codes free, so that guessing one
+/// is hopeless and a free one is found quickly.
+pub const MAX_PENDING_DEVICES: usize = 1000;
+/// With [`MAX_PENDING_DEVICES`] codes in use, every attempt at finding a free code fails with a chance of 1 in 1000.
+const PAIRING_CODE_ATTEMPTS: usize = 8;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Device {
     id: DeviceID,
     name: String,
+    /// The user that claimed the device. Devices registered by the admin have no owner.
+    #[serde(default)]
+    owner: Option<RawUser>,
+    /// Whether the device shows the author of a message in a footer.
+    #[serde(default)]
+    show_author: bool,
+    /// The firmware version the device reported when it last connected. Unknown until then.
+    #[serde(default)]
+    firmware: Option<FirmwareVersion>,
+    /// When the device last reported its status. Unknown until then.
+    #[serde(default)]
+    last_seen: Option<DateTime<Utc>>,
+    /// The status the device reported last.
+    #[serde(default)]
+    status: Option<DeviceStatus>,
 }
 
 impl Device {
     // a.d. TODO best way to take strings like this? AsRef<str>/Cow/Borrowed?
     pub fn new(id: DeviceID, name: String) -> Self {
-        Self { id, name }
+        Self {
+            id,
+            name,
+            owner: None,
+            show_author: false,
+            firmware: None,
+            last_seen: None,
+            status: None,
+        }
+    }
+
+    pub(crate) fn with_owner(mut self, owner: Option<RawUser>) -> Self {
+        self.owner = owner;
+        self
     }
 
     pub fn id(&self) -> DeviceID {
         self.id
     }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub(crate) fn owner(&self) -> Option<RawUser> {
+        self.owner
+    }
+
+    pub fn show_author(&self) -> bool {
+        self.show_author
+    }
+
+    pub fn with_show_author(mut self, show_author: bool) -> Self {
+        self.show_author = show_author;
+        self
+    }
+
+    pub fn set_show_author(&mut self, show_author: bool) {
+        self.show_author = show_author;
+    }
+
+    pub fn firmware(&self) -> Option<FirmwareVersion> {
+        self.firmware
+    }
+
+    pub fn with_firmware(mut self, firmware: Option<FirmwareVersion>) -> Self {
+        self.firmware = firmware;
+        self
+    }
+
+    pub fn set_firmware(&mut self, firmware: FirmwareVersion) {
+        self.firmware = Some(firmware);
+    }
+
+    pub fn last_seen(&self) -> Option<DateTime<Utc>> {
+        self.last_seen
+    }
+
+    pub fn status(&self) -> Option<&DeviceStatus> {
+        self.status.as_ref()
+    }
+
+    pub fn with_status(mut self, last_seen: Option<DateTime<Utc>>, status: Option<DeviceStatus>) -> Self {
+        self.last_seen = last_seen;
+        self.status = status;
+        self
+    }
+
+    pub fn set_status(&mut self, status: DeviceStatus, seen_at: DateTime<Utc>) {
+        self.last_seen = Some(seen_at);
+        self.status = Some(status);
+    }
+}
+
+/// Create a random pairing code for an unregistered device. `take` claims the code and returns false if it is
+/// already in use, then we try another one. Gives up after a few attempts, which only happens if most codes are taken.
+pub fn new_pairing_code(mut take: impl FnMut(PairingCode) -> Result<bool>) -> Result<PairingCode> {
+    for _ in 0..PAIRING_CODE_ATTEMPTS {
+        let code = PairingCode(rand::random_range(0..=PairingCode::MAX));
+        if take(code)? {
+            return Ok(code);
+        }
+    }
+    Err(anyhow!(
+        "no free pairing code found in {PAIRING_CODE_ATTEMPTS} attempts"
+    ))
+}
+
+/// The secrets with which a device proves its identity when it connects.
+/// They are kept when a device is removed, since they belong to the hardware and not to its registration.
+#[derive(Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeviceKeys {
+    pub secret: DeviceSecret,
+    /// A new secret that replaces `secret` once the device authenticates with it, i.e. after it was flashed.
+    #[serde(default)]
+    pub next_secret: Option<DeviceSecret>,
+}
+
+impl DeviceKeys {
+    pub fn new(secret: DeviceSecret) -> Self {
+        Self {
+            secret,
+            next_secret: None,
+        }
+    }
+}
+
+impl fmt::Debug for DeviceKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Keep the secrets out of the logs.
+        f.debug_struct("DeviceKeys")
+            .field("next_secret", &self.next_secret.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Create a random device secret.
+pub fn new_device_secret() -> DeviceSecret {
+    rand::random()
+}
+
+/// Check a user-supplied device name and return it without surrounding whitespace.
+pub fn validate_name(name: &str) -> Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        Err(anyhow!("Device name must not be empty."))
+    } else if name.chars().count() > MAX_DEVICE_NAME_LEN {
+        Err(anyhow!(
+            "Device name must be at most {MAX_DEVICE_NAME_LEN} characters long."
+        ))
+    } else {
+        Ok(name.to_string())
+    }
 }
 
 impl fmt::Display for Device {
@@ -0,0 +1,95 @@
+//! The markup with which senders style texts, e.g. `<red on yellow><large>**Heading**` followed by more lines.
+//! The display draws a text in a single style, so the markup styles the whole text:
+//!
+//! - Tags at the start of the text choose the colors with `<red>`, `<on yellow>` or `<red on yellow>`,
+//!   the alignment with `<left>`, `<center>` or `<right>` and the font with `<small>` or `<large>`.
+//! - A first line between `**` is a heading in a bold font.
+//!
+//! Texts without markup are shown like before.
+
+use common::protocols::pico::{Color, FontSize, TextAlignment, TextStyle};
+
+#[cfg(test)]
+mod tests;
+
+/// The text without its markup, and its style if it has any markup.
+pub fn parse(markup: &str) -> (String, Option<TextStyle>) {
+    let mut style = TextStyle::default();
+    let mut styled = false;
+    let mut rest = markup;
+    while let Some((tag, after)) = rest.strip_prefix('<').and_then(|rest| rest.split_once('>')) {
+        if !apply_tag(&mut style, tag) {
+            // Not our markup, e.g. "<3".
+            break;
+        }
+        styled = true;
+        rest = after.trim_start_matches(' ');
+    }
+    let rest = rest.strip_prefix('\n').filter(|_| styled).unwrap_or(rest);
+
+    let (first_line, more_lines) = match rest.split_once('\n') {
+        Some((first_line, more_lines)) => (first_line, Some(more_lines)),
+        None => (rest, None),
+    };
+    let heading = first_line
+        .strip_prefix("**")
+        .and_then(|line| line.strip_suffix("**"))
+        .filter(|heading| !heading.is_empty());
+    let text = match heading {
+        Some(heading) => {
+            style.heading = true;
+            styled = true;
+            match more_lines {
+                Some(more_lines) => format!("{heading}\n{more_lines}"),
+                None => heading.to_string(),
+            }
+        }
+        None => rest.to_string(),
+    };
+    (text, styled.then_some(style))
+}
+
+/// Change `style` according to `tag`. Returns false if the tag is unknown.
+fn apply_tag(style: &mut TextStyle, tag: &str) -> bool {
+    let tag = tag.trim().to_ascii_lowercase();
+    match tag.as_str() {
+        "left" => style.alignment = TextAlignment::Left,
+        "center" => style.alignment = TextAlignment::Center,
+        "right" => style.alignment = TextAlignment::Right,
+        "small" => style.font = FontSize::Small,
+        "large" => style.font = FontSize::Large,
+        colors => {
+            let (foreground, background) = match colors.strip_prefix("on ") {
+                Some(background) => (None, Some(background)),
+                None => match colors.split_once(" on ") {
+                    Some((foreground, background)) => (Some(foreground), Some(background)),
+                    None => (Some(colors), None),
+                },
+            };
+            let foreground = foreground.map(color).unwrap_or(Some(style.foreground));
+            let background = background.map(color).unwrap_or(Some(style.background));
+            let (Some(foreground), Some(background)) = (foreground, background) else {
+                return false;
+            };
+            style.foreground = foreground;
+            style.background = background;
+        }
+    }
+    true
+}
+
+fn color(name: &str) -> Option<Color> {
+    Some(match name.trim() {
+        "black" => Color::BLACK,
+        "white" => Color::WHITE,
+        "gray" | "grey" => Color::GRAY,
+        "red" => Color::RED,
+        "orange" => Color::ORANGE,
+        "yellow" => Color::YELLOW,
+        "green" => Color::GREEN,
+        "cyan" => Color::CYAN,
+        "blue" => Color::BLUE,
+        "magenta" => Color::MAGENTA,
+        _ => return None,
+    })
+}
@@ -0,0 +1,90 @@
+use super::*;
+
+fn styled(text: &str, style: TextStyle) -> (String, Option<TextStyle>) {
+    (text.to_string(), Some(style))
+}
+
+fn plain(text: &str) -> (String, Option<TextStyle>) {
+    (text.to_string(), None)
+}
+
+fn colors(foreground: Color, background: Color) -> TextStyle {
+    TextStyle {
+        foreground,
+        background,
+        ..TextStyle::default()
+    }
+}
+
+#[test]
+fn text_without_markup_is_kept() {
+    assert_eq!(parse("Hello\nthere"), plain("Hello\nthere"));
+    assert_eq!(parse(""), plain(""));
+}
+
+#[test]
+fn tags_are_combined_with_a_heading() {
+    let style = TextStyle {
+        foreground: Color::RED,
+        background: Color::YELLOW,
+        alignment: TextAlignment::Left,
+        font: FontSize::Large,
+        heading: true,
+    };
+    assert_eq!(
+        parse("<red on yellow> <LEFT><large>**Hello**\nthere"),
+        styled("Hello\nthere", style)
+    );
+    // The line break after the tags is not part of the text.
+    let style = TextStyle {
+        font: FontSize::Large,
+        heading: true,
+        ..TextStyle::default()
+    };
+    assert_eq!(parse("<large>\n**Hello**\nthere"), styled("Hello\nthere", style));
+}
+
+#[test]
+fn text_can_be_only_a_heading() {
+    let heading = TextStyle {
+        heading: true,
+        ..TextStyle::default()
+    };
+    assert_eq!(parse("**Hello**"), styled("Hello", heading));
+    assert_eq!(parse("****"), plain("****"));
+    assert_eq!(parse("**Hello** there"), plain("**Hello** there"));
+    // Only the first line can be a heading.
+    assert_eq!(parse("Hello\n**there**"), plain("Hello\n**there**"));
+}
+
+#[test]
+fn unknown_tags_are_left_as_text() {
+    assert_eq!(parse("<3 you"), plain("<3 you"));
+    assert_eq!(parse("<3 you <red>"), plain("<3 you <red>"));
+    assert_eq!(parse("<red><3"), styled("<3", colors(Color::RED, Color::WHITE)));
+    assert_eq!(parse("<b>bold</b>"), plain("<b>bold</b>"));
+}
+
+#[test]
+fn background_is_set_alone_or_with_the_foreground() {
+    assert_eq!(parse("<on blue>x"), styled("x", colors(Color::BLACK, Color::BLUE)));
+    assert_eq!(
+        parse("<white on blue>x"),
+        styled("x", colors(Color::WHITE, Color::BLUE))
+    );
+    assert_eq!(
+        parse("<grey><on green>x"),
+        styled("x", colors(Color::GRAY, Color::GREEN))
+    );
+}
+
+#[test]
+fn unknown_colors_leave_the_style_alone() {
+    for tag in ["on x", "x on blue", "red on x", "x", "on", "red on"] {
+        let mut style = TextStyle::default();
+        assert!(!apply_tag(&mut style, tag), "{tag}");
+        assert_eq!(style, TextStyle::default(), "{tag}");
+    }
+    assert_eq!(parse("<on x>text"), plain("<on x>text"));
+    assert_eq!(parse("<x on y>text"), plain("<x on y>text"));
+}
@@ -1,24 +1,45 @@
-use std::{collections::HashMap, fs::File, path::Path};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use anyhow::{anyhow, Context};
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 use common::{
-    protocols::web::MessageMeta,
-    types::{DeviceID, MessageID},
+    protocols::{pico::DeviceStatus, web::MessageMeta},
+    types::{DeviceID, FirmwareVersion, MessageID, PairingCode, RecurringID},
 };
-use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::sync::{Mutex, Notify};
 use uuid::Uuid;
 
 use super::{
+    access::Grant,
     authorization::AuthRequest,
-    device::Device,
-    message::{image_from_bytes_mime, InsertMessage, Message, MessageContent, SenderID},
+    device::{self, Device, DeviceKeys},
+    message::{
+        image_from_bytes_mime, Delivery, InsertMessage, Message, MessageContent, MessageFilter, Recall, SenderID,
+    },
+    recurring::{InsertRecurringMessage, RecurringMessage},
     user::{Authorized, RawUser, User},
-    Db,
+    Db, DbError,
 };
 use crate::error::Result;
 
-const MESSAGE_PATH: &str = "./messages.json";
+#[cfg(test)]
+mod tests;
+
+pub const MESSAGE_PATH: &str = "./messages.json";
+/// Version of the snapshot format written by [`MemoryDb::store`].
+/// Increase it whenever the serialized form of [`InnerMemoryDb`] changes and add a step to [`upgrade_snapshot`].
+const SNAPSHOT_VERSION: u64 = 17;
+/// After a change we wait a bit before writing a snapshot, so that bursts of changes result in a single write.
+const SNAPSHOT_DEBOUNCE: Duration = Duration::from_secs(2);
 
 // use type alias to switch out implementations as needed (or enum maybe)
 // Db as trait has some restrictions that I don't want to deal with right now.
@@ -30,11 +51,38 @@ const MESSAGE_PATH: &str = "./messages.json";
 #[derive(Debug, Serialize, Deserialize)]
 struct InnerMemoryDb {
     devices: HashMap<DeviceID, Device>,
+    grants: Vec<Grant>,
+    /// Unregistered devices that asked for updates, with the code they show to be claimed.
+    pending_devices: HashMap<DeviceID, PendingDevice>,
+    /// Secrets of registered and unregistered devices.
+    device_keys: HashMap<DeviceID, DeviceKeys>,
     messages: Vec<Message>,
+    recalls: Vec<Recall>,
+    /// Message IDs are never reused, even after messages are deleted, since devices use them as a cursor.
+    next_message_id: MessageID,
+    recurring_messages: Vec<RecurringMessage>,
+    next_recurring_id: RecurringID,
+    #[serde(with = "authorized_users_serde")]
     authorized_users: HashMap<RawUser, User<Authorized>>,
+    #[serde(with = "user_names_serde")]
+    user_names: HashMap<RawUser, String>,
     // a.d. TODO use User instead of UserId?
     telegram_admin_id: teloxide::types::UserId,
     telegram_auth_requests: HashMap<Uuid, AuthRequest>,
+    api_tokens: HashMap<Uuid, RawUser>,
+}
+
+/// An unregistered device that waits to be claimed.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct PendingDevice {
+    code: PairingCode,
+    since: DateTime<Utc>,
+}
+
+impl PendingDevice {
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.since + device::PAIRING_CODE_LIFETIME <= now
+    }
 }
 
 impl InnerMemoryDb {
@@ -43,6 +91,8 @@ impl InnerMemoryDb {
         let meta = MessageMeta {
             receiver_id: test_id,
             duration: chrono::Duration::hours(24),
+            urgent: false,
+            not_before: None,
         };
         let love_bytes = include_bytes!("../../pictures/love.png");
 
@@ -55,95 +105,579 @@ impl InnerMemoryDb {
         devices.insert(test_id, test_device);
 
         let telegram_auth_requests = HashMap::new();
+        let api_tokens = HashMap::new();
 
         Self {
             devices,
+            grants: Vec::new(),
+            pending_devices: HashMap::new(),
+            device_keys: HashMap::new(),
             messages: vec![
                 Message {
                     id: MessageID(0),
+                    sequence: MessageID(0),
                     meta,
                     sender_id: SenderID::Web,
+                    author: None,
                     created_at: chrono::Utc::now(),
                     content: MessageContent::new_text("Dummy text").unwrap(),
+                    delivery: Delivery::default(),
+                    scheduled: false,
                 },
                 Message {
                     id: MessageID(1),
+                    sequence: MessageID(1),
                     meta,
                     sender_id: SenderID::Web,
+                    author: None,
                     created_at: chrono::Utc::now(),
                     content: MessageContent::new_image(
                         image_from_bytes_mime(love_bytes, "image/png".to_string()).unwrap(),
                     )
                     .unwrap(),
+                    delivery: Delivery::default(),
+                    scheduled: false,
                 },
                 Message {
                     id: MessageID(2),
+                    sequence: MessageID(2),
                     meta,
                     sender_id: SenderID::Web,
+                    author: None,
                     created_at: chrono::Utc::now(),
                     content: MessageContent::new_text("Another dummy text").unwrap(),
+                    delivery: Delivery::default(),
+                    scheduled: false,
                 },
             ],
+            recalls: Vec::new(),
+            next_message_id: MessageID(3),
+            recurring_messages: Vec::new(),
+            next_recurring_id: RecurringID(0),
             authorized_users,
+            user_names: HashMap::new(),
             telegram_admin_id,
             telegram_auth_requests,
+            api_tokens,
         }
     }
 
     fn load<P: AsRef<Path>>(p: &P) -> Result<Self> {
         let file = File::open(p)?;
-        let messages = serde_json::from_reader(&file)?;
-        Ok(messages)
+        let snapshot: serde_json::Value = serde_json::from_reader(&file)?;
+
+        // Snapshots from before we introduced the version field contain the database at the top level.
+        let (mut version, mut db) = match snapshot {
+            serde_json::Value::Object(mut map) if map.contains_key("version") => {
+                let version = map
+                    .remove("version")
+                    .and_then(|version| version.as_u64())
+                    .context("snapshot version is not a number")?;
+                let db = map.remove("db").context("snapshot does not contain db")?;
+                (version, db)
+            }
+            db => (0, db),
+        };
+
+        if version > SNAPSHOT_VERSION {
+            return Err(anyhow!(
+                "snapshot version {version} is newer than the supported version {SNAPSHOT_VERSION}"
+            ));
+        }
+        while version < SNAPSHOT_VERSION {
+            upgrade_snapshot(version, &mut db)?;
+            version += 1;
+        }
+
+        Ok(serde_json::from_value(db)?)
     }
+}
 
-    pub fn store<P: AsRef<Path>>(&self, p: &P) -> Result<()> {
-        let file = File::create(p)?;
-        serde_json::to_writer(&file, self)?;
-        Ok(())
+/// Upgrade the serialized database `db` from snapshot version `version` to `version + 1`.
+fn upgrade_snapshot(version: u64, db: &mut serde_json::Value) -> Result<()> {
+    match version {
+        // Version 1 only introduced the version field.
+        0 => Ok(()),
+        // Version 2 introduced the message ID counter. Before, IDs were assigned by the number of messages.
+        1 => {
+            let messages = db["messages"].as_array().context("snapshot messages are not a list")?;
+            db["next_message_id"] = messages.len().into();
+            Ok(())
+        }
+        // Version 3 introduced web API tokens.
+        2 => {
+            db["api_tokens"] = serde_json::json!({});
+            Ok(())
+        }
+        // Version 4 introduced pending devices. Device owners default to none.
+        3 => {
+            db["pending_devices"] = serde_json::json!({});
+            Ok(())
+        }
+        // Version 5 introduced recalls. Message authors default to none.
+        4 => {
+            db["recalls"] = serde_json::json!([]);
+            Ok(())
+        }
+        // Version 6 introduced display names of users. We take them from the authorization requests.
+        5 => {
+            let mut user_names = serde_json::Map::new();
+            let auth_requests = db["telegram_auth_requests"]
+                .as_object()
+                .context("snapshot auth requests are not a map")?;
+            for auth_request in auth_requests.values() {
+                let auth_request: AuthRequest = serde_json::from_value(auth_request.clone())?;
+                let user = User::new_telegram(auth_request.user_id()).raw();
+                user_names.insert(user.to_string(), auth_request.user_name().into());
+            }
+            db["user_names"] = user_names.into();
+            Ok(())
+        }
+        // Version 7 introduced rights granted on devices.
+        6 => {
+            db["grants"] = serde_json::json!([]);
+            Ok(())
+        }
+        // Version 8 introduced firmware versions of devices, which default to none.
+        7 => Ok(()),
+        // Version 9 introduced device secrets.
+        8 => {
+            db["device_keys"] = serde_json::json!({});
+            Ok(())
+        }
+        // Version 10 introduced the delivery state of messages, which defaults to not delivered.
+        9 => Ok(()),
+        // Version 11 introduced the status of devices, which defaults to unknown.
+        10 => Ok(()),
+        // Version 12 introduced urgent messages, which default to not urgent.
+        11 => Ok(()),
+        // Version 13 introduced scheduled messages, which default to being shown right away.
+        12 => Ok(()),
+        // Version 14 introduced recurring messages.
+        13 => {
+            db["recurring_messages"] = serde_json::json!([]);
+            db["next_recurring_id"] = 0.into();
+            Ok(())
+        }
+        // Version 15 introduced styled texts, which default to no style.
+        14 => Ok(()),
+        // Version 16 introduced the time since when devices are pending, so that their codes expire.
+        // Older pending devices get a new code the next time they ask.
+        15 => {
+            db["pending_devices"] = serde_json::json!({});
+            Ok(())
+        }
+        // Version 17 introduced the sequence of messages, which used to be their ID.
+        16 => {
+            let messages = db["messages"]
+                .as_array_mut()
+                .context("snapshot messages are not a list")?;
+            for message in messages {
+                message["sequence"] = message["id"].clone();
+            }
+            Ok(())
+        }
+        _ => Err(anyhow!("no upgrade from snapshot version {version}")),
+    }
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    version: u64,
+    db: &'a InnerMemoryDb,
+}
+
+/// Write `bytes` to a temporary file next to `path` and then move it over `path`,
+/// so that a crash while writing never leaves a truncated snapshot behind.
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// JSON maps need string keys, so we serialize the authorized users as a list. The key is part of the user anyways.
+mod authorized_users_serde {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        users: &HashMap<RawUser, User<Authorized>>,
+        s: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_seq(users.values())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> std::result::Result<HashMap<RawUser, User<Authorized>>, D::Error> {
+        let users = Vec::<User<Authorized>>::deserialize(d)?;
+        Ok(users.into_iter().map(|user| (user.raw(), user)).collect())
+    }
+}
+
+/// Users are not valid JSON map keys, so we use their textual representation.
+mod user_names_serde {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(names: &HashMap<RawUser, String>, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_map(names.iter().map(|(user, name)| (user.to_string(), name)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<HashMap<RawUser, String>, D::Error> {
+        let names = HashMap::<String, String>::deserialize(d)?;
+        names
+            .into_iter()
+            .map(|(user, name)| Ok((user.parse().map_err(serde::de::Error::custom)?, name)))
+            .collect()
     }
 }
 
 /// non-async implementations of Db functions
 impl InnerMemoryDb {
     fn get_devices(&self) -> Vec<Device> {
-        self.devices.values().cloned().collect()
+        let mut devices: Vec<_> = self.devices.values().cloned().collect();
+        devices.sort_by(|a, b| a.name().cmp(b.name()).then(a.id().cmp(&b.id())));
+        devices
     }
 
     fn get_device(&self, id: DeviceID) -> Option<Device> {
         self.devices.get(&id).cloned()
     }
 
+    fn add_device(&mut self, device: Device) -> Result<()> {
+        if self.devices.contains_key(&device.id()) {
+            return Err(DbError::DeviceExists(device.id()).into());
+        }
+        self.pending_devices.remove(&device.id());
+        self.devices.insert(device.id(), device);
+        Ok(())
+    }
+
+    fn rename_device(&mut self, id: DeviceID, name: String) -> Result<Device> {
+        let device = self.devices.get_mut(&id).ok_or(DbError::DeviceNotFound(id))?;
+        device.set_name(name);
+        Ok(device.clone())
+    }
+
+    fn set_show_author(&mut self, id: DeviceID, show_author: bool) -> Result<Device> {
+        let device = self.devices.get_mut(&id).ok_or(DbError::DeviceNotFound(id))?;
+        device.set_show_author(show_author);
+        Ok(device.clone())
+    }
+
+    fn set_firmware(&mut self, id: DeviceID, firmware: FirmwareVersion) -> Result<Device> {
+        let device = self.devices.get_mut(&id).ok_or(DbError::DeviceNotFound(id))?;
+        device.set_firmware(firmware);
+        Ok(device.clone())
+    }
+
+    fn set_status(&mut self, id: DeviceID, status: DeviceStatus, seen_at: DateTime<Utc>) -> Result<Device> {
+        let device = self.devices.get_mut(&id).ok_or(DbError::DeviceNotFound(id))?;
+        device.set_status(status, seen_at);
+        Ok(device.clone())
+    }
+
+    fn remove_device(&mut self, id: DeviceID) -> Result<Device> {
+        let device = self.devices.remove(&id).ok_or(DbError::DeviceNotFound(id))?;
+        self.grants.retain(|grant| grant.device_id != id);
+        self.recurring_messages.retain(|message| message.receiver_id != id);
+        Ok(device)
+    }
+
+    fn get_device_grants(&self, id: DeviceID) -> Vec<Grant> {
+        self.grants
+            .iter()
+            .filter(|grant| grant.device_id == id)
+            .copied()
+            .collect()
+    }
+
+    fn get_user_grants(&self, user: RawUser) -> Vec<Grant> {
+        self.grants.iter().filter(|grant| grant.user == user).copied().collect()
+    }
+
+    fn set_grant(&mut self, grant: Grant) -> Result<()> {
+        if !self.devices.contains_key(&grant.device_id) {
+            return Err(DbError::DeviceNotFound(grant.device_id).into());
+        }
+        self.remove_grant(grant.device_id, grant.user);
+        self.grants.push(grant);
+        Ok(())
+    }
+
+    fn remove_grant(&mut self, id: DeviceID, user: RawUser) -> bool {
+        let len = self.grants.len();
+        self.grants.retain(|grant| grant.device_id != id || grant.user != user);
+        self.grants.len() != len
+    }
+
+    fn get_pairing_code(&mut self, id: DeviceID, now: DateTime<Utc>) -> Result<PairingCode> {
+        self.pending_devices.retain(|_, pending| !pending.is_expired(now));
+        if let Some(pending) = self.pending_devices.get(&id) {
+            return Ok(pending.code);
+        }
+        if self.pending_devices.len() >= device::MAX_PENDING_DEVICES {
+            return Err(DbError::TooManyPendingDevices.into());
+        }
+
+        let code =
+            device::new_pairing_code(|code| Ok(!self.pending_devices.values().any(|pending| pending.code == code)))?;
+        self.pending_devices.insert(id, PendingDevice { code, since: now });
+        Ok(code)
+    }
+
+    fn claim_device(
+        &mut self,
+        code: PairingCode,
+        name: String,
+        owner: User<Authorized>,
+        now: DateTime<Utc>,
+    ) -> Result<Device> {
+        let id = self
+            .pending_devices
+            .iter()
+            .find_map(|(id, pending)| (pending.code == code && !pending.is_expired(now)).then_some(*id))
+            .ok_or(DbError::PairingCodeNotFound(code))?;
+        let device = Device::new(id, name).with_owner(Some(owner.raw()));
+        self.add_device(device.clone())?;
+        Ok(device)
+    }
+
+    fn reset_pending_device(&mut self, id: DeviceID) -> Result<bool> {
+        if self.devices.contains_key(&id) {
+            return Err(DbError::DeviceExists(id).into());
+        }
+        let had_keys = self.device_keys.remove(&id).is_some();
+        let was_pending = self.pending_devices.remove(&id).is_some();
+        Ok(had_keys || was_pending)
+    }
+
     fn add_message(&mut self, message: Message) {
         self.messages.push(message);
-        // guard.store(&MESSAGE_PATH).ok();
     }
 
-    fn get_next_message(&self, receiver_id: DeviceID, after_id: Option<MessageID>) -> Option<Message> {
-        let after_time = after_id.and_then(|id| self.get_message(id)).map(|msg| msg.created_at);
+    fn get_next_message(
+        &self,
+        receiver_id: DeviceID,
+        after_id: Option<MessageID>,
+        now: DateTime<Utc>,
+    ) -> Option<Message> {
+        // Sequences are handed out in increasing order, so they also work as a cursor when the `after` message was
+        // deleted.
+        self.messages
+            .iter()
+            .filter(|message| {
+                message.meta.receiver_id == receiver_id
+                    && Some(message.sequence) > after_id
+                    && !message.scheduled
+                    && !message.is_expired(now)
+            })
+            .min_by_key(|message| message.sequence)
+            .cloned()
+    }
+
+    fn delete_message(&mut self, id: MessageID) -> Result<Message> {
+        let index = self
+            .messages
+            .iter()
+            .position(|message| message.id == id)
+            .ok_or(DbError::MessageNotFound(id))?;
+        let message = self.messages.remove(index);
+        if !message.scheduled {
+            let recall = Recall {
+                id: self.next_id(),
+                message_id: message.sequence,
+                receiver_id: message.meta.receiver_id,
+                expires_at: message.expires_at(),
+            };
+            self.recalls.push(recall);
+        }
+        Ok(message)
+    }
 
+    /// The message that `device_id` knows by `sequence`.
+    fn device_message_mut(&mut self, device_id: DeviceID, sequence: MessageID) -> Option<&mut Message> {
         self.messages
+            .iter_mut()
+            .find(|message| message.sequence == sequence && message.meta.receiver_id == device_id)
+    }
+
+    fn mark_delivered(&mut self, device_id: DeviceID, sequence: MessageID, at: DateTime<Utc>) -> Option<Message> {
+        let message = self.device_message_mut(device_id, sequence)?;
+        if message.delivery.delivered_at.is_some() {
+            return None;
+        }
+        message.delivery.delivered_at = Some(at);
+        Some(message.clone())
+    }
+
+    fn record_failed_delivery(&mut self, device_id: DeviceID, sequence: MessageID) -> bool {
+        match self.device_message_mut(device_id, sequence) {
+            Some(message) => {
+                message.delivery.failed_attempts += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get_message_history(&self, filter: &MessageFilter, before: Option<MessageID>, limit: usize) -> Vec<Message> {
+        let mut messages: Vec<_> = self
+            .messages
             .iter()
-            .filter(|message| message.meta.receiver_id == receiver_id && Some(message.created_at) > after_time)
-            .min_by_key(|message| message.created_at)
+            .filter(|message| before.is_none_or(|before| message.id < before) && filter.matches(message))
+            .collect();
+        messages.sort_by_key(|message| std::cmp::Reverse(message.id));
+        messages.into_iter().take(limit).cloned().collect()
+    }
+
+    fn get_active_messages_by(&self, author: RawUser, now: DateTime<Utc>) -> Vec<Message> {
+        let mut messages: Vec<_> = self
+            .messages
+            .iter()
+            .filter(|message| message.author == Some(author) && !message.scheduled && !message.is_expired(now))
+            .cloned()
+            .collect();
+        messages.sort_by_key(|message| std::cmp::Reverse(message.id));
+        messages
+    }
+
+    fn get_scheduled_messages(&self) -> Vec<Message> {
+        let mut messages: Vec<_> = self
+            .messages
+            .iter()
+            .filter(|message| message.scheduled)
             .cloned()
+            .collect();
+        messages.sort_by_key(|message| (message.shown_from(), message.id));
+        messages
+    }
+
+    fn release_due_messages(&mut self, now: DateTime<Utc>) -> Vec<Message> {
+        let mut due: Vec<_> = self
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| message.scheduled && message.shown_from() <= now)
+            .map(|(index, message)| (message.shown_from(), message.id, index))
+            .collect();
+        // Messages that were due first get the lower sequences, so that devices get them first.
+        due.sort();
+
+        let mut released = Vec::with_capacity(due.len());
+        for (_, _, index) in due {
+            let sequence = self.next_id();
+            let message = &mut self.messages[index];
+            message.sequence = sequence;
+            message.scheduled = false;
+            released.push(message.clone());
+        }
+        released
+    }
+
+    fn get_next_recall(
+        &self,
+        receiver_id: DeviceID,
+        after_id: Option<MessageID>,
+        now: DateTime<Utc>,
+    ) -> Option<Recall> {
+        self.recalls
+            .iter()
+            .filter(|recall| recall.receiver_id == receiver_id && Some(recall.id) > after_id && recall.expires_at > now)
+            .min_by_key(|recall| recall.id)
+            .cloned()
+    }
+
+    fn delete_expired_messages(&mut self, expired_before: DateTime<Utc>) -> usize {
+        let len = self.messages.len();
+        self.messages.retain(|message| message.expires_at() >= expired_before);
+        self.recalls.retain(|recall| recall.expires_at >= expired_before);
+        len - self.messages.len()
     }
 
     fn get_message(&self, id: MessageID) -> Option<Message> {
         self.messages.iter().find(|message| message.id == id).cloned()
     }
 
-    fn next_id(&self) -> MessageID {
-        MessageID(self.messages.len() as u32)
+    fn get_recurring_message(&self, id: RecurringID) -> Option<RecurringMessage> {
+        self.recurring_messages.iter().find(|message| message.id == id).cloned()
+    }
+
+    fn get_recurring_messages(&self) -> Vec<RecurringMessage> {
+        let mut messages = self.recurring_messages.clone();
+        messages.sort_by_key(|message| (message.next_at, message.id));
+        messages
+    }
+
+    fn add_recurring_message(&mut self, message: InsertRecurringMessage) -> RecurringID {
+        let id = self.next_recurring_id;
+        self.next_recurring_id = RecurringID(id.0 + 1);
+        self.recurring_messages.push(RecurringMessage::from_insert(id, message));
+        id
+    }
+
+    fn delete_recurring_message(&mut self, id: RecurringID) -> Result<RecurringMessage> {
+        let index = self
+            .recurring_messages
+            .iter()
+            .position(|message| message.id == id)
+            .ok_or(DbError::RecurringMessageNotFound(id))?;
+        Ok(self.recurring_messages.remove(index))
+    }
+
+    fn set_recurring_next_at(&mut self, id: RecurringID, next_at: DateTime<Utc>) -> Result<()> {
+        let message = self
+            .recurring_messages
+            .iter_mut()
+            .find(|message| message.id == id)
+            .ok_or(DbError::RecurringMessageNotFound(id))?;
+        message.next_at = next_at;
+        Ok(())
+    }
+
+    fn next_id(&mut self) -> MessageID {
+        let id = self.next_message_id;
+        self.next_message_id = MessageID(id.0 + 1);
+        id
     }
 
     fn is_user_authorized(&self, user: RawUser) -> Option<User<Authorized>> {
-        self.authorized_users.get(&user).map(|user| *user)
+        self.authorized_users.get(&user).copied()
     }
 
     fn add_authorized_user(&mut self, user: User<Authorized>) {
         self.authorized_users.insert(user.raw(), user);
     }
 
+    fn get_authorized_users(&self) -> Vec<User<Authorized>> {
+        let mut users: Vec<_> = self.authorized_users.values().copied().collect();
+        users.sort_by_key(|user| user.raw().to_string());
+        users
+    }
+
+    fn get_user_name(&self, user: RawUser) -> Option<String> {
+        self.user_names.get(&user).cloned()
+    }
+
+    fn set_user_name(&mut self, user: RawUser, name: String) {
+        self.user_names.insert(user, name);
+    }
+
+    fn add_api_token(&mut self, token: Uuid, user: User<Authorized>) {
+        self.api_tokens.insert(token, user.raw());
+    }
+
+    fn get_api_token_user(&self, token: Uuid) -> Option<User<Authorized>> {
+        self.api_tokens
+            .get(&token)
+            .and_then(|user| self.is_user_authorized(*user))
+    }
+
     fn get_telegram_admin_id(&self) -> teloxide::types::UserId {
         self.telegram_admin_id
     }
@@ -157,78 +691,386 @@ impl InnerMemoryDb {
     }
 }
 
+/// Where and when to write snapshots of a [`MemoryDb`].
+struct Snapshots {
+    path: PathBuf,
+    changed: Notify,
+    /// Held while writing, since the snapshot task and [`Db::flush`] write through the same temporary file.
+    writing: Mutex<()>,
+}
+
 // a.d. TODO also put the Arc here?
 pub struct MemoryDb {
     inner: Mutex<InnerMemoryDb>,
+    snapshots: Snapshots,
 }
 
 impl MemoryDb {
-    pub fn dummy(telegram_admin_id: teloxide::types::UserId) -> Self {
-        Self {
-            inner: Mutex::new(InnerMemoryDb::dummy(telegram_admin_id)),
-        }
-    }
+    /// Restore the database from the snapshot at `path`, or start with dummy data if there is no snapshot yet.
+    /// All changes are written back to `path` by [`MemoryDb::run_snapshots`].
+    pub fn open<P: AsRef<Path>>(path: P, telegram_admin_id: teloxide::types::UserId) -> Result<Self> {
+        let path = path.as_ref();
+        let inner = if path.exists() {
+            let mut inner =
+                InnerMemoryDb::load(&path).with_context(|| format!("loading snapshot {} failed", path.display()))?;
+            // The admin might have been changed in the environment since the snapshot was written.
+            inner.telegram_admin_id = telegram_admin_id;
+            inner.add_authorized_user(User::new_telegram(telegram_admin_id).authorize());
+            inner
+        } else {
+            log::info!("No snapshot at {}, starting with dummy data.", path.display());
+            InnerMemoryDb::dummy(telegram_admin_id)
+        };
 
-    fn new(inner: InnerMemoryDb) -> Self {
-        Self {
+        Ok(Self {
             inner: Mutex::new(inner),
-        }
+            snapshots: Snapshots {
+                path: path.to_owned(),
+                changed: Notify::new(),
+                writing: Mutex::new(()),
+            },
+        })
     }
 
-    fn load<P: AsRef<Path>>(p: &P) -> Result<Self> {
-        let file = File::open(p)?;
-        let inner = serde_json::from_reader(&file)?;
-        Ok(Self::new(inner))
+    /// Write a snapshot shortly after each change. Runs forever, so it should be spawned as a separate task.
+    pub async fn run_snapshots(self: Arc<Self>) {
+        let snapshots = &self.snapshots;
+        loop {
+            snapshots.changed.notified().await;
+            tokio::time::sleep(SNAPSHOT_DEBOUNCE).await;
+            if let Err(e) = self.store().await {
+                log::error!("Writing snapshot failed: {e:#}");
+            }
+        }
     }
 
-    pub fn store<P: AsRef<Path>>(&mut self, p: &P) -> Result<()> {
-        let file = File::create(p)?;
-        let inner = self.inner.get_mut();
-        serde_json::to_writer(&file, inner)?;
+    async fn store(&self) -> Result<()> {
+        let snapshots = &self.snapshots;
+        // Taking the write lock first makes sure that an older snapshot never replaces a newer one.
+        let _writing = snapshots.writing.lock().await;
+        // Only hold the database lock while serializing, not while writing to disk.
+        let bytes = {
+            let guard = self.inner.lock().await;
+            serde_json::to_vec(&SnapshotRef {
+                version: SNAPSHOT_VERSION,
+                db: &guard,
+            })?
+        };
+        let path = snapshots.path.clone();
+        tokio::task::spawn_blocking(move || write_atomic(&path, &bytes)).await??;
+        log::debug!("Wrote snapshot to {}.", snapshots.path.display());
         Ok(())
     }
+
+    /// Notify the snapshot task that the database has changed.
+    fn changed(&self) {
+        self.snapshots.changed.notify_one();
+    }
 }
 
 // a.d. TODO according to the Tokio docs, since we don't do async operations on in-memory data (i.e. we don't need to hold the mutex across an await)
 // We know that we don't hold the mutex across an await because we never lock it outside of this impl (2nd TODO, wrap the mutex in a struct so that it's private)
 #[async_trait]
 impl Db for MemoryDb {
-    async fn get_devices(&self) -> Vec<Device> {
+    async fn get_devices(&self) -> Result<Vec<Device>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_devices(&guard))
+    }
+
+    async fn get_device(&self, id: DeviceID) -> Result<Option<Device>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_device(&guard, id))
+    }
+
+    async fn add_device(&self, device: Device) -> Result<()> {
+        let mut guard = self.inner.lock().await;
+        InnerMemoryDb::add_device(&mut guard, device)?;
+        self.changed();
+        Ok(())
+    }
+
+    async fn rename_device(&self, id: DeviceID, name: String) -> Result<Device> {
+        let mut guard = self.inner.lock().await;
+        let device = InnerMemoryDb::rename_device(&mut guard, id, name)?;
+        self.changed();
+        Ok(device)
+    }
+
+    async fn set_show_author(&self, id: DeviceID, show_author: bool) -> Result<Device> {
+        let mut guard = self.inner.lock().await;
+        let device = InnerMemoryDb::set_show_author(&mut guard, id, show_author)?;
+        self.changed();
+        Ok(device)
+    }
+
+    async fn set_firmware(&self, id: DeviceID, firmware: FirmwareVersion) -> Result<Device> {
+        let mut guard = self.inner.lock().await;
+        let old = InnerMemoryDb::get_device(&guard, id).and_then(|device| device.firmware());
+        let device = InnerMemoryDb::set_firmware(&mut guard, id, firmware)?;
+        // Devices report their firmware on every connection, so we only write a snapshot when it changed.
+        if old != Some(firmware) {
+            self.changed();
+        }
+        Ok(device)
+    }
+
+    async fn set_status(&self, id: DeviceID, status: DeviceStatus, seen_at: DateTime<Utc>) -> Result<Device> {
+        let mut guard = self.inner.lock().await;
+        let device = InnerMemoryDb::set_status(&mut guard, id, status, seen_at)?;
+        self.changed();
+        Ok(device)
+    }
+
+    async fn remove_device(&self, id: DeviceID) -> Result<Device> {
+        let mut guard = self.inner.lock().await;
+        let device = InnerMemoryDb::remove_device(&mut guard, id)?;
+        self.changed();
+        Ok(device)
+    }
+
+    async fn get_device_grants(&self, id: DeviceID) -> Result<Vec<Grant>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_device_grants(&guard, id))
+    }
+
+    async fn get_user_grants(&self, user: RawUser) -> Result<Vec<Grant>> {
         let guard = self.inner.lock().await;
-        InnerMemoryDb::get_devices(&guard)
+        Ok(InnerMemoryDb::get_user_grants(&guard, user))
+    }
+
+    async fn set_grant(&self, grant: Grant) -> Result<()> {
+        let mut guard = self.inner.lock().await;
+        InnerMemoryDb::set_grant(&mut guard, grant)?;
+        self.changed();
+        Ok(())
+    }
+
+    async fn remove_grant(&self, id: DeviceID, user: RawUser) -> Result<bool> {
+        let mut guard = self.inner.lock().await;
+        let removed = InnerMemoryDb::remove_grant(&mut guard, id, user);
+        if removed {
+            self.changed();
+        }
+        Ok(removed)
     }
 
-    async fn get_device(&self, id: DeviceID) -> Option<Device> {
+    async fn get_device_keys(&self, id: DeviceID) -> Result<Option<DeviceKeys>> {
         let guard = self.inner.lock().await;
-        InnerMemoryDb::get_device(&guard, id)
+        Ok(guard.device_keys.get(&id).copied())
     }
 
-    async fn add_message(&self, message: InsertMessage) -> MessageID {
+    async fn set_device_keys(&self, id: DeviceID, keys: DeviceKeys) -> Result<()> {
         let mut guard = self.inner.lock().await;
-        let next_id = InnerMemoryDb::next_id(&guard);
+        guard.device_keys.insert(id, keys);
+        self.changed();
+        Ok(())
+    }
+
+    async fn get_pairing_code(&self, id: DeviceID, now: DateTime<Utc>) -> Result<PairingCode> {
+        let mut guard = self.inner.lock().await;
+        // Devices ask again and again until they are claimed, which should not write a snapshot each time.
+        let pending = |db: &InnerMemoryDb| (db.pending_devices.len(), db.pending_devices.get(&id).map(|p| p.since));
+        let before = pending(&guard);
+        let code = InnerMemoryDb::get_pairing_code(&mut guard, id, now);
+        if pending(&guard) != before {
+            self.changed();
+        }
+        code
+    }
+
+    async fn claim_device(
+        &self,
+        code: PairingCode,
+        name: String,
+        owner: User<Authorized>,
+        now: DateTime<Utc>,
+    ) -> Result<Device> {
+        let mut guard = self.inner.lock().await;
+        let device = InnerMemoryDb::claim_device(&mut guard, code, name, owner, now)?;
+        self.changed();
+        Ok(device)
+    }
+
+    async fn reset_pending_device(&self, id: DeviceID) -> Result<bool> {
+        let mut guard = self.inner.lock().await;
+        let reset = InnerMemoryDb::reset_pending_device(&mut guard, id)?;
+        if reset {
+            self.changed();
+        }
+        Ok(reset)
+    }
+
+    async fn add_message(&self, message: InsertMessage) -> Result<MessageID> {
+        let mut guard = self.inner.lock().await;
+        let next_id = InnerMemoryDb::next_id(&mut guard);
         let message = Message::from_insert(next_id, message);
         InnerMemoryDb::add_message(&mut guard, message);
-        next_id
+        self.changed();
+        Ok(next_id)
     }
 
-    async fn get_next_message(&self, receiver_id: DeviceID, after_id: Option<MessageID>) -> Option<Message> {
+    async fn delete_message(&self, id: MessageID) -> Result<Message> {
+        let mut guard = self.inner.lock().await;
+        let message = InnerMemoryDb::delete_message(&mut guard, id)?;
+        self.changed();
+        Ok(message)
+    }
+
+    async fn mark_delivered(
+        &self,
+        device_id: DeviceID,
+        sequence: MessageID,
+        at: DateTime<Utc>,
+    ) -> Result<Option<Message>> {
+        let mut guard = self.inner.lock().await;
+        let message = InnerMemoryDb::mark_delivered(&mut guard, device_id, sequence, at);
+        if message.is_some() {
+            self.changed();
+        }
+        Ok(message)
+    }
+
+    async fn record_failed_delivery(&self, device_id: DeviceID, sequence: MessageID) -> Result<()> {
+        let mut guard = self.inner.lock().await;
+        if InnerMemoryDb::record_failed_delivery(&mut guard, device_id, sequence) {
+            self.changed();
+        }
+        Ok(())
+    }
+
+    async fn get_message_history(
+        &self,
+        filter: &MessageFilter,
+        before: Option<MessageID>,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_message_history(&guard, filter, before, limit))
+    }
+
+    async fn get_active_messages_by(&self, author: RawUser, now: DateTime<Utc>) -> Result<Vec<Message>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_active_messages_by(&guard, author, now))
+    }
+
+    async fn get_scheduled_messages(&self) -> Result<Vec<Message>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_scheduled_messages(&guard))
+    }
+
+    async fn release_due_messages(&self, now: DateTime<Utc>) -> Result<Vec<Message>> {
+        let mut guard = self.inner.lock().await;
+        let released = InnerMemoryDb::release_due_messages(&mut guard, now);
+        if !released.is_empty() {
+            self.changed();
+        }
+        Ok(released)
+    }
+
+    async fn get_next_message(
+        &self,
+        receiver_id: DeviceID,
+        after_id: Option<MessageID>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Message>> {
         let guard = self.inner.lock().await;
-        InnerMemoryDb::get_next_message(&guard, receiver_id, after_id)
+        Ok(InnerMemoryDb::get_next_message(&guard, receiver_id, after_id, now))
     }
 
-    async fn get_message(&self, id: MessageID) -> Option<Message> {
+    async fn get_next_recall(
+        &self,
+        receiver_id: DeviceID,
+        after_id: Option<MessageID>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Recall>> {
         let guard = self.inner.lock().await;
-        InnerMemoryDb::get_message(&guard, id)
+        Ok(InnerMemoryDb::get_next_recall(&guard, receiver_id, after_id, now))
     }
 
-    async fn is_user_authorized(&self, user: RawUser) -> Option<User<Authorized>> {
+    async fn delete_expired_messages(&self, expired_before: DateTime<Utc>) -> Result<usize> {
+        let mut guard = self.inner.lock().await;
+        let deleted = InnerMemoryDb::delete_expired_messages(&mut guard, expired_before);
+        if deleted > 0 {
+            self.changed();
+        }
+        Ok(deleted)
+    }
+
+    async fn get_message(&self, id: MessageID) -> Result<Option<Message>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_message(&guard, id))
+    }
+
+    async fn get_recurring_message(&self, id: RecurringID) -> Result<Option<RecurringMessage>> {
         let guard = self.inner.lock().await;
-        InnerMemoryDb::is_user_authorized(&guard, user)
+        Ok(InnerMemoryDb::get_recurring_message(&guard, id))
     }
 
-    async fn add_authorized_user(&self, user: User<Authorized>) {
+    async fn get_recurring_messages(&self) -> Result<Vec<RecurringMessage>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_recurring_messages(&guard))
+    }
+
+    async fn add_recurring_message(&self, message: InsertRecurringMessage) -> Result<RecurringID> {
+        let mut guard = self.inner.lock().await;
+        let id = InnerMemoryDb::add_recurring_message(&mut guard, message);
+        self.changed();
+        Ok(id)
+    }
+
+    async fn delete_recurring_message(&self, id: RecurringID) -> Result<RecurringMessage> {
+        let mut guard = self.inner.lock().await;
+        let message = InnerMemoryDb::delete_recurring_message(&mut guard, id)?;
+        self.changed();
+        Ok(message)
+    }
+
+    async fn set_recurring_next_at(&self, id: RecurringID, next_at: DateTime<Utc>) -> Result<()> {
+        let mut guard = self.inner.lock().await;
+        InnerMemoryDb::set_recurring_next_at(&mut guard, id, next_at)?;
+        self.changed();
+        Ok(())
+    }
+
+    async fn is_user_authorized(&self, user: RawUser) -> Result<Option<User<Authorized>>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::is_user_authorized(&guard, user))
+    }
+
+    async fn add_authorized_user(&self, user: User<Authorized>) -> Result<()> {
         let mut guard = self.inner.lock().await;
         InnerMemoryDb::add_authorized_user(&mut guard, user);
+        self.changed();
+        Ok(())
+    }
+
+    async fn get_authorized_users(&self) -> Result<Vec<User<Authorized>>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_authorized_users(&guard))
+    }
+
+    async fn get_user_name(&self, user: RawUser) -> Result<Option<String>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_user_name(&guard, user))
+    }
+
+    async fn set_user_name(&self, user: RawUser, name: String) -> Result<()> {
+        let mut guard = self.inner.lock().await;
+        InnerMemoryDb::set_user_name(&mut guard, user, name);
+        self.changed();
+        Ok(())
+    }
+
+    async fn add_api_token(&self, token: Uuid, user: User<Authorized>) -> Result<()> {
+        let mut guard = self.inner.lock().await;
+        InnerMemoryDb::add_api_token(&mut guard, token, user);
+        self.changed();
+        Ok(())
+    }
+
+    async fn get_api_token_user(&self, token: Uuid) -> Result<Option<User<Authorized>>> {
+        let guard = self.inner.lock().await;
+        Ok(InnerMemoryDb::get_api_token_user(&guard, token))
     }
 
     async fn get_telegram_admin_id(&self) -> teloxide::types::UserId {
@@ -236,13 +1078,19 @@ impl Db for MemoryDb {
         InnerMemoryDb::get_telegram_admin_id(&guard)
     }
 
-    async fn get_auth_request(&self, id: Uuid) -> Option<AuthRequest> {
+    async fn get_auth_request(&self, id: Uuid) -> Result<Option<AuthRequest>> {
         let guard = self.inner.lock().await;
-        InnerMemoryDb::get_auth_request(&guard, id)
+        Ok(InnerMemoryDb::get_auth_request(&guard, id))
     }
 
-    async fn add_auth_request(&self, auth_request: AuthRequest) {
+    async fn add_auth_request(&self, auth_request: AuthRequest) -> Result<()> {
         let mut guard = self.inner.lock().await;
-        InnerMemoryDb::add_auth_request(&mut guard, auth_request)
+        InnerMemoryDb::add_auth_request(&mut guard, auth_request);
+        self.changed();
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.store().await
     }
 }
@@ -0,0 +1,200 @@
+//! The [`MemoryDb`]: loading, upgrading and writing its snapshots, and the pending devices it keeps track of.
+
+use serde_json::{json, Value};
+use teloxide::types::UserId;
+
+use super::*;
+
+const DEVICE_ID: DeviceID = DeviceID(0x1234);
+const ADMIN_ID: UserId = UserId(1);
+
+/// A snapshot file of its own for each test, which is removed again when the test ends.
+struct SnapshotFile(PathBuf);
+
+impl SnapshotFile {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("memory-db-{}-{name}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        Self(path)
+    }
+
+    fn write(&self, snapshot: &Value) {
+        fs::write(&self.0, serde_json::to_vec(snapshot).unwrap()).unwrap();
+    }
+
+    fn read(&self) -> Value {
+        serde_json::from_slice(&fs::read(&self.0).unwrap()).unwrap()
+    }
+}
+
+impl Drop for SnapshotFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn text_message(text: &str) -> InsertMessage {
+    let meta = MessageMeta {
+        receiver_id: DEVICE_ID,
+        duration: chrono::Duration::hours(1),
+        urgent: false,
+        not_before: None,
+    };
+    InsertMessage::new(meta, SenderID::Web, Utc::now(), MessageContent::new_text(text).unwrap())
+}
+
+fn text_of(message: &Message) -> &str {
+    match &message.content {
+        MessageContent::Text(text) => text.text(),
+        MessageContent::Image(_) => panic!("message {} is an image", message.id),
+    }
+}
+
+/// A snapshot from before versions were introduced, with only the fields the database had back then.
+fn version_0_snapshot() -> Value {
+    let mut message = serde_json::to_value(Message::from_insert(MessageID(0), text_message("Hello"))).unwrap();
+    for field in ["author", "delivery", "scheduled", "sequence"] {
+        message.as_object_mut().unwrap().remove(field);
+    }
+    for field in ["urgent", "not_before"] {
+        message["meta"].as_object_mut().unwrap().remove(field);
+    }
+    let auth_request = AuthRequest::from_raw(Uuid::nil(), UserId(5), "Alice".to_string());
+
+    json!({
+        "devices": { DEVICE_ID.0.to_string(): { "id": DEVICE_ID.0, "name": "Old device" } },
+        "messages": [message],
+        "authorized_users": [User::new_telegram(ADMIN_ID).authorize()],
+        "telegram_admin_id": ADMIN_ID,
+        "telegram_auth_requests": { Uuid::nil().to_string(): auth_request },
+    })
+}
+
+#[tokio::test]
+async fn unversioned_snapshot_is_upgraded() {
+    let file = SnapshotFile::new("unversioned");
+    file.write(&version_0_snapshot());
+
+    let db = MemoryDb::open(&file.0, ADMIN_ID).unwrap();
+    assert_eq!(db.get_device(DEVICE_ID).await.unwrap().unwrap().name(), "Old device");
+    let message = db.get_message(MessageID(0)).await.unwrap().unwrap();
+    assert_eq!(text_of(&message), "Hello");
+    assert!(!message.meta.urgent && !message.scheduled);
+    // The ID counter starts after the existing messages.
+    assert_eq!(db.add_message(text_message("New")).await.unwrap(), MessageID(1));
+    // User names are taken from the authorization requests.
+    let alice = User::new_telegram(UserId(5)).raw();
+    assert_eq!(db.get_user_name(alice).await.unwrap().as_deref(), Some("Alice"));
+    assert!(db.get_recurring_messages().await.unwrap().is_empty());
+}
+
+#[test]
+fn every_older_version_has_an_upgrade_step() {
+    let mut db = version_0_snapshot();
+    for version in 0..SNAPSHOT_VERSION {
+        upgrade_snapshot(version, &mut db).unwrap();
+    }
+    assert!(upgrade_snapshot(SNAPSHOT_VERSION, &mut db).is_err());
+    serde_json::from_value::<InnerMemoryDb>(db).unwrap();
+}
+
+#[tokio::test]
+async fn snapshot_from_before_recurring_messages_is_upgraded() {
+    let file = SnapshotFile::new("before-recurring");
+    let db = MemoryDb::open(&file.0, ADMIN_ID).unwrap();
+    let id = db.add_message(text_message("Hello")).await.unwrap();
+    db.flush().await.unwrap();
+
+    let mut snapshot = file.read();
+    snapshot["version"] = 13.into();
+    for field in ["recurring_messages", "next_recurring_id"] {
+        snapshot["db"].as_object_mut().unwrap().remove(field);
+    }
+    file.write(&snapshot);
+
+    let db = MemoryDb::open(&file.0, ADMIN_ID).unwrap();
+    assert_eq!(text_of(&db.get_message(id).await.unwrap().unwrap()), "Hello");
+    assert!(db.get_recurring_messages().await.unwrap().is_empty());
+}
+
+#[test]
+fn newer_snapshot_is_refused() {
+    let file = SnapshotFile::new("newer");
+    file.write(&json!({ "version": SNAPSHOT_VERSION + 1, "db": {} }));
+    assert!(MemoryDb::open(&file.0, ADMIN_ID).is_err());
+}
+
+#[tokio::test]
+async fn stored_snapshot_is_loaded_again() {
+    let file = SnapshotFile::new("roundtrip");
+    let db = MemoryDb::open(&file.0, ADMIN_ID).unwrap();
+    let deleted = db.add_message(text_message("Deleted")).await.unwrap();
+    let kept = db.add_message(text_message("Kept")).await.unwrap();
+    db.delete_message(deleted).await.unwrap();
+    db.flush().await.unwrap();
+
+    let db = MemoryDb::open(&file.0, ADMIN_ID).unwrap();
+    assert_eq!(text_of(&db.get_message(kept).await.unwrap().unwrap()), "Kept");
+    assert!(db.get_message(deleted).await.unwrap().is_none());
+    // Neither the ID of the deleted message nor the one of its recall are reused.
+    let recall = db.get_next_recall(DEVICE_ID, None, Utc::now()).await.unwrap().unwrap();
+    assert_eq!(recall.message_id, deleted);
+    assert!(db.add_message(text_message("New")).await.unwrap() > recall.id);
+}
+
+#[tokio::test]
+async fn concurrent_stores_leave_the_latest_snapshot() {
+    let file = SnapshotFile::new("concurrent");
+    let db = Arc::new(MemoryDb::open(&file.0, ADMIN_ID).unwrap());
+
+    let mut stores = Vec::new();
+    for i in 0..20 {
+        let db = db.clone();
+        stores.push(tokio::spawn(async move {
+            db.add_message(text_message(&format!("Message {i}"))).await.unwrap();
+            db.flush().await
+        }));
+    }
+    for store in stores {
+        store.await.unwrap().unwrap();
+    }
+
+    let mut tmp_path = file.0.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    assert!(!Path::new(&tmp_path).exists());
+    let reopened = MemoryDb::open(&file.0, ADMIN_ID).unwrap();
+    let all = MessageFilter::default();
+    assert_eq!(
+        reopened.get_message_history(&all, None, 100).await.unwrap().len(),
+        db.get_message_history(&all, None, 100).await.unwrap().len()
+    );
+}
+
+#[tokio::test]
+async fn pending_devices_expire_and_are_limited() {
+    let file = SnapshotFile::new("pending");
+    let db = MemoryDb::open(&file.0, ADMIN_ID).unwrap();
+    let now = Utc::now();
+    let code = db.get_pairing_code(DEVICE_ID, now).await.unwrap();
+    assert_eq!(db.get_pairing_code(DEVICE_ID, now).await.unwrap(), code);
+
+    for id in 1..device::MAX_PENDING_DEVICES {
+        db.get_pairing_code(DeviceID(0x10000 + id as u32), now).await.unwrap();
+    }
+    let error = db.get_pairing_code(DeviceID(0x5678), now).await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<DbError>(),
+        Some(DbError::TooManyPendingDevices)
+    ));
+
+    let expired = now + device::PAIRING_CODE_LIFETIME;
+    let owner = User::new_telegram(UserId(2)).authorize();
+    assert!(db
+        .claim_device(code, "Kitchen".to_string(), owner, expired)
+        .await
+        .is_err());
+    let code = db.get_pairing_code(DEVICE_ID, expired).await.unwrap();
+    db.claim_device(code, "Kitchen".to_string(), owner, expired)
+        .await
+        .unwrap();
+}
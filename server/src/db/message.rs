@@ -5,32 +5,67 @@ use std::io::Cursor;
 
 use anyhow::anyhow;
 use common::{
-    consts::{IMAGE_BYTES_PER_PIXEL, IMAGE_HEIGHT, IMAGE_WIDTH, TEXT_BUFFER_SIZE},
-    protocols::{pico::UpdateKind, web::MessageMeta},
-    types::{MessageID, TextLength},
+    consts::{
+        FOOTER_BUFFER_SIZE, FOOTER_LENGTH, IMAGE_BUFFER_SIZE, IMAGE_BYTES_PER_PIXEL, IMAGE_HEIGHT, IMAGE_WIDTH,
+        MAX_PAGES, PAGE_LINES, TEXT_BUFFER_SIZE, TEXT_COLUMNS, TEXT_LINES,
+    },
+    protocols::{
+        pico::{self, TextStyle},
+        web::{MessageInfo, MessageKind, MessageMeta, MessageSender},
+    },
+    types::{DeviceID, MessageID},
 };
 use image::{codecs::png::PngEncoder, DynamicImage, ImageFormat, ImageReader, ImageResult};
 use serde::{Deserialize, Serialize};
 
+use super::{markup, user::RawUser};
 use crate::error::Result;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SenderID {
     Web,
     Telegram,
 }
 
+impl From<SenderID> for MessageSender {
+    fn from(sender_id: SenderID) -> Self {
+        match sender_id {
+            SenderID::Web => MessageSender::Web,
+            SenderID::Telegram => MessageSender::Telegram,
+        }
+    }
+}
+
+impl From<MessageSender> for SenderID {
+    fn from(sender: MessageSender) -> Self {
+        match sender {
+            MessageSender::Web => SenderID::Web,
+            MessageSender::Telegram => SenderID::Telegram,
+        }
+    }
+}
+
 /// Contains textual content of a message.
 /// To uphold an invariant on the string length this is a separate struct with private fields.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TextContent {
     text: String,
+    /// Texts with a style fit on the display in their font, see [`MessageContent::new_styled_text`].
+    #[serde(default)]
+    style: Option<TextStyle>,
 }
 
 impl TextContent {
     pub fn text(&self) -> &str {
         &self.text
     }
+
+    pub fn style(&self) -> Option<TextStyle> {
+        self.style
+    }
 }
 
 /// Contains image content of a message.
@@ -59,28 +94,37 @@ pub enum MessageContent {
 }
 
 impl MessageContent {
-    // a.d. TOOD str vs String
+    /// Fails if the text does not fit on [`MAX_PAGES`] pages, see [`paginate`].
     pub fn new_text(text: &str) -> Result<Self> {
-        if text.bytes().len() <= TEXT_BUFFER_SIZE {
-            Ok(MessageContent::Text(TextContent { text: text.to_string() }))
+        if fits_on_display(text) || paginate(text).len() <= MAX_PAGES {
+            Ok(MessageContent::Text(TextContent {
+                text: text.to_string(),
+                style: None,
+            }))
         } else {
-            Err(anyhow!("Text message too long."))
+            Err(anyhow!("Text message does not fit on {MAX_PAGES} pages."))
         }
     }
 
-    pub fn new_texts(text: String) -> Result<Vec<Self>> {
-        // TODO iterate in a way that we don't split up unicode chars.
-        let mut texts = vec![];
-        let mut bytes = text.as_bytes();
-
-        while bytes.len() > TEXT_BUFFER_SIZE {
-            let text = &bytes[..TEXT_BUFFER_SIZE];
-            let s = std::str::from_utf8(text).unwrap().to_owned();
-            texts.push(MessageContent::Text(TextContent { text: s }));
+    /// Fails if the text does not fit on the display in the font of `style`. Styled texts are not split into pages.
+    pub fn new_styled_text(text: &str, style: TextStyle) -> Result<Self> {
+        let columns = style.font.columns();
+        if text.len() <= TEXT_BUFFER_SIZE && wrap(text, columns).len() <= style.font.lines() {
+            Ok(MessageContent::Text(TextContent {
+                text: text.to_string(),
+                style: Some(style),
+            }))
+        } else {
+            Err(anyhow!("Styled text message does not fit on the display."))
+        }
+    }
 
-            bytes = &bytes[TEXT_BUFFER_SIZE..]
+    /// A text that is styled by its `markup`, see [`markup`].
+    pub fn from_markup(markup: &str) -> Result<Self> {
+        match markup::parse(markup) {
+            (text, Some(style)) => MessageContent::new_styled_text(&text, style),
+            (text, None) => MessageContent::new_text(&text),
         }
-        Ok(texts)
     }
 
     pub fn new_image(img: DynamicImage) -> Result<Self> {
@@ -106,14 +150,89 @@ impl MessageContent {
         }
         Ok(MessageContent::Image(ImageContent { png, rgb565 }))
     }
+
+    /// Reassemble image content that was created by [`MessageContent::new_image`], e.g. when loading it from a database.
+    pub fn from_raw_image(png: Vec<u8>, rgb565: Vec<u8>) -> Result<Self> {
+        if rgb565.len() == IMAGE_BUFFER_SIZE {
+            Ok(MessageContent::Image(ImageContent { png, rgb565 }))
+        } else {
+            Err(anyhow!("Image data has wrong size {}.", rgb565.len()))
+        }
+    }
 }
-impl From<&MessageContent> for UpdateKind {
-    fn from(value: &MessageContent) -> UpdateKind {
-        match value {
-            MessageContent::Text(tc) => UpdateKind::Text(tc.text.len() as TextLength),
-            MessageContent::Image { .. } => UpdateKind::Image,
+impl MessageContent {
+    pub fn kind(&self) -> MessageKind {
+        match self {
+            MessageContent::Text(_) => MessageKind::Text,
+            MessageContent::Image(_) => MessageKind::Image,
+        }
+    }
+}
+
+/// Whether `text` fits on the display as it is, so that it is not split into pages.
+pub fn fits_on_display(text: &str) -> bool {
+    text.len() <= TEXT_BUFFER_SIZE && wrap(text, TEXT_COLUMNS).len() <= TEXT_LINES
+}
+
+/// Split `text` into the pages that devices show one after the other. A page has up to [`PAGE_LINES`] lines of
+/// [`TEXT_COLUMNS`] characters, which are joined by line breaks and fit into [`TEXT_BUFFER_SIZE`] bytes.
+/// Lines are broken between words, only words that are longer than a line are split.
+pub fn paginate(text: &str) -> Vec<String> {
+    let mut pages = Vec::new();
+    let mut page = String::new();
+    let mut page_lines = 0;
+    for line in wrap(text, TEXT_COLUMNS) {
+        if page_lines == PAGE_LINES || page.len() + 1 + line.len() > TEXT_BUFFER_SIZE {
+            pages.push(std::mem::take(&mut page));
+            page_lines = 0;
+        }
+        // Empty lines between paragraphs would only waste the top of the next page.
+        if page_lines == 0 && line.is_empty() && !pages.is_empty() {
+            continue;
+        }
+        if page_lines > 0 {
+            page.push('\n');
         }
+        page.push_str(&line);
+        page_lines += 1;
     }
+    if page_lines > 0 || pages.is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+/// Break `text` into lines of at most `columns` characters, keeping its own line breaks.
+fn wrap(text: &str, columns: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        let mut line_chars = 0;
+        for word in paragraph.split_whitespace() {
+            let mut word = word;
+            let mut word_chars = word.chars().count();
+            if line_chars > 0 && line_chars + 1 + word_chars > columns {
+                lines.push(std::mem::take(&mut line));
+                line_chars = 0;
+            }
+            // Words that do not fit on a line of their own are split.
+            while word_chars > columns {
+                // PANIC: unwrap cannot fail since the word has more than `columns` characters.
+                let (split, _) = word.char_indices().nth(columns).unwrap();
+                lines.push(word[..split].to_string());
+                word = &word[split..];
+                word_chars -= columns;
+            }
+            if line_chars > 0 {
+                line.push(' ');
+                line_chars += 1;
+            }
+            line.push_str(word);
+            line_chars += word_chars;
+        }
+        lines.push(line);
+    }
+    lines
 }
 
 pub fn image_from_bytes_mime(bytes: &[u8], mime: String) -> ImageResult<DynamicImage> {
@@ -131,23 +250,158 @@ pub fn image_from_bytes_mime(bytes: &[u8], mime: String) -> ImageResult<DynamicI
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
+    /// Stays the same while the message exists, so that senders can refer to it.
     pub id: MessageID,
+    /// Where devices get the message in the order of all messages and recalls, and how devices refer to it.
+    /// The same as `id`, except for scheduled messages, which get a new one when they are released so that devices
+    /// which already got newer messages do not skip them.
+    pub sequence: MessageID,
     // a.d. TODO why meta separate? either put other stuff also in there or remove it.
     pub meta: MessageMeta,
     pub sender_id: SenderID,
+    /// The user that sent the message, if it was sent by a known user. Only they can recall it.
+    #[serde(default)]
+    pub(crate) author: Option<RawUser>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub content: MessageContent,
+    #[serde(default)]
+    pub delivery: Delivery,
+    /// Whether the message still waits for its `not_before` time, see [`Db::release_due_messages`].
+    ///
+    /// [`Db::release_due_messages`]: super::Db::release_due_messages
+    #[serde(default)]
+    pub scheduled: bool,
+}
+
+/// What the receiver told us about a message.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Delivery {
+    /// When the device acknowledged that it stored the message and shows it.
+    pub delivered_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Transfers of the message that the device reported as corrupted.
+    pub failed_attempts: u32,
 }
 
 impl Message {
+    /// When devices get the message, which is when its lifetime starts.
+    pub fn shown_from(&self) -> chrono::DateTime<chrono::Utc> {
+        self.meta
+            .not_before
+            .map_or(self.created_at, |not_before| not_before.max(self.created_at))
+    }
+
+    pub fn expires_at(&self) -> chrono::DateTime<chrono::Utc> {
+        self.shown_from() + self.meta.duration
+    }
+
+    pub fn is_expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.expires_at() <= now
+    }
+
+    /// How long the message should still be shown, counted from `now`.
+    pub fn remaining_lifetime(&self, now: chrono::DateTime<chrono::Utc>) -> chrono::Duration {
+        (self.expires_at() - now).max(chrono::Duration::zero())
+    }
+
+    /// Metadata of the message for the web API. The caller looks up the `author_name`.
+    pub fn info(&self, author_name: Option<String>) -> MessageInfo {
+        MessageInfo {
+            id: self.id,
+            receiver_id: self.meta.receiver_id,
+            sender: self.sender_id.into(),
+            author: self.author.map(|author| author.to_string()),
+            author_name,
+            created_at: self.created_at,
+            expires_at: self.expires_at(),
+            kind: self.content.kind(),
+            text: match &self.content {
+                MessageContent::Text(text) => Some(text.text().to_string()),
+                MessageContent::Image(_) => None,
+            },
+            urgent: self.meta.urgent,
+            not_before: self.meta.not_before,
+            scheduled: self.scheduled,
+            delivered_at: self.delivery.delivered_at,
+            failed_deliveries: self.delivery.failed_attempts,
+        }
+    }
+
     pub fn from_insert(id: MessageID, message: InsertMessage) -> Self {
+        let scheduled = message.is_scheduled();
         Self {
             id,
+            sequence: id,
             meta: message.meta,
             sender_id: message.sender_id,
+            author: message.author,
             created_at: message.created_at,
             content: message.content,
+            delivery: Delivery::default(),
+            scheduled,
+        }
+    }
+
+    /// Short description of the message, e.g. for buttons.
+    pub fn preview(&self, max_chars: usize) -> String {
+        match &self.content {
+            MessageContent::Text(text) => text_preview(text.text(), max_chars),
+            MessageContent::Image(_) => "Image".to_string(),
+        }
+    }
+}
+
+/// The first `max_chars` characters of `text`, with an ellipsis if it is longer.
+pub fn text_preview(text: &str, max_chars: usize) -> String {
+    let mut preview: String = text.chars().take(max_chars).collect();
+    if text.chars().count() > max_chars {
+        preview.push('…');
+    }
+    preview
+}
+
+/// Footer that attributes a message to its author on a device, shortened to fit the display.
+pub fn author_footer(author_name: &str) -> String {
+    let mut footer = String::new();
+    for c in format!("from {author_name}").chars().take(FOOTER_LENGTH) {
+        if footer.len() + c.len_utf8() > FOOTER_BUFFER_SIZE {
+            break;
         }
+        footer.push(c);
+    }
+    footer
+}
+
+/// Criteria to search the message history. Criteria that are `None` match all messages.
+#[derive(Debug, Clone, Default)]
+pub struct MessageFilter {
+    pub receiver_id: Option<DeviceID>,
+    /// Only messages to one of these devices, e.g. the ones a user may see.
+    pub receiver_ids: Option<Vec<DeviceID>>,
+    pub sender_id: Option<SenderID>,
+    /// Only messages created at or after this time.
+    pub from: Option<chrono::DateTime<chrono::Utc>>,
+    /// Only messages created before this time.
+    pub to: Option<chrono::DateTime<chrono::Utc>>,
+    pub kind: Option<MessageKind>,
+    /// Only text messages containing this text, ignoring ASCII case.
+    pub text: Option<String>,
+}
+
+impl MessageFilter {
+    pub fn matches(&self, message: &Message) -> bool {
+        self.receiver_id.is_none_or(|id| message.meta.receiver_id == id)
+            && self
+                .receiver_ids
+                .as_ref()
+                .is_none_or(|ids| ids.contains(&message.meta.receiver_id))
+            && self.sender_id.is_none_or(|sender_id| message.sender_id == sender_id)
+            && self.from.is_none_or(|from| message.created_at >= from)
+            && self.to.is_none_or(|to| message.created_at < to)
+            && self.kind.is_none_or(|kind| message.content.kind() == kind)
+            && self.text.as_ref().is_none_or(|search| match &message.content {
+                MessageContent::Text(text) => text.text().to_ascii_lowercase().contains(&search.to_ascii_lowercase()),
+                MessageContent::Image(_) => false,
+            })
     }
 }
 
@@ -155,6 +409,7 @@ impl Message {
 pub struct InsertMessage {
     pub meta: MessageMeta,
     pub sender_id: SenderID,
+    pub(crate) author: Option<RawUser>,
     pub created_at: chrono::DateTime<chrono::Utc>,
     pub content: MessageContent,
 }
@@ -169,8 +424,43 @@ impl InsertMessage {
         Self {
             meta,
             sender_id,
+            author: None,
             created_at,
             content,
         }
     }
+
+    pub(crate) fn with_author(mut self, author: Option<RawUser>) -> Self {
+        self.author = author;
+        self
+    }
+
+    /// Whether devices must not get the message yet. Times in the past are ignored.
+    pub fn is_scheduled(&self) -> bool {
+        self.meta
+            .not_before
+            .is_some_and(|not_before| not_before > self.created_at)
+    }
+}
+
+/// Tells a device to drop a message that was deleted, since it may have received the message already.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Recall {
+    /// Taken from the same counter as [`Message::sequence`], so that devices get messages and recalls in order with a
+    /// single cursor.
+    pub id: MessageID,
+    /// The [`Message::sequence`] of the deleted message, by which the device knows it.
+    pub message_id: MessageID,
+    pub receiver_id: DeviceID,
+    /// When the recalled message would have expired. After that devices have dropped it anyways.
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<&Recall> for pico::Recall {
+    fn from(recall: &Recall) -> Self {
+        Self {
+            id: recall.id,
+            message_id: recall.message_id,
+        }
+    }
 }
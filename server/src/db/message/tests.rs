@@ -0,0 +1,63 @@
+use super::*;
+
+fn numbered_lines(count: usize) -> Vec<String> {
+    (1..=count).map(|i| format!("line {i}")).collect()
+}
+
+#[test]
+fn lines_are_broken_between_words() {
+    assert_eq!(
+        wrap("the quick brown fox jumps over the lazy dog", TEXT_COLUMNS),
+        ["the quick brown", "fox jumps over", "the lazy dog"]
+    );
+    // Line breaks of the text are kept, even empty lines.
+    assert_eq!(wrap("one\n\ntwo  three", TEXT_COLUMNS), ["one", "", "two three"]);
+}
+
+#[test]
+fn long_words_are_split_after_as_many_characters_as_fit() {
+    let umlauts = "ü".repeat(TEXT_COLUMNS + 3);
+    assert_eq!(
+        wrap(&format!("ab {umlauts}"), TEXT_COLUMNS),
+        ["ab".to_string(), "ü".repeat(TEXT_COLUMNS), "üüü".to_string()]
+    );
+}
+
+#[test]
+fn last_page_has_the_remaining_lines() {
+    let lines = numbered_lines(PAGE_LINES + 2);
+    assert_eq!(
+        paginate(&lines.join("\n")),
+        [lines[..PAGE_LINES].join("\n"), lines[PAGE_LINES..].join("\n")]
+    );
+    assert_eq!(paginate("short"), ["short"]);
+}
+
+#[test]
+fn pages_fit_into_the_text_buffer() {
+    // A line takes three bytes per character, so only two of them fit on a page.
+    let line = "€".repeat(TEXT_COLUMNS);
+    let pages = paginate(&line.repeat(5));
+    assert_eq!(pages, [format!("{line}\n{line}"), format!("{line}\n{line}"), line]);
+    assert!(pages.iter().all(|page| page.len() <= TEXT_BUFFER_SIZE));
+}
+
+#[test]
+fn pages_do_not_start_with_an_empty_line() {
+    let lines = numbered_lines(PAGE_LINES);
+    let text = format!("{}\n\nnext", lines.join("\n"));
+    assert_eq!(paginate(&text), [lines.join("\n"), "next".to_string()]);
+}
+
+#[test]
+fn short_text_with_more_lines_than_the_display_is_paged() {
+    let fitting = numbered_lines(TEXT_LINES).join("\n");
+    assert!(fits_on_display(&fitting));
+    let too_many_lines = numbered_lines(TEXT_LINES + 1).join("\n");
+    assert!(too_many_lines.len() <= TEXT_BUFFER_SIZE);
+    assert!(!fits_on_display(&too_many_lines));
+
+    assert!(MessageContent::new_text(&too_many_lines).is_ok());
+    let too_many_pages = numbered_lines(MAX_PAGES * PAGE_LINES + 1).join("\n");
+    assert!(MessageContent::new_text(&too_many_pages).is_err());
+}
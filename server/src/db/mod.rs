@@ -1,18 +1,30 @@
 use async_trait::async_trait;
-use common::types::{DeviceID, MessageID};
+use chrono::{DateTime, Utc};
+use common::{
+    protocols::pico::DeviceStatus,
+    types::{DeviceID, FirmwareVersion, MessageID, PairingCode, RecurringID},
+};
+use thiserror::Error;
 use uuid::Uuid;
 
 use self::{
+    access::Grant,
     authorization::AuthRequest,
-    device::Device,
-    message::{InsertMessage, Message},
+    device::{Device, DeviceKeys},
+    message::{InsertMessage, Message, MessageFilter, Recall},
+    recurring::{InsertRecurringMessage, RecurringMessage},
     user::{Authorized, RawUser, User},
 };
+use crate::error::Result;
 
+pub mod access;
 pub mod authorization;
 pub mod device;
+pub mod markup;
 pub mod memory_db;
 pub mod message;
+pub mod recurring;
+pub mod sqlite_db;
 pub mod user;
 
 // The different ways of declaring async functions in traits (after Rust 1.75) as far as I understand it.
@@ -41,17 +53,145 @@ pub mod user;
 //   - One caveat, the `is_user_authorized` function used to be generic and take a User<T>, but even though this is supposed to be supported, it did not work for me.
 //       (even adding a where bound like User<T>: Send).
 
+/// Errors of [`Db`] functions that are caused by the request instead of the database itself.
+/// They are returned wrapped in an [`anyhow::Error`], so callers can downcast to handle them.
+#[derive(Debug, Error)]
+pub enum DbError {
+    #[error("Device {0} already exists.")]
+    DeviceExists(DeviceID),
+    #[error("Device {0} not found.")]
+    DeviceNotFound(DeviceID),
+    #[error("No device is waiting to be claimed with the pairing code {0}.")]
+    PairingCodeNotFound(PairingCode),
+    #[error("Too many devices are waiting to be claimed.")]
+    TooManyPendingDevices,
+    #[error("Message {0} not found.")]
+    MessageNotFound(MessageID),
+    #[error("Recurring message {0} not found.")]
+    RecurringMessageNotFound(RecurringID),
+}
+
 /// Generic interface to our application state.
+///
+/// Accessing the state can fail for persistent implementations, so most functions return a [`Result`].
 #[async_trait]
 pub trait Db: Send + Sync {
-    async fn get_devices(&self) -> Vec<Device>;
-    async fn get_device(&self, id: DeviceID) -> Option<Device>;
-    async fn get_message(&self, id: MessageID) -> Option<Message>;
-    async fn add_message(&self, message: InsertMessage) -> MessageID;
-    async fn get_next_message(&self, receiver_id: DeviceID, after: Option<MessageID>) -> Option<Message>;
-    async fn is_user_authorized(&self, user: RawUser) -> Option<User<Authorized>>;
-    async fn add_authorized_user(&self, user: User<Authorized>);
+    async fn get_devices(&self) -> Result<Vec<Device>>;
+    async fn get_device(&self, id: DeviceID) -> Result<Option<Device>>;
+    /// Register a new device. Fails with [`DbError::DeviceExists`] if the ID is already taken.
+    async fn add_device(&self, device: Device) -> Result<()>;
+    /// Fails with [`DbError::DeviceNotFound`] if there is no such device.
+    async fn rename_device(&self, id: DeviceID, name: String) -> Result<Device>;
+    /// Fails with [`DbError::DeviceNotFound`] if there is no such device.
+    async fn set_show_author(&self, id: DeviceID, show_author: bool) -> Result<Device>;
+    /// Record the firmware version that the device reported.
+    /// Fails with [`DbError::DeviceNotFound`] if there is no such device.
+    async fn set_firmware(&self, id: DeviceID, firmware: FirmwareVersion) -> Result<Device>;
+    /// Store the status the device reported at `seen_at`.
+    async fn set_status(&self, id: DeviceID, status: DeviceStatus, seen_at: DateTime<Utc>) -> Result<Device>;
+    /// Remove the device together with the rights granted on it and the recurring messages to it.
+    /// Fails with [`DbError::DeviceNotFound`] if there is no such device.
+    async fn remove_device(&self, id: DeviceID) -> Result<Device>;
+    /// The rights granted on the device `id`. Does not include its owner.
+    async fn get_device_grants(&self, id: DeviceID) -> Result<Vec<Grant>>;
+    /// The rights granted to `user` on any device.
+    async fn get_user_grants(&self, user: RawUser) -> Result<Vec<Grant>>;
+    /// Add the grant or replace the right the user had on the device before.
+    /// Fails with [`DbError::DeviceNotFound`] if there is no such device.
+    async fn set_grant(&self, grant: Grant) -> Result<()>;
+    /// Returns false if `user` had no right on the device `id`.
+    async fn remove_grant(&self, id: DeviceID, user: RawUser) -> Result<bool>;
+    /// The secrets of the device `id`, whether it is registered or not. `None` if it never enrolled.
+    async fn get_device_keys(&self, id: DeviceID) -> Result<Option<DeviceKeys>>;
+    /// Add or replace the secrets of the device `id`, whether it is registered or not.
+    async fn set_device_keys(&self, id: DeviceID, keys: DeviceKeys) -> Result<()>;
+    /// The pairing code of the unregistered device `id`. A new code is created the first time the device asks and
+    /// after the previous one expired, see [`device::PAIRING_CODE_LIFETIME`].
+    /// Fails with [`DbError::TooManyPendingDevices`] if [`device::MAX_PENDING_DEVICES`] are already waiting.
+    async fn get_pairing_code(&self, id: DeviceID, now: DateTime<Utc>) -> Result<PairingCode>;
+    /// Register the device waiting with `code` under `name` and make `owner` its owner.
+    /// Fails with [`DbError::PairingCodeNotFound`] if no device is waiting with that code or the code expired.
+    async fn claim_device(
+        &self,
+        code: PairingCode,
+        name: String,
+        owner: User<Authorized>,
+        now: DateTime<Utc>,
+    ) -> Result<Device>;
+    /// Forget the secret and the pairing code of the unregistered device `id`, so that it enrolls again the next time
+    /// it connects. Returns false if it had neither. Fails with [`DbError::DeviceExists`] if the device is registered.
+    async fn reset_pending_device(&self, id: DeviceID) -> Result<bool>;
+    async fn get_message(&self, id: MessageID) -> Result<Option<Message>>;
+    async fn add_message(&self, message: InsertMessage) -> Result<MessageID>;
+    /// Delete the message `id` and record a [`Recall`] so that its receiver drops it, too.
+    /// Scheduled messages need no recall since their receiver never got them.
+    /// Fails with [`DbError::MessageNotFound`] if there is no such message.
+    async fn delete_message(&self, id: MessageID) -> Result<Message>;
+    /// Record that the device `device_id` stored the message with the [`Message::sequence`] `sequence`.
+    /// Returns the message the first time, and `None` if it was acknowledged before or is not addressed to the device.
+    async fn mark_delivered(
+        &self,
+        device_id: DeviceID,
+        sequence: MessageID,
+        at: DateTime<Utc>,
+    ) -> Result<Option<Message>>;
+    /// Count a corrupted transfer of the message with the [`Message::sequence`] `sequence` to the device `device_id`.
+    async fn record_failed_delivery(&self, device_id: DeviceID, sequence: MessageID) -> Result<()>;
+    /// Up to `limit` messages matching `filter` with an ID below `before`, newest first.
+    /// Includes expired messages that have not been deleted yet.
+    async fn get_message_history(
+        &self,
+        filter: &MessageFilter,
+        before: Option<MessageID>,
+        limit: usize,
+    ) -> Result<Vec<Message>>;
+    /// The messages sent by `author` that are shown and have not expired at time `now`, newest first.
+    async fn get_active_messages_by(&self, author: RawUser, now: DateTime<Utc>) -> Result<Vec<Message>>;
+    /// The messages that still wait for their `not_before` time, the next one due first.
+    async fn get_scheduled_messages(&self) -> Result<Vec<Message>>;
+    /// Hand the scheduled messages that are due at time `now` to their devices and return them.
+    /// They keep their IDs but get a new [`Message::sequence`], so that devices which already received newer messages
+    /// do not skip them.
+    async fn release_due_messages(&self, now: DateTime<Utc>) -> Result<Vec<Message>>;
+    /// The first message for `receiver_id` with a [`Message::sequence`] after `after` that is not scheduled and has not
+    /// expired at time `now`.
+    async fn get_next_message(
+        &self,
+        receiver_id: DeviceID,
+        after: Option<MessageID>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Message>>;
+    /// The oldest recall for `receiver_id` after the [`Message::sequence`] `after` that is still relevant at time `now`.
+    async fn get_next_recall(
+        &self,
+        receiver_id: DeviceID,
+        after: Option<MessageID>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Recall>>;
+    async fn get_recurring_message(&self, id: RecurringID) -> Result<Option<RecurringMessage>>;
+    /// All recurring messages, the next one due first.
+    async fn get_recurring_messages(&self) -> Result<Vec<RecurringMessage>>;
+    async fn add_recurring_message(&self, message: InsertRecurringMessage) -> Result<RecurringID>;
+    /// Messages that were already sent for it stay.
+    /// Fails with [`DbError::RecurringMessageNotFound`] if there is no such recurring message.
+    async fn delete_recurring_message(&self, id: RecurringID) -> Result<RecurringMessage>;
+    /// Set when the next message of the recurring message `id` is due.
+    /// Fails with [`DbError::RecurringMessageNotFound`] if there is no such recurring message.
+    async fn set_recurring_next_at(&self, id: RecurringID, next_at: DateTime<Utc>) -> Result<()>;
+    /// Delete all messages and recalls that expired before `expired_before` and return how many messages there were.
+    async fn delete_expired_messages(&self, expired_before: DateTime<Utc>) -> Result<usize>;
+    async fn is_user_authorized(&self, user: RawUser) -> Result<Option<User<Authorized>>>;
+    async fn add_authorized_user(&self, user: User<Authorized>) -> Result<()>;
+    async fn get_authorized_users(&self) -> Result<Vec<User<Authorized>>>;
+    /// The display name of `user`, e.g. to attribute messages.
+    async fn get_user_name(&self, user: RawUser) -> Result<Option<String>>;
+    async fn set_user_name(&self, user: RawUser, name: String) -> Result<()>;
+    async fn add_api_token(&self, token: Uuid, user: User<Authorized>) -> Result<()>;
+    /// The user that owns the web API `token`, as long as they are still authorized.
+    async fn get_api_token_user(&self, token: Uuid) -> Result<Option<User<Authorized>>>;
     async fn get_telegram_admin_id(&self) -> teloxide::types::UserId;
-    async fn get_auth_request(&self, id: Uuid) -> Option<AuthRequest>;
-    async fn add_auth_request(&self, auth_request: AuthRequest);
+    async fn get_auth_request(&self, id: Uuid) -> Result<Option<AuthRequest>>;
+    async fn add_auth_request(&self, auth_request: AuthRequest) -> Result<()>;
+    /// Persist all changes that have not been written yet, e.g. before shutting down.
+    async fn flush(&self) -> Result<()>;
 }
@@ -0,0 +1,144 @@
+//! Messages that are sent again and again, e.g. as reminders of weekly chores.
+//! The schedule task turns them into normal messages each time they are due.
+
+use chrono::{DateTime, Datelike, Local, TimeDelta, TimeZone, Utc};
+use common::{
+    protocols::web::{MessageMeta, NewRecurringMessage, Recurrence, RecurringMessageInfo},
+    types::{DeviceID, RecurringID},
+};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    message::{self, InsertMessage, MessageContent, SenderID},
+    user::RawUser,
+};
+use crate::error::Result;
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecurringMessage {
+    pub id: RecurringID,
+    pub receiver_id: DeviceID,
+    /// Checked to fit into a message when the recurring message is created.
+    pub text: String,
+    pub recurrence: Recurrence,
+    /// How long each message is shown.
+    pub duration: chrono::Duration,
+    pub urgent: bool,
+    pub sender_id: SenderID,
+    /// The user that created the recurring message. Messages are only sent as long as they may send to the device.
+    pub(crate) author: Option<RawUser>,
+    /// When the next message is due.
+    pub next_at: DateTime<Utc>,
+}
+
+impl RecurringMessage {
+    pub fn from_insert(id: RecurringID, message: InsertRecurringMessage) -> Self {
+        Self {
+            id,
+            receiver_id: message.receiver_id,
+            text: message.text,
+            recurrence: message.recurrence,
+            duration: message.duration,
+            urgent: message.urgent,
+            sender_id: message.sender_id,
+            author: message.author,
+            next_at: message.next_at,
+        }
+    }
+
+    /// The message that is due at [`RecurringMessage::next_at`].
+    /// It counts as created then, so that it expires in time even if it is sent late.
+    pub fn occurrence(&self) -> Result<InsertMessage> {
+        let meta = MessageMeta {
+            receiver_id: self.receiver_id,
+            duration: self.duration,
+            urgent: self.urgent,
+            not_before: None,
+        };
+        let content = MessageContent::from_markup(&self.text)?;
+        Ok(InsertMessage::new(meta, self.sender_id, self.next_at, content).with_author(self.author))
+    }
+
+    /// Short description of the message, e.g. for buttons.
+    pub fn preview(&self, max_chars: usize) -> String {
+        message::text_preview(&self.text, max_chars)
+    }
+
+    pub fn info(&self) -> RecurringMessageInfo {
+        RecurringMessageInfo {
+            id: self.id,
+            receiver_id: self.receiver_id,
+            text: self.text.clone(),
+            recurrence: self.recurrence,
+            duration: self.duration,
+            urgent: self.urgent,
+            author: self.author.map(|author| author.to_string()),
+            next_at: self.next_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct InsertRecurringMessage {
+    pub receiver_id: DeviceID,
+    pub text: String,
+    pub recurrence: Recurrence,
+    pub duration: chrono::Duration,
+    pub urgent: bool,
+    pub sender_id: SenderID,
+    pub(crate) author: Option<RawUser>,
+    pub next_at: DateTime<Utc>,
+}
+
+impl InsertRecurringMessage {
+    /// The first message is due at the first occurrence after `now`. Fails if the text does not fit into a message.
+    pub fn new(message: NewRecurringMessage, sender_id: SenderID, now: DateTime<Utc>) -> Result<Self> {
+        MessageContent::from_markup(&message.text)?;
+        Ok(Self {
+            receiver_id: message.receiver_id,
+            text: message.text,
+            recurrence: message.recurrence,
+            duration: message.duration,
+            urgent: message.urgent,
+            sender_id,
+            author: None,
+            next_at: next_occurrence(message.recurrence, now),
+        })
+    }
+
+    pub(crate) fn with_author(mut self, author: Option<RawUser>) -> Self {
+        self.author = author;
+        self
+    }
+}
+
+/// The first time after `after` at which `recurrence` is due, in the local time of the server.
+pub fn next_occurrence(recurrence: Recurrence, after: DateTime<Utc>) -> DateTime<Utc> {
+    next_occurrence_in(&Local, recurrence, after)
+}
+
+/// Like [`next_occurrence`], in the time zone `tz`.
+fn next_occurrence_in<Tz: TimeZone>(tz: &Tz, recurrence: Recurrence, after: DateTime<Utc>) -> DateTime<Utc> {
+    let (weekday, time) = match recurrence {
+        Recurrence::Day { time } => (None, time),
+        Recurrence::Week { weekday, time } => (Some(weekday), time),
+    };
+    let today = after.with_timezone(tz).date_naive();
+    (0..)
+        .map(|days| today + TimeDelta::days(days))
+        .filter(|day| weekday.is_none_or(|weekday| day.weekday() == weekday))
+        .find_map(|day| {
+            let local = day.and_time(time);
+            // A time that is skipped when the clocks are put forward is due an hour later.
+            let due = tz
+                .from_local_datetime(&local)
+                .earliest()
+                .or_else(|| tz.from_local_datetime(&(local + TimeDelta::hours(1))).earliest())?;
+            Some(due.to_utc()).filter(|due| *due > after)
+        })
+        // PANIC: unwrap cannot fail since the iterator is endless and every week has a time after `after`.
+        .unwrap()
+}
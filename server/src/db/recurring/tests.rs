@@ -0,0 +1,124 @@
+use chrono::{NaiveTime, Weekday};
+use chrono_tz::Europe::Berlin;
+
+use super::*;
+
+fn utc(month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2024, month, day, hour, minute, 0).unwrap()
+}
+
+fn daily(hour: u32, minute: u32) -> Recurrence {
+    Recurrence::Day {
+        time: NaiveTime::from_hms_opt(hour, minute, 0).unwrap(),
+    }
+}
+
+fn weekly(weekday: Weekday, hour: u32, minute: u32) -> Recurrence {
+    Recurrence::Week {
+        weekday,
+        time: NaiveTime::from_hms_opt(hour, minute, 0).unwrap(),
+    }
+}
+
+// Berlin is an hour ahead of UTC in winter and two hours in summer. In 2024, the clocks were put forward from 2:00 to
+// 3:00 on March 31 and back from 3:00 to 2:00 on October 27.
+
+#[test]
+fn daily_message_is_due_at_the_next_time_of_day() {
+    assert_eq!(
+        next_occurrence_in(&Berlin, daily(8, 0), utc(1, 10, 6, 0)),
+        utc(1, 10, 7, 0)
+    );
+    // An occurrence is only due after `after`, not at the same time.
+    assert_eq!(
+        next_occurrence_in(&Berlin, daily(8, 0), utc(1, 10, 7, 0)),
+        utc(1, 11, 7, 0)
+    );
+    // It is already January 11 in Berlin.
+    assert_eq!(
+        next_occurrence_in(&Berlin, daily(0, 15), utc(1, 10, 23, 30)),
+        utc(1, 11, 23, 15)
+    );
+}
+
+#[test]
+fn weekly_message_is_due_on_its_weekday() {
+    // January 10, 2024 is a Wednesday.
+    assert_eq!(
+        next_occurrence_in(&Berlin, weekly(Weekday::Tue, 18, 0), utc(1, 10, 6, 0)),
+        utc(1, 16, 17, 0)
+    );
+    assert_eq!(
+        next_occurrence_in(&Berlin, weekly(Weekday::Wed, 8, 0), utc(1, 10, 6, 0)),
+        utc(1, 10, 7, 0)
+    );
+    assert_eq!(
+        next_occurrence_in(&Berlin, weekly(Weekday::Wed, 8, 0), utc(1, 10, 8, 0)),
+        utc(1, 17, 7, 0)
+    );
+}
+
+#[test]
+fn local_time_is_kept_when_the_clocks_change() {
+    assert_eq!(
+        next_occurrence_in(&Berlin, daily(8, 0), utc(3, 30, 7, 0)),
+        utc(3, 31, 6, 0)
+    );
+    assert_eq!(
+        next_occurrence_in(&Berlin, daily(8, 0), utc(10, 26, 6, 0)),
+        utc(10, 27, 7, 0)
+    );
+}
+
+#[test]
+fn time_skipped_by_the_clocks_is_due_an_hour_later() {
+    assert_eq!(
+        next_occurrence_in(&Berlin, daily(2, 30), utc(3, 30, 2, 0)),
+        utc(3, 31, 1, 30)
+    );
+}
+
+#[test]
+fn time_repeated_by_the_clocks_is_only_due_once() {
+    let first = next_occurrence_in(&Berlin, daily(2, 30), utc(10, 26, 12, 0));
+    assert_eq!(first, utc(10, 27, 0, 30));
+    // Not again at the second 2:30 an hour later, but on the next day.
+    assert_eq!(next_occurrence_in(&Berlin, daily(2, 30), first), utc(10, 28, 1, 30));
+}
+
+#[test]
+fn occurrence_counts_as_created_when_it_is_due() {
+    let new_message = NewRecurringMessage {
+        receiver_id: DeviceID(0x1234),
+        text: "<red>Take out the trash".to_string(),
+        recurrence: daily(18, 0),
+        duration: TimeDelta::hours(2),
+        urgent: true,
+    };
+    let now = Utc::now();
+    let insert = InsertRecurringMessage::new(new_message, SenderID::Web, now).unwrap();
+    assert_eq!(insert.next_at, next_occurrence(daily(18, 0), now));
+    let message = RecurringMessage::from_insert(RecurringID(1), insert);
+
+    let occurrence = message.occurrence().unwrap();
+    assert_eq!(occurrence.created_at, message.next_at);
+    assert_eq!(occurrence.meta.duration, TimeDelta::hours(2));
+    assert!(occurrence.meta.urgent);
+    let MessageContent::Text(text) = &occurrence.content else {
+        panic!("expected a text");
+    };
+    assert_eq!(text.text(), "Take out the trash");
+    assert!(text.style().is_some());
+}
+
+#[test]
+fn text_that_does_not_fit_into_a_message_is_refused() {
+    let new_message = NewRecurringMessage {
+        receiver_id: DeviceID(0x1234),
+        text: "lorem ".repeat(1_000),
+        recurrence: daily(18, 0),
+        duration: TimeDelta::hours(2),
+        urgent: false,
+    };
+    assert!(InsertRecurringMessage::new(new_message, SenderID::Web, Utc::now()).is_err());
+}
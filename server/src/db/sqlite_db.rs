@@ -0,0 +1,1191 @@
+//! Persistent implementation of [`Db`] backed by an SQLite database file.
+//!
+//! The schema is created and upgraded by the [`MIGRATIONS`] below. SQLite keeps track of the number of migrations
+//! that were already applied to a database file in its `user_version` pragma.
+
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use common::{
+    protocols::{
+        pico::DeviceStatus,
+        web::{DeviceRight, MessageKind, MessageMeta, Recurrence},
+    },
+    types::{DeviceID, FirmwareVersion, MessageID, PairingCode, RecurringID},
+};
+use rusqlite::{params, params_from_iter, types::Value, Connection, OptionalExtension, Row};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::{
+    access::Grant,
+    authorization::AuthRequest,
+    device::{self, Device, DeviceKeys},
+    message::{Delivery, InsertMessage, Message, MessageContent, MessageFilter, Recall, SenderID},
+    recurring::{InsertRecurringMessage, RecurringMessage},
+    user::{Authorized, RawUser, User},
+    Db, DbError,
+};
+use crate::error::Result;
+
+#[cfg(test)]
+mod tests;
+
+/// Each entry upgrades the schema by one version. Entries must never be changed after they have been deployed,
+/// changes to the schema need a new entry at the end.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema.
+    "CREATE TABLE devices (
+        id INTEGER PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL
+    );
+    CREATE TABLE messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        receiver_id INTEGER NOT NULL,
+        duration_sec INTEGER NOT NULL,
+        sender_id TEXT NOT NULL,
+        created_at_us INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        text TEXT,
+        png BLOB,
+        rgb565 BLOB
+    );
+    CREATE INDEX messages_receiver ON messages (receiver_id, created_at_us);
+    CREATE TABLE authorized_users (
+        user TEXT PRIMARY KEY NOT NULL
+    );
+    CREATE TABLE auth_requests (
+        id BLOB PRIMARY KEY NOT NULL,
+        user_id INTEGER NOT NULL,
+        user_name TEXT NOT NULL
+    );",
+    // 2: Web API tokens.
+    "CREATE TABLE api_tokens (
+        token BLOB PRIMARY KEY NOT NULL,
+        user TEXT NOT NULL
+    );",
+    // 3: Device owners and devices waiting to be claimed.
+    "ALTER TABLE devices ADD COLUMN owner TEXT;
+    CREATE TABLE pending_devices (
+        id INTEGER PRIMARY KEY NOT NULL,
+        code INTEGER NOT NULL UNIQUE
+    );",
+    // 4: Message authors and recalls of deleted messages.
+    "ALTER TABLE messages ADD COLUMN author TEXT;
+    CREATE INDEX messages_author ON messages (author);
+    CREATE TABLE recalls (
+        id INTEGER PRIMARY KEY NOT NULL,
+        message_id INTEGER NOT NULL,
+        receiver_id INTEGER NOT NULL,
+        expires_at_us INTEGER NOT NULL
+    );",
+    // 5: Display names of users and author footers on devices.
+    "ALTER TABLE devices ADD COLUMN show_author INTEGER NOT NULL DEFAULT 0;
+    CREATE TABLE user_names (
+        user TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL
+    );
+    INSERT OR REPLACE INTO user_names (user, name)
+        SELECT 'telegram:' || user_id, user_name FROM auth_requests;",
+    // 6: Rights granted on devices.
+    "CREATE TABLE grants (
+        device_id INTEGER NOT NULL,
+        user TEXT NOT NULL,
+        access TEXT NOT NULL,
+        PRIMARY KEY (device_id, user)
+    );
+    CREATE INDEX grants_user ON grants (user);",
+    // 7: Firmware versions of devices.
+    "ALTER TABLE devices ADD COLUMN firmware TEXT;",
+    // 8: Secrets with which devices authenticate. Not tied to registered devices.
+    "CREATE TABLE device_keys (
+        id INTEGER PRIMARY KEY NOT NULL,
+        secret BLOB NOT NULL,
+        next_secret BLOB
+    );",
+    // 9: Delivery state of messages.
+    "ALTER TABLE messages ADD COLUMN delivered_at_us INTEGER;
+    ALTER TABLE messages ADD COLUMN failed_deliveries INTEGER NOT NULL DEFAULT 0;",
+    // 10: Last status reported by devices, as JSON.
+    "ALTER TABLE devices ADD COLUMN last_seen_us INTEGER;
+    ALTER TABLE devices ADD COLUMN status TEXT;",
+    // 11: Urgent messages.
+    "ALTER TABLE messages ADD COLUMN urgent INTEGER NOT NULL DEFAULT 0;",
+    // 12: Scheduled messages.
+    "ALTER TABLE messages ADD COLUMN not_before_us INTEGER;
+    ALTER TABLE messages ADD COLUMN scheduled INTEGER NOT NULL DEFAULT 0;
+    CREATE INDEX messages_scheduled ON messages (not_before_us) WHERE scheduled;",
+    // 13: Recurring messages, with their recurrence as JSON.
+    "CREATE TABLE recurring_messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        receiver_id INTEGER NOT NULL,
+        text TEXT NOT NULL,
+        recurrence TEXT NOT NULL,
+        duration_sec INTEGER NOT NULL,
+        urgent INTEGER NOT NULL,
+        sender_id TEXT NOT NULL,
+        author TEXT,
+        next_at_us INTEGER NOT NULL
+    );",
+    // 14: Styled texts, with their style as JSON.
+    "ALTER TABLE messages ADD COLUMN style TEXT;",
+    // 15: Expiring pairing codes. The codes of devices that were already pending expire right away.
+    "ALTER TABLE pending_devices ADD COLUMN since_us INTEGER NOT NULL DEFAULT 0;",
+    // 16: Sequences of released scheduled messages, see [`MESSAGE_SEQUENCE`].
+    "ALTER TABLE messages ADD COLUMN sequence INTEGER;
+    CREATE INDEX messages_receiver_sequence ON messages (receiver_id, coalesce(sequence, id));",
+];
+
+const MESSAGE_KIND_TEXT: &str = "text";
+const MESSAGE_KIND_IMAGE: &str = "image";
+const SENDER_WEB: &str = "web";
+const SENDER_TELEGRAM: &str = "telegram";
+
+fn sender_column(sender_id: SenderID) -> &'static str {
+    match sender_id {
+        SenderID::Web => SENDER_WEB,
+        SenderID::Telegram => SENDER_TELEGRAM,
+    }
+}
+
+const RIGHT_SEND: &str = "send";
+const RIGHT_MANAGE: &str = "manage";
+
+fn right_column(right: DeviceRight) -> &'static str {
+    match right {
+        DeviceRight::Send => RIGHT_SEND,
+        DeviceRight::Manage => RIGHT_MANAGE,
+    }
+}
+
+const DEVICE_COLUMNS: &str = "id, name, owner, show_author, firmware, last_seen_us, status";
+const MESSAGE_COLUMNS: &str =
+    "id, receiver_id, duration_sec, sender_id, created_at_us, kind, text, png, rgb565, author, delivered_at_us, failed_deliveries, urgent, not_before_us, scheduled, style, coalesce(sequence, id)";
+const RECURRING_COLUMNS: &str =
+    "id, receiver_id, text, recurrence, duration_sec, urgent, sender_id, author, next_at_us";
+/// SQL expression for the [`Message::sequence`], which is only stored for released scheduled messages.
+const MESSAGE_SEQUENCE: &str = "coalesce(sequence, id)";
+/// SQL expression for the time from which a message is shown, see [`Message::shown_from`].
+const MESSAGE_SHOWN_FROM_US: &str = "max(created_at_us, coalesce(not_before_us, created_at_us))";
+/// SQL expression for the time at which a message expires.
+const MESSAGE_EXPIRES_AT_US: &str =
+    "(max(created_at_us, coalesce(not_before_us, created_at_us)) + duration_sec * 1000000)";
+
+pub struct SqliteDb {
+    // The connection is only used synchronously while the mutex is locked, so we never hold it across an await.
+    conn: Mutex<Connection>,
+    telegram_admin_id: teloxide::types::UserId,
+}
+
+impl SqliteDb {
+    /// Open (or create) the database at `path` and bring its schema up to date.
+    /// The telegram admin is always an authorized user.
+    pub fn open<P: AsRef<Path>>(path: P, telegram_admin_id: teloxide::types::UserId) -> Result<Self> {
+        let mut conn = Connection::open(path.as_ref())
+            .with_context(|| format!("opening database {} failed", path.as_ref().display()))?;
+        migrate(&mut conn)?;
+
+        let admin = User::new_telegram(telegram_admin_id).authorize();
+        conn.execute(
+            "INSERT OR IGNORE INTO authorized_users (user) VALUES (?1)",
+            params![admin.raw().to_string()],
+        )?;
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            telegram_admin_id,
+        })
+    }
+}
+
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(anyhow!(
+            "database schema version {version} is newer than the supported version {}",
+            MIGRATIONS.len()
+        ));
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)
+            .with_context(|| format!("database migration {} failed", i + 1))?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+        log::info!("Migrated database schema to version {}.", i + 1);
+    }
+    Ok(())
+}
+
+/// Raw column values of a device, see [`MessageRow`].
+struct DeviceRow {
+    id: u32,
+    name: String,
+    owner: Option<String>,
+    show_author: bool,
+    firmware: Option<String>,
+    last_seen_us: Option<i64>,
+    status: Option<String>,
+}
+
+impl DeviceRow {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            owner: row.get(2)?,
+            show_author: row.get(3)?,
+            firmware: row.get(4)?,
+            last_seen_us: row.get(5)?,
+            status: row.get(6)?,
+        })
+    }
+
+    fn into_device(self) -> Result<Device> {
+        let owner = self.owner.map(|owner| owner.parse::<RawUser>()).transpose()?;
+        let firmware = self
+            .firmware
+            .map(|firmware| firmware.parse::<FirmwareVersion>())
+            .transpose()?;
+        let last_seen = self
+            .last_seen_us
+            .map(|us| {
+                chrono::DateTime::from_timestamp_micros(us)
+                    .with_context(|| format!("invalid last seen time of device {}", self.id))
+            })
+            .transpose()?;
+        let status = self
+            .status
+            .map(|status| serde_json::from_str::<DeviceStatus>(&status))
+            .transpose()?;
+        Ok(Device::new(DeviceID(self.id), self.name)
+            .with_owner(owner)
+            .with_show_author(self.show_author)
+            .with_firmware(firmware)
+            .with_status(last_seen, status))
+    }
+}
+
+/// Raw column values of a message, converted into a [`Message`] outside of rusqlite's row mapping
+/// so that we can use our own error type.
+struct MessageRow {
+    id: u32,
+    receiver_id: u32,
+    duration_sec: i64,
+    sender_id: String,
+    created_at_us: i64,
+    kind: String,
+    text: Option<String>,
+    png: Option<Vec<u8>>,
+    rgb565: Option<Vec<u8>>,
+    author: Option<String>,
+    delivered_at_us: Option<i64>,
+    failed_deliveries: u32,
+    urgent: bool,
+    not_before_us: Option<i64>,
+    scheduled: bool,
+    style: Option<String>,
+    sequence: u32,
+}
+
+impl MessageRow {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            receiver_id: row.get(1)?,
+            duration_sec: row.get(2)?,
+            sender_id: row.get(3)?,
+            created_at_us: row.get(4)?,
+            kind: row.get(5)?,
+            text: row.get(6)?,
+            png: row.get(7)?,
+            rgb565: row.get(8)?,
+            author: row.get(9)?,
+            delivered_at_us: row.get(10)?,
+            failed_deliveries: row.get(11)?,
+            urgent: row.get(12)?,
+            not_before_us: row.get(13)?,
+            scheduled: row.get(14)?,
+            style: row.get(15)?,
+            sequence: row.get(16)?,
+        })
+    }
+
+    fn into_message(self) -> Result<Message> {
+        let sender_id = match self.sender_id.as_str() {
+            SENDER_WEB => SenderID::Web,
+            SENDER_TELEGRAM => SenderID::Telegram,
+            other => return Err(anyhow!("unknown sender '{other}' of message {}", self.id)),
+        };
+        let content = match (self.kind.as_str(), self.text, self.png, self.rgb565) {
+            (MESSAGE_KIND_TEXT, Some(text), _, _) => match self.style {
+                Some(style) => MessageContent::new_styled_text(&text, serde_json::from_str(&style)?)?,
+                None => MessageContent::new_text(&text)?,
+            },
+            (MESSAGE_KIND_IMAGE, _, Some(png), Some(rgb565)) => MessageContent::from_raw_image(png, rgb565)?,
+            (kind, ..) => return Err(anyhow!("malformed content of kind '{kind}' in message {}", self.id)),
+        };
+        let created_at = chrono::DateTime::from_timestamp_micros(self.created_at_us)
+            .with_context(|| format!("invalid creation time of message {}", self.id))?;
+        let author = self.author.map(|author| author.parse::<RawUser>()).transpose()?;
+        let delivered_at = self
+            .delivered_at_us
+            .map(|us| {
+                chrono::DateTime::from_timestamp_micros(us)
+                    .with_context(|| format!("invalid delivery time of message {}", self.id))
+            })
+            .transpose()?;
+        let not_before = self
+            .not_before_us
+            .map(|us| {
+                chrono::DateTime::from_timestamp_micros(us)
+                    .with_context(|| format!("invalid scheduled time of message {}", self.id))
+            })
+            .transpose()?;
+
+        Ok(Message {
+            id: MessageID(self.id),
+            sequence: MessageID(self.sequence),
+            meta: MessageMeta {
+                receiver_id: DeviceID(self.receiver_id),
+                duration: chrono::Duration::seconds(self.duration_sec),
+                urgent: self.urgent,
+                not_before,
+            },
+            sender_id,
+            author,
+            created_at,
+            content,
+            delivery: Delivery {
+                delivered_at,
+                failed_attempts: self.failed_deliveries,
+            },
+            scheduled: self.scheduled,
+        })
+    }
+}
+
+/// Raw column values of a recurring message, see [`MessageRow`].
+struct RecurringRow {
+    id: u32,
+    receiver_id: u32,
+    text: String,
+    recurrence: String,
+    duration_sec: i64,
+    urgent: bool,
+    sender_id: String,
+    author: Option<String>,
+    next_at_us: i64,
+}
+
+impl RecurringRow {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get(0)?,
+            receiver_id: row.get(1)?,
+            text: row.get(2)?,
+            recurrence: row.get(3)?,
+            duration_sec: row.get(4)?,
+            urgent: row.get(5)?,
+            sender_id: row.get(6)?,
+            author: row.get(7)?,
+            next_at_us: row.get(8)?,
+        })
+    }
+
+    fn into_recurring_message(self) -> Result<RecurringMessage> {
+        let sender_id = match self.sender_id.as_str() {
+            SENDER_WEB => SenderID::Web,
+            SENDER_TELEGRAM => SenderID::Telegram,
+            other => return Err(anyhow!("unknown sender '{other}' of recurring message {}", self.id)),
+        };
+        let recurrence = serde_json::from_str::<Recurrence>(&self.recurrence)?;
+        let author = self.author.map(|author| author.parse::<RawUser>()).transpose()?;
+        let next_at = chrono::DateTime::from_timestamp_micros(self.next_at_us)
+            .with_context(|| format!("invalid due time of recurring message {}", self.id))?;
+
+        Ok(RecurringMessage {
+            id: RecurringID(self.id),
+            receiver_id: DeviceID(self.receiver_id),
+            text: self.text,
+            recurrence,
+            duration: chrono::Duration::seconds(self.duration_sec),
+            urgent: self.urgent,
+            sender_id,
+            author,
+            next_at,
+        })
+    }
+}
+
+fn get_message(conn: &Connection, id: MessageID) -> Result<Option<Message>> {
+    let row = conn
+        .query_row(
+            &format!("SELECT {MESSAGE_COLUMNS} FROM messages WHERE id = ?1"),
+            params![id.0],
+            MessageRow::from_row,
+        )
+        .optional()?;
+    row.map(MessageRow::into_message).transpose()
+}
+
+/// Take a new ID from the AUTOINCREMENT counter of the messages, e.g. for recalls so that they order with the messages.
+/// The counter exists once the first message was inserted.
+fn next_message_id(conn: &Connection) -> Result<u32> {
+    let id = conn.query_row(
+        "UPDATE sqlite_sequence SET seq = seq + 1 WHERE name = 'messages' RETURNING seq",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(id)
+}
+
+fn recall_from_row(row: &Row<'_>) -> rusqlite::Result<(u32, u32, u32, i64)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+}
+
+fn into_recall((id, message_id, receiver_id, expires_at_us): (u32, u32, u32, i64)) -> Result<Recall> {
+    let expires_at = chrono::DateTime::from_timestamp_micros(expires_at_us)
+        .with_context(|| format!("invalid expiry time of recall {id}"))?;
+    Ok(Recall {
+        id: MessageID(id),
+        message_id: MessageID(message_id),
+        receiver_id: DeviceID(receiver_id),
+        expires_at,
+    })
+}
+
+fn grant_from_row(row: &Row<'_>) -> rusqlite::Result<(u32, String, String)> {
+    Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+}
+
+fn into_grant((device_id, user, access): (u32, String, String)) -> Result<Grant> {
+    let right = match access.as_str() {
+        RIGHT_SEND => DeviceRight::Send,
+        RIGHT_MANAGE => DeviceRight::Manage,
+        other => return Err(anyhow!("unknown right '{other}' of {user} on device {device_id}")),
+    };
+    Ok(Grant {
+        device_id: DeviceID(device_id),
+        user: user.parse()?,
+        right,
+    })
+}
+
+fn get_device(conn: &Connection, id: DeviceID) -> Result<Option<Device>> {
+    let row = conn
+        .query_row(
+            &format!("SELECT {DEVICE_COLUMNS} FROM devices WHERE id = ?1"),
+            params![id.0],
+            DeviceRow::from_row,
+        )
+        .optional()?;
+    row.map(DeviceRow::into_device).transpose()
+}
+
+/// Insert `device` and remove it from the pending devices, in case it was waiting to be claimed.
+fn add_device(conn: &Connection, device: &Device) -> Result<()> {
+    let inserted = conn.execute(
+        "INSERT OR IGNORE INTO devices (id, name, owner, show_author, firmware, last_seen_us, status)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            device.id().0,
+            device.name(),
+            device.owner().map(|owner| owner.to_string()),
+            device.show_author(),
+            device.firmware().map(|firmware| firmware.to_string()),
+            device.last_seen().map(|last_seen| last_seen.timestamp_micros()),
+            device.status().map(serde_json::to_string).transpose()?
+        ],
+    )?;
+    if inserted == 0 {
+        return Err(DbError::DeviceExists(device.id()).into());
+    }
+    conn.execute("DELETE FROM pending_devices WHERE id = ?1", params![device.id().0])?;
+    Ok(())
+}
+
+#[async_trait]
+impl Db for SqliteDb {
+    async fn get_devices(&self) -> Result<Vec<Device>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare_cached(&format!("SELECT {DEVICE_COLUMNS} FROM devices ORDER BY name, id"))?;
+        let rows = stmt
+            .query_map([], DeviceRow::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter().map(DeviceRow::into_device).collect()
+    }
+
+    async fn get_device(&self, id: DeviceID) -> Result<Option<Device>> {
+        let conn = self.conn.lock().await;
+        get_device(&conn, id)
+    }
+
+    async fn add_device(&self, device: Device) -> Result<()> {
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        add_device(&tx, &device)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    async fn rename_device(&self, id: DeviceID, name: String) -> Result<Device> {
+        let conn = self.conn.lock().await;
+        let updated = conn.execute("UPDATE devices SET name = ?2 WHERE id = ?1", params![id.0, name])?;
+        if updated == 0 {
+            return Err(DbError::DeviceNotFound(id).into());
+        }
+        get_device(&conn, id)?.ok_or_else(|| DbError::DeviceNotFound(id).into())
+    }
+
+    async fn set_show_author(&self, id: DeviceID, show_author: bool) -> Result<Device> {
+        let conn = self.conn.lock().await;
+        let updated = conn.execute(
+            "UPDATE devices SET show_author = ?2 WHERE id = ?1",
+            params![id.0, show_author],
+        )?;
+        if updated == 0 {
+            return Err(DbError::DeviceNotFound(id).into());
+        }
+        get_device(&conn, id)?.ok_or_else(|| DbError::DeviceNotFound(id).into())
+    }
+
+    async fn set_firmware(&self, id: DeviceID, firmware: FirmwareVersion) -> Result<Device> {
+        let conn = self.conn.lock().await;
+        let updated = conn.execute(
+            "UPDATE devices SET firmware = ?2 WHERE id = ?1",
+            params![id.0, firmware.to_string()],
+        )?;
+        if updated == 0 {
+            return Err(DbError::DeviceNotFound(id).into());
+        }
+        get_device(&conn, id)?.ok_or_else(|| DbError::DeviceNotFound(id).into())
+    }
+
+    async fn set_status(&self, id: DeviceID, status: DeviceStatus, seen_at: DateTime<Utc>) -> Result<Device> {
+        let conn = self.conn.lock().await;
+        let updated = conn.execute(
+            "UPDATE devices SET last_seen_us = ?2, status = ?3 WHERE id = ?1",
+            params![id.0, seen_at.timestamp_micros(), serde_json::to_string(&status)?],
+        )?;
+        if updated == 0 {
+            return Err(DbError::DeviceNotFound(id).into());
+        }
+        get_device(&conn, id)?.ok_or_else(|| DbError::DeviceNotFound(id).into())
+    }
+
+    async fn remove_device(&self, id: DeviceID) -> Result<Device> {
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        let row = tx
+            .query_row(
+                &format!("DELETE FROM devices WHERE id = ?1 RETURNING {DEVICE_COLUMNS}"),
+                params![id.0],
+                DeviceRow::from_row,
+            )
+            .optional()?
+            .ok_or(DbError::DeviceNotFound(id))?;
+        tx.execute("DELETE FROM grants WHERE device_id = ?1", params![id.0])?;
+        tx.execute("DELETE FROM recurring_messages WHERE receiver_id = ?1", params![id.0])?;
+        tx.commit()?;
+        row.into_device()
+    }
+
+    async fn get_device_grants(&self, id: DeviceID) -> Result<Vec<Grant>> {
+        let conn = self.conn.lock().await;
+        let mut stmt =
+            conn.prepare_cached("SELECT device_id, user, access FROM grants WHERE device_id = ?1 ORDER BY user")?;
+        let rows = stmt
+            .query_map(params![id.0], grant_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter().map(into_grant).collect()
+    }
+
+    async fn get_user_grants(&self, user: RawUser) -> Result<Vec<Grant>> {
+        let conn = self.conn.lock().await;
+        let mut stmt =
+            conn.prepare_cached("SELECT device_id, user, access FROM grants WHERE user = ?1 ORDER BY device_id")?;
+        let rows = stmt
+            .query_map(params![user.to_string()], grant_from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter().map(into_grant).collect()
+    }
+
+    async fn set_grant(&self, grant: Grant) -> Result<()> {
+        let conn = self.conn.lock().await;
+        if get_device(&conn, grant.device_id)?.is_none() {
+            return Err(DbError::DeviceNotFound(grant.device_id).into());
+        }
+        conn.execute(
+            "INSERT OR REPLACE INTO grants (device_id, user, access) VALUES (?1, ?2, ?3)",
+            params![grant.device_id.0, grant.user.to_string(), right_column(grant.right)],
+        )?;
+        Ok(())
+    }
+
+    async fn remove_grant(&self, id: DeviceID, user: RawUser) -> Result<bool> {
+        let conn = self.conn.lock().await;
+        let removed = conn.execute(
+            "DELETE FROM grants WHERE device_id = ?1 AND user = ?2",
+            params![id.0, user.to_string()],
+        )?;
+        Ok(removed > 0)
+    }
+
+    async fn get_device_keys(&self, id: DeviceID) -> Result<Option<DeviceKeys>> {
+        let conn = self.conn.lock().await;
+        let keys = conn
+            .query_row(
+                "SELECT secret, next_secret FROM device_keys WHERE id = ?1",
+                params![id.0],
+                |row| {
+                    Ok(DeviceKeys {
+                        secret: row.get(0)?,
+                        next_secret: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?;
+        Ok(keys)
+    }
+
+    async fn set_device_keys(&self, id: DeviceID, keys: DeviceKeys) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT OR REPLACE INTO device_keys (id, secret, next_secret) VALUES (?1, ?2, ?3)",
+            params![id.0, keys.secret, keys.next_secret],
+        )?;
+        Ok(())
+    }
+
+    async fn get_pairing_code(&self, id: DeviceID, now: DateTime<Utc>) -> Result<PairingCode> {
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM pending_devices WHERE since_us <= ?1",
+            params![(now - device::PAIRING_CODE_LIFETIME).timestamp_micros()],
+        )?;
+        let code = tx
+            .query_row("SELECT code FROM pending_devices WHERE id = ?1", params![id.0], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        if let Some(code) = code {
+            tx.commit()?;
+            return Ok(PairingCode(code));
+        }
+        let pending: usize = tx.query_row("SELECT count(*) FROM pending_devices", [], |row| row.get(0))?;
+        if pending >= device::MAX_PENDING_DEVICES {
+            // Keep the expired devices deleted, like the memory database does.
+            tx.commit()?;
+            return Err(DbError::TooManyPendingDevices.into());
+        }
+
+        // The device is not pending, so an insert can only be ignored because the code is already taken.
+        let code = device::new_pairing_code(|code| {
+            let inserted = tx.execute(
+                "INSERT OR IGNORE INTO pending_devices (id, code, since_us) VALUES (?1, ?2, ?3)",
+                params![id.0, code.0, now.timestamp_micros()],
+            )?;
+            Ok(inserted == 1)
+        })?;
+        tx.commit()?;
+        Ok(code)
+    }
+
+    async fn claim_device(
+        &self,
+        code: PairingCode,
+        name: String,
+        owner: User<Authorized>,
+        now: DateTime<Utc>,
+    ) -> Result<Device> {
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        let id: u32 = tx
+            .query_row(
+                "SELECT id FROM pending_devices WHERE code = ?1 AND since_us > ?2",
+                params![code.0, (now - device::PAIRING_CODE_LIFETIME).timestamp_micros()],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or(DbError::PairingCodeNotFound(code))?;
+        let device = Device::new(DeviceID(id), name).with_owner(Some(owner.raw()));
+        add_device(&tx, &device)?;
+        tx.commit()?;
+        Ok(device)
+    }
+
+    async fn reset_pending_device(&self, id: DeviceID) -> Result<bool> {
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        let registered = tx
+            .query_row("SELECT 1 FROM devices WHERE id = ?1", params![id.0], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if registered {
+            return Err(DbError::DeviceExists(id).into());
+        }
+        let keys = tx.execute("DELETE FROM device_keys WHERE id = ?1", params![id.0])?;
+        let pending = tx.execute("DELETE FROM pending_devices WHERE id = ?1", params![id.0])?;
+        tx.commit()?;
+        Ok(keys + pending > 0)
+    }
+
+    async fn get_message(&self, id: MessageID) -> Result<Option<Message>> {
+        let conn = self.conn.lock().await;
+        get_message(&conn, id)
+    }
+
+    async fn add_message(&self, message: InsertMessage) -> Result<MessageID> {
+        let sender_id = sender_column(message.sender_id);
+        let (kind, text, png, rgb565) = match &message.content {
+            MessageContent::Text(text) => (MESSAGE_KIND_TEXT, Some(text.text()), None, None),
+            MessageContent::Image(image) => (MESSAGE_KIND_IMAGE, None, Some(image.png()), Some(image.rgb565())),
+        };
+        let style = match &message.content {
+            MessageContent::Text(text) => text.style().map(|style| serde_json::to_string(&style)).transpose()?,
+            MessageContent::Image(_) => None,
+        };
+
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO messages (receiver_id, duration_sec, sender_id, created_at_us, kind, text, png, rgb565, author, urgent,
+                not_before_us, scheduled, style)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                message.meta.receiver_id.0,
+                message.meta.duration.num_seconds(),
+                sender_id,
+                message.created_at.timestamp_micros(),
+                kind,
+                text,
+                png,
+                rgb565,
+                message.author.map(|author| author.to_string()),
+                message.meta.urgent,
+                message.meta.not_before.map(|not_before| not_before.timestamp_micros()),
+                message.is_scheduled(),
+                style
+            ],
+        )?;
+        let id = u32::try_from(conn.last_insert_rowid()).context("message id overflow")?;
+        Ok(MessageID(id))
+    }
+
+    async fn mark_delivered(
+        &self,
+        device_id: DeviceID,
+        sequence: MessageID,
+        at: DateTime<Utc>,
+    ) -> Result<Option<Message>> {
+        let conn = self.conn.lock().await;
+        let row = conn
+            .query_row(
+                &format!(
+                    "UPDATE messages SET delivered_at_us = ?3
+                    WHERE {MESSAGE_SEQUENCE} = ?1 AND receiver_id = ?2 AND delivered_at_us IS NULL
+                    RETURNING {MESSAGE_COLUMNS}"
+                ),
+                params![sequence.0, device_id.0, at.timestamp_micros()],
+                MessageRow::from_row,
+            )
+            .optional()?;
+        row.map(MessageRow::into_message).transpose()
+    }
+
+    async fn record_failed_delivery(&self, device_id: DeviceID, sequence: MessageID) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            &format!(
+                "UPDATE messages SET failed_deliveries = failed_deliveries + 1
+                WHERE {MESSAGE_SEQUENCE} = ?1 AND receiver_id = ?2"
+            ),
+            params![sequence.0, device_id.0],
+        )?;
+        Ok(())
+    }
+
+    async fn delete_message(&self, id: MessageID) -> Result<Message> {
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        let message = get_message(&tx, id)?.ok_or(DbError::MessageNotFound(id))?;
+        tx.execute("DELETE FROM messages WHERE id = ?1", params![id.0])?;
+
+        if !message.scheduled {
+            // The counter exists since the message we just deleted was inserted.
+            let recall_id = next_message_id(&tx)?;
+            tx.execute(
+                "INSERT INTO recalls (id, message_id, receiver_id, expires_at_us) VALUES (?1, ?2, ?3, ?4)",
+                params![
+                    recall_id,
+                    message.sequence.0,
+                    message.meta.receiver_id.0,
+                    message.expires_at().timestamp_micros()
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(message)
+    }
+
+    async fn get_message_history(
+        &self,
+        filter: &MessageFilter,
+        before: Option<MessageID>,
+        limit: usize,
+    ) -> Result<Vec<Message>> {
+        let mut conditions = vec!["1"];
+        let mut values = Vec::new();
+        if let Some(before) = before {
+            conditions.push("id < ?");
+            values.push(Value::from(before.0));
+        }
+        if let Some(receiver_id) = filter.receiver_id {
+            conditions.push("receiver_id = ?");
+            values.push(Value::from(receiver_id.0));
+        }
+        if let Some(receiver_ids) = &filter.receiver_ids {
+            if receiver_ids.is_empty() {
+                conditions.push("0");
+            } else {
+                conditions.push("receiver_id IN (SELECT value FROM json_each(?))");
+                let ids: Vec<_> = receiver_ids.iter().map(|id| id.0).collect();
+                values.push(Value::from(serde_json::to_string(&ids)?));
+            }
+        }
+        if let Some(sender_id) = filter.sender_id {
+            conditions.push("sender_id = ?");
+            values.push(Value::from(sender_column(sender_id).to_string()));
+        }
+        if let Some(from) = filter.from {
+            conditions.push("created_at_us >= ?");
+            values.push(Value::from(from.timestamp_micros()));
+        }
+        if let Some(to) = filter.to {
+            conditions.push("created_at_us < ?");
+            values.push(Value::from(to.timestamp_micros()));
+        }
+        if let Some(kind) = filter.kind {
+            let kind = match kind {
+                MessageKind::Text => MESSAGE_KIND_TEXT,
+                MessageKind::Image => MESSAGE_KIND_IMAGE,
+            };
+            conditions.push("kind = ?");
+            values.push(Value::from(kind.to_string()));
+        }
+        if let Some(text) = &filter.text {
+            // SQLite's lower() only folds ASCII characters, just like MessageFilter::matches.
+            conditions.push("instr(lower(text), lower(?)) > 0");
+            values.push(Value::from(text.clone()));
+        }
+        values.push(Value::from(i64::try_from(limit).unwrap_or(i64::MAX)));
+
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {MESSAGE_COLUMNS} FROM messages WHERE {} ORDER BY id DESC LIMIT ?",
+            conditions.join(" AND ")
+        ))?;
+        let rows = stmt
+            .query_map(params_from_iter(values), MessageRow::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter().map(MessageRow::into_message).collect()
+    }
+
+    async fn get_active_messages_by(&self, author: RawUser, now: DateTime<Utc>) -> Result<Vec<Message>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {MESSAGE_COLUMNS} FROM messages
+            WHERE author = ?1 AND NOT scheduled AND {MESSAGE_EXPIRES_AT_US} > ?2
+            ORDER BY id DESC"
+        ))?;
+        let rows = stmt
+            .query_map(
+                params![author.to_string(), now.timestamp_micros()],
+                MessageRow::from_row,
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter().map(MessageRow::into_message).collect()
+    }
+
+    async fn get_scheduled_messages(&self) -> Result<Vec<Message>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {MESSAGE_COLUMNS} FROM messages WHERE scheduled ORDER BY {MESSAGE_SHOWN_FROM_US}, id"
+        ))?;
+        let rows = stmt
+            .query_map([], MessageRow::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter().map(MessageRow::into_message).collect()
+    }
+
+    async fn release_due_messages(&self, now: DateTime<Utc>) -> Result<Vec<Message>> {
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        // Messages that were due first get the lower sequences, so that devices get them first.
+        let due: Vec<u32> = tx
+            .prepare_cached(&format!(
+                "SELECT id FROM messages WHERE scheduled AND {MESSAGE_SHOWN_FROM_US} <= ?1
+                ORDER BY {MESSAGE_SHOWN_FROM_US}, id"
+            ))?
+            .query_map(params![now.timestamp_micros()], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let mut released = Vec::with_capacity(due.len());
+        for id in due {
+            let sequence = next_message_id(&tx)?;
+            tx.execute(
+                "UPDATE messages SET sequence = ?2, scheduled = 0 WHERE id = ?1",
+                params![id, sequence],
+            )?;
+            released.push(get_message(&tx, MessageID(id))?.ok_or(DbError::MessageNotFound(MessageID(id)))?);
+        }
+        tx.commit()?;
+        Ok(released)
+    }
+
+    async fn get_next_recall(
+        &self,
+        receiver_id: DeviceID,
+        after: Option<MessageID>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Recall>> {
+        let conn = self.conn.lock().await;
+        let row = conn
+            .query_row(
+                "SELECT id, message_id, receiver_id, expires_at_us FROM recalls
+                WHERE receiver_id = ?1 AND (?2 IS NULL OR id > ?2) AND expires_at_us > ?3
+                ORDER BY id LIMIT 1",
+                params![receiver_id.0, after.map(|id| id.0), now.timestamp_micros()],
+                recall_from_row,
+            )
+            .optional()?;
+        row.map(into_recall).transpose()
+    }
+
+    async fn get_next_message(
+        &self,
+        receiver_id: DeviceID,
+        after: Option<MessageID>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Message>> {
+        let conn = self.conn.lock().await;
+        // Sequences come from the AUTOINCREMENT counter, so they are increasing and never reused and work as a cursor.
+        let row = conn
+            .query_row(
+                &format!(
+                    "SELECT {MESSAGE_COLUMNS} FROM messages
+                    WHERE receiver_id = ?1 AND (?2 IS NULL OR {MESSAGE_SEQUENCE} > ?2) AND NOT scheduled
+                        AND {MESSAGE_EXPIRES_AT_US} > ?3
+                    ORDER BY {MESSAGE_SEQUENCE} LIMIT 1"
+                ),
+                params![receiver_id.0, after.map(|id| id.0), now.timestamp_micros()],
+                MessageRow::from_row,
+            )
+            .optional()?;
+        row.map(MessageRow::into_message).transpose()
+    }
+
+    async fn delete_expired_messages(&self, expired_before: DateTime<Utc>) -> Result<usize> {
+        let conn = self.conn.lock().await;
+        let deleted = conn.execute(
+            &format!("DELETE FROM messages WHERE {MESSAGE_EXPIRES_AT_US} < ?1"),
+            params![expired_before.timestamp_micros()],
+        )?;
+        conn.execute(
+            "DELETE FROM recalls WHERE expires_at_us < ?1",
+            params![expired_before.timestamp_micros()],
+        )?;
+        Ok(deleted)
+    }
+
+    async fn get_recurring_message(&self, id: RecurringID) -> Result<Option<RecurringMessage>> {
+        let conn = self.conn.lock().await;
+        let row = conn
+            .query_row(
+                &format!("SELECT {RECURRING_COLUMNS} FROM recurring_messages WHERE id = ?1"),
+                params![id.0],
+                RecurringRow::from_row,
+            )
+            .optional()?;
+        row.map(RecurringRow::into_recurring_message).transpose()
+    }
+
+    async fn get_recurring_messages(&self) -> Result<Vec<RecurringMessage>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare_cached(&format!(
+            "SELECT {RECURRING_COLUMNS} FROM recurring_messages ORDER BY next_at_us, id"
+        ))?;
+        let rows = stmt
+            .query_map([], RecurringRow::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows.into_iter().map(RecurringRow::into_recurring_message).collect()
+    }
+
+    async fn add_recurring_message(&self, message: InsertRecurringMessage) -> Result<RecurringID> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO recurring_messages (receiver_id, text, recurrence, duration_sec, urgent, sender_id, author,
+                next_at_us)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                message.receiver_id.0,
+                message.text,
+                serde_json::to_string(&message.recurrence)?,
+                message.duration.num_seconds(),
+                message.urgent,
+                sender_column(message.sender_id),
+                message.author.map(|author| author.to_string()),
+                message.next_at.timestamp_micros()
+            ],
+        )?;
+        let id = u32::try_from(conn.last_insert_rowid()).context("recurring message id overflow")?;
+        Ok(RecurringID(id))
+    }
+
+    async fn delete_recurring_message(&self, id: RecurringID) -> Result<RecurringMessage> {
+        let conn = self.conn.lock().await;
+        let row = conn
+            .query_row(
+                &format!("DELETE FROM recurring_messages WHERE id = ?1 RETURNING {RECURRING_COLUMNS}"),
+                params![id.0],
+                RecurringRow::from_row,
+            )
+            .optional()?
+            .ok_or(DbError::RecurringMessageNotFound(id))?;
+        row.into_recurring_message()
+    }
+
+    async fn set_recurring_next_at(&self, id: RecurringID, next_at: DateTime<Utc>) -> Result<()> {
+        let conn = self.conn.lock().await;
+        let updated = conn.execute(
+            "UPDATE recurring_messages SET next_at_us = ?2 WHERE id = ?1",
+            params![id.0, next_at.timestamp_micros()],
+        )?;
+        if updated == 0 {
+            return Err(DbError::RecurringMessageNotFound(id).into());
+        }
+        Ok(())
+    }
+
+    async fn is_user_authorized(&self, user: RawUser) -> Result<Option<User<Authorized>>> {
+        let conn = self.conn.lock().await;
+        let authorized = conn
+            .query_row(
+                "SELECT 1 FROM authorized_users WHERE user = ?1",
+                params![user.to_string()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(authorized.map(|()| User::new(user).authorize()))
+    }
+
+    async fn add_authorized_user(&self, user: User<Authorized>) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT OR IGNORE INTO authorized_users (user) VALUES (?1)",
+            params![user.raw().to_string()],
+        )?;
+        Ok(())
+    }
+
+    async fn get_authorized_users(&self) -> Result<Vec<User<Authorized>>> {
+        let conn = self.conn.lock().await;
+        let mut stmt = conn.prepare_cached("SELECT user FROM authorized_users ORDER BY user")?;
+        let users = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        users
+            .into_iter()
+            .map(|user| Ok(User::new(user.parse()?).authorize()))
+            .collect()
+    }
+
+    async fn get_user_name(&self, user: RawUser) -> Result<Option<String>> {
+        let conn = self.conn.lock().await;
+        let name = conn
+            .query_row(
+                "SELECT name FROM user_names WHERE user = ?1",
+                params![user.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(name)
+    }
+
+    async fn set_user_name(&self, user: RawUser, name: String) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT OR REPLACE INTO user_names (user, name) VALUES (?1, ?2)",
+            params![user.to_string(), name],
+        )?;
+        Ok(())
+    }
+
+    async fn add_api_token(&self, token: Uuid, user: User<Authorized>) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO api_tokens (token, user) VALUES (?1, ?2)",
+            params![token.as_bytes(), user.raw().to_string()],
+        )?;
+        Ok(())
+    }
+
+    async fn get_api_token_user(&self, token: Uuid) -> Result<Option<User<Authorized>>> {
+        let conn = self.conn.lock().await;
+        let user: Option<String> = conn
+            .query_row(
+                "SELECT api_tokens.user FROM api_tokens
+                JOIN authorized_users ON authorized_users.user = api_tokens.user
+                WHERE api_tokens.token = ?1",
+                params![token.as_bytes()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let user = user.map(|user| user.parse::<RawUser>()).transpose()?;
+        Ok(user.map(|user| User::new(user).authorize()))
+    }
+
+    async fn get_telegram_admin_id(&self) -> teloxide::types::UserId {
+        self.telegram_admin_id
+    }
+
+    async fn get_auth_request(&self, id: Uuid) -> Result<Option<AuthRequest>> {
+        let conn = self.conn.lock().await;
+        let auth_request = conn
+            .query_row(
+                "SELECT user_id, user_name FROM auth_requests WHERE id = ?1",
+                params![id.as_bytes()],
+                |row| {
+                    Ok(AuthRequest::from_raw(
+                        id,
+                        teloxide::types::UserId(row.get::<_, i64>(0)? as u64),
+                        row.get(1)?,
+                    ))
+                },
+            )
+            .optional()?;
+        Ok(auth_request)
+    }
+
+    async fn add_auth_request(&self, auth_request: AuthRequest) -> Result<()> {
+        let conn = self.conn.lock().await;
+        conn.execute(
+            "INSERT INTO auth_requests (id, user_id, user_name) VALUES (?1, ?2, ?3)",
+            params![
+                auth_request.id().as_bytes(),
+                auth_request.user_id().0 as i64,
+                auth_request.user_name()
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        // Every change is committed immediately.
+        Ok(())
+    }
+}
@@ -0,0 +1,313 @@
+//! The SQLite specifics behind the [`Db`] interface: migrations, message IDs and the SQL built for queries.
+
+use chrono::Duration;
+use teloxide::types::UserId;
+
+use super::*;
+
+const DEVICE_ID: DeviceID = DeviceID(0x1234);
+const OTHER_DEVICE_ID: DeviceID = DeviceID(0x5678);
+const ADMIN_ID: UserId = UserId(1);
+
+async fn open() -> SqliteDb {
+    let db = SqliteDb::open(":memory:", ADMIN_ID).unwrap();
+    db.add_device(Device::new(DEVICE_ID, "Test device".to_string()))
+        .await
+        .unwrap();
+    db
+}
+
+fn schema_version(conn: &Connection) -> usize {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap()
+}
+
+fn text_message(receiver_id: DeviceID, text: &str, created_at: DateTime<Utc>) -> InsertMessage {
+    let meta = MessageMeta {
+        receiver_id,
+        duration: Duration::days(1),
+        urgent: false,
+        not_before: None,
+    };
+    InsertMessage::new(meta, SenderID::Web, created_at, MessageContent::new_text(text).unwrap())
+}
+
+fn text_of(message: &Message) -> &str {
+    match &message.content {
+        MessageContent::Text(text) => text.text(),
+        MessageContent::Image(_) => panic!("message {} is an image", message.id),
+    }
+}
+
+fn ids(messages: &[Message]) -> Vec<MessageID> {
+    messages.iter().map(|message| message.id).collect()
+}
+
+fn user(id: u64) -> RawUser {
+    User::new_telegram(UserId(id)).raw()
+}
+
+#[tokio::test]
+async fn migrations_keep_the_data_of_the_first_schema() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.execute_batch(MIGRATIONS[0]).unwrap();
+    conn.pragma_update(None, "user_version", 1).unwrap();
+    conn.execute(
+        "INSERT INTO devices (id, name) VALUES (?1, 'Old device')",
+        params![DEVICE_ID.0],
+    )
+    .unwrap();
+    conn.execute(
+        "INSERT INTO messages (receiver_id, duration_sec, sender_id, created_at_us, kind, text)
+        VALUES (?1, 60, 'telegram', 0, 'text', 'Hello')",
+        params![DEVICE_ID.0],
+    )
+    .unwrap();
+
+    migrate(&mut conn).unwrap();
+    assert_eq!(schema_version(&conn), MIGRATIONS.len());
+
+    let db = SqliteDb {
+        conn: Mutex::new(conn),
+        telegram_admin_id: ADMIN_ID,
+    };
+    let device = db.get_device(DEVICE_ID).await.unwrap().unwrap();
+    assert_eq!(device.name(), "Old device");
+    assert_eq!(device.owner(), None);
+    assert!(!device.show_author());
+
+    let message = db.get_message(MessageID(1)).await.unwrap().unwrap();
+    assert_eq!(text_of(&message), "Hello");
+    assert_eq!(message.sender_id, SenderID::Telegram);
+    assert!(!message.meta.urgent && !message.scheduled);
+    assert_eq!(message.delivery.failed_attempts, 0);
+    let MessageContent::Text(text) = &message.content else {
+        unreachable!()
+    };
+    assert_eq!(text.style(), None);
+}
+
+#[test]
+fn migrating_twice_changes_nothing() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    migrate(&mut conn).unwrap();
+    migrate(&mut conn).unwrap();
+    assert_eq!(schema_version(&conn), MIGRATIONS.len());
+}
+
+#[test]
+fn migrating_a_newer_schema_fails() {
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1).unwrap();
+    assert!(migrate(&mut conn).is_err());
+}
+
+#[tokio::test]
+async fn recalls_and_messages_share_one_increasing_id_sequence() {
+    let db = open().await;
+    let now = Utc::now();
+    let first = db.add_message(text_message(DEVICE_ID, "first", now)).await.unwrap();
+    let second = db.add_message(text_message(DEVICE_ID, "second", now)).await.unwrap();
+    assert!(first < second);
+
+    db.delete_message(second).await.unwrap();
+    let recall = db.get_next_recall(DEVICE_ID, None, now).await.unwrap().unwrap();
+    assert_eq!(recall.message_id, second);
+    assert!(recall.id > second);
+
+    // The ID of the deleted message is not reused, and the next message comes after the recall.
+    let third = db.add_message(text_message(DEVICE_ID, "third", now)).await.unwrap();
+    assert!(third > recall.id);
+    assert!(db
+        .get_next_recall(DEVICE_ID, Some(recall.id), now)
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn released_messages_keep_their_ids_and_get_sequences_in_the_order_they_are_due() {
+    let db = open().await;
+    let now = Utc::now();
+    let mut later = text_message(DEVICE_ID, "later", now);
+    later.meta.not_before = Some(now + Duration::hours(2));
+    let later = db.add_message(later).await.unwrap();
+    let mut sooner = text_message(DEVICE_ID, "sooner", now);
+    sooner.meta.not_before = Some(now + Duration::hours(1));
+    let sooner = db.add_message(sooner).await.unwrap();
+    let current = db.add_message(text_message(DEVICE_ID, "now", now)).await.unwrap();
+
+    let next = db.get_next_message(DEVICE_ID, None, now).await.unwrap().unwrap();
+    assert_eq!(next.id, current);
+    assert_eq!(ids(&db.get_scheduled_messages().await.unwrap()), [sooner, later]);
+    assert!(db.release_due_messages(now).await.unwrap().is_empty());
+
+    let released = db.release_due_messages(now + Duration::hours(3)).await.unwrap();
+    assert_eq!(released.iter().map(text_of).collect::<Vec<_>>(), ["sooner", "later"]);
+    assert_eq!(ids(&released), [sooner, later]);
+    assert!(released[0].sequence > current && released[1].sequence > released[0].sequence);
+    assert!(released.iter().all(|message| !message.scheduled));
+    assert!(!db.get_message(sooner).await.unwrap().unwrap().scheduled);
+    assert!(db.get_scheduled_messages().await.unwrap().is_empty());
+
+    // Devices that already have the current message get the released ones next.
+    let next = db
+        .get_next_message(DEVICE_ID, Some(current), now + Duration::hours(3))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(next.id, sooner);
+    let next = db
+        .get_next_message(DEVICE_ID, Some(next.sequence), now + Duration::hours(3))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(next.id, later);
+}
+
+#[tokio::test]
+async fn deleting_a_scheduled_message_does_not_recall_it() {
+    let db = open().await;
+    let now = Utc::now();
+    let mut scheduled = text_message(DEVICE_ID, "later", now);
+    scheduled.meta.not_before = Some(now + Duration::hours(1));
+    let scheduled = db.add_message(scheduled).await.unwrap();
+
+    db.delete_message(scheduled).await.unwrap();
+    assert!(db.get_next_recall(DEVICE_ID, None, now).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn history_pages_backwards_from_the_cursor() {
+    let db = open().await;
+    db.add_device(Device::new(OTHER_DEVICE_ID, "Other device".to_string()))
+        .await
+        .unwrap();
+    let now = Utc::now();
+    let mut messages = Vec::new();
+    for i in 0..5 {
+        let receiver_id = if i % 2 == 0 { DEVICE_ID } else { OTHER_DEVICE_ID };
+        let message = text_message(receiver_id, &format!("Message {i}"), now + Duration::seconds(i));
+        messages.push(db.add_message(message).await.unwrap());
+    }
+
+    let all = MessageFilter::default();
+    let page = db.get_message_history(&all, None, 2).await.unwrap();
+    assert_eq!(ids(&page), [messages[4], messages[3]]);
+    let page = db.get_message_history(&all, Some(messages[3]), 2).await.unwrap();
+    assert_eq!(ids(&page), [messages[2], messages[1]]);
+    let page = db.get_message_history(&all, Some(messages[1]), 2).await.unwrap();
+    assert_eq!(ids(&page), [messages[0]]);
+
+    let to_device = MessageFilter {
+        receiver_id: Some(DEVICE_ID),
+        ..Default::default()
+    };
+    let page = db.get_message_history(&to_device, Some(messages[4]), 10).await.unwrap();
+    assert_eq!(ids(&page), [messages[2], messages[0]]);
+
+    let no_devices = MessageFilter {
+        receiver_ids: Some(Vec::new()),
+        ..Default::default()
+    };
+    assert!(db.get_message_history(&no_devices, None, 10).await.unwrap().is_empty());
+
+    let by_text_and_time = MessageFilter {
+        receiver_ids: Some(vec![DEVICE_ID, OTHER_DEVICE_ID]),
+        from: Some(now + Duration::seconds(1)),
+        to: Some(now + Duration::seconds(4)),
+        text: Some("MESSAGE".to_string()),
+        ..Default::default()
+    };
+    let page = db.get_message_history(&by_text_and_time, None, 10).await.unwrap();
+    assert_eq!(ids(&page), [messages[3], messages[2], messages[1]]);
+}
+
+#[tokio::test]
+async fn grants_are_replaced_and_removed_with_their_device() {
+    let db = open().await;
+    let grant = |user, right| Grant {
+        device_id: DEVICE_ID,
+        user,
+        right,
+    };
+
+    let unknown_device = Grant {
+        device_id: OTHER_DEVICE_ID,
+        ..grant(user(2), DeviceRight::Send)
+    };
+    let error = db.set_grant(unknown_device).await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<DbError>(),
+        Some(DbError::DeviceNotFound(_))
+    ));
+
+    db.set_grant(grant(user(2), DeviceRight::Send)).await.unwrap();
+    db.set_grant(grant(user(3), DeviceRight::Send)).await.unwrap();
+    db.set_grant(grant(user(2), DeviceRight::Manage)).await.unwrap();
+    assert_eq!(
+        db.get_device_grants(DEVICE_ID).await.unwrap(),
+        [grant(user(2), DeviceRight::Manage), grant(user(3), DeviceRight::Send)]
+    );
+    assert_eq!(
+        db.get_user_grants(user(3)).await.unwrap(),
+        [grant(user(3), DeviceRight::Send)]
+    );
+
+    assert!(db.remove_grant(DEVICE_ID, user(3)).await.unwrap());
+    assert!(!db.remove_grant(DEVICE_ID, user(3)).await.unwrap());
+    assert!(db.get_user_grants(user(3)).await.unwrap().is_empty());
+
+    db.remove_device(DEVICE_ID).await.unwrap();
+    assert!(db.get_user_grants(user(2)).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn pairing_codes_expire_and_are_replaced() {
+    let db = open().await;
+    let pending_id = DeviceID(0x5678);
+    let now = Utc::now();
+    let code = db.get_pairing_code(pending_id, now).await.unwrap();
+    assert_eq!(
+        db.get_pairing_code(pending_id, now + Duration::minutes(1))
+            .await
+            .unwrap(),
+        code
+    );
+
+    let expired = now + device::PAIRING_CODE_LIFETIME;
+    let owner = User::new_telegram(UserId(2)).authorize();
+    let error = db
+        .claim_device(code, "Kitchen".to_string(), owner, expired)
+        .await
+        .unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<DbError>(),
+        Some(DbError::PairingCodeNotFound(_))
+    ));
+
+    let new_code = db.get_pairing_code(pending_id, expired).await.unwrap();
+    let device = db
+        .claim_device(new_code, "Kitchen".to_string(), owner, expired)
+        .await
+        .unwrap();
+    assert_eq!(device.owner(), Some(owner.raw()));
+}
+
+#[tokio::test]
+async fn pending_devices_are_limited() {
+    let db = open().await;
+    let now = Utc::now();
+    for id in 0..device::MAX_PENDING_DEVICES {
+        db.get_pairing_code(DeviceID(0x10000 + id as u32), now).await.unwrap();
+    }
+    let error = db.get_pairing_code(OTHER_DEVICE_ID, now).await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<DbError>(),
+        Some(DbError::TooManyPendingDevices)
+    ));
+    // Devices that already wait keep their code, and there is room again once the codes expired.
+    db.get_pairing_code(DeviceID(0x10000), now).await.unwrap();
+    db.get_pairing_code(OTHER_DEVICE_ID, now + device::PAIRING_CODE_LIFETIME)
+        .await
+        .unwrap();
+}
@@ -1,5 +1,6 @@
-use std::marker::PhantomData;
+use std::{fmt, marker::PhantomData, str::FromStr};
 
+use anyhow::anyhow;
 use serde::{Deserialize, Serialize};
 
 /// Trait to classify authentication states.
@@ -29,6 +30,13 @@ impl<T: Auth> User<T> {
 }
 
 impl User<Unauthorized> {
+    pub fn new(raw: RawUser) -> Self {
+        Self {
+            _auth: PhantomData,
+            raw,
+        }
+    }
+
     pub fn new_telegram(id: teloxide::types::UserId) -> Self {
         Self {
             _auth: PhantomData,
@@ -48,3 +56,25 @@ impl User<Unauthorized> {
 pub(crate) enum RawUser {
     Telegram { id: teloxide::types::UserId },
 }
+
+/// Textual representation of a [`RawUser`], e.g. to use it as a key in a database.
+impl fmt::Display for RawUser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RawUser::Telegram { id } => write!(f, "telegram:{id}"),
+        }
+    }
+}
+
+impl FromStr for RawUser {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("telegram", id)) => Ok(RawUser::Telegram {
+                id: teloxide::types::UserId(id.parse()?),
+            }),
+            _ => Err(anyhow!("malformed user '{s}'")),
+        }
+    }
+}
@@ -3,6 +3,8 @@ use std::fmt;
 use anyhow::anyhow;
 use axum::{http::StatusCode, response::IntoResponse};
 
+use crate::db::DbError;
+
 #[derive(Debug)]
 pub struct WebError {
     code: StatusCode,
@@ -27,6 +29,20 @@ impl WebError {
             error: anyhow!("{}", msg),
         }
     }
+
+    pub fn unauthorized() -> Self {
+        Self {
+            code: StatusCode::UNAUTHORIZED,
+            error: anyhow!("Missing or invalid API token. Use the /token command of the Telegram bot to get one."),
+        }
+    }
+
+    pub fn forbidden() -> Self {
+        Self {
+            code: StatusCode::FORBIDDEN,
+            error: anyhow!("You are not allowed to do this."),
+        }
+    }
 }
 
 impl fmt::Display for WebError {
@@ -46,9 +62,23 @@ pub type WebResult<T> = std::result::Result<T, WebError>;
 
 impl From<anyhow::Error> for WebError {
     fn from(error: anyhow::Error) -> Self {
-        Self {
-            code: StatusCode::INTERNAL_SERVER_ERROR,
-            error,
-        }
+        let code = match error.downcast_ref::<DbError>() {
+            Some(DbError::DeviceExists(_)) => StatusCode::CONFLICT,
+            Some(
+                DbError::DeviceNotFound(_)
+                | DbError::PairingCodeNotFound(_)
+                | DbError::MessageNotFound(_)
+                | DbError::RecurringMessageNotFound(_),
+            ) => StatusCode::NOT_FOUND,
+            Some(DbError::TooManyPendingDevices) => StatusCode::SERVICE_UNAVAILABLE,
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        Self { code, error }
+    }
+}
+
+impl From<DbError> for WebError {
+    fn from(error: DbError) -> Self {
+        anyhow::Error::from(error).into()
     }
 }
@@ -1 +1 @@
-mod uf2;
+pub mod uf2;
@@ -4,7 +4,11 @@ use axum::{
     response::IntoResponse,
     Form,
 };
-use common::consts::{WIFI_PW_LEN, WIFI_SSID_LEN};
+use common::{
+    consts::{WIFI_PW_LEN, WIFI_SSID_LEN},
+    protocols::pico::DeviceSecret,
+    types::DeviceID,
+};
 use serde::Deserialize;
 
 use crate::error::WebResult;
@@ -40,13 +44,37 @@ fn gen_block(address: u32, block_id: u32, data: &[u8]) -> Vec<u8> {
 
     assert!(result.len() == 512);
 
-    return result;
+    result
+}
+
+/// A UF2 file that writes `data` to the 4K flash section at `base_address` and zeroes the rest of it.
+fn gen_file(base_address: u32, data: &[u8]) -> Vec<u8> {
+    assert!(data.len() == 256);
+    let mut file = Vec::with_capacity(16 * 512);
+    file.append(&mut gen_block(base_address, 0, data));
+
+    for i in 1..16 {
+        file.append(&mut gen_block(base_address + 256 * i, i, &[0u8; 256]));
+    }
+    file
+}
+
+/// A UF2 file with the ID and secret of a device, laid out like the `.device_info` section in `pico/memory.x`.
+pub fn device_info_uf2(id: DeviceID, secret: &DeviceSecret) -> Vec<u8> {
+    const DEVICE_INFO_BASE_ADDRESS: u32 = 0x10ffe000;
+
+    let mut device_data = Vec::with_capacity(256);
+    device_data.extend_from_slice(&id.0.to_le_bytes());
+    device_data.extend_from_slice(secret);
+    device_data.resize(256, 0);
+
+    gen_file(DEVICE_INFO_BASE_ADDRESS, &device_data)
 }
 
 pub async fn submit_wifi_config(Form(data): Form<WifiData>) -> WebResult<impl IntoResponse> {
     println!("ssid: {}\npw: {}", data.wifissid, data.wifipw);
     // Compare >= X_LEN because we are saving null-terminated strings, so the data must be stricly smaller.
-    if data.wifissid.as_bytes().len() >= WIFI_SSID_LEN || data.wifipw.as_bytes().len() >= WIFI_PW_LEN {
+    if data.wifissid.len() >= WIFI_SSID_LEN || data.wifipw.len() >= WIFI_PW_LEN {
         return Err(anyhow!("Wifi password or SSID are too long.").into());
     }
 
@@ -61,12 +89,7 @@ pub async fn submit_wifi_config(Form(data): Form<WifiData>) -> WebResult<impl In
     wifi_data.extend_from_slice(&vec![0u8; 32 - pw.len()]);
     wifi_data.extend_from_slice(&[0u8; 256 - 64]);
 
-    let mut file = Vec::with_capacity(16 * 512);
-    file.append(&mut gen_block(WIFI_BASE_ADDRESS, 0, &wifi_data[..]));
-
-    for i in 1..16 {
-        file.append(&mut gen_block(WIFI_BASE_ADDRESS + 256 * i, i, &[0u8; 256]));
-    }
+    let file = gen_file(WIFI_BASE_ADDRESS, &wifi_data);
 
     let mut headers = HeaderMap::new();
     headers.insert(
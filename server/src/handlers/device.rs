@@ -1,19 +1,154 @@
+//! The server that devices connect to on port 1338 to fetch their messages.
+//!
+//! Devices authenticate with a secret of their own. A device that the server does not know yet enrolls its secret on
+//! its first connection, which is trust on first use: the secret is sent in plain text and whoever enrolls a device ID
+//! first gets it. The port must therefore only be reachable from a trusted network.
+
 use std::{
+    borrow::Cow,
+    collections::HashMap,
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    sync::Arc,
+    sync::{Arc, LazyLock, Mutex, Weak},
+    time::Duration,
 };
 
-use common::protocols::pico::{serialization::Transmission, ClientCommand, RequestUpdateResult, Update, UpdateKind};
+use chrono::{DateTime, Utc};
+use common::{
+    consts::{MAX_PAGES, PAGE_SEPARATOR},
+    protocols::pico::{
+        self,
+        serialization::{AbstractSocket, Transmission},
+        AuthChallenge, AuthMac, AuthNonce, AuthResult, Capabilities, ClientCommand, DeviceSecret, Hello, HelloResult,
+        RequestUpdateResult, Update, UpdateKind, DEVICE_SECRET_LEN, KEEP_ALIVE_INTERVAL_SEC, PROTOCOL_VERSION,
+    },
+    rle,
+    types::{DeviceID, FirmwareVersion, MessageID, TextLength},
+};
 use tokio::{
-    io::AsyncWriteExt,
-    net::{TcpListener, TcpStream},
+    net::TcpListener,
+    sync::{mpsc, Notify},
+    time::{self, Instant},
 };
 
-use crate::db::{message::MessageContent, Db};
+use crate::{
+    db::{
+        device::{new_device_secret, Device, DeviceKeys},
+        message::{self, Message, MessageContent, Recall},
+        Db, DbError,
+    },
+    error::Result,
+    handlers::configure::uf2,
+};
+
+#[cfg(test)]
+mod tests;
 
 const ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 1338);
+/// The oldest protocol version that the server still speaks.
+/// Devices before version 4 use a different framing, so we cannot even read their hello.
+/// Devices of version 4 do not know the urgent flag of updates.
+const MIN_PROTOCOL_VERSION: u16 = 5;
+/// The capabilities that the server makes use of, if the device supports them.
+const SERVER_CAPABILITIES: Capabilities = Capabilities::FOOTER
+    .union(Capabilities::IMAGE_COMPRESSION)
+    .union(Capabilities::PAGING)
+    .union(Capabilities::PRIORITY)
+    .union(Capabilities::DELIVERY_ACK)
+    .union(Capabilities::PUSH)
+    .union(Capabilities::STATUS)
+    .union(Capabilities::STYLED_TEXT);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(KEEP_ALIVE_INTERVAL_SEC);
+/// How many devices one address may enroll within [`ENROLLMENT_WINDOW`]. A device enrolls only once, so an address
+/// that enrolls more is likely taking device IDs that are not its own, or filling up the pending devices.
+const MAX_ENROLLMENTS_PER_PEER: usize = 5;
+const ENROLLMENT_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// The newest firmware version, read from the `LATEST_FIRMWARE_VERSION` environment variable.
+static LATEST_FIRMWARE: LazyLock<Option<FirmwareVersion>> = LazyLock::new(|| {
+    let version = std::env::var("LATEST_FIRMWARE_VERSION").ok()?;
+    match version.parse() {
+        Ok(version) => Some(version),
+        Err(e) => {
+            log::error!("LATEST_FIRMWARE_VERSION '{version}' is invalid: {e}");
+            None
+        }
+    }
+});
+
+/// When each address enrolled devices within the last [`ENROLLMENT_WINDOW`].
+static ENROLLMENTS: LazyLock<Mutex<HashMap<IpAddr, Vec<Instant>>>> = LazyLock::new(Default::default);
+
+/// The connections that wait for updates, by device. Entries of devices without such a connection are dropped lazily.
+static WAITING_DEVICES: LazyLock<Mutex<HashMap<DeviceID, Weak<Notify>>>> = LazyLock::new(Default::default);
+
+/// Push new updates to the device if it is waiting for them.
+/// Must be called whenever a message or recall for the device was added.
+pub fn wake(device_id: DeviceID) {
+    let waiting = WAITING_DEVICES.lock().unwrap();
+    if let Some(notify) = waiting.get(&device_id).and_then(Weak::upgrade) {
+        notify.notify_waiters();
+    }
+}
+
+/// Whether the device currently keeps a connection open to wait for updates.
+pub fn is_connected(device_id: DeviceID) -> bool {
+    let waiting = WAITING_DEVICES.lock().unwrap();
+    waiting.get(&device_id).is_some_and(|notify| notify.strong_count() > 0)
+}
+
+/// The notification of new updates for the device, shared by all of its waiting connections.
+fn subscribe(device_id: DeviceID) -> Arc<Notify> {
+    let mut waiting = WAITING_DEVICES.lock().unwrap();
+    waiting.retain(|_, notify| notify.strong_count() > 0);
+    if let Some(notify) = waiting.get(&device_id).and_then(Weak::upgrade) {
+        return notify;
+    }
+    let notify = Arc::new(Notify::new());
+    waiting.insert(device_id, Arc::downgrade(&notify));
+    notify
+}
+
+/// Count an enrollment from `peer` at `now`. Returns false if the address already enrolled
+/// [`MAX_ENROLLMENTS_PER_PEER`] devices within [`ENROLLMENT_WINDOW`].
+fn count_enrollment(peer: IpAddr, now: Instant) -> bool {
+    let mut enrollments = ENROLLMENTS.lock().unwrap();
+    enrollments.retain(|_, times| {
+        times.retain(|time| now.duration_since(*time) < ENROLLMENT_WINDOW);
+        !times.is_empty()
+    });
+    let times = enrollments.entry(peer).or_default();
+    if times.len() >= MAX_ENROLLMENTS_PER_PEER {
+        return false;
+    }
+    times.push(now);
+    true
+}
 
-pub async fn run(messages: Arc<dyn Db>) {
+/// Whether `device` runs an older firmware than the latest one.
+/// Devices that did not report their firmware yet are not flagged.
+pub fn is_outdated(device: &Device) -> bool {
+    matches!((device.firmware(), *LATEST_FIRMWARE), (Some(firmware), Some(latest)) if firmware < latest)
+}
+
+/// Create a new secret for `device` and return the UF2 file that flashes it onto the device.
+/// The device may still authenticate with its old secret until it connected with the new one.
+pub async fn rotate_key(db: &dyn Db, device: &Device) -> Result<Vec<u8>> {
+    let secret = new_device_secret();
+    let keys = match db.get_device_keys(device.id()).await? {
+        Some(keys) => DeviceKeys {
+            next_secret: Some(secret),
+            ..keys
+        },
+        // The device never enrolled, so from now on only the flashed secret is accepted.
+        None => DeviceKeys::new(secret),
+    };
+    db.set_device_keys(device.id(), keys).await?;
+    log::info!("Created a new secret for device {device}.");
+    Ok(uf2::device_info_uf2(device.id(), &secret))
+}
+
+/// `delivered` receives each message when its device acknowledges it for the first time.
+pub async fn run(messages: Arc<dyn Db>, delivered: mpsc::UnboundedSender<Message>) {
     log::info!("Listening for TCP connections from device at {ADDRESS}.");
     let listener = TcpListener::bind(ADDRESS).await.unwrap();
 
@@ -25,8 +160,9 @@ pub async fn run(messages: Arc<dyn Db>) {
                 // a.d. TODO collect join handles and clean up?
                 tokio::spawn({
                     let messages = messages.clone();
+                    let delivered = delivered.clone();
                     // note: need async move block so that messages is not dropped too early. the block will own the messages object.
-                    async move { handle_client(socket, &*messages).await }
+                    async move { handle_client(socket, addr, &*messages, &delivered).await }
                 });
             }
             Err(e) => log::error!("couldn't get client: {e:?}"),
@@ -34,41 +170,557 @@ pub async fn run(messages: Arc<dyn Db>) {
     }
 }
 
+enum NextUpdate {
+    Message(Message),
+    Recall(Recall),
+}
+
+/// The next message or recall for the device, whichever comes first.
+async fn next_update(
+    db: &dyn Db,
+    device_id: DeviceID,
+    after: Option<MessageID>,
+    now: DateTime<Utc>,
+) -> Result<Option<NextUpdate>> {
+    let message = db.get_next_message(device_id, after, now).await?;
+    let recall = db.get_next_recall(device_id, after, now).await?;
+
+    Ok(match (message, recall) {
+        (Some(message), Some(recall)) if recall.id < message.sequence => Some(NextUpdate::Recall(recall)),
+        (Some(message), _) => Some(NextUpdate::Message(message)),
+        (None, Some(recall)) => Some(NextUpdate::Recall(recall)),
+        (None, None) => None,
+    })
+}
+
+/// Wait until there is an update for the device, sending keep-alives in the meantime.
+/// Returns false if the connection ended while waiting.
+async fn wait_for_update<S: AbstractSocket>(
+    socket: &mut S,
+    db: &dyn Db,
+    device_id: DeviceID,
+    after: Option<MessageID>,
+) -> Result<bool> {
+    let wake = subscribe(device_id);
+    let mut keep_alive = time::interval_at(Instant::now() + KEEP_ALIVE_INTERVAL, KEEP_ALIVE_INTERVAL);
+    loop {
+        // Register before looking for updates, so that we do not miss a wake-up in between.
+        let woken = wake.notified();
+        tokio::pin!(woken);
+        woken.as_mut().enable();
+
+        if next_update(db, device_id, after, Utc::now()).await?.is_some() {
+            return Ok(true);
+        }
+
+        let mut byte = [0];
+        tokio::select! {
+            _ = woken => {}
+            _ = keep_alive.tick() => {
+                if RequestUpdateResult::KeepAlive.send_alloc(socket).await.is_err() {
+                    return Ok(false);
+                }
+            }
+            // The device does not send anything while it waits, so this is either a closed connection or a protocol error.
+            _ = socket.read_exact(&mut byte) => return Ok(false),
+        }
+    }
+}
+
+/// The footer to show below `message` on `device`. Empty if the device should not show one.
+async fn footer(db: &dyn Db, device: &Device, message: &Message) -> Result<String> {
+    let Some(author) = message.author.filter(|_| device.show_author()) else {
+        return Ok(String::new());
+    };
+    let name = match db.get_user_name(author).await? {
+        Some(name) => name,
+        None => author.to_string(),
+    };
+    Ok(message::author_footer(&name))
+}
+
+/// The kind of update, the payload to send for `content` and the bytes the device stores, which the checksum covers.
+/// Images are compressed if the device can unpack them and compression makes them smaller.
+/// Devices that cannot draw styled texts get them in the default style.
+fn wire_payload(content: &MessageContent, capabilities: Capabilities) -> (UpdateKind, Cow<'_, [u8]>, Cow<'_, [u8]>) {
+    let image = match content {
+        MessageContent::Text(text) => {
+            if let Some(style) = text
+                .style()
+                .filter(|_| capabilities.contains(Capabilities::STYLED_TEXT))
+            {
+                // Styled texts fit on one page.
+                let payload = text.text().as_bytes();
+                return (
+                    UpdateKind::StyledText(style, payload.len() as TextLength),
+                    Cow::Borrowed(payload),
+                    Cow::Borrowed(payload),
+                );
+            }
+            let (kind, text) = wire_text(text.text(), capabilities);
+            let payload = match text {
+                Cow::Borrowed(text) => Cow::Borrowed(text.as_bytes()),
+                Cow::Owned(text) => Cow::Owned(text.into_bytes()),
+            };
+            return (kind, payload.clone(), payload);
+        }
+        MessageContent::Image(image) => image,
+    };
+    if capabilities.contains(Capabilities::IMAGE_COMPRESSION) {
+        let compressed = rle::encode(image.rgb565());
+        if compressed.len() < image.rgb565().len() {
+            log::debug!(
+                "Compressed image from {} to {} bytes.",
+                image.rgb565().len(),
+                compressed.len()
+            );
+            return (
+                UpdateKind::CompressedImage(compressed.len() as u16),
+                Cow::Owned(compressed),
+                Cow::Borrowed(image.rgb565()),
+            );
+        }
+    }
+    let rgb565 = Cow::Borrowed(image.rgb565());
+    (UpdateKind::Image, rgb565.clone(), rgb565)
+}
+
+/// The kind of update and the text to send for `text`. Texts that do not fit on the display are sent page by page,
+/// devices that cannot page only get the first page.
+fn wire_text(text: &str, capabilities: Capabilities) -> (UpdateKind, Cow<'_, str>) {
+    if message::fits_on_display(text) {
+        return (UpdateKind::Text(text.len() as TextLength), Cow::Borrowed(text));
+    }
+    let mut pages = message::paginate(text);
+    if capabilities.contains(Capabilities::PAGING) {
+        pages.truncate(MAX_PAGES);
+        let pages = pages.join(&PAGE_SEPARATOR.to_string());
+        (UpdateKind::PagedText(pages.len() as u16), Cow::Owned(pages))
+    } else {
+        let page = pages.swap_remove(0);
+        (UpdateKind::Text(page.len() as TextLength), Cow::Owned(page))
+    }
+}
+
+/// Check that we speak the device's protocol and choose the capabilities to use.
+fn handle_hello(hello: &Hello) -> HelloResult {
+    if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&hello.protocol_version) {
+        log::warn!(
+            "Device {} speaks protocol version {} but we only support {MIN_PROTOCOL_VERSION} to {PROTOCOL_VERSION}.",
+            hello.device_id,
+            hello.protocol_version
+        );
+        return HelloResult::UnsupportedProtocol {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        };
+    }
+    HelloResult::Accepted(hello.capabilities.intersection(SERVER_CAPABILITIES))
+}
+
+/// Record the firmware that the device `device_id` reported in its hello, once it authenticated.
+/// Anyone can say hello with any device ID, so what they report is not trusted before.
+async fn record_firmware(db: &dyn Db, device_id: DeviceID, firmware_version: FirmwareVersion) -> Result<()> {
+    // Unregistered devices report their firmware again on the first connection after they were claimed.
+    match db.set_firmware(device_id, firmware_version).await {
+        Ok(device) if is_outdated(&device) => {
+            log::warn!("Device {device} runs outdated firmware {firmware_version}.");
+            Ok(())
+        }
+        Ok(_) => Ok(()),
+        Err(e) if matches!(e.downcast_ref::<DbError>(), Some(DbError::DeviceNotFound(_))) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The challenge for the device `device_id`. Only unregistered devices whose secret we do not know yet may enroll,
+/// registered ones have to be flashed with a secret from [`rotate_key`] first.
+async fn challenge(db: &dyn Db, device_id: DeviceID) -> Result<AuthChallenge> {
+    if db.get_device_keys(device_id).await?.is_none() && db.get_device(device_id).await?.is_none() {
+        return Ok(AuthChallenge::Enroll);
+    }
+    Ok(AuthChallenge::Challenge(rand::random()))
+}
+
+/// Check the answer of the device `device_id` to the challenge `nonce`.
+/// An answer made with the next secret completes a key rotation, after which the old secret is no longer accepted.
+async fn authenticate(db: &dyn Db, device_id: DeviceID, nonce: &AuthNonce, mac: &AuthMac) -> Result<bool> {
+    let Some(keys) = db.get_device_keys(device_id).await? else {
+        log::warn!("Device {device_id} is registered without a secret, it needs to be flashed with a new key.");
+        return Ok(false);
+    };
+    if pico::verify_auth_mac(&keys.secret, device_id, nonce, mac) {
+        return Ok(true);
+    }
+    match keys.next_secret {
+        Some(next_secret) if pico::verify_auth_mac(&next_secret, device_id, nonce, mac) => {
+            log::info!("Device {device_id} authenticated with its new secret, the old one is no longer valid.");
+            db.set_device_keys(device_id, DeviceKeys::new(next_secret)).await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Trust the secret of an unregistered device that we see for the first time, which comes from `peer`.
+/// Registered devices never enroll, otherwise anyone could take over those that have no secret yet.
+/// A device whose ID someone else enrolled is denied until the admin resets its enrollment, see
+/// [`Db::reset_pending_device`].
+async fn enroll(db: &dyn Db, device_id: DeviceID, secret: DeviceSecret, peer: IpAddr) -> Result<bool> {
+    if secret == [0; DEVICE_SECRET_LEN] {
+        log::warn!("Device {device_id} has no secret configured, it needs to be flashed with one.");
+        return Ok(false);
+    }
+    // Another connection may have enrolled, or the device may have been registered, since we sent the challenge.
+    if db.get_device_keys(device_id).await?.is_some() || db.get_device(device_id).await?.is_some() {
+        return Ok(false);
+    }
+    if !count_enrollment(peer, Instant::now()) {
+        log::warn!("Device {device_id} at {peer} may not enroll, this address enrolled too many devices lately.");
+        return Ok(false);
+    }
+    db.set_device_keys(device_id, DeviceKeys::new(secret)).await?;
+    log::info!("Device {device_id} enrolled its secret.");
+    Ok(true)
+}
+
+/// Tell the device whether it passed. Returns false if the connection must be closed.
+async fn send_auth_result<S: AbstractSocket>(
+    socket: &mut S,
+    device_id: DeviceID,
+    peer: SocketAddr,
+    authenticated: bool,
+) -> Result<bool> {
+    if authenticated {
+        log::info!("Device {device_id} at {peer} authenticated.");
+        AuthResult::Authenticated.send_alloc(socket).await?;
+    } else {
+        // This might be someone trying to pull the messages of a device they do not own.
+        log::warn!("Device {device_id} at {peer} failed to authenticate.");
+        AuthResult::Denied.send_alloc(socket).await?;
+    }
+    Ok(authenticated)
+}
+
+/// Record the acknowledgement of the message with `sequence` and pass the message on if the device stored it for the
+/// first time.
+async fn acknowledge(
+    db: &dyn Db,
+    device_id: DeviceID,
+    sequence: MessageID,
+    delivered: &mpsc::UnboundedSender<Message>,
+) -> Result<()> {
+    if let Some(message) = db.mark_delivered(device_id, sequence, Utc::now()).await? {
+        log::info!("Device {device_id} stored message {}.", message.id);
+        // Nobody listens if the Telegram bot stopped, the delivery is recorded anyway.
+        delivered.send(message).ok();
+    }
+    Ok(())
+}
+
+/// How far a connection got in the connection setup.
+#[derive(Clone, Copy)]
+enum Connection {
+    /// The device did not say hello yet.
+    New,
+    /// The device said hello and has to answer the challenge.
+    Authenticating {
+        device_id: DeviceID,
+        /// Only recorded once the device authenticated.
+        firmware_version: FirmwareVersion,
+        capabilities: Capabilities,
+        challenge: AuthChallenge,
+    },
+    /// The device proved that it holds the secret of `device_id`.
+    Authenticated {
+        device_id: DeviceID,
+        capabilities: Capabilities,
+    },
+}
+
+/// Serve the device at `peer` until it or we close the connection.
 // a.d. TODO I'm not sure I want a Sync here => read the async book
-async fn handle_client(mut socket: TcpStream, messages: &dyn Db) {
+async fn handle_client<S: AbstractSocket>(
+    mut socket: S,
+    peer: SocketAddr,
+    messages: &dyn Db,
+    delivered: &mpsc::UnboundedSender<Message>,
+) {
+    // Devices must say hello and authenticate before anything else.
+    let mut connection = Connection::New;
+
     loop {
         match ClientCommand::receive_alloc(&mut socket).await {
+            Err(pico::Error::Eof) => {
+                log::info!("Client at {peer} disconnected.");
+                break;
+            }
             Err(e) => {
-                log::error!("{e}");
+                log::error!("Receiving command from client at {peer} failed: {e}");
                 break;
             }
-            Ok(ClientCommand::RequestUpdate(device_id, after)) => {
+            Ok(ClientCommand::Hello(hello)) => {
+                let result = handle_hello(&hello);
+                if let Err(e) = result.send_alloc(&mut socket).await {
+                    log::error!("Sending hello result to client at {peer} failed: {e}");
+                    break;
+                }
+                let capabilities = match result {
+                    HelloResult::Accepted(capabilities) => capabilities,
+                    HelloResult::UnsupportedProtocol { .. } => break,
+                };
+                let challenge = match challenge(messages, hello.device_id).await {
+                    Ok(challenge) => challenge,
+                    Err(e) => {
+                        log::error!("Creating challenge for device {} failed: {e:#}", hello.device_id);
+                        break;
+                    }
+                };
+                if let Err(e) = challenge.send_alloc(&mut socket).await {
+                    log::error!("Sending challenge to device {} failed: {e}", hello.device_id);
+                    break;
+                }
+                connection = Connection::Authenticating {
+                    device_id: hello.device_id,
+                    firmware_version: hello.firmware_version,
+                    capabilities,
+                    challenge,
+                };
+            }
+            Ok(ClientCommand::Authenticate(mac)) => {
+                let Connection::Authenticating {
+                    device_id,
+                    firmware_version,
+                    capabilities,
+                    challenge: AuthChallenge::Challenge(nonce),
+                } = connection
+                else {
+                    log::warn!("Client at {peer} authenticated without being challenged.");
+                    break;
+                };
+                let authenticated = match authenticate(messages, device_id, &nonce, &mac).await {
+                    Ok(authenticated) => authenticated,
+                    Err(e) => {
+                        log::error!("Authenticating device {device_id} failed: {e:#}");
+                        break;
+                    }
+                };
+                match send_auth_result(&mut socket, device_id, peer, authenticated).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        log::error!("Sending authentication result to device {device_id} failed: {e:#}");
+                        break;
+                    }
+                }
+                if let Err(e) = record_firmware(messages, device_id, firmware_version).await {
+                    log::error!("Recording the firmware of device {device_id} failed: {e:#}");
+                    break;
+                }
+                connection = Connection::Authenticated {
+                    device_id,
+                    capabilities,
+                };
+            }
+            Ok(ClientCommand::Enroll(secret)) => {
+                let Connection::Authenticating {
+                    device_id,
+                    firmware_version,
+                    capabilities,
+                    challenge: AuthChallenge::Enroll,
+                } = connection
+                else {
+                    log::warn!("Client at {peer} tried to enroll without being asked to.");
+                    break;
+                };
+                let authenticated = match enroll(messages, device_id, secret, peer.ip()).await {
+                    Ok(authenticated) => authenticated,
+                    Err(e) => {
+                        log::error!("Enrolling device {device_id} failed: {e:#}");
+                        break;
+                    }
+                };
+                match send_auth_result(&mut socket, device_id, peer, authenticated).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(e) => {
+                        log::error!("Sending authentication result to device {device_id} failed: {e:#}");
+                        break;
+                    }
+                }
+                if let Err(e) = record_firmware(messages, device_id, firmware_version).await {
+                    log::error!("Recording the firmware of device {device_id} failed: {e:#}");
+                    break;
+                }
+                connection = Connection::Authenticated {
+                    device_id,
+                    capabilities,
+                };
+            }
+            Ok(ClientCommand::Ack(id)) => {
+                let Connection::Authenticated { device_id, .. } = connection else {
+                    log::warn!("Client at {peer} acknowledged message {id} without authenticating.");
+                    break;
+                };
+                if let Err(e) = acknowledge(messages, device_id, id, delivered).await {
+                    log::error!("Recording delivery of message {id} to device {device_id} failed: {e:#}");
+                    break;
+                }
+            }
+            Ok(ClientCommand::Nack(id)) => {
+                let Connection::Authenticated { device_id, .. } = connection else {
+                    log::warn!("Client at {peer} rejected message {id} without authenticating.");
+                    break;
+                };
+                // The device requests the message again with the same cursor, so the retry needs nothing from us.
+                log::warn!("Device {device_id} received message {id} corrupted.");
+                if let Err(e) = messages.record_failed_delivery(device_id, id).await {
+                    log::error!("Recording failed delivery of message {id} to device {device_id} failed: {e:#}");
+                    break;
+                }
+            }
+            Ok(ClientCommand::Status(status)) => {
+                let Connection::Authenticated { device_id, .. } = connection else {
+                    log::warn!("Client at {peer} reported its status without authenticating.");
+                    break;
+                };
+                log::debug!("Device {device_id} reported {status:?}.");
+                match messages.set_status(device_id, status, Utc::now()).await {
+                    Ok(_) => {}
+                    // Unregistered devices have nowhere to store their status.
+                    Err(e) if matches!(e.downcast_ref::<DbError>(), Some(DbError::DeviceNotFound(_))) => {}
+                    Err(e) => {
+                        log::error!("Storing status of device {device_id} failed: {e:#}");
+                        break;
+                    }
+                }
+            }
+            Ok(
+                command @ (ClientCommand::RequestUpdate(device_id, after)
+                | ClientCommand::WaitForUpdate(device_id, after)),
+            ) => {
+                let capabilities = match connection {
+                    Connection::Authenticated {
+                        device_id: authenticated,
+                        capabilities,
+                    } if authenticated == device_id => capabilities,
+                    Connection::Authenticated {
+                        device_id: authenticated,
+                        ..
+                    } => {
+                        log::warn!("Device {authenticated} at {peer} requested the updates of device {device_id}.");
+                        break;
+                    }
+                    Connection::New | Connection::Authenticating { .. } => {
+                        log::error!(
+                            "Device {device_id} at {peer} requested an update without authenticating, its firmware may be too old."
+                        );
+                        break;
+                    }
+                };
                 log::trace!("RequestUpdate acquiring lock.");
 
-                match messages.get_next_message(device_id, after).await {
-                    Some(message) => {
+                let device = match messages.get_device(device_id).await {
+                    Err(e) => {
+                        log::error!("Looking up device {device_id} failed: {e:#}");
+                        break;
+                    }
+                    Ok(None) => {
+                        // Unknown devices are not rejected but get a code to show, with which a user can claim them.
+                        match messages.get_pairing_code(device_id, Utc::now()).await {
+                            Ok(code) => {
+                                log::info!("Unregistered device {device_id} is waiting to be claimed.");
+                                let result = RequestUpdateResult::Unclaimed(code);
+                                if let Err(e) = result.send_alloc(&mut socket).await {
+                                    log::error!("Sending pairing code to device {device_id} failed: {e}");
+                                }
+                            }
+                            Err(e) => log::error!("Creating pairing code for device {device_id} failed: {e:#}"),
+                        }
+                        break;
+                    }
+                    Ok(Some(device)) => device,
+                };
+
+                let waiting = matches!(command, ClientCommand::WaitForUpdate(..));
+                if waiting {
+                    match wait_for_update(&mut socket, messages, device_id, after).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            log::info!("Device {device_id} at {peer} stopped waiting for updates.");
+                            break;
+                        }
+                        Err(e) => {
+                            log::error!("Waiting for updates of device {device_id} failed: {e:#}");
+                            break;
+                        }
+                    }
+                }
+
+                let now = Utc::now();
+                let next = match next_update(messages, device_id, after, now).await {
+                    Ok(next) => next,
+                    Err(e) => {
+                        log::error!("Retrieving next update for device {device_id} failed: {e:#}");
+                        break;
+                    }
+                };
+
+                match next {
+                    Some(NextUpdate::Message(message)) => {
+                        let footer = if capabilities.contains(Capabilities::FOOTER) {
+                            footer(messages, &device, &message).await
+                        } else {
+                            Ok(String::new())
+                        };
+                        let footer = match footer {
+                            Ok(footer) => footer,
+                            Err(e) => {
+                                log::error!("Retrieving footer of message {} failed: {e:#}", message.id);
+                                break;
+                            }
+                        };
+                        let (kind, payload, stored) = wire_payload(&message.content, capabilities);
                         let message_update = Update {
-                            lifetime_sec: message.meta.duration.num_seconds() as u32,
-                            id: message.id,
-                            kind: UpdateKind::from(&message.content),
+                            // The device only knows when it received the message, so we send the remaining lifetime.
+                            lifetime_sec: message.remaining_lifetime(now).num_seconds() as u32,
+                            // Devices know messages by their sequence, which is also their cursor.
+                            id: message.sequence,
+                            kind,
+                            footer_len: footer.len() as u8,
+                            checksum: pico::payload_checksum(&stored, footer.as_bytes()),
+                            // Other devices would show it like any other message anyways.
+                            urgent: message.meta.urgent && capabilities.contains(Capabilities::PRIORITY),
                         };
                         let result = RequestUpdateResult::Update(message_update);
-                        result.send_alloc(&mut socket).await.unwrap();
-
-                        match &message.content {
-                            MessageContent::Text(text) => {
-                                socket.write_all(text.text().as_bytes()).await.unwrap();
-                            }
-                            MessageContent::Image(image) => {
-                                socket.write_all(image.rgb565()).await.unwrap();
-                            }
+                        let sent = async {
+                            result.send_alloc(&mut socket).await?;
+                            socket.write_all(&payload).await?;
+                            socket.write_all(footer.as_bytes()).await
+                        };
+                        if let Err(e) = sent.await {
+                            log::error!("Sending message {} to device {device_id} failed: {e}", message.id);
+                            break;
+                        }
+                    }
+                    Some(NextUpdate::Recall(recall)) => {
+                        log::info!("Recalling message {} from device {device_id}.", recall.message_id);
+                        let result = RequestUpdateResult::Recall(pico::Recall::from(&recall));
+                        if let Err(e) = result.send_alloc(&mut socket).await {
+                            log::error!("Sending recall {} to device {device_id} failed: {e}", recall.id);
+                            break;
                         }
                     }
                     None => {
-                        let result = RequestUpdateResult::NoUpdate;
-                        result.send_alloc(&mut socket).await.unwrap();
-                        socket.flush().await.ok();
-                        break;
+                        if let Err(e) = RequestUpdateResult::NoUpdate.send_alloc(&mut socket).await {
+                            log::error!("Telling device {device_id} that there is no update failed: {e}");
+                            break;
+                        }
+                        // A waiting device just asks again, e.g. if the update it was woken for was recalled meanwhile.
+                        // Devices that get pushed updates start waiting on this connection once they are up to date.
+                        if !waiting && !capabilities.contains(Capabilities::PUSH) {
+                            break;
+                        }
                     }
                 };
             }
@@ -0,0 +1,1119 @@
+//! The server and a device that runs the client of the firmware in `pico/src/fetch_data.rs`,
+//! connected through an in-memory socket.
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use chrono::Utc;
+use common::{
+    client::{self, Client, Identity, ImageSlot, MessageStore, Storage, SyncState, Timer},
+    consts::{IMAGE_BUFFER_SIZE, IMAGE_HEIGHT, IMAGE_WIDTH, PAGE_LINES},
+    protocols::{
+        pico::{
+            serialization::{AbstractSocket, FRAME_MAGIC},
+            ClientCommand, Color, DeviceError, DeviceErrorKind, DeviceSecret, DeviceStatus, FontSize, TextAlignment,
+            TextStyle, Update,
+        },
+        web::{MessageMeta, NewRecurringMessage, Recurrence},
+    },
+    types::PairingCode,
+};
+use image::{DynamicImage, Rgb, RgbImage};
+use teloxide::types::UserId;
+use tokio::{io::DuplexStream, time::timeout};
+
+use super::*;
+use crate::{
+    db::{
+        message::{InsertMessage, SenderID},
+        recurring::InsertRecurringMessage,
+        sqlite_db::SqliteDb,
+        user::{RawUser, User},
+    },
+    schedule,
+};
+
+const DEVICE_ID: DeviceID = DeviceID(0x1234);
+const SECRET: DeviceSecret = [7; DEVICE_SECRET_LEN];
+/// All capabilities the server supports except [`Capabilities::PUSH`], so that a sync ends with [`RequestUpdateResult::NoUpdate`].
+const CAPABILITIES: Capabilities = Capabilities::FOOTER
+    .union(Capabilities::IMAGE_COMPRESSION)
+    .union(Capabilities::PAGING)
+    .union(Capabilities::PRIORITY)
+    .union(Capabilities::DELIVERY_ACK)
+    .union(Capabilities::STATUS)
+    .union(Capabilities::STYLED_TEXT);
+/// No test should take nearly as long, so a test that runs into this timeout hangs.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Gives each [`Server`] an address of its own.
+static NEXT_PEER: AtomicU32 = AtomicU32::new(1);
+
+struct Server {
+    db: Arc<dyn Db>,
+    /// The device that receives the messages of [`Self::add_message`].
+    device_id: DeviceID,
+    delivered_tx: mpsc::UnboundedSender<Message>,
+    delivered_rx: mpsc::UnboundedReceiver<Message>,
+    /// The address that all connections come from. Each server has its own, since enrollments are counted per address
+    /// across all servers.
+    peer: SocketAddr,
+}
+
+impl Server {
+    /// A server that knows our device and its secret, so that it hands out its messages.
+    async fn new() -> Self {
+        Self::with_device(DEVICE_ID).await
+    }
+
+    /// A server that knows the device `device_id` and its secret.
+    async fn with_device(device_id: DeviceID) -> Self {
+        let mut server = Self::unregistered();
+        server.device_id = device_id;
+        server
+            .db
+            .add_device(Device::new(device_id, "Test device".to_string()))
+            .await
+            .unwrap();
+        server
+            .db
+            .set_device_keys(device_id, DeviceKeys::new(SECRET))
+            .await
+            .unwrap();
+        server
+    }
+
+    /// A server that has never seen our device.
+    fn unregistered() -> Self {
+        let db: Arc<dyn Db> = Arc::new(SqliteDb::open(":memory:", UserId(1)).unwrap());
+        let (delivered_tx, delivered_rx) = mpsc::unbounded_channel();
+        let peer = Ipv4Addr::from(u32::from(Ipv4Addr::new(10, 0, 0, 0)) + NEXT_PEER.fetch_add(1, Ordering::Relaxed));
+        Self {
+            db,
+            device_id: DEVICE_ID,
+            delivered_tx,
+            delivered_rx,
+            peer: SocketAddr::new(peer.into(), 1338),
+        }
+    }
+
+    async fn add_text(&self, text: &str) -> MessageID {
+        self.add_message(MessageContent::new_text(text).unwrap(), None).await
+    }
+
+    async fn add_message(&self, content: MessageContent, author: Option<RawUser>) -> MessageID {
+        let meta = MessageMeta {
+            receiver_id: self.device_id,
+            duration: chrono::Duration::hours(1),
+            urgent: false,
+            not_before: None,
+        };
+        let mut message = InsertMessage::new(meta, SenderID::Web, Utc::now(), content);
+        message.author = author;
+        self.db.add_message(message).await.unwrap()
+    }
+
+    /// Serve a new connection and return the device's end of it.
+    fn connect(&self) -> DuplexStream {
+        // Large enough for any update, so that the server is never blocked by a device that stopped reading.
+        let (device, server) = tokio::io::duplex(2 * IMAGE_BUFFER_SIZE);
+        let db = self.db.clone();
+        let delivered = self.delivered_tx.clone();
+        let peer = self.peer;
+        tokio::spawn(async move { handle_client(server, peer, &*db, &delivered).await });
+        device
+    }
+
+    /// The IDs of the messages that were acknowledged for the first time, in order.
+    fn delivered(&mut self) -> Vec<MessageID> {
+        let mut delivered = Vec::new();
+        while let Ok(message) = self.delivered_rx.try_recv() {
+            delivered.push(message.id);
+        }
+        delivered
+    }
+}
+
+/// What the device shows after receiving an update.
+#[derive(Debug, PartialEq)]
+enum Shown {
+    Text {
+        id: MessageID,
+        text: String,
+        footer: String,
+    },
+    Image {
+        id: MessageID,
+        rgb565: Vec<u8>,
+    },
+    StyledText {
+        id: MessageID,
+        text: String,
+        style: TextStyle,
+    },
+    Recalled(MessageID),
+}
+
+struct TokioTimer;
+
+impl Timer for TokioTimer {
+    async fn with_timeout<F: Future>(&self, wait: Duration, future: F) -> Option<F::Output> {
+        timeout(wait, future).await.ok()
+    }
+}
+
+/// Keeps the messages like `pico/src/messagebuf.rs` and passes on what the device shows.
+struct TestStorage {
+    messages: TestMessages,
+    shown: mpsc::UnboundedSender<Shown>,
+    /// The updates in the order they were stored.
+    updates: Vec<Update>,
+    pairing_code: Option<PairingCode>,
+}
+
+impl TestStorage {
+    fn new(shown: mpsc::UnboundedSender<Shown>) -> Self {
+        Self {
+            messages: TestMessages {
+                shown: None,
+                image: TestImageSlot {
+                    image: vec![0; IMAGE_BUFFER_SIZE].try_into().unwrap(),
+                    id: None,
+                },
+            },
+            shown,
+            updates: Vec::new(),
+            pairing_code: None,
+        }
+    }
+}
+
+impl Storage for TestStorage {
+    type Messages = TestMessages;
+    type Guard<'a> = &'a mut TestMessages;
+
+    async fn lock(&mut self) -> &mut TestMessages {
+        &mut self.messages
+    }
+
+    fn set_pairing_code(&mut self, code: Option<PairingCode>) {
+        self.pairing_code = code;
+    }
+
+    fn stored(&mut self, update: &Update) {
+        self.updates.push(*update);
+        let shown = self
+            .messages
+            .shown
+            .take()
+            .expect("the client stored an update without a message");
+        self.shown.send(shown).unwrap();
+    }
+
+    fn recalled(&mut self, id: MessageID) {
+        self.shown.send(Shown::Recalled(id)).unwrap();
+    }
+}
+
+struct TestMessages {
+    /// The message received last, until the client confirms that it was stored.
+    shown: Option<Shown>,
+    image: TestImageSlot,
+}
+
+impl MessageStore for TestMessages {
+    type ImageSlot = TestImageSlot;
+
+    fn add_text(&mut self, update: &Update, text: &str, footer: &str) {
+        self.shown = Some(match update.kind {
+            UpdateKind::StyledText(style, _) => Shown::StyledText {
+                id: update.id,
+                text: text.to_string(),
+                style,
+            },
+            _ => Shown::Text {
+                id: update.id,
+                text: text.to_string(),
+                footer: footer.to_string(),
+            },
+        });
+    }
+
+    fn image_slot(&mut self) -> &mut TestImageSlot {
+        &mut self.image
+    }
+
+    fn commit_image(&mut self) {
+        self.shown = Some(Shown::Image {
+            id: self.image.id.take().unwrap(),
+            rgb565: self.image.image.to_vec(),
+        });
+    }
+
+    fn recall(&mut self, _id: MessageID) {}
+}
+
+struct TestImageSlot {
+    image: Box<[u8; IMAGE_BUFFER_SIZE]>,
+    id: Option<MessageID>,
+}
+
+impl ImageSlot for TestImageSlot {
+    fn image(&mut self) -> &mut [u8; IMAGE_BUFFER_SIZE] {
+        &mut self.image
+    }
+
+    fn finish(&mut self, update: &Update, _footer: &str) {
+        self.id = Some(update.id);
+    }
+}
+
+/// What a device got during a sync.
+struct Synced {
+    shown: Vec<Shown>,
+    updates: Vec<Update>,
+    pairing_code: Option<PairingCode>,
+}
+
+/// A device that runs the client of the firmware.
+struct TestDevice {
+    identity: Identity,
+    state: SyncState,
+    /// Reported after connecting, if the server wants it.
+    status: Option<DeviceStatus>,
+}
+
+impl TestDevice {
+    fn new(capabilities: Capabilities) -> Self {
+        Self::with_id(DEVICE_ID, capabilities)
+    }
+
+    fn with_id(device_id: DeviceID, capabilities: Capabilities) -> Self {
+        Self {
+            identity: Identity {
+                device_id,
+                secret: SECRET,
+                firmware_version: "1.0.0".parse().unwrap(),
+                capabilities,
+            },
+            state: SyncState::new(),
+            status: None,
+        }
+    }
+
+    /// A device that knows the messages up to `after`.
+    fn after(mut self, after: Option<MessageID>) -> Self {
+        self.state.last_message_id = after;
+        self
+    }
+
+    fn reporting(mut self, status: DeviceStatus) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Say hello on `socket`, authenticate and report our status, like `pico/src/fetch_data.rs`.
+    async fn connect<S: AbstractSocket>(&self, socket: S) -> std::result::Result<Client<S, TokioTimer>, client::Error> {
+        let mut client = Client::connect(socket, TokioTimer, &self.identity).await?;
+        if let Some(status) = self.status {
+            client.report_status(status).await?;
+        }
+        Ok(client)
+    }
+
+    /// Connect to `server` and fetch updates until there are no more, like the main loop of the firmware.
+    async fn sync(&mut self, server: &Server) -> Synced {
+        self.sync_on(server.connect()).await
+    }
+
+    /// Fetch updates on `socket`. The server closes the connection afterwards, since the device does not wait for
+    /// pushed updates.
+    async fn sync_on<S: AbstractSocket>(&mut self, socket: S) -> Synced {
+        let mut client = self.connect(socket).await.unwrap();
+        let (shown_tx, mut shown_rx) = mpsc::unbounded_channel();
+        let mut storage = TestStorage::new(shown_tx);
+        client.sync(&mut storage, &mut self.state).await.unwrap();
+        assert_closed(&mut client.into_socket()).await;
+
+        let mut shown = Vec::new();
+        while let Ok(next) = shown_rx.try_recv() {
+            shown.push(next);
+        }
+        Synced {
+            shown,
+            updates: storage.updates,
+            pairing_code: storage.pairing_code,
+        }
+    }
+
+    /// Connect to `server` and keep syncing in the background. Returns what the device shows as it happens.
+    async fn sync_pushed(mut self, server: &Server) -> mpsc::UnboundedReceiver<Shown> {
+        let mut client = self.connect(server.connect()).await.unwrap();
+        assert!(client.capabilities().contains(Capabilities::PUSH));
+        let (shown_tx, shown_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let mut storage = TestStorage::new(shown_tx);
+            client.sync(&mut storage, &mut self.state).await
+        });
+        shown_rx
+    }
+}
+
+/// Wait until the server closed `socket`.
+async fn assert_closed<S: AbstractSocket>(socket: &mut S) {
+    let mut byte = [0];
+    assert!(matches!(socket.read_exact(&mut byte).await, Err(pico::Error::Eof)));
+}
+
+/// Wait until a device waits for pushed updates from the server.
+async fn until_waiting(device_id: DeviceID) {
+    while !is_connected(device_id) {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+/// Say hello as the device `device_id` and return the challenge the server answers with.
+async fn hello(socket: &mut DuplexStream, device_id: DeviceID) -> AuthChallenge {
+    let hello = Hello {
+        device_id,
+        protocol_version: PROTOCOL_VERSION,
+        firmware_version: "1.0.0".parse().unwrap(),
+        capabilities: CAPABILITIES,
+    };
+    ClientCommand::Hello(hello).send_alloc(socket).await.unwrap();
+    HelloResult::receive_alloc(socket).await.unwrap();
+    AuthChallenge::receive_alloc(socket).await.unwrap()
+}
+
+/// Run a test with a timeout, so that a server that stopped talking fails it instead of hanging it.
+async fn bounded<F: Future>(test: F) -> F::Output {
+    timeout(TIMEOUT, test).await.expect("test timed out")
+}
+
+fn text(id: MessageID, text: &str) -> Shown {
+    Shown::Text {
+        id,
+        text: text.to_string(),
+        footer: String::new(),
+    }
+}
+
+/// An image with a flat top half, which compresses well, and a noisy bottom half.
+fn test_image() -> MessageContent {
+    let image = RgbImage::from_fn(IMAGE_WIDTH as u32, IMAGE_HEIGHT as u32, |x, y| {
+        if y < IMAGE_HEIGHT as u32 / 2 {
+            Rgb([200, 30, 30])
+        } else {
+            Rgb([(x * 7) as u8, (y * 13) as u8, (x * y) as u8])
+        }
+    });
+    MessageContent::new_image(DynamicImage::ImageRgb8(image)).unwrap()
+}
+
+fn rgb565(image: &MessageContent) -> Vec<u8> {
+    let MessageContent::Image(image) = image else {
+        panic!("expected an image");
+    };
+    image.rgb565().to_vec()
+}
+
+#[tokio::test]
+async fn syncs_all_messages_in_order() {
+    bounded(async {
+        let mut server = Server::new().await;
+        let first = server.add_text("first").await;
+        let image = test_image();
+        let rgb565 = rgb565(&image);
+        let second = server.add_message(image, None).await;
+        let third = server.add_text("third").await;
+
+        let mut device = TestDevice::new(CAPABILITIES);
+        let synced = device.sync(&server).await;
+
+        assert_eq!(
+            synced.shown,
+            [
+                text(first, "first"),
+                Shown::Image { id: second, rgb565 },
+                text(third, "third"),
+            ]
+        );
+        assert_eq!(device.state.last_message_id, Some(third));
+        assert_eq!(server.delivered(), [first, second, third]);
+        let message = server.db.get_message(second).await.unwrap().unwrap();
+        assert!(message.delivery.delivered_at.is_some());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn images_are_sent_uncompressed_to_devices_that_cannot_unpack_them() {
+    bounded(async {
+        let server = Server::new().await;
+        let image = test_image();
+        let rgb565 = rgb565(&image);
+        let id = server.add_message(image, None).await;
+
+        let synced = TestDevice::new(Capabilities::FOOTER | Capabilities::DELIVERY_ACK)
+            .sync(&server)
+            .await;
+        assert_eq!(synced.updates[0].kind, UpdateKind::Image);
+        assert_eq!(synced.shown, [Shown::Image { id, rgb565 }]);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn footer_names_the_author() {
+    bounded(async {
+        let server = Server::new().await;
+        let author = RawUser::Telegram { id: UserId(42) };
+        server.db.set_user_name(author, "Alice".to_string()).await.unwrap();
+        server.db.set_show_author(DEVICE_ID, true).await.unwrap();
+        let id = server
+            .add_message(MessageContent::new_text("hi").unwrap(), Some(author))
+            .await;
+
+        let synced = TestDevice::new(CAPABILITIES).sync(&server).await;
+
+        assert_eq!(
+            synced.shown,
+            [Shown::Text {
+                id,
+                text: "hi".to_string(),
+                footer: message::author_footer("Alice"),
+            }]
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn urgent_flag_is_only_sent_to_devices_that_show_urgent_messages() {
+    bounded(async {
+        let server = Server::new().await;
+        let meta = MessageMeta {
+            receiver_id: DEVICE_ID,
+            duration: chrono::Duration::hours(1),
+            urgent: true,
+            not_before: None,
+        };
+        let content = MessageContent::new_text("hurry").unwrap();
+        let id = server
+            .db
+            .add_message(InsertMessage::new(meta, SenderID::Web, Utc::now(), content))
+            .await
+            .unwrap();
+
+        for (capabilities, urgent) in [(CAPABILITIES, true), (Capabilities::FOOTER, false)] {
+            let synced = TestDevice::new(capabilities).sync(&server).await;
+            assert_eq!(synced.updates[0].urgent, urgent);
+            assert_eq!(synced.shown, [text(id, "hurry")]);
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn long_text_is_sent_in_pages_or_as_its_first_page() {
+    bounded(async {
+        let server = Server::new().await;
+        let long_text = ["lorem"; 60].join(" ");
+        let id = server.add_text(&long_text).await;
+
+        let line = "lorem lorem lorem";
+        let page = [line; PAGE_LINES].join("\n");
+        let last_page = [line; 6].join("\n");
+        let pages = [page.as_str(), &page, &last_page].join(&PAGE_SEPARATOR.to_string());
+        for (capabilities, expected) in [(CAPABILITIES, &pages), (Capabilities::FOOTER, &page)] {
+            let synced = TestDevice::new(capabilities).sync(&server).await;
+            assert_eq!(synced.shown, [text(id, expected)]);
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn short_text_with_more_lines_than_the_display_is_sent_in_pages() {
+    bounded(async {
+        let server = Server::new().await;
+        let lines: Vec<_> = (1..=PAGE_LINES + 2).map(|i| i.to_string()).collect();
+        let id = server.add_text(&lines.join("\n")).await;
+
+        let page = lines[..PAGE_LINES].join("\n");
+        let pages = [page.clone(), lines[PAGE_LINES..].join("\n")].join(&PAGE_SEPARATOR.to_string());
+        let synced = TestDevice::new(CAPABILITIES).sync(&server).await;
+        assert!(matches!(synced.updates[0].kind, UpdateKind::PagedText(_)));
+        assert_eq!(synced.shown, [text(id, &pages)]);
+
+        let synced = TestDevice::new(Capabilities::FOOTER).sync(&server).await;
+        assert!(matches!(synced.updates[0].kind, UpdateKind::Text(_)));
+        assert_eq!(synced.shown, [text(id, &page)]);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn styled_text_is_sent_plain_to_devices_that_cannot_draw_it() {
+    bounded(async {
+        let server = Server::new().await;
+        let content = MessageContent::from_markup("<red on yellow><large>**Hello**\nthere").unwrap();
+        let id = server.add_message(content, None).await;
+
+        let style = TextStyle {
+            foreground: Color::RED,
+            background: Color::YELLOW,
+            alignment: TextAlignment::Center,
+            font: FontSize::Large,
+            heading: true,
+        };
+        let styled = Shown::StyledText {
+            id,
+            text: "Hello\nthere".to_string(),
+            style,
+        };
+        for (capabilities, expected) in [(CAPABILITIES, styled), (Capabilities::FOOTER, text(id, "Hello\nthere"))] {
+            let synced = TestDevice::new(capabilities).sync(&server).await;
+            assert_eq!(synced.shown, [expected]);
+        }
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn scheduled_message_is_sent_after_newer_messages_once_it_is_due() {
+    bounded(async {
+        let server = Server::new().await;
+        let now = Utc::now();
+        let meta = MessageMeta {
+            receiver_id: DEVICE_ID,
+            duration: chrono::Duration::hours(1),
+            urgent: false,
+            not_before: Some(now + chrono::Duration::hours(2)),
+        };
+        let content = MessageContent::new_text("later").unwrap();
+        server
+            .db
+            .add_message(InsertMessage::new(meta, SenderID::Web, now, content))
+            .await
+            .unwrap();
+        let id = server.add_text("now").await;
+
+        let mut device = TestDevice::new(CAPABILITIES);
+        assert_eq!(device.sync(&server).await.shown, [text(id, "now")]);
+
+        assert!(server.db.release_due_messages(now).await.unwrap().is_empty());
+        let released = server
+            .db
+            .release_due_messages(now + chrono::Duration::hours(2))
+            .await
+            .unwrap();
+        assert_eq!(released.len(), 1);
+
+        let synced = device.sync(&server).await;
+        // The device knows the message by its sequence, which is newer than the message it already has.
+        assert_eq!(synced.shown, [text(released[0].sequence, "later")]);
+        // The lifetime starts when the message is due, not when it was sent.
+        assert!(synced.updates[0].lifetime_sec > 2 * 3600);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn recurring_message_is_sent_when_due_and_moves_on() {
+    bounded(async {
+        let server = Server::new().await;
+        let now = Utc::now();
+        let new_message = NewRecurringMessage {
+            receiver_id: DEVICE_ID,
+            text: "Take out the trash".to_string(),
+            recurrence: Recurrence::Day {
+                time: chrono::NaiveTime::MIN,
+            },
+            duration: chrono::Duration::hours(1),
+            urgent: false,
+        };
+        let message = InsertRecurringMessage::new(new_message, SenderID::Web, now).unwrap();
+        assert!(message.next_at > now);
+        let id = server.db.add_recurring_message(message).await.unwrap();
+        // Pretend that it became due a minute ago.
+        server
+            .db
+            .set_recurring_next_at(id, now - chrono::Duration::minutes(1))
+            .await
+            .unwrap();
+
+        let next_due = schedule::release_due(server.db.as_ref(), now).await.unwrap();
+        let recurring = server.db.get_recurring_message(id).await.unwrap().unwrap();
+        assert!(recurring.next_at > now);
+        assert_eq!(next_due, Some(recurring.next_at));
+
+        let synced = TestDevice::new(CAPABILITIES).sync(&server).await;
+        let [Shown::Text { text, .. }] = &synced.shown[..] else {
+            panic!("expected the recurring message, got {:?}", synced.shown);
+        };
+        assert_eq!(text, "Take out the trash");
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn cursor_resumes_after_known_messages() {
+    bounded(async {
+        let mut server = Server::new().await;
+        let first = server.add_text("first").await;
+        let second = server.add_text("second").await;
+
+        let mut device = TestDevice::new(CAPABILITIES);
+        let synced = device.sync(&server).await;
+        assert_eq!(synced.shown, [text(first, "first"), text(second, "second")]);
+
+        // The next connection only gets what was added or recalled since.
+        let third = server.add_text("third").await;
+        server.db.delete_message(first).await.unwrap();
+        let synced = device.sync(&server).await;
+        assert_eq!(synced.shown, [text(third, "third"), Shown::Recalled(first)]);
+
+        // Nothing is left after the new cursor, and each message was only reported as delivered once.
+        assert_eq!(device.sync(&server).await.shown, []);
+        assert_eq!(server.delivered(), [first, second, third]);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn recalls_are_sent_in_order_with_new_messages() {
+    bounded(async {
+        let server = Server::new().await;
+        let first = server.add_text("first").await;
+        let second = server.add_text("second").await;
+        let mut device = TestDevice::new(CAPABILITIES);
+        device.sync(&server).await;
+
+        server.db.delete_message(second).await.unwrap();
+        let third = server.add_text("third").await;
+        server.db.delete_message(first).await.unwrap();
+        // A message the device never got is recalled as well, since the server does not know what a device shows.
+        let fourth = server.add_text("fourth").await;
+        server.db.delete_message(fourth).await.unwrap();
+
+        let synced = device.sync(&server).await;
+        assert_eq!(
+            synced.shown,
+            [
+                Shown::Recalled(second),
+                text(third, "third"),
+                Shown::Recalled(first),
+                Shown::Recalled(fourth)
+            ]
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn recalls_of_expired_messages_are_not_sent() {
+    bounded(async {
+        let server = Server::new().await;
+        let meta = MessageMeta {
+            receiver_id: DEVICE_ID,
+            duration: chrono::Duration::hours(1),
+            urgent: false,
+            not_before: None,
+        };
+        let created_at = Utc::now() - chrono::Duration::hours(2);
+        let content = MessageContent::new_text("expired").unwrap();
+        let expired = server
+            .db
+            .add_message(InsertMessage::new(meta, SenderID::Web, created_at, content))
+            .await
+            .unwrap();
+        server.db.delete_message(expired).await.unwrap();
+
+        let synced = TestDevice::new(CAPABILITIES).sync(&server).await;
+        assert_eq!(synced.shown, []);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn recall_is_pushed_to_a_waiting_device() {
+    bounded(async {
+        // Devices that wait are known to the whole server, so each test that pushes has a device of its own.
+        let device_id = DeviceID(0x1235);
+        let server = Server::with_device(device_id).await;
+        let id = server.add_text("recalled").await;
+        let mut shown = TestDevice::with_id(device_id, CAPABILITIES | Capabilities::PUSH)
+            .sync_pushed(&server)
+            .await;
+        assert_eq!(shown.recv().await, Some(text(id, "recalled")));
+
+        until_waiting(device_id).await;
+        server.db.delete_message(id).await.unwrap();
+        wake(device_id);
+        assert_eq!(shown.recv().await, Some(Shown::Recalled(id)));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn push_device_waits_on_the_same_connection() {
+    bounded(async {
+        let device_id = DeviceID(0x1236);
+        let server = Server::with_device(device_id).await;
+        let mut shown = TestDevice::with_id(device_id, CAPABILITIES | Capabilities::PUSH)
+            .sync_pushed(&server)
+            .await;
+
+        // Like the firmware, the device waits for updates once it is up to date instead of reconnecting.
+        until_waiting(device_id).await;
+        let id = server.add_text("pushed").await;
+        wake(device_id);
+        assert_eq!(shown.recv().await, Some(text(id, "pushed")));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn cursor_in_the_middle_skips_earlier_messages() {
+    bounded(async {
+        let server = Server::new().await;
+        server.add_text("first").await;
+        let second = server.add_text("second").await;
+        let third = server.add_text("third").await;
+
+        let mut device = TestDevice::new(CAPABILITIES).after(Some(second));
+        let synced = device.sync(&server).await;
+
+        assert_eq!(synced.shown, [text(third, "third")]);
+        assert_eq!(device.state.last_message_id, Some(third));
+    })
+    .await;
+}
+
+/// Damages the first payload that equals `corrupt`, as if it was corrupted on the way.
+struct CorruptingSocket {
+    socket: DuplexStream,
+    corrupt: Option<&'static [u8]>,
+}
+
+impl AbstractSocket for CorruptingSocket {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> std::result::Result<(), pico::Error> {
+        self.socket.read_exact(buf).await?;
+        if self.corrupt == Some(&*buf) {
+            buf[0] ^= 1;
+            self.corrupt = None;
+        }
+        Ok(())
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> std::result::Result<(), pico::Error> {
+        self.socket.write_all(buf).await
+    }
+}
+
+#[tokio::test]
+async fn nack_is_recorded_and_the_message_sent_again() {
+    bounded(async {
+        let mut server = Server::new().await;
+        let id = server.add_text("again").await;
+
+        let socket = CorruptingSocket {
+            socket: server.connect(),
+            corrupt: Some(b"again"),
+        };
+        let mut device = TestDevice::new(CAPABILITIES);
+        let synced = device.sync_on(socket).await;
+
+        assert_eq!(synced.shown, [text(id, "again")]);
+        assert_eq!(device.state.checksum_failures, 1);
+        let message = server.db.get_message(id).await.unwrap().unwrap();
+        assert_eq!(message.delivery.failed_attempts, 1);
+        assert_eq!(server.delivered(), [id]);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn garbage_before_the_hello_is_skipped() {
+    bounded(async {
+        let server = Server::new().await;
+        let id = server.add_text("hello").await;
+
+        let mut socket = server.connect();
+        socket.write_all(b"\x00\xffR\x13garbage").await.unwrap();
+        let synced = TestDevice::new(CAPABILITIES).sync_on(socket).await;
+
+        assert_eq!(synced.shown, [text(id, "hello")]);
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn undecodable_frame_closes_the_connection() {
+    bounded(async {
+        let server = Server::new().await;
+        let device = TestDevice::new(CAPABILITIES);
+        let mut socket = device.connect(server.connect()).await.unwrap().into_socket();
+
+        // There is no command with this discriminant.
+        let mut frame = FRAME_MAGIC.to_vec();
+        frame.extend_from_slice(&1u16.to_le_bytes());
+        frame.push(0x7f);
+        socket.write_all(&frame).await.unwrap();
+
+        assert_closed(&mut socket).await;
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn oversize_frame_closes_the_connection() {
+    bounded(async {
+        let server = Server::new().await;
+        let device = TestDevice::new(CAPABILITIES);
+        let mut socket = device.connect(server.connect()).await.unwrap().into_socket();
+
+        let mut frame = FRAME_MAGIC.to_vec();
+        frame.extend_from_slice(&u16::MAX.to_le_bytes());
+        socket.write_all(&frame).await.unwrap();
+
+        assert_closed(&mut socket).await;
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn device_hanging_up_ends_the_connection() {
+    bounded(async {
+        let server = Server::new().await;
+        let (mut device, socket) = tokio::io::duplex(IMAGE_BUFFER_SIZE);
+        let hello = Hello {
+            device_id: DEVICE_ID,
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: "1.0.0".parse().unwrap(),
+            capabilities: CAPABILITIES,
+        };
+        ClientCommand::Hello(hello).send_alloc(&mut device).await.unwrap();
+        drop(device);
+
+        // The server reads the hello, fails to answer it and returns.
+        handle_client(socket, server.peer, &*server.db, &server.delivered_tx).await;
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn requests_before_authenticating_are_refused() {
+    bounded(async {
+        let server = Server::new().await;
+        server.add_text("secret").await;
+
+        let mut socket = server.connect();
+        ClientCommand::RequestUpdate(DEVICE_ID, None)
+            .send_alloc(&mut socket)
+            .await
+            .unwrap();
+
+        assert_closed(&mut socket).await;
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn wrong_secret_is_denied() {
+    bounded(async {
+        let server = Server::new().await;
+        let mut device = TestDevice::new(CAPABILITIES);
+        device.identity.secret = [8; DEVICE_SECRET_LEN];
+
+        let result = device.connect(server.connect()).await;
+        assert!(matches!(result, Err(client::Error::AuthenticationDenied)));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn firmware_is_only_recorded_after_authenticating() {
+    bounded(async {
+        let server = Server::new().await;
+        TestDevice::new(CAPABILITIES).sync(&server).await;
+
+        let mut impostor = TestDevice::new(CAPABILITIES);
+        impostor.identity.secret = [8; DEVICE_SECRET_LEN];
+        impostor.identity.firmware_version = "0.1.0".parse().unwrap();
+        let result = impostor.connect(server.connect()).await;
+        assert!(matches!(result, Err(client::Error::AuthenticationDenied)));
+
+        let stored = server.db.get_device(DEVICE_ID).await.unwrap().unwrap();
+        assert_eq!(stored.firmware(), Some("1.0.0".parse().unwrap()));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn registered_device_without_secret_cannot_enroll() {
+    bounded(async {
+        let server = Server::new().await;
+        let old_id = DeviceID(0x5678);
+        server
+            .db
+            .add_device(Device::new(old_id, "Old device".to_string()))
+            .await
+            .unwrap();
+
+        // The server asks for proof of a secret it does not have, which nobody can give.
+        let result = TestDevice::with_id(old_id, CAPABILITIES)
+            .connect(server.connect())
+            .await;
+        assert!(matches!(result, Err(client::Error::AuthenticationDenied)));
+
+        // Enrolling anyways closes the connection.
+        let mut socket = server.connect();
+        let AuthChallenge::Challenge(_) = hello(&mut socket, old_id).await else {
+            panic!("registered devices must not enroll");
+        };
+        ClientCommand::Enroll(SECRET).send_alloc(&mut socket).await.unwrap();
+        assert_closed(&mut socket).await;
+        assert!(server.db.get_device_keys(old_id).await.unwrap().is_none());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn one_address_can_only_enroll_a_few_devices() {
+    bounded(async {
+        let server = Server::unregistered();
+        for i in 0..MAX_ENROLLMENTS_PER_PEER {
+            let device = TestDevice::with_id(DeviceID(0x2000 + i as u32), CAPABILITIES);
+            device.connect(server.connect()).await.unwrap();
+        }
+
+        let one_too_many = DeviceID(0x2fff);
+        let result = TestDevice::with_id(one_too_many, CAPABILITIES)
+            .connect(server.connect())
+            .await;
+        assert!(matches!(result, Err(client::Error::AuthenticationDenied)));
+        assert!(server.db.get_device_keys(one_too_many).await.unwrap().is_none());
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn device_enrolls_again_after_its_enrollment_was_reset() {
+    bounded(async {
+        let server = Server::unregistered();
+        let mut impostor = TestDevice::new(CAPABILITIES);
+        impostor.identity.secret = [8; DEVICE_SECRET_LEN];
+        let stolen_code = impostor.sync(&server).await.pairing_code;
+        assert!(stolen_code.is_some());
+
+        let result = TestDevice::new(CAPABILITIES).connect(server.connect()).await;
+        assert!(matches!(result, Err(client::Error::AuthenticationDenied)));
+
+        assert!(server.db.reset_pending_device(DEVICE_ID).await.unwrap());
+        assert!(!server.db.reset_pending_device(DEVICE_ID).await.unwrap());
+        // Nobody can claim the device with the code that the impostor saw.
+        let admin = User::new_telegram(UserId(1)).authorize();
+        let claim = server
+            .db
+            .claim_device(stolen_code.unwrap(), "Stolen".to_string(), admin, Utc::now())
+            .await;
+        assert!(claim.is_err());
+
+        let synced = TestDevice::new(CAPABILITIES).sync(&server).await;
+        assert!(synced.pairing_code.is_some());
+        assert_eq!(
+            server.db.get_device_keys(DEVICE_ID).await.unwrap(),
+            Some(DeviceKeys::new(SECRET))
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn enrollment_of_registered_device_is_not_reset() {
+    let server = Server::new().await;
+    let error = server.db.reset_pending_device(DEVICE_ID).await.unwrap_err();
+    assert!(matches!(
+        error.downcast_ref::<DbError>(),
+        Some(DbError::DeviceExists(_))
+    ));
+    assert!(server.db.get_device_keys(DEVICE_ID).await.unwrap().is_some());
+}
+
+fn test_status() -> DeviceStatus {
+    DeviceStatus {
+        uptime_sec: 3_600,
+        rssi: Some(-60),
+        free_text_slots: 3,
+        free_image_slots: 1,
+        checksum_failures: 2,
+        last_error: Some(DeviceError {
+            kind: DeviceErrorKind::ServerConnect,
+            uptime_sec: 60,
+        }),
+    }
+}
+
+#[tokio::test]
+async fn unregistered_device_enrolls_and_gets_a_pairing_code() {
+    bounded(async {
+        let server = Server::unregistered();
+
+        // Unregistered devices have nowhere to store their status, which does not keep them from getting a code.
+        let synced = TestDevice::new(CAPABILITIES)
+            .reporting(test_status())
+            .sync(&server)
+            .await;
+
+        let code = server.db.get_pairing_code(DEVICE_ID, Utc::now()).await.unwrap();
+        assert_eq!(synced.pairing_code, Some(code));
+        assert_eq!(
+            server.db.get_device_keys(DEVICE_ID).await.unwrap(),
+            Some(DeviceKeys::new(SECRET))
+        );
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn status_is_stored_with_the_time_it_was_seen() {
+    bounded(async {
+        let server = Server::new().await;
+        let before = Utc::now();
+        TestDevice::new(CAPABILITIES)
+            .reporting(test_status())
+            .sync(&server)
+            .await;
+
+        let stored = server.db.get_device(DEVICE_ID).await.unwrap().unwrap();
+        assert_eq!(stored.status(), Some(&test_status()));
+        assert!((before..=Utc::now()).contains(&stored.last_seen().unwrap()));
+        assert_eq!(stored.firmware(), Some("1.0.0".parse().unwrap()));
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn status_before_authenticating_is_refused() {
+    bounded(async {
+        let server = Server::new().await;
+        let mut socket = server.connect();
+        ClientCommand::Status(test_status())
+            .send_alloc(&mut socket)
+            .await
+            .unwrap();
+
+        assert_closed(&mut socket).await;
+        let stored = server.db.get_device(DEVICE_ID).await.unwrap().unwrap();
+        assert!(stored.status().is_none() && stored.last_seen().is_none());
+    })
+    .await;
+}
@@ -1,9 +1,12 @@
-use std::{error::Error, sync::Arc};
+use std::{error::Error, str::FromStr, sync::Arc};
 
 use anyhow::{anyhow, Context};
 use base64::{engine::general_purpose::STANDARD as B64, Engine as _};
-use chrono::{TimeDelta, Utc};
-use common::{protocols::web::MessageMeta, types::DeviceID};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeDelta, TimeZone, Timelike, Utc, Weekday};
+use common::{
+    protocols::web::{DeviceRight, MessageMeta, NewRecurringMessage, Recurrence},
+    types::{DeviceID, MessageID, PairingCode, RecurringID},
+};
 use serde::{Deserialize, Serialize};
 use teloxide::{
     dispatching::{
@@ -12,35 +15,65 @@ use teloxide::{
     },
     dptree,
     prelude::*,
-    types::{InlineKeyboardButton, InlineKeyboardMarkup, MaybeInaccessibleMessage, User},
-    utils::command::BotCommands,
+    types::{InlineKeyboardButton, InlineKeyboardMarkup, InputFile, MaybeInaccessibleMessage, MessageEntityKind, User},
+    utils::command::{BotCommands, ParseError},
     Bot,
 };
+use tokio::sync::mpsc;
+use uuid::Uuid;
 
 use crate::{
     db::{
+        access::{self, Grant},
         authorization::{AuthReply, AuthReplyChoice, AuthRequest},
-        device::Device,
-        message::{InsertMessage, MessageContent, SenderID},
-        user::User as DbUser,
-        Db,
+        device::{self, Device},
+        markup,
+        message::{self, InsertMessage, MessageContent, SenderID},
+        recurring::InsertRecurringMessage,
+        user::{Authorized, RawUser, User as DbUser},
+        Db, DbError,
     },
     error::Result,
+    handlers, schedule,
 };
 
+#[cfg(test)]
+mod tests;
+
 const ALLOWED_CALLBACK_DATA_LENGTH: usize = 64;
+/// Only the most recent messages are offered by /recall, to keep the keyboard usable.
+const RECALL_BUTTONS_MAX: usize = 10;
+const RECALL_PREVIEW_CHARS: usize = 24;
+/// How many days ahead /schedule offers.
+const SCHEDULE_DAYS: i64 = 7;
+const SCHEDULE_HOURS_PER_ROW: usize = 6;
+const SCHEDULE_MINUTES: [u32; 4] = [0, 15, 30, 45];
 
 #[derive(Debug, Clone, Default)]
 enum State {
     #[default]
     Unauthorized,
     Authorized,
-    ReceiveTarget,
+    /// Picking the time of a message sent with /schedule.
+    ReceiveSchedule,
+    ReceiveTarget {
+        options: SendOptions,
+    },
     ReceiveMessage {
         device: Device,
+        options: SendOptions,
     },
 }
 
+/// How the message is sent, depending on the command that started the dialogue.
+#[derive(Debug, Clone, Copy, Default)]
+struct SendOptions {
+    /// Sent with /urgent instead of /send.
+    urgent: bool,
+    /// Picked after /schedule.
+    not_before: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Clone, BotCommands)]
 #[command(rename_rule = "lowercase")]
 enum SimpleCommand {
@@ -55,8 +88,139 @@ enum SimpleCommand {
 enum AuthorizedCommand {
     #[command(description = "Send a message to a device")]
     Send,
+    #[command(description = "Send a message that interrupts the device until someone acknowledges it")]
+    Urgent,
+    #[command(description = "Send a message that is shown from a later time on")]
+    Schedule,
+    #[command(description = "Cancel one of your messages that is not shown yet")]
+    Scheduled,
+    #[command(
+        description = "Send a message again and again: /repeat <device id> <daily|mon|tue|...> <HH:MM> <text>",
+        parse_with = parse_repeat
+    )]
+    Repeat(DeviceID, Recurrence, String),
+    #[command(description = "Stop one of your recurring messages")]
+    Recurring,
     #[command(description = "Cancel the current operation")]
     Cancel,
+    #[command(description = "Create a token for the web API")]
+    Token,
+    #[command(
+        description = "Claim a new device by the code on its display: /claim <code> <name>",
+        parse_with = parse_code_and_name
+    )]
+    Claim(PairingCode, String),
+    #[command(description = "Recall one of your messages that is still shown")]
+    Recall,
+    #[command(description = "List the devices you can send messages to")]
+    Devices,
+    #[command(description = "List the authorized users and their IDs")]
+    Users,
+    #[command(description = "List who else may use one of your devices: /grants <device id>")]
+    Grants(DeviceID),
+    #[command(
+        description = "Let a user send to or manage one of your devices: /grant <device id> <user id> <send|manage>",
+        parse_with = parse_grant
+    )]
+    Grant(DeviceID, u64, DeviceRight),
+    #[command(
+        description = "Take away the right of a user on one of your devices: /revoke <device id> <user id>",
+        parse_with = "split"
+    )]
+    Revoke(DeviceID, u64),
+}
+
+// The variant names are the names of the commands.
+#[allow(clippy::enum_variant_names)]
+#[derive(Clone, BotCommands)]
+#[command(rename_rule = "snake_case")]
+enum AdminCommand {
+    #[command(description = "Register a device: /add_device <id> <name>", parse_with = parse_device_and_name)]
+    AddDevice(DeviceID, String),
+    #[command(description = "Rename a device: /rename_device <id> <name>", parse_with = parse_device_and_name)]
+    RenameDevice(DeviceID, String),
+    #[command(description = "Remove a device: /remove_device <id>")]
+    RemoveDevice(DeviceID),
+    #[command(description = "Create a new secret for a device and get the file to flash it: /rotate_key <id>")]
+    RotateKey(DeviceID),
+    #[command(description = "Show whether the devices are online and healthy.")]
+    Status,
+}
+
+/// Parse command arguments of the form `<id> <name>`, where the name may contain spaces.
+fn parse_device_and_name(input: String) -> std::result::Result<(DeviceID, String), ParseError> {
+    parse_key_and_name(input, "a device ID")
+}
+
+/// Parse command arguments of the form `<code> <name>`, where the name may contain spaces.
+fn parse_code_and_name(input: String) -> std::result::Result<(PairingCode, String), ParseError> {
+    parse_key_and_name(input, "a pairing code")
+}
+
+fn parse_key_and_name<K>(input: String, key: &str) -> std::result::Result<(K, String), ParseError>
+where
+    K: FromStr,
+    K::Err: Error + Send + Sync + 'static,
+{
+    let (k, name) = input
+        .trim()
+        .split_once(char::is_whitespace)
+        .ok_or_else(|| ParseError::Custom(format!("Expected {key} and a name.").into()))?;
+    let k = K::from_str(k).map_err(|e| ParseError::IncorrectFormat(e.into()))?;
+    Ok((k, name.to_string()))
+}
+
+fn right_name(right: DeviceRight) -> &'static str {
+    match right {
+        DeviceRight::Send => "send",
+        DeviceRight::Manage => "manage",
+    }
+}
+
+/// Parse command arguments of the form `<device id> <user id> <send|manage>`.
+fn parse_grant(input: String) -> std::result::Result<(DeviceID, u64, DeviceRight), ParseError> {
+    let args: Vec<_> = input.split_whitespace().collect();
+    let [id, user_id, right] = args[..] else {
+        return Err(ParseError::Custom(
+            "Expected a device ID, a user ID and either send or manage.".into(),
+        ));
+    };
+    let id = DeviceID::from_str(id).map_err(|e| ParseError::IncorrectFormat(e.into()))?;
+    let user_id = u64::from_str(user_id).map_err(|e| ParseError::IncorrectFormat(e.into()))?;
+    let right = [DeviceRight::Send, DeviceRight::Manage]
+        .into_iter()
+        .find(|r| right_name(*r) == right)
+        .ok_or_else(|| ParseError::Custom(format!("Unknown right '{right}', expected send or manage.").into()))?;
+    Ok((id, user_id, right))
+}
+
+/// Parse command arguments of the form `<device id> <daily|weekday> <HH:MM> <text>`, where the text may contain spaces.
+fn parse_repeat(input: String) -> std::result::Result<(DeviceID, Recurrence, String), ParseError> {
+    let args: Vec<_> = input.trim().splitn(4, char::is_whitespace).collect();
+    let [id, every, time, text] = args[..] else {
+        return Err(ParseError::Custom(
+            "Expected a device ID, daily or a weekday, a time like 18:30 and the text.".into(),
+        ));
+    };
+    let id = DeviceID::from_str(id).map_err(|e| ParseError::IncorrectFormat(e.into()))?;
+    let time = NaiveTime::parse_from_str(time, "%H:%M").map_err(|e| ParseError::IncorrectFormat(e.into()))?;
+    let recurrence = match every {
+        "daily" => Recurrence::Day { time },
+        weekday => Recurrence::Week {
+            weekday: Weekday::from_str(weekday).map_err(|_| {
+                ParseError::Custom(format!("Unknown day '{weekday}', expected daily or a weekday like tue.").into())
+            })?,
+            time,
+        },
+    };
+    Ok((id, recurrence, text.trim().to_string()))
+}
+
+fn recurrence_description(recurrence: Recurrence) -> String {
+    match recurrence {
+        Recurrence::Day { time } => format!("daily at {}", time.format("%H:%M")),
+        Recurrence::Week { weekday, time } => format!("every {weekday} at {}", time.format("%H:%M")),
+    }
 }
 
 // a.d. TODO dependencies need to be clone-able. If this is not in the teloxide docs, add it.
@@ -69,6 +233,17 @@ struct Config {
 enum CallbackData {
     Auth(AuthReply),
     Target(DeviceID),
+    Recall(MessageID),
+    Schedule(SchedulePick),
+    StopRecurring(RecurringID),
+}
+
+/// The steps of picking the time of a scheduled message, in the local time of the server.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum SchedulePick {
+    Day(NaiveDate),
+    Hour(NaiveDate, u32),
+    Time(NaiveDateTime),
 }
 
 impl CallbackData {
@@ -97,16 +272,18 @@ async fn reset_dialogue(state: State, dialogue: MyDialogue, user: User) -> Handl
         State::Unauthorized => {
             log::warn!("Trying to reset dialogue of unauthorized user: {user:?}");
         }
-        State::Authorized | State::ReceiveTarget | State::ReceiveMessage { .. } => {
+        State::Authorized | State::ReceiveSchedule | State::ReceiveTarget { .. } | State::ReceiveMessage { .. } => {
             dialogue.update(State::Authorized).await?;
         }
     }
     Ok(())
 }
 
-pub async fn run(db: Arc<dyn Db>) {
+/// `delivered` yields messages when they reached their device, of which we tell their senders.
+pub async fn run(db: Arc<dyn Db>, delivered: mpsc::UnboundedReceiver<message::Message>) {
     log::info!("Starting Telegram bot.");
     let bot = Bot::from_env();
+    tokio::spawn(notify_delivered(bot.clone(), db.clone(), delivered));
 
     let config = Config {
         admin_id: db.get_telegram_admin_id().await,
@@ -129,10 +306,43 @@ pub async fn run(db: Arc<dyn Db>) {
         .await;
 }
 
+/// Tell the authors of messages that their message is now shown on the device.
+async fn notify_delivered(bot: Bot, db: Arc<dyn Db>, mut delivered: mpsc::UnboundedReceiver<message::Message>) {
+    while let Some(message) = delivered.recv().await {
+        let Some(RawUser::Telegram { id }) = message.author else {
+            continue;
+        };
+        let device = match db.get_device(message.meta.receiver_id).await {
+            Ok(Some(device)) => device.to_string(),
+            Ok(None) => message.meta.receiver_id.to_string(),
+            Err(e) => {
+                log::error!("Looking up receiver of message {} failed: {e:#}", message.id);
+                continue;
+            }
+        };
+        let text = format!(
+            "Your message \"{}\" is now shown on {device}.",
+            message.preview(RECALL_PREVIEW_CHARS)
+        );
+        if let Err(e) = bot.send_message(ChatId::from(id), text).await {
+            log::error!(
+                "Notifying {id} about the delivery of message {} failed: {e}",
+                message.id
+            );
+        }
+    }
+}
+
 fn schema() -> UpdateHandler<Box<dyn Error + Send + Sync + 'static>> {
     use dptree::case;
 
     let command_handler = dptree::entry()
+        // Admin commands are only handled for the admin, independent of the dialogue state.
+        .branch(
+            teloxide::filter_command::<AdminCommand, _>()
+                .filter(|config: Config, user: User| config.admin_id == user.id)
+                .endpoint(handle_admin_command),
+        )
         // Simple command are always handled.
         .branch(
             teloxide::filter_command::<SimpleCommand, _>()
@@ -142,24 +352,43 @@ fn schema() -> UpdateHandler<Box<dyn Error + Send + Sync + 'static>> {
         // Authorized command handling depends on the current state of the dialogue.
         .branch(
             teloxide::filter_command::<AuthorizedCommand, _>()
-                // The /send command is only handled for clients in the Authorized state.
+                // The commands that send a message are only handled for clients in the Authorized state.
                 // For example, to prevent sending multiple messages at once.
-                .branch(case![State::Authorized].branch(case![AuthorizedCommand::Send].endpoint(send)))
+                .branch(
+                    case![State::Authorized]
+                        .branch(case![AuthorizedCommand::Send].endpoint(send))
+                        .branch(case![AuthorizedCommand::Urgent].endpoint(send_urgent))
+                        .branch(case![AuthorizedCommand::Schedule].endpoint(send_scheduled)),
+                )
                 // The /cancel command is only handled after authorization.
                 // (Not strictly necessary but I wanted to see how to implement that.)
                 .branch(
                     dptree::entry()
                         .filter(|state: State| match state {
                             State::Unauthorized => false,
-                            State::Authorized | State::ReceiveTarget | State::ReceiveMessage { .. } => true,
+                            State::Authorized
+                            | State::ReceiveSchedule
+                            | State::ReceiveTarget { .. }
+                            | State::ReceiveMessage { .. } => true,
                         })
-                        .branch(case![AuthorizedCommand::Cancel].endpoint(cancel)),
+                        .branch(case![AuthorizedCommand::Cancel].endpoint(cancel))
+                        .branch(case![AuthorizedCommand::Token].endpoint(token))
+                        .branch(case![AuthorizedCommand::Claim(code, name)].endpoint(claim))
+                        .branch(case![AuthorizedCommand::Recall].endpoint(recall))
+                        .branch(case![AuthorizedCommand::Scheduled].endpoint(scheduled))
+                        .branch(case![AuthorizedCommand::Repeat(id, recurrence, text)].endpoint(repeat))
+                        .branch(case![AuthorizedCommand::Recurring].endpoint(recurring))
+                        .branch(case![AuthorizedCommand::Devices].endpoint(devices))
+                        .branch(case![AuthorizedCommand::Users].endpoint(users))
+                        .branch(case![AuthorizedCommand::Grants(id)].endpoint(grants))
+                        .branch(case![AuthorizedCommand::Grant(id, user_id, right)].endpoint(grant))
+                        .branch(case![AuthorizedCommand::Revoke(id, user_id)].endpoint(revoke)),
                 ),
         );
 
     let message_handler = Update::filter_message()
         .branch(command_handler)
-        .branch(case![State::ReceiveMessage { device }].endpoint(receive_message))
+        .branch(case![State::ReceiveMessage { device, options }].endpoint(receive_message))
         .branch(dptree::endpoint(invalid_state));
 
     let callback_query_handler = Update::filter_callback_query()
@@ -173,10 +402,29 @@ fn schema() -> UpdateHandler<Box<dyn Error + Send + Sync + 'static>> {
         )
         // Other CallbackQueries
         .branch(
-            case![State::ReceiveTarget]
+            case![State::ReceiveSchedule]
+                .filter_map(|q: CallbackQuery| CallbackData::deserialize(&q.data.unwrap_or_default()).ok())
+                .chain(case![CallbackData::Schedule(pick)])
+                .endpoint(handle_schedule_callback),
+        )
+        .branch(
+            case![State::ReceiveTarget { options }]
                 .filter_map(|q: CallbackQuery| CallbackData::deserialize(&q.data.unwrap_or_default()).ok())
                 .chain(case![CallbackData::Target(device_id)])
                 .endpoint(handle_target_callback),
+        )
+        // Recalls are checked against the author of the message, independent of the dialogue state.
+        .branch(
+            dptree::entry()
+                .filter_map(|q: CallbackQuery| CallbackData::deserialize(&q.data.unwrap_or_default()).ok())
+                .chain(case![CallbackData::Recall(message_id)])
+                .endpoint(handle_recall_callback),
+        )
+        .branch(
+            dptree::entry()
+                .filter_map(|q: CallbackQuery| CallbackData::deserialize(&q.data.unwrap_or_default()).ok())
+                .chain(case![CallbackData::StopRecurring(id)])
+                .endpoint(handle_stop_recurring_callback),
         );
 
     dialogue::enter::<Update, InMemStorage<State>, State, _>()
@@ -197,7 +445,7 @@ async fn send_auth_request(bot: &Bot, db: &dyn Db, requester: User) -> HandlerRe
 
     let auth_request = AuthRequest::new(&requester);
     let auth_request_id = auth_request.id();
-    db.add_auth_request(auth_request).await;
+    db.add_auth_request(auth_request).await?;
 
     let mut answers = Vec::new();
     for choice in [AuthReplyChoice::Accept, AuthReplyChoice::Deny] {
@@ -219,7 +467,7 @@ async fn start(bot: Bot, state: State, db: Arc<dyn Db>, dialogue: MyDialogue, us
     bot.send_message(dialogue.chat_id(), format!("You are in state {state:?}"))
         .await?;
     let dbuser = DbUser::new_telegram(user.id);
-    if db.is_user_authorized(dbuser.raw()).await.is_some() {
+    if db.is_user_authorized(dbuser.raw()).await?.is_some() {
         bot.send_message(
             dialogue.chat_id(),
             "You are authorized. Use /send command to send a message to someone.",
@@ -235,50 +483,700 @@ async fn start(bot: Bot, state: State, db: Arc<dyn Db>, dialogue: MyDialogue, us
     Ok(())
 }
 
-async fn send(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue) -> HandlerResult {
+/// The database user of `user`, or `None` after telling them that they are not authorized anymore.
+async fn authorized_user(
+    bot: &Bot,
+    db: &dyn Db,
+    dialogue: &MyDialogue,
+    user: &User,
+) -> Result<Option<DbUser<Authorized>>> {
+    let dbuser = db.is_user_authorized(DbUser::new_telegram(user.id).raw()).await?;
+    if dbuser.is_none() {
+        bot.send_message(dialogue.chat_id(), "You are not authorized anymore.")
+            .await?;
+    }
+    Ok(dbuser)
+}
+
+async fn token(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User) -> HandlerResult {
+    let Some(dbuser) = authorized_user(&bot, db.as_ref(), &dialogue, &user).await? else {
+        return Ok(());
+    };
+
+    let token = Uuid::new_v4();
+    db.add_api_token(token, dbuser).await?;
+    bot.send_message(
+        dialogue.chat_id(),
+        format!("Your new web API token is\n\n{token}\n\nSend it in the header \"Authorization: Bearer <token>\"."),
+    )
+    .await?;
+    Ok(())
+}
+
+async fn claim(
+    bot: Bot,
+    db: Arc<dyn Db>,
+    dialogue: MyDialogue,
+    user: User,
+    (code, name): (PairingCode, String),
+) -> HandlerResult {
+    let Some(dbuser) = authorized_user(&bot, db.as_ref(), &dialogue, &user).await? else {
+        return Ok(());
+    };
+
+    let reply = match device::validate_name(&name) {
+        Ok(name) => db_reply(db.claim_device(code, name, dbuser, Utc::now()).await, |device| {
+            format!("You are now the owner of {device}.")
+        })?,
+        Err(e) => e.to_string(),
+    };
+    bot.send_message(dialogue.chat_id(), reply).await?;
+    Ok(())
+}
+
+/// How we refer to `user` in replies, with the ID that the commands take.
+async fn user_label(db: &dyn Db, user: RawUser) -> Result<String> {
+    let id = match user {
+        RawUser::Telegram { id } => id,
+    };
+    Ok(match db.get_user_name(user).await? {
+        Some(name) => format!("{name} ({id})"),
+        None => id.to_string(),
+    })
+}
+
+/// The device `id` if `user` has at least `right` on it, otherwise the reply explaining why not.
+async fn device_with_right(
+    db: &dyn Db,
+    user: &DbUser<Authorized>,
+    id: DeviceID,
+    right: DeviceRight,
+) -> Result<std::result::Result<Device, String>> {
+    let Some(device) = db.get_device(id).await? else {
+        return Ok(Err(DbError::DeviceNotFound(id).to_string()));
+    };
+    match access::device_right(db, user, &device).await? {
+        Some(granted) if granted >= right => Ok(Ok(device)),
+        _ => Ok(Err(format!("You may not {} {device}.", right_description(right)))),
+    }
+}
+
+fn right_description(right: DeviceRight) -> &'static str {
+    match right {
+        DeviceRight::Send => "send messages to",
+        DeviceRight::Manage => "manage",
+    }
+}
+
+async fn devices(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User) -> HandlerResult {
+    let Some(dbuser) = authorized_user(&bot, db.as_ref(), &dialogue, &user).await? else {
+        return Ok(());
+    };
+
+    let mut lines = Vec::new();
+    for device in access::devices_with_right(db.as_ref(), &dbuser, DeviceRight::Send).await? {
+        let role = if device.owner() == Some(dbuser.raw()) {
+            "owner"
+        } else {
+            // PANIC: unwrap cannot fail since we only got devices with at least the send right.
+            right_name(access::device_right(db.as_ref(), &dbuser, &device).await?.unwrap())
+        };
+        let mut line = format!("{device}: {role}");
+        if let Some(firmware) = device.firmware() {
+            line.push_str(&format!(", firmware {firmware}"));
+        }
+        if handlers::device::is_outdated(&device) {
+            line.push_str(" (outdated)");
+        }
+        lines.push(line);
+    }
+    let reply = if lines.is_empty() {
+        "There are no devices you can send messages to.".to_string()
+    } else {
+        lines.join("\n")
+    };
+    bot.send_message(dialogue.chat_id(), reply).await?;
+    Ok(())
+}
+
+async fn users(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User) -> HandlerResult {
+    if authorized_user(&bot, db.as_ref(), &dialogue, &user).await?.is_none() {
+        return Ok(());
+    }
+
+    let mut lines = Vec::new();
+    for user in db.get_authorized_users().await? {
+        lines.push(user_label(db.as_ref(), user.raw()).await?);
+    }
+    bot.send_message(dialogue.chat_id(), lines.join("\n")).await?;
+    Ok(())
+}
+
+async fn grants(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User, id: DeviceID) -> HandlerResult {
+    let Some(dbuser) = authorized_user(&bot, db.as_ref(), &dialogue, &user).await? else {
+        return Ok(());
+    };
+
+    let reply = match device_with_right(db.as_ref(), &dbuser, id, DeviceRight::Manage).await? {
+        Ok(device) => {
+            let mut lines = Vec::new();
+            if let Some(owner) = device.owner() {
+                lines.push(format!("{}: owner", user_label(db.as_ref(), owner).await?));
+            }
+            for grant in db.get_device_grants(id).await? {
+                let label = user_label(db.as_ref(), grant.user).await?;
+                lines.push(format!("{label}: {}", right_name(grant.right)));
+            }
+            if lines.is_empty() {
+                format!("Only the admin may use {device}.")
+            } else {
+                lines.join("\n")
+            }
+        }
+        Err(reply) => reply,
+    };
+    bot.send_message(dialogue.chat_id(), reply).await?;
+    Ok(())
+}
+
+async fn grant(
+    bot: Bot,
+    db: Arc<dyn Db>,
+    dialogue: MyDialogue,
+    user: User,
+    (id, user_id, right): (DeviceID, u64, DeviceRight),
+) -> HandlerResult {
+    let Some(dbuser) = authorized_user(&bot, db.as_ref(), &dialogue, &user).await? else {
+        return Ok(());
+    };
+
+    let grantee = DbUser::new_telegram(UserId(user_id)).raw();
+    let reply = match device_with_right(db.as_ref(), &dbuser, id, DeviceRight::Manage).await? {
+        Ok(_) if db.is_user_authorized(grantee).await?.is_none() => {
+            format!("User {user_id} is not authorized. Use /users to see who is.")
+        }
+        Ok(device) => {
+            let label = user_label(db.as_ref(), grantee).await?;
+            let grant = Grant {
+                device_id: id,
+                user: grantee,
+                right,
+            };
+            db_reply(db.set_grant(grant).await, |()| {
+                format!("{label} may now {} {device}.", right_description(right))
+            })?
+        }
+        Err(reply) => reply,
+    };
+    bot.send_message(dialogue.chat_id(), reply).await?;
+    Ok(())
+}
+
+async fn revoke(
+    bot: Bot,
+    db: Arc<dyn Db>,
+    dialogue: MyDialogue,
+    user: User,
+    (id, user_id): (DeviceID, u64),
+) -> HandlerResult {
+    let Some(dbuser) = authorized_user(&bot, db.as_ref(), &dialogue, &user).await? else {
+        return Ok(());
+    };
+
+    let grantee = DbUser::new_telegram(UserId(user_id)).raw();
+    let reply = match device_with_right(db.as_ref(), &dbuser, id, DeviceRight::Manage).await? {
+        Ok(device) => {
+            let label = user_label(db.as_ref(), grantee).await?;
+            if db.remove_grant(id, grantee).await? {
+                format!("{label} may not use {device} anymore.")
+            } else {
+                format!("{label} had no right on {device}.")
+            }
+        }
+        Err(reply) => reply,
+    };
+    bot.send_message(dialogue.chat_id(), reply).await?;
+    Ok(())
+}
+
+async fn handle_admin_command(bot: Bot, db: Arc<dyn Db>, cmd: AdminCommand, msg: Message) -> HandlerResult {
+    let reply = match cmd {
+        AdminCommand::AddDevice(id, name) => match device::validate_name(&name) {
+            Ok(name) => {
+                let device = Device::new(id, name);
+                db_reply(db.add_device(device.clone()).await, |()| {
+                    format!("Added device {device}.")
+                })?
+            }
+            Err(e) => e.to_string(),
+        },
+        AdminCommand::RenameDevice(id, name) => match device::validate_name(&name) {
+            Ok(name) => db_reply(db.rename_device(id, name).await, |device| {
+                format!("Renamed device to {device}.")
+            })?,
+            Err(e) => e.to_string(),
+        },
+        AdminCommand::RemoveDevice(id) => {
+            db_reply(db.remove_device(id).await, |device| format!("Removed device {device}."))?
+        }
+        AdminCommand::RotateKey(id) => match db.get_device(id).await? {
+            Some(device) => {
+                let file = handlers::device::rotate_key(db.as_ref(), &device).await?;
+                let file = InputFile::memory(file).file_name(format!("device-{id}.uf2"));
+                let caption =
+                    format!("New secret for {device}. Flash this file, the device can use its old secret until then.");
+                bot.send_document(msg.chat.id, file).caption(caption).await?;
+                return Ok(());
+            }
+            None => DbError::DeviceNotFound(id).to_string(),
+        },
+        AdminCommand::Status => {
+            let devices = db.get_devices().await?;
+            if devices.is_empty() {
+                "There are no devices.".to_string()
+            } else {
+                devices.iter().map(status_line).collect::<Vec<_>>().join("\n\n")
+            }
+        }
+    };
+    bot.send_message(msg.chat.id, reply).await?;
+    Ok(())
+}
+
+/// A summary of whether `device` is online and what it reported about its health.
+fn status_line(device: &Device) -> String {
+    let mut line = format!("{device}: ");
+    line.push_str(if handlers::device::is_connected(device.id()) {
+        "connected"
+    } else {
+        "not connected"
+    });
+    match device.last_seen() {
+        Some(last_seen) => line.push_str(&format!(", last seen {}", last_seen.format("%Y-%m-%d %H:%M UTC"))),
+        None => line.push_str(", never seen"),
+    }
+    if let Some(firmware) = device.firmware() {
+        line.push_str(&format!(", firmware {firmware}"));
+        if handlers::device::is_outdated(device) {
+            line.push_str(" (outdated)");
+        }
+    }
+    if let Some(status) = device.status() {
+        line.push_str(&format!("\nUp {}", format_duration(status.uptime_sec)));
+        if let Some(rssi) = status.rssi {
+            line.push_str(&format!(", WiFi {rssi} dBm"));
+        }
+        line.push_str(&format!(
+            ", {} text and {} image slots free",
+            status.free_text_slots, status.free_image_slots
+        ));
+        if status.checksum_failures > 0 {
+            line.push_str(&format!(", {} corrupted transfers", status.checksum_failures));
+        }
+        if let Some(error) = status.last_error {
+            line.push_str(&format!(
+                "\nLast error: {} after {} uptime",
+                error.kind,
+                format_duration(error.uptime_sec)
+            ));
+        }
+    }
+    line
+}
+
+/// Format seconds as days, hours and minutes, e.g. `2d 3h 4m`.
+fn format_duration(seconds: u32) -> String {
+    let (days, hours, minutes) = (seconds / 86_400, seconds / 3_600 % 24, seconds / 60 % 60);
+    if days > 0 {
+        format!("{days}d {hours}h {minutes}m")
+    } else if hours > 0 {
+        format!("{hours}h {minutes}m")
+    } else {
+        format!("{minutes}m")
+    }
+}
+
+/// Turn the result of a [`Db`] call into a reply, showing [`DbError`]s to the user and passing on other errors.
+fn db_reply<T>(result: Result<T>, ok: impl FnOnce(T) -> String) -> Result<String> {
+    match result {
+        Ok(value) => Ok(ok(value)),
+        Err(e) => match e.downcast_ref::<DbError>() {
+            Some(db_error) => Ok(db_error.to_string()),
+            None => Err(e),
+        },
+    }
+}
+
+async fn send(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User) -> HandlerResult {
+    select_target(bot, db, dialogue, user, SendOptions::default()).await
+}
+
+async fn send_urgent(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User) -> HandlerResult {
+    let options = SendOptions {
+        urgent: true,
+        ..SendOptions::default()
+    };
+    select_target(bot, db, dialogue, user, options).await
+}
+
+/// Ask for the day of the message, the device is selected once the time is picked.
+async fn send_scheduled(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User) -> HandlerResult {
+    if authorized_user(&bot, db.as_ref(), &dialogue, &user).await?.is_none() {
+        return Ok(());
+    }
+
+    let today = Local::now().date_naive();
+    let mut days = Vec::new();
+    for offset in 0..SCHEDULE_DAYS {
+        let day = today + TimeDelta::days(offset);
+        let label = match offset {
+            0 => "Today".to_string(),
+            1 => "Tomorrow".to_string(),
+            _ => day.format("%A, %b %-d").to_string(),
+        };
+        let serialized = CallbackData::Schedule(SchedulePick::Day(day)).serialize()?;
+        days.push([InlineKeyboardButton::callback(label, serialized)]);
+    }
+    bot.send_message(dialogue.chat_id(), "When should the message be shown?")
+        .reply_markup(InlineKeyboardMarkup::new(days))
+        .await?;
+    dialogue.update(State::ReceiveSchedule).await?;
+    Ok(())
+}
+
+/// The hours of `day` that have not passed at `now`.
+fn hour_buttons(day: NaiveDate, now: NaiveDateTime) -> Result<Vec<Vec<InlineKeyboardButton>>> {
+    let first_hour = if day == now.date() { now.hour() } else { 0 };
+    let mut buttons = Vec::new();
+    for hour in first_hour..24 {
+        let serialized = CallbackData::Schedule(SchedulePick::Hour(day, hour)).serialize()?;
+        buttons.push(InlineKeyboardButton::callback(format!("{hour:02}:00"), serialized));
+    }
+    Ok(buttons.chunks(SCHEDULE_HOURS_PER_ROW).map(<[_]>::to_vec).collect())
+}
+
+fn minute_buttons(day: NaiveDate, hour: u32) -> Result<Vec<InlineKeyboardButton>> {
+    let mut buttons = Vec::new();
+    for minute in SCHEDULE_MINUTES {
+        let time = NaiveTime::from_hms_opt(hour, minute, 0).context("invalid hour picked")?;
+        let serialized = CallbackData::Schedule(SchedulePick::Time(day.and_time(time))).serialize()?;
+        buttons.push(InlineKeyboardButton::callback(
+            format!("{hour:02}:{minute:02}"),
+            serialized,
+        ));
+    }
+    Ok(buttons)
+}
+
+async fn handle_schedule_callback(
+    bot: Bot,
+    db: Arc<dyn Db>,
+    dialogue: MyDialogue,
+    pick: SchedulePick,
+    user: User,
+    q: CallbackQuery,
+) -> HandlerResult {
+    bot.answer_callback_query(q.id).await?;
+    let Some(MaybeInaccessibleMessage::Regular(message)) = q.message else {
+        log::warn!("Source message of callback not available. User {:?}", user);
+        bot.send_message(dialogue.chat_id(), "Internal error. Resetting.")
+            .await?;
+        dialogue.update(State::Authorized).await?;
+        return Ok(());
+    };
+
+    let buttons = match pick {
+        SchedulePick::Day(day) => hour_buttons(day, Local::now().naive_local())?,
+        SchedulePick::Hour(day, hour) => vec![minute_buttons(day, hour)?],
+        SchedulePick::Time(time) => return schedule_at(bot, db, dialogue, user, message.id, time).await,
+    };
+    bot.edit_message_text(dialogue.chat_id(), message.id, "At what time?")
+        .reply_markup(InlineKeyboardMarkup::new(buttons))
+        .await?;
+    Ok(())
+}
+
+/// Continue with selecting the device once the time of the message is picked.
+async fn schedule_at(
+    bot: Bot,
+    db: Arc<dyn Db>,
+    dialogue: MyDialogue,
+    user: User,
+    message_id: teloxide::types::MessageId,
+    time: NaiveDateTime,
+) -> HandlerResult {
+    // Times that are skipped when the clocks are put forward do not exist.
+    let reply = match Local.from_local_datetime(&time).earliest() {
+        Some(local) if local > Local::now() => {
+            bot.edit_message_text(
+                dialogue.chat_id(),
+                message_id,
+                format!("The message will be shown at {}.", time.format("%Y-%m-%d %H:%M")),
+            )
+            .await?;
+            let options = SendOptions {
+                not_before: Some(local.to_utc()),
+                ..SendOptions::default()
+            };
+            return select_target(bot, db, dialogue, user, options).await;
+        }
+        Some(_) => "This time has passed already.",
+        None => "This time does not exist because the clocks are changed.",
+    };
+    bot.edit_message_text(dialogue.chat_id(), message_id, reply).await?;
+    dialogue.update(State::Authorized).await?;
+    Ok(())
+}
+
+/// Ask for the device to send the next message to.
+async fn select_target(
+    bot: Bot,
+    db: Arc<dyn Db>,
+    dialogue: MyDialogue,
+    user: User,
+    options: SendOptions,
+) -> HandlerResult {
+    let Some(dbuser) = authorized_user(&bot, db.as_ref(), &dialogue, &user).await? else {
+        return Ok(());
+    };
+
     let mut devices = Vec::new();
-    for device in db.get_devices().await {
+    for device in access::devices_with_right(db.as_ref(), &dbuser, DeviceRight::Send).await? {
         let callback_data = CallbackData::Target(device.id());
         let serialized = callback_data.serialize()?;
         devices.push([InlineKeyboardButton::callback(device.to_string(), serialized)]);
     }
+    if devices.is_empty() {
+        bot.send_message(dialogue.chat_id(), "There are no devices to send messages to.")
+            .await?;
+        // We might come from picking the time of a scheduled message.
+        dialogue.update(State::Authorized).await?;
+        return Ok(());
+    }
     bot.send_message(dialogue.chat_id(), "Select target device:")
         .reply_markup(InlineKeyboardMarkup::new(devices))
         .await?;
-    dialogue.update(State::ReceiveTarget).await?;
+    dialogue.update(State::ReceiveTarget { options }).await?;
     Ok(())
 }
 
+async fn recall(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User) -> HandlerResult {
+    let author = DbUser::new_telegram(user.id).raw();
+    let messages = db.get_active_messages_by(author, Utc::now()).await?;
+    if messages.is_empty() {
+        bot.send_message(dialogue.chat_id(), "You have no messages that are still shown.")
+            .await?;
+        return Ok(());
+    }
+
+    let mut buttons = Vec::new();
+    for message in messages.iter().take(RECALL_BUTTONS_MAX) {
+        let device = match db.get_device(message.meta.receiver_id).await? {
+            Some(device) => device.name().to_string(),
+            None => message.meta.receiver_id.to_string(),
+        };
+        let label = format!("{device}: {}", message.preview(RECALL_PREVIEW_CHARS));
+        let serialized = CallbackData::Recall(message.id).serialize()?;
+        buttons.push([InlineKeyboardButton::callback(label, serialized)]);
+    }
+    bot.send_message(dialogue.chat_id(), "Select the message to recall:")
+        .reply_markup(InlineKeyboardMarkup::new(buttons))
+        .await?;
+    Ok(())
+}
+
+async fn scheduled(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User) -> HandlerResult {
+    let author = DbUser::new_telegram(user.id).raw();
+    let messages: Vec<_> = db
+        .get_scheduled_messages()
+        .await?
+        .into_iter()
+        .filter(|message| message.author == Some(author))
+        .collect();
+    if messages.is_empty() {
+        bot.send_message(dialogue.chat_id(), "You have no scheduled messages.")
+            .await?;
+        return Ok(());
+    }
+
+    let mut buttons = Vec::new();
+    for message in messages.iter().take(RECALL_BUTTONS_MAX) {
+        let device = match db.get_device(message.meta.receiver_id).await? {
+            Some(device) => device.name().to_string(),
+            None => message.meta.receiver_id.to_string(),
+        };
+        let shown_from = message.shown_from().with_timezone(&Local).format("%b %-d %H:%M");
+        let label = format!("{device} {shown_from}: {}", message.preview(RECALL_PREVIEW_CHARS));
+        let serialized = CallbackData::Recall(message.id).serialize()?;
+        buttons.push([InlineKeyboardButton::callback(label, serialized)]);
+    }
+    bot.send_message(dialogue.chat_id(), "Select the message to cancel:")
+        .reply_markup(InlineKeyboardMarkup::new(buttons))
+        .await?;
+    Ok(())
+}
+
+async fn repeat(
+    bot: Bot,
+    db: Arc<dyn Db>,
+    dialogue: MyDialogue,
+    user: User,
+    (id, recurrence, text): (DeviceID, Recurrence, String),
+) -> HandlerResult {
+    let Some(dbuser) = authorized_user(&bot, db.as_ref(), &dialogue, &user).await? else {
+        return Ok(());
+    };
+
+    let reply = match device_with_right(db.as_ref(), &dbuser, id, DeviceRight::Send).await? {
+        Ok(device) => {
+            let new_message = NewRecurringMessage {
+                receiver_id: id,
+                text,
+                recurrence,
+                duration: TimeDelta::days(1),
+                urgent: false,
+            };
+            match InsertRecurringMessage::new(new_message, SenderID::Telegram, Utc::now()) {
+                Ok(message) => {
+                    let next_at = message.next_at.with_timezone(&Local).format("%b %-d %H:%M");
+                    db.add_recurring_message(message.with_author(Some(dbuser.raw())))
+                        .await?;
+                    schedule::wake();
+                    format!(
+                        "{device} gets the message {}, next on {next_at}.",
+                        recurrence_description(recurrence)
+                    )
+                }
+                Err(e) => e.to_string(),
+            }
+        }
+        Err(reply) => reply,
+    };
+    bot.send_message(dialogue.chat_id(), reply).await?;
+    Ok(())
+}
+
+async fn recurring(bot: Bot, db: Arc<dyn Db>, dialogue: MyDialogue, user: User) -> HandlerResult {
+    let author = DbUser::new_telegram(user.id).raw();
+    let messages: Vec<_> = db
+        .get_recurring_messages()
+        .await?
+        .into_iter()
+        .filter(|message| message.author == Some(author))
+        .collect();
+    if messages.is_empty() {
+        bot.send_message(dialogue.chat_id(), "You have no recurring messages.")
+            .await?;
+        return Ok(());
+    }
+
+    let mut buttons = Vec::new();
+    for message in &messages {
+        let device = match db.get_device(message.receiver_id).await? {
+            Some(device) => device.name().to_string(),
+            None => message.receiver_id.to_string(),
+        };
+        let label = format!(
+            "{device} {}: {}",
+            recurrence_description(message.recurrence),
+            message.preview(RECALL_PREVIEW_CHARS)
+        );
+        let serialized = CallbackData::StopRecurring(message.id).serialize()?;
+        buttons.push([InlineKeyboardButton::callback(label, serialized)]);
+    }
+    bot.send_message(dialogue.chat_id(), "Select the message to stop:")
+        .reply_markup(InlineKeyboardMarkup::new(buttons))
+        .await?;
+    Ok(())
+}
+
+async fn handle_stop_recurring_callback(bot: Bot, db: Arc<dyn Db>, id: RecurringID, q: CallbackQuery) -> HandlerResult {
+    bot.answer_callback_query(q.id).await?;
+
+    let author = DbUser::new_telegram(q.from.id).raw();
+    let reply = match db.get_recurring_message(id).await? {
+        Some(message) if message.author == Some(author) => db_reply(db.delete_recurring_message(id).await, |_| {
+            "Recurring message was stopped.".to_string()
+        })?,
+        Some(_) => "You can only stop your own recurring messages.".to_string(),
+        None => "The recurring message was stopped already.".to_string(),
+    };
+
+    if let Some(MaybeInaccessibleMessage::Regular(message)) = q.message {
+        bot.edit_message_text(q.from.id, message.id, reply).await?;
+    } else {
+        bot.send_message(q.from.id, reply).await?;
+    }
+    Ok(())
+}
+
+async fn handle_recall_callback(bot: Bot, db: Arc<dyn Db>, message_id: MessageID, q: CallbackQuery) -> HandlerResult {
+    bot.answer_callback_query(q.id).await?;
+
+    let author = DbUser::new_telegram(q.from.id).raw();
+    let reply = match db.get_message(message_id).await? {
+        Some(message) if message.author == Some(author) => db_reply(db.delete_message(message_id).await, |_| {
+            if message.scheduled {
+                "Scheduled message was cancelled.".to_string()
+            } else {
+                handlers::device::wake(message.meta.receiver_id);
+                "Message was recalled.".to_string()
+            }
+        })?,
+        Some(_) => "You can only recall your own messages.".to_string(),
+        None => "The message has expired or was recalled already.".to_string(),
+    };
+
+    if let Some(MaybeInaccessibleMessage::Regular(message)) = q.message {
+        bot.edit_message_text(q.from.id, message.id, reply).await?;
+    } else {
+        bot.send_message(q.from.id, reply).await?;
+    }
+    Ok(())
+}
+
+// The arguments are injected by the dispatcher.
+#[allow(clippy::too_many_arguments)]
 async fn handle_target_callback(
     bot: Bot,
     db: Arc<dyn Db>,
     state: State,
     dialogue: MyDialogue,
     target_id: DeviceID,
+    options: SendOptions,
     user: User,
     q: CallbackQuery,
 ) -> HandlerResult {
     bot.answer_callback_query(q.id).await?;
 
-    if let Some(device) = db.get_device(target_id).await {
-        if let Some(MaybeInaccessibleMessage::Regular(message)) = q.message {
-            bot.edit_message_text(
-                dialogue.chat_id(),
-                message.id,
-                format!("Target {device} has been selected successfully!"),
-            )
-            .await?;
-            dialogue.update(State::ReceiveMessage { device }).await?;
-        } else {
-            log::warn!("Source message of callback not available. User {:?}", user);
-            bot.send_message(dialogue.chat_id(), "Internal error. Resetting.")
+    let Some(dbuser) = authorized_user(&bot, db.as_ref(), &dialogue, &user).await? else {
+        return Ok(());
+    };
+
+    match device_with_right(db.as_ref(), &dbuser, target_id, DeviceRight::Send).await? {
+        Ok(device) => {
+            if let Some(MaybeInaccessibleMessage::Regular(message)) = q.message {
+                bot.edit_message_text(
+                    dialogue.chat_id(),
+                    message.id,
+                    format!(
+                        "Target {device} has been selected successfully! A bold first line becomes a heading, tags \
+                        like <red on yellow>, <large> or <left> at the start style the text."
+                    ),
+                )
                 .await?;
+                dialogue.update(State::ReceiveMessage { device, options }).await?;
+            } else {
+                log::warn!("Source message of callback not available. User {:?}", user);
+                bot.send_message(dialogue.chat_id(), "Internal error. Resetting.")
+                    .await?;
+                reset_dialogue(state, dialogue, user).await?;
+            }
+        }
+        Err(reply) => {
+            bot.send_message(dialogue.chat_id(), reply).await?;
             reset_dialogue(state, dialogue, user).await?;
         }
-    } else {
-        bot.send_message(dialogue.chat_id(), format!("Target with id {target_id} not found."))
-            .await?;
-        reset_dialogue(state, dialogue, user).await?;
     }
 
     Ok(())
@@ -289,20 +1187,46 @@ async fn receive_message(
     db: Arc<dyn Db>,
     state: State,
     dialogue: MyDialogue,
-    device: Device,
+    (device, options): (Device, SendOptions),
     user: User,
     msg: Message,
 ) -> HandlerResult {
-    if let Some(text) = msg.text() {
-        bot.send_message(dialogue.chat_id(), format!("Sending message")).await?;
+    // The right to send might have been revoked since the target was selected.
+    let permission = match authorized_user(&bot, db.as_ref(), &dialogue, &user).await? {
+        Some(dbuser) => device_with_right(db.as_ref(), &dbuser, device.id(), DeviceRight::Send).await?,
+        None => Err("You are not authorized anymore.".to_string()),
+    };
+
+    let content = text_markup(&msg).map(|markup| MessageContent::from_markup(&markup));
+    if let Err(reply) = permission {
+        bot.send_message(dialogue.chat_id(), reply).await?;
+    } else if let Some(Err(e)) = content {
+        bot.send_message(dialogue.chat_id(), format!("Cannot send this text: {e}"))
+            .await?;
+    } else if let Some(Ok(content)) = content {
+        bot.send_message(dialogue.chat_id(), "Sending message").await?;
 
         let meta = MessageMeta {
             receiver_id: device.id(),
             duration: TimeDelta::days(1),
+            urgent: options.urgent,
+            not_before: options.not_before,
         };
-        let content = MessageContent::new_text(text)?;
-        let insert_message = InsertMessage::new(meta, SenderID::Telegram, Utc::now(), content);
-        db.add_message(insert_message).await;
+        let author = DbUser::new_telegram(user.id).raw();
+        // The admin never requests authorization, so we might not know their name yet.
+        if db.get_user_name(author).await?.is_none() {
+            db.set_user_name(author, user.full_name()).await?;
+        }
+
+        let insert_message =
+            InsertMessage::new(meta, SenderID::Telegram, Utc::now(), content).with_author(Some(author));
+        let scheduled = insert_message.is_scheduled();
+        db.add_message(insert_message).await?;
+        if scheduled {
+            schedule::wake();
+        } else {
+            handlers::device::wake(device.id());
+        }
     } else {
         bot.send_message(dialogue.chat_id(), "Cannot send empty text.").await?;
     }
@@ -310,6 +1234,27 @@ async fn receive_message(
     Ok(())
 }
 
+/// The text of `msg` as markup. Telegram sends bold text as an entity, so a bold first line becomes a heading.
+fn text_markup(msg: &Message) -> Option<String> {
+    let text = msg.text()?;
+    let first_line_len = text.split('\n').next().unwrap_or_default().len();
+    let heading = msg.parse_entities().unwrap_or_default().into_iter().find(|entity| {
+        *entity.kind() == MessageEntityKind::Bold
+            && entity.end() == first_line_len
+            // Tags that style the text may come before the heading.
+            && markup::parse(&text[..entity.start()]).0.trim().is_empty()
+    });
+    Some(match heading {
+        Some(heading) => format!(
+            "{}**{}**{}",
+            &text[..heading.start()],
+            heading.text(),
+            &text[heading.end()..]
+        ),
+        None => text.to_string(),
+    })
+}
+
 async fn cancel(bot: Bot, state: State, dialogue: MyDialogue, user: User) -> HandlerResult {
     bot.send_message(dialogue.chat_id(), "Cancelling dialogue.").await?;
     reset_dialogue(state, dialogue, user).await?;
@@ -328,22 +1273,22 @@ async fn invalid_state(bot: Bot, msg: Message) -> HandlerResult {
 async fn handle_auth_callback(bot: Bot, db: Arc<dyn Db>, auth_reply: AuthReply, q: CallbackQuery) -> HandlerResult {
     bot.answer_callback_query(q.id).await?;
 
-    if let Some(auth_request) = db.get_auth_request(auth_reply.id()).await {
+    if let Some(auth_request) = db.get_auth_request(auth_reply.id()).await? {
         match auth_reply.choice() {
             AuthReplyChoice::Accept => {
                 let dbuser = DbUser::new_telegram(auth_request.user_id()).authorize();
-                db.add_authorized_user(dbuser).await;
+                db.add_authorized_user(dbuser).await?;
+                // Messages of the user are attributed with the name they had when requesting authorization.
+                db.set_user_name(dbuser.raw(), auth_request.user_name().to_string())
+                    .await?;
                 bot.send_message(
                     auth_request.user_id(),
                     "Congratulations, you were authorized by the admin. Use the /send command to send messages.",
                 )
                 .await?;
-                match q.message {
-                    Some(MaybeInaccessibleMessage::Regular(message)) => {
-                        bot.edit_message_text(q.from.id, message.id, "User was authorized.")
-                            .await?;
-                    }
-                    _ => {}
+                if let Some(MaybeInaccessibleMessage::Regular(message)) = q.message {
+                    bot.edit_message_text(q.from.id, message.id, "User was authorized.")
+                        .await?;
                 }
             }
             AuthReplyChoice::Deny => {
@@ -352,11 +1297,8 @@ async fn handle_auth_callback(bot: Bot, db: Arc<dyn Db>, auth_reply: AuthReply,
                     "Sorry, your authorization request was denied. Go away please.",
                 )
                 .await?;
-                match q.message {
-                    Some(MaybeInaccessibleMessage::Regular(message)) => {
-                        bot.edit_message_text(q.from.id, message.id, "User was denied.").await?;
-                    }
-                    _ => {}
+                if let Some(MaybeInaccessibleMessage::Regular(message)) = q.message {
+                    bot.edit_message_text(q.from.id, message.id, "User was denied.").await?;
                 }
             }
         }
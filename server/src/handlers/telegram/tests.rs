@@ -0,0 +1,104 @@
+use chrono::Utc;
+use common::protocols::pico::{DeviceError, DeviceErrorKind, DeviceStatus};
+
+use super::*;
+
+/// No other test connects this device, so it is never shown as connected.
+const DEVICE_ID: DeviceID = DeviceID(0x7e1e);
+
+#[test]
+fn durations_show_their_largest_units() {
+    assert_eq!(format_duration(59), "0m");
+    assert_eq!(format_duration(3 * 60), "3m");
+    assert_eq!(format_duration(2 * 3_600 + 5 * 60), "2h 5m");
+    assert_eq!(format_duration(86_400 + 59), "1d 0h 0m");
+}
+
+#[test]
+fn status_line_of_a_device_that_never_reported() {
+    let device = Device::new(DEVICE_ID, "Kitchen".to_string());
+    assert_eq!(
+        status_line(&device),
+        format!("Kitchen ({DEVICE_ID}): not connected, never seen")
+    );
+}
+
+#[test]
+fn status_line_shows_what_the_device_reported() {
+    let status = DeviceStatus {
+        uptime_sec: 86_400 + 3_600,
+        rssi: Some(-60),
+        free_text_slots: 3,
+        free_image_slots: 1,
+        checksum_failures: 2,
+        last_error: Some(DeviceError {
+            kind: DeviceErrorKind::ServerConnect,
+            uptime_sec: 120,
+        }),
+    };
+    let seen_at = Utc.with_ymd_and_hms(2024, 5, 6, 7, 8, 9).unwrap();
+    let device = Device::new(DEVICE_ID, "Kitchen".to_string()).with_status(Some(seen_at), Some(status));
+
+    let line = status_line(&device);
+    assert!(
+        line.starts_with(&format!(
+            "Kitchen ({DEVICE_ID}): not connected, last seen 2024-05-06 07:08 UTC\n"
+        )),
+        "{line}"
+    );
+    assert!(line.contains("Up 1d 1h 0m, WiFi -60 dBm, 3 text and 1 image slots free, 2 corrupted transfers"));
+    assert!(line.ends_with(&format!(
+        "\nLast error: {} after 2m uptime",
+        DeviceErrorKind::ServerConnect
+    )));
+}
+
+/// A text message as the Bot API sends it. Entities are given as `(offset, length)` in UTF-16 code units.
+fn text_message(text: &str, bold: &[(usize, usize)]) -> Message {
+    let entities: Vec<_> = bold
+        .iter()
+        .map(|(offset, length)| serde_json::json!({ "type": "bold", "offset": offset, "length": length }))
+        .collect();
+    serde_json::from_value(serde_json::json!({
+        "message_id": 1,
+        "date": 0,
+        "chat": { "id": 2, "type": "private", "first_name": "Alice" },
+        "from": { "id": 2, "is_bot": false, "first_name": "Alice" },
+        "text": text,
+        "entities": entities,
+    }))
+    .unwrap()
+}
+
+#[test]
+fn bold_first_line_becomes_a_heading() {
+    let msg = text_message("Hello\nthere", &[(0, 5)]);
+    assert_eq!(text_markup(&msg).as_deref(), Some("**Hello**\nthere"));
+    let msg = text_message("Hello", &[(0, 5)]);
+    assert_eq!(text_markup(&msg).as_deref(), Some("**Hello**"));
+}
+
+#[test]
+fn bold_text_after_leading_tags_becomes_a_heading() {
+    let msg = text_message("<red on yellow> <large>Hello\nthere", &[(23, 5)]);
+    assert_eq!(
+        text_markup(&msg).as_deref(),
+        Some("<red on yellow> <large>**Hello**\nthere")
+    );
+    // Entities count UTF-16 code units, the emoji takes two of them.
+    let msg = text_message("<red>😀 Hi", &[(5, 5)]);
+    assert_eq!(text_markup(&msg).as_deref(), Some("<red>**😀 Hi**"));
+}
+
+#[test]
+fn other_bold_text_is_kept_plain() {
+    for (text, bold) in [
+        ("Hello there", (0, 5)),
+        ("Oh, hello", (4, 5)),
+        ("<3 hello", (3, 5)),
+        ("Hello\nthere", (6, 5)),
+    ] {
+        let msg = text_message(text, &[bold]);
+        assert_eq!(text_markup(&msg).as_deref(), Some(text));
+    }
+}
@@ -0,0 +1,76 @@
+//! Authentication of web API requests.
+//!
+//! Authorized Telegram users can create API tokens with the bot's `/token` command.
+//! Requests send the token in the `Authorization: Bearer <token>` header.
+
+use std::{str::FromStr, sync::Arc};
+
+use axum::{
+    extract::FromRequestParts,
+    http::{header, request::Parts},
+};
+use common::{protocols::web::DeviceRight, types::DeviceID};
+use uuid::Uuid;
+
+use crate::{
+    db::{
+        access::{self, is_admin},
+        device::Device,
+        user::{Authorized, User},
+        Db, DbError,
+    },
+    error::{WebError, WebResult},
+};
+
+/// Extracts the authorized user that made the request.
+pub struct WebUser(pub User<Authorized>);
+
+/// Extracts the authorized user that made the request and checks that it is the admin.
+pub struct WebAdmin;
+
+impl FromRequestParts<Arc<dyn Db>> for WebUser {
+    type Rejection = WebError;
+
+    async fn from_request_parts(parts: &mut Parts, db: &Arc<dyn Db>) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| Uuid::from_str(token.trim()).ok())
+            .ok_or_else(WebError::unauthorized)?;
+
+        match db.get_api_token_user(token).await? {
+            Some(user) => Ok(WebUser(user)),
+            None => Err(WebError::unauthorized()),
+        }
+    }
+}
+
+impl FromRequestParts<Arc<dyn Db>> for WebAdmin {
+    type Rejection = WebError;
+
+    async fn from_request_parts(parts: &mut Parts, db: &Arc<dyn Db>) -> Result<Self, Self::Rejection> {
+        let WebUser(user) = WebUser::from_request_parts(parts, db).await?;
+
+        if is_admin(db.as_ref(), &user).await {
+            Ok(WebAdmin)
+        } else {
+            Err(WebError::forbidden())
+        }
+    }
+}
+
+/// The device `id`, if `user` has at least `right` on it.
+pub async fn device_with_right(
+    db: &dyn Db,
+    user: &User<Authorized>,
+    id: DeviceID,
+    right: DeviceRight,
+) -> WebResult<Device> {
+    let device = db.get_device(id).await?.ok_or(DbError::DeviceNotFound(id))?;
+    match access::device_right(db, user, &device).await? {
+        Some(granted) if granted >= right => Ok(device),
+        _ => Err(WebError::forbidden()),
+    }
+}
@@ -0,0 +1,245 @@
+//! Management of the device registry and of the rights users have on devices.
+
+use std::{str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use chrono::Utc;
+use common::{
+    protocols::web::{
+        ClaimDevice, DeviceInfo, DeviceRight, DeviceStatusInfo, GrantInfo, NewDevice, SetGrant, UpdateDevice,
+    },
+    types::DeviceID,
+};
+
+use super::auth::{device_with_right, WebAdmin, WebUser};
+use crate::{
+    db::{
+        access::{self, Grant},
+        device::{self, Device},
+        user::RawUser,
+        Db, DbError,
+    },
+    error::{WebError, WebResult},
+    handlers,
+};
+
+impl From<&Device> for DeviceInfo {
+    fn from(device: &Device) -> Self {
+        Self {
+            id: device.id(),
+            name: device.name().to_string(),
+            owner: device.owner().map(|owner| owner.to_string()),
+            show_author: device.show_author(),
+            firmware: device.firmware(),
+            outdated: handlers::device::is_outdated(device),
+        }
+    }
+}
+
+async fn grant_info(db: &dyn Db, grant: &Grant) -> WebResult<GrantInfo> {
+    Ok(GrantInfo {
+        user: grant.user.to_string(),
+        user_name: db.get_user_name(grant.user).await?,
+        right: grant.right,
+    })
+}
+
+fn parse_device_id(id: &str) -> WebResult<DeviceID> {
+    DeviceID::from_str(id).map_err(|_| WebError::bad_request(&format!("Invalid device ID '{id}'.")))
+}
+
+fn parse_user(user: &str) -> WebResult<RawUser> {
+    RawUser::from_str(user).map_err(|_| WebError::bad_request(&format!("Invalid user '{user}'.")))
+}
+
+fn parse_device_name(name: &str) -> WebResult<String> {
+    device::validate_name(name).map_err(|e| WebError::bad_request(&e.to_string()))
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn list_devices(State(db): State<Arc<dyn Db>>, WebUser(user): WebUser) -> WebResult<Json<Vec<DeviceInfo>>> {
+    let devices = access::devices_with_right(db.as_ref(), &user, DeviceRight::Send).await?;
+    Ok(Json(devices.iter().map(DeviceInfo::from).collect()))
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn add_device(
+    State(db): State<Arc<dyn Db>>,
+    _admin: WebAdmin,
+    Json(new_device): Json<NewDevice>,
+) -> WebResult<(StatusCode, Json<DeviceInfo>)> {
+    let name = parse_device_name(&new_device.name)?;
+    let device = Device::new(new_device.id, name);
+    db.add_device(device.clone()).await?;
+    log::info!("Added device {device}.");
+    Ok((StatusCode::CREATED, Json(DeviceInfo::from(&device))))
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn claim_device(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Json(claim): Json<ClaimDevice>,
+) -> WebResult<(StatusCode, Json<DeviceInfo>)> {
+    let name = parse_device_name(&claim.name)?;
+    let device = db.claim_device(claim.code, name, user, Utc::now()).await?;
+    log::info!("Device {device} was claimed by {}.", user.raw());
+    Ok((StatusCode::CREATED, Json(DeviceInfo::from(&device))))
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn update_device(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Path(id): Path<String>,
+    Json(update): Json<UpdateDevice>,
+) -> WebResult<Json<DeviceInfo>> {
+    let id = parse_device_id(&id)?;
+    let name = update.name.as_deref().map(parse_device_name).transpose()?;
+
+    let mut device = device_with_right(db.as_ref(), &user, id, DeviceRight::Manage).await?;
+    if let Some(name) = name {
+        device = db.rename_device(id, name).await?;
+    }
+    if let Some(show_author) = update.show_author {
+        device = db.set_show_author(id, show_author).await?;
+    }
+    Ok(Json(DeviceInfo::from(&device)))
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn remove_device(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Path(id): Path<String>,
+) -> WebResult<Json<DeviceInfo>> {
+    let id = parse_device_id(&id)?;
+    let device = db.get_device(id).await?.ok_or(DbError::DeviceNotFound(id))?;
+    if !access::is_owner(db.as_ref(), &user, &device).await {
+        return Err(WebError::forbidden());
+    }
+
+    let device = db.remove_device(id).await?;
+    log::info!("Removed device {device}.");
+    Ok(Json(DeviceInfo::from(&device)))
+}
+
+/// Whether the device is online and what it reported about its health.
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn device_status(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Path(id): Path<String>,
+) -> WebResult<Json<DeviceStatusInfo>> {
+    let id = parse_device_id(&id)?;
+    let device = device_with_right(db.as_ref(), &user, id, DeviceRight::Manage).await?;
+    Ok(Json(DeviceStatusInfo {
+        id,
+        connected: handlers::device::is_connected(id),
+        last_seen: device.last_seen(),
+        firmware: device.firmware(),
+        outdated: handlers::device::is_outdated(&device),
+        status: device.status().copied(),
+    }))
+}
+
+/// Create a new secret for the device and return the UF2 file that flashes it, which is reserved to its owner.
+pub async fn rotate_key(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Path(id): Path<String>,
+) -> WebResult<impl IntoResponse> {
+    let id = parse_device_id(&id)?;
+    let device = db.get_device(id).await?.ok_or(DbError::DeviceNotFound(id))?;
+    if !access::is_owner(db.as_ref(), &user, &device).await {
+        return Err(WebError::forbidden());
+    }
+
+    let file = handlers::device::rotate_key(db.as_ref(), &device).await?;
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_DISPOSITION,
+        format!("attachment; filename=\"device-{id}.uf2\"").parse().unwrap(),
+    );
+    headers.insert(header::CONTENT_TYPE, "application/octet-stream".parse().unwrap());
+    Ok((headers, file))
+}
+
+/// Forget the secret and pairing code of an unregistered device, e.g. because someone else enrolled its ID before the
+/// real device connected. The device enrolls again when it connects next. Unregistered devices have no owner, so this
+/// is reserved to the admin.
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn reset_pending_device(
+    State(db): State<Arc<dyn Db>>,
+    _admin: WebAdmin,
+    Path(id): Path<String>,
+) -> WebResult<StatusCode> {
+    let id = parse_device_id(&id)?;
+    if !db.reset_pending_device(id).await? {
+        return Err(WebError::not_found(&format!("Enrollment of device {id}")));
+    }
+    log::info!("Reset the enrollment of unregistered device {id}.");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn list_grants(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Path(id): Path<String>,
+) -> WebResult<Json<Vec<GrantInfo>>> {
+    let id = parse_device_id(&id)?;
+    device_with_right(db.as_ref(), &user, id, DeviceRight::Manage).await?;
+
+    let mut infos = Vec::new();
+    for grant in db.get_device_grants(id).await? {
+        infos.push(grant_info(db.as_ref(), &grant).await?);
+    }
+    Ok(Json(infos))
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn set_grant(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Path((id, grantee)): Path<(String, String)>,
+    Json(set_grant): Json<SetGrant>,
+) -> WebResult<Json<GrantInfo>> {
+    let id = parse_device_id(&id)?;
+    let grantee = parse_user(&grantee)?;
+    let device = device_with_right(db.as_ref(), &user, id, DeviceRight::Manage).await?;
+    if db.is_user_authorized(grantee).await?.is_none() {
+        return Err(WebError::not_found(&format!("User {grantee}")));
+    }
+
+    let grant = Grant {
+        device_id: id,
+        user: grantee,
+        right: set_grant.right,
+    };
+    db.set_grant(grant).await?;
+    log::info!("{} granted {:?} on {device} to {grantee}.", user.raw(), grant.right);
+    Ok(Json(grant_info(db.as_ref(), &grant).await?))
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn remove_grant(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Path((id, grantee)): Path<(String, String)>,
+) -> WebResult<StatusCode> {
+    let id = parse_device_id(&id)?;
+    let grantee = parse_user(&grantee)?;
+    let device = device_with_right(db.as_ref(), &user, id, DeviceRight::Manage).await?;
+
+    if !db.remove_grant(id, grantee).await? {
+        return Err(WebError::not_found(&format!("Right of {grantee} on {device}")));
+    }
+    log::info!("{} revoked the right of {grantee} on {device}.", user.raw());
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,138 @@
+//! Search through the messages that were sent or are scheduled to be shown.
+
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    extract::{Query, State},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use common::{
+    protocols::web::{DeviceRight, MessageHistory, MessageInfo, MessageKind, MessageSender},
+    types::{DeviceID, MessageID},
+};
+use serde::Deserialize;
+
+use super::{auth::WebUser, empty_string_as_none};
+use crate::{
+    db::{
+        access,
+        message::{Message, MessageFilter},
+        user::{Authorized, User},
+        Db,
+    },
+    error::{Result, WebError, WebResult},
+};
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 200;
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQueryParams {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    receiver: Option<DeviceID>,
+    #[serde(default)]
+    sender: Option<MessageSender>,
+    /// RFC 3339 timestamp, inclusive.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    from: Option<DateTime<Utc>>,
+    /// RFC 3339 timestamp, exclusive.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    kind: Option<MessageKind>,
+    /// Text to search for in text messages.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    q: Option<String>,
+    /// Cursor from [`MessageHistory::next_cursor`] of the previous page.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    before: Option<MessageID>,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    limit: Option<usize>,
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn message_history(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Query(params): Query<HistoryQueryParams>,
+) -> WebResult<Json<MessageHistory>> {
+    let limit = params.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+    if limit == 0 || limit > MAX_PAGE_SIZE {
+        return Err(WebError::bad_request(&format!(
+            "limit must be between 1 and {MAX_PAGE_SIZE}."
+        )));
+    }
+
+    let receiver_ids = visible_receivers(db.as_ref(), &user).await?;
+    let filter = MessageFilter {
+        receiver_id: params.receiver,
+        receiver_ids,
+        sender_id: params.sender.map(Into::into),
+        from: params.from,
+        to: params.to,
+        kind: params.kind,
+        text: params.q,
+    };
+
+    // Ask for one more message than we return to know if there is another page.
+    let mut messages = db.get_message_history(&filter, params.before, limit + 1).await?;
+    let next_cursor = if messages.len() > limit {
+        messages.truncate(limit);
+        messages.last().map(|message| message.id)
+    } else {
+        None
+    };
+
+    Ok(Json(MessageHistory {
+        messages: infos(db.as_ref(), &messages).await?,
+        next_cursor,
+    }))
+}
+
+/// The messages that still wait for their time to be shown, the next one due first.
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn scheduled_messages(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+) -> WebResult<Json<Vec<MessageInfo>>> {
+    let receiver_ids = visible_receivers(db.as_ref(), &user).await?;
+    let mut messages = db.get_scheduled_messages().await?;
+    if let Some(receiver_ids) = receiver_ids {
+        messages.retain(|message| receiver_ids.contains(&message.meta.receiver_id));
+    }
+    Ok(Json(infos(db.as_ref(), &messages).await?))
+}
+
+/// The devices whose messages `user` may see, or `None` for all of them.
+/// Users only see the messages to devices they may send to, except for the admin who sees everything,
+/// including messages to devices that were removed.
+async fn visible_receivers(db: &dyn Db, user: &User<Authorized>) -> Result<Option<Vec<DeviceID>>> {
+    if access::is_admin(db, user).await {
+        return Ok(None);
+    }
+    let devices = access::devices_with_right(db, user, DeviceRight::Send).await?;
+    Ok(Some(devices.iter().map(|device| device.id()).collect()))
+}
+
+/// The metadata of `messages` with the names of their authors.
+async fn infos(db: &dyn Db, messages: &[Message]) -> Result<Vec<MessageInfo>> {
+    // Most pages only contain messages of a few authors, so we only look up each name once.
+    let mut author_names: HashMap<_, Option<String>> = HashMap::new();
+    let mut infos = Vec::with_capacity(messages.len());
+    for message in messages {
+        let author_name = match message.author {
+            Some(author) => match author_names.get(&author) {
+                Some(name) => name.clone(),
+                None => {
+                    let name = db.get_user_name(author).await?;
+                    author_names.insert(author, name.clone());
+                    name
+                }
+            },
+            None => None,
+        };
+        infos.push(message.info(author_name));
+    }
+    Ok(infos)
+}
@@ -8,30 +8,39 @@ use std::{
 use anyhow::{anyhow, Context};
 use axum::{
     extract::{DefaultBodyLimit, Multipart, OriginalUri, Path, Query, Request, State},
-    http::header,
+    http::{header, StatusCode},
     response::{self, IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, patch, post, put},
     Form, Json, Router, ServiceExt,
 };
 use bytes::Bytes;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use common::{
-    protocols::web::{MessageMeta, NewMessageCreated, NewTextMessage},
+    protocols::web::{DeviceRight, MessageMeta, NewMessageCreated, NewTextMessage},
     types::{DeviceID, MessageID},
 };
 use serde::{de, Deserialize, Deserializer};
 use tower::Layer;
 use tower_http::{normalize_path::NormalizePathLayer, services::ServeFile, trace::TraceLayer};
 
+use self::auth::{device_with_right, WebUser};
 use crate::{
     db::{
+        access,
         message::{image_from_bytes_mime, InsertMessage, Message, MessageContent, SenderID},
-        Db,
+        Db, DbError,
     },
     error::{WebError, WebResult},
+    handlers, schedule,
 };
 
+mod auth;
+mod devices;
+mod history;
 mod image;
+mod recurring;
+#[cfg(test)]
+mod tests;
 
 const ADDRESS: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 3000);
 // Define maximum upload file size to be 8MB.
@@ -39,16 +48,32 @@ const UPLOAD_BODY_LIMIT: usize = 8 * 1024 * 1024;
 static INDEX_PATH: &str = "webclient/index.html";
 static INDEX_JS_PATH: &str = "webclient/index.js";
 
-#[axum::debug_handler]
+#[axum::debug_handler(state = Arc<dyn Db>)]
 async fn new_text_message(
     State(messages): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
     Form(new_message): Form<NewTextMessage>,
-) -> WebResult<Json<()>> {
-    let new_message_content = MessageContent::new_text(&new_message.text)?;
-    let new_message = InsertMessage::new(new_message.meta, SenderID::Web, Utc::now(), new_message_content);
+) -> WebResult<Json<NewMessageCreated>> {
+    device_with_right(
+        messages.as_ref(),
+        &user,
+        new_message.meta.receiver_id,
+        DeviceRight::Send,
+    )
+    .await?;
+    let new_message_content = MessageContent::from_markup(&new_message.text)?;
+    let new_message = InsertMessage::new(new_message.meta, SenderID::Web, Utc::now(), new_message_content)
+        .with_author(Some(user.raw()));
 
-    messages.add_message(new_message).await;
-    Ok(Json(()))
+    let receiver_id = new_message.meta.receiver_id;
+    let scheduled = new_message.is_scheduled();
+    let id = messages.add_message(new_message).await?;
+    if scheduled {
+        schedule::wake();
+    } else {
+        handlers::device::wake(receiver_id);
+    }
+    Ok(Json(NewMessageCreated { id }))
 }
 
 // #[axum::debug_handler]
@@ -72,15 +97,18 @@ async fn new_text_message(
 //     Ok(Json(()))
 // }
 
-#[axum::debug_handler]
+#[axum::debug_handler(state = Arc<dyn Db>)]
 async fn new_image_message(
     State(messages): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
     mut multipart: Multipart,
 ) -> WebResult<Json<NewMessageCreated>> {
     log::info!("Handling new image multipart message.");
     let mut image_bytes_mime: Option<(Bytes, String)> = None;
     let mut receiver: Option<DeviceID> = None;
     let mut duration: Option<chrono::Duration> = None;
+    let mut urgent = false;
+    let mut not_before: Option<DateTime<Utc>> = None;
 
     while let Some(field) = multipart
         .next_field()
@@ -112,6 +140,17 @@ async fn new_image_message(
                 log::info!("\tis duration of '{seconds}' seconds.");
                 duration = Some(chrono::Duration::seconds(seconds));
             }
+            "urgent" => {
+                let data = field.text().await.context("urgent field text extraction failed")?;
+                urgent = bool::from_str(&data).context("urgent parsing failed")?;
+                log::info!("\tis urgent '{urgent}'.");
+            }
+            "not_before" => {
+                let data = field.text().await.context("not_before field text extraction failed")?;
+                let time = DateTime::parse_from_rfc3339(&data).context("not_before parsing failed")?;
+                log::info!("\tis not before '{time}'.");
+                not_before = Some(time.to_utc());
+            }
             _ => return Err(anyhow!("malformed multipart field {name}").into()),
         }
     }
@@ -120,15 +159,57 @@ async fn new_image_message(
     let image = image_from_bytes_mime(&bytes, mime).context("parsing image failed")?;
     let receiver_id = receiver.context("receiver ID missing")?;
     let duration = duration.context("duration missing")?;
-    let meta = MessageMeta { receiver_id, duration };
+    let meta = MessageMeta {
+        receiver_id,
+        duration,
+        urgent,
+        not_before,
+    };
+    device_with_right(messages.as_ref(), &user, receiver_id, DeviceRight::Send).await?;
 
     let new_message_content = MessageContent::new_image(image)?;
-    let new_message = InsertMessage::new(meta, SenderID::Web, Utc::now(), new_message_content);
-    let id = messages.add_message(new_message).await;
+    let new_message =
+        InsertMessage::new(meta, SenderID::Web, Utc::now(), new_message_content).with_author(Some(user.raw()));
+    let scheduled = new_message.is_scheduled();
+    let id = messages.add_message(new_message).await?;
+    if scheduled {
+        schedule::wake();
+    } else {
+        handlers::device::wake(receiver_id);
+    }
 
     Ok(Json(NewMessageCreated { id }))
 }
 
+#[axum::debug_handler(state = Arc<dyn Db>)]
+async fn delete_message(
+    State(messages): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Path(id): Path<String>,
+) -> WebResult<StatusCode> {
+    let id = MessageID::from_str(&id).map_err(|_| WebError::bad_request(&format!("Invalid message ID '{id}'.")))?;
+    let message = messages.get_message(id).await?.ok_or(DbError::MessageNotFound(id))?;
+
+    // Authors can recall their messages and whoever manages the receiving device can remove any message from it.
+    // Messages to devices that were removed can only be deleted by the admin.
+    let may_delete = message.author == Some(user.raw())
+        || access::is_admin(messages.as_ref(), &user).await
+        || match messages.get_device(message.meta.receiver_id).await? {
+            Some(device) => access::device_right(messages.as_ref(), &user, &device).await? == Some(DeviceRight::Manage),
+            None => false,
+        };
+    if !may_delete {
+        return Err(WebError::forbidden());
+    }
+
+    messages.delete_message(id).await?;
+    if !message.scheduled {
+        handlers::device::wake(message.meta.receiver_id);
+    }
+    log::info!("Message {id} was deleted by {}.", user.raw());
+    Ok(StatusCode::NO_CONTENT)
+}
+
 // Note, it's important to put the parameters into a struct since the FromRequestParts impl for Query
 // uses a MapDeserializer, i.e. expects to build some map value. And structs are internally represented as maps.
 // If we ust just Option<i32> the deserialization always fails.
@@ -153,15 +234,18 @@ where
     }
 }
 
-#[axum::debug_handler]
+/// The next message a device gets after `after`, which only those who may send to the device may see.
+#[axum::debug_handler(state = Arc<dyn Db>)]
 async fn latest_message(
     State(messages): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
     Path(for_device): Path<String>,
     Query(params): Query<LatestQueryParams>,
 ) -> WebResult<Response> {
     let receiver_id = DeviceID::from_str(&for_device).context("failed to parse receiver_id")?;
+    device_with_right(messages.as_ref(), &user, receiver_id, DeviceRight::Send).await?;
 
-    match messages.get_next_message(receiver_id, params.after).await {
+    match messages.get_next_message(receiver_id, params.after, Utc::now()).await? {
         Some(Message {
             content: MessageContent::Text(text),
             ..
@@ -177,6 +261,41 @@ async fn latest_message(
     }
 }
 
+/// The routes of the web API, which the web client and other clients use.
+fn api(messages: Arc<dyn Db>) -> Router {
+    Router::new()
+        .route("/latest/{for_device}", get(latest_message))
+        .route("/new_text_message", post(new_text_message))
+        .route("/devices", get(devices::list_devices).post(devices::add_device))
+        .route("/devices/claim", post(devices::claim_device))
+        .route("/messages", get(history::message_history))
+        .route("/messages/scheduled", get(history::scheduled_messages))
+        .route("/messages/{id}", delete(delete_message))
+        .route(
+            "/recurring",
+            get(recurring::list_recurring).post(recurring::add_recurring),
+        )
+        .route("/recurring/{id}", delete(recurring::delete_recurring))
+        .route(
+            "/devices/{id}",
+            patch(devices::update_device).delete(devices::remove_device),
+        )
+        .route("/devices/{id}/key", post(devices::rotate_key))
+        .route("/devices/{id}/enrollment", delete(devices::reset_pending_device))
+        .route("/devices/{id}/status", get(devices::device_status))
+        .route("/devices/{id}/grants", get(devices::list_grants))
+        .route(
+            "/devices/{id}/grants/{user}",
+            put(devices::set_grant).delete(devices::remove_grant),
+        )
+        // .route("/new_image_message", post(new_image_message))
+        .route(
+            "/new_image_message",
+            post(new_image_message).layer(DefaultBodyLimit::max(UPLOAD_BODY_LIMIT)),
+        )
+        .with_state(messages)
+}
+
 pub async fn run(messages: Arc<dyn Db>) {
     let web_client = {
         let index_html = ServeFile::new(INDEX_PATH);
@@ -195,17 +314,7 @@ pub async fn run(messages: Arc<dyn Db>) {
             .route_service("/index.js", index_js)
     };
 
-    let api = {
-        Router::new()
-            .route("/latest/{for_device}", get(latest_message))
-            .route("/new_text_message", post(new_text_message))
-            // .route("/new_image_message", post(new_image_message))
-            .route(
-                "/new_image_message",
-                post(new_image_message).layer(DefaultBodyLimit::max(UPLOAD_BODY_LIMIT)),
-            )
-            .with_state(messages)
-    };
+    let api = api(messages);
     let router = Router::new()
         .nest("/web", web_client)
         .nest("/api", api)
@@ -0,0 +1,85 @@
+//! Management of recurring messages, which the schedule task sends each time they are due.
+
+use std::{str::FromStr, sync::Arc};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::Utc;
+use common::{
+    protocols::web::{DeviceRight, NewRecurringMessage, RecurringMessageInfo},
+    types::RecurringID,
+};
+
+use super::auth::{device_with_right, WebUser};
+use crate::{
+    db::{access, message::SenderID, recurring::InsertRecurringMessage, Db, DbError},
+    error::{WebError, WebResult},
+    schedule,
+};
+
+/// The recurring messages to the devices `user` may send to, the next one due first.
+/// The admin sees all of them.
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn list_recurring(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+) -> WebResult<Json<Vec<RecurringMessageInfo>>> {
+    let mut messages = db.get_recurring_messages().await?;
+    if !access::is_admin(db.as_ref(), &user).await {
+        let devices = access::devices_with_right(db.as_ref(), &user, DeviceRight::Send).await?;
+        messages.retain(|message| devices.iter().any(|device| device.id() == message.receiver_id));
+    }
+    Ok(Json(messages.iter().map(|message| message.info()).collect()))
+}
+
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn add_recurring(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Json(new_message): Json<NewRecurringMessage>,
+) -> WebResult<(StatusCode, Json<RecurringMessageInfo>)> {
+    device_with_right(db.as_ref(), &user, new_message.receiver_id, DeviceRight::Send).await?;
+    let message = InsertRecurringMessage::new(new_message, SenderID::Web, Utc::now())?.with_author(Some(user.raw()));
+
+    let id = db.add_recurring_message(message).await?;
+    schedule::wake();
+    let message = db
+        .get_recurring_message(id)
+        .await?
+        .ok_or(DbError::RecurringMessageNotFound(id))?;
+    log::info!("Recurring message {id} was added by {}.", user.raw());
+    Ok((StatusCode::CREATED, Json(message.info())))
+}
+
+/// Stop sending the recurring message. Messages that were already sent stay.
+#[axum::debug_handler(state = Arc<dyn Db>)]
+pub async fn delete_recurring(
+    State(db): State<Arc<dyn Db>>,
+    WebUser(user): WebUser,
+    Path(id): Path<String>,
+) -> WebResult<StatusCode> {
+    let id = RecurringID::from_str(&id)
+        .map_err(|_| WebError::bad_request(&format!("Invalid recurring message ID '{id}'.")))?;
+    let message = db
+        .get_recurring_message(id)
+        .await?
+        .ok_or(DbError::RecurringMessageNotFound(id))?;
+
+    // Like messages, recurring messages can be stopped by their author and whoever manages the receiving device.
+    let may_delete = message.author == Some(user.raw())
+        || access::is_admin(db.as_ref(), &user).await
+        || match db.get_device(message.receiver_id).await? {
+            Some(device) => access::device_right(db.as_ref(), &user, &device).await? == Some(DeviceRight::Manage),
+            None => false,
+        };
+    if !may_delete {
+        return Err(WebError::forbidden());
+    }
+
+    db.delete_recurring_message(id).await?;
+    log::info!("Recurring message {id} was deleted by {}.", user.raw());
+    Ok(StatusCode::NO_CONTENT)
+}
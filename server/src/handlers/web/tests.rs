@@ -0,0 +1,590 @@
+//! Requests against the web API, with the tokens of different users.
+
+use axum::{body::Body, http::Method};
+use chrono::{SecondsFormat, SubsecRound, TimeDelta};
+use common::protocols::{
+    pico::{DeviceStatus, DEVICE_SECRET_LEN},
+    web::{DeviceInfo, DeviceStatusInfo, MessageHistory, MessageInfo},
+};
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use teloxide::types::UserId;
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use super::*;
+use crate::db::{
+    access::Grant,
+    device::{Device, DeviceKeys},
+    sqlite_db::SqliteDb,
+    user::{RawUser, User},
+};
+
+const ADMIN_ID: UserId = UserId(1);
+const DEVICE_ID: DeviceID = DeviceID(0x1234);
+
+struct Api {
+    db: Arc<dyn Db>,
+    router: Router,
+}
+
+impl Api {
+    /// The API of a server that knows a device without an owner.
+    async fn new() -> Self {
+        let db: Arc<dyn Db> = Arc::new(SqliteDb::open(":memory:", ADMIN_ID).unwrap());
+        db.add_device(Device::new(DEVICE_ID, "Test device".to_string()))
+            .await
+            .unwrap();
+        let router = api(db.clone());
+        Self { db, router }
+    }
+
+    /// An API token of the authorized Telegram user `id`.
+    async fn token(&self, id: u64) -> Uuid {
+        let user = User::new_telegram(UserId(id)).authorize();
+        self.db.add_authorized_user(user).await.unwrap();
+        let token = Uuid::new_v4();
+        self.db.add_api_token(token, user).await.unwrap();
+        token
+    }
+
+    async fn add_text(
+        &self,
+        receiver_id: DeviceID,
+        sender_id: SenderID,
+        text: &str,
+        created_at: DateTime<Utc>,
+    ) -> MessageID {
+        let meta = MessageMeta {
+            receiver_id,
+            duration: TimeDelta::days(1),
+            urgent: false,
+            not_before: None,
+        };
+        let content = MessageContent::new_text(text).unwrap();
+        self.db
+            .add_message(InsertMessage::new(meta, sender_id, created_at, content))
+            .await
+            .unwrap()
+    }
+
+    async fn request(&self, method: Method, uri: &str, token: Option<Uuid>, body: Option<Value>) -> Response {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+        }
+        let body = match body {
+            Some(body) => {
+                request = request.header(header::CONTENT_TYPE, "application/json");
+                Body::from(body.to_string())
+            }
+            None => Body::empty(),
+        };
+        self.router.clone().oneshot(request.body(body).unwrap()).await.unwrap()
+    }
+
+    async fn status(&self, method: Method, uri: &str, token: Uuid, body: Option<Value>) -> StatusCode {
+        self.request(method, uri, Some(token), body).await.status()
+    }
+
+    /// The IDs of the messages on the first page of the history for `query`.
+    async fn history(&self, query: &str, token: Uuid) -> Vec<MessageID> {
+        let history: MessageHistory = self.json(Method::GET, &format!("/messages?{query}"), token, None).await;
+        message_ids(&history)
+    }
+
+    /// The JSON response to a successful request.
+    async fn json<T: DeserializeOwned>(&self, method: Method, uri: &str, token: Uuid, body: Option<Value>) -> T {
+        let response = self.request(method.clone(), uri, Some(token), body).await;
+        assert!(
+            response.status().is_success(),
+            "{method} {uri} failed with {}",
+            response.status()
+        );
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+}
+
+fn telegram_user(id: u64) -> RawUser {
+    User::new_telegram(UserId(id)).raw()
+}
+
+fn device_ids(devices: &[DeviceInfo]) -> Vec<DeviceID> {
+    devices.iter().map(|device| device.id).collect()
+}
+
+#[tokio::test]
+async fn requests_without_a_valid_token_are_unauthorized() {
+    let api = Api::new().await;
+    let response = api.request(Method::GET, "/devices", None, None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = api.request(Method::GET, "/devices", Some(Uuid::new_v4()), None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn admin_manages_the_device_registry() {
+    let api = Api::new().await;
+    let admin = api.token(ADMIN_ID.0).await;
+
+    let new_device = json!({ "id": 0x5678, "name": "Kitchen" });
+    let device: DeviceInfo = api
+        .json(Method::POST, "/devices", admin, Some(new_device.clone()))
+        .await;
+    assert_eq!(
+        (device.id, device.name.as_str(), device.owner),
+        (DeviceID(0x5678), "Kitchen", None)
+    );
+    assert_eq!(
+        api.status(Method::POST, "/devices", admin, Some(new_device)).await,
+        StatusCode::CONFLICT
+    );
+
+    let devices: Vec<DeviceInfo> = api.json(Method::GET, "/devices", admin, None).await;
+    assert_eq!(device_ids(&devices), [DeviceID(0x5678), DEVICE_ID]);
+
+    let rename = json!({ "name": "Hallway", "show_author": true });
+    let device: DeviceInfo = api.json(Method::PATCH, "/devices/5678", admin, Some(rename)).await;
+    assert_eq!(device.name, "Hallway");
+    assert!(device.show_author);
+
+    let removed: DeviceInfo = api.json(Method::DELETE, "/devices/5678", admin, None).await;
+    assert_eq!(removed.id, DeviceID(0x5678));
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/5678", admin, None).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn admin_resets_the_enrollment_of_unregistered_devices() {
+    let api = Api::new().await;
+    let admin = api.token(ADMIN_ID.0).await;
+    let user = api.token(2).await;
+    let pending = DeviceID(0x5678);
+    api.db
+        .set_device_keys(pending, DeviceKeys::new([7; DEVICE_SECRET_LEN]))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/5678/enrollment", user, None).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/5678/enrollment", admin, None)
+            .await,
+        StatusCode::NO_CONTENT
+    );
+    assert!(api.db.get_device_keys(pending).await.unwrap().is_none());
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/5678/enrollment", admin, None)
+            .await,
+        StatusCode::NOT_FOUND
+    );
+    // Registered devices get a new secret from their owner instead.
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/1234/enrollment", admin, None)
+            .await,
+        StatusCode::CONFLICT
+    );
+}
+
+#[tokio::test]
+async fn invalid_device_ids_and_names_are_bad_requests() {
+    let api = Api::new().await;
+    let admin = api.token(ADMIN_ID.0).await;
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/kitchen", admin, None).await,
+        StatusCode::BAD_REQUEST
+    );
+    let rename = json!({ "name": "" });
+    assert_eq!(
+        api.status(Method::PATCH, "/devices/1234", admin, Some(rename)).await,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn users_cannot_change_the_registry_without_rights() {
+    let api = Api::new().await;
+    let user = api.token(2).await;
+
+    let devices: Vec<DeviceInfo> = api.json(Method::GET, "/devices", user, None).await;
+    assert!(devices.is_empty());
+
+    let new_device = json!({ "id": 0x5678, "name": "Kitchen" });
+    assert_eq!(
+        api.status(Method::POST, "/devices", user, Some(new_device)).await,
+        StatusCode::FORBIDDEN
+    );
+    let rename = json!({ "name": "Mine now" });
+    assert_eq!(
+        api.status(Method::PATCH, "/devices/1234", user, Some(rename)).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/1234", user, None).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::POST, "/devices/1234/key", user, None).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::GET, "/devices/1234/status", user, None).await,
+        StatusCode::FORBIDDEN
+    );
+    assert!(api.db.get_device(DEVICE_ID).await.unwrap().is_some());
+    assert!(api.db.get_device(DeviceID(0x5678)).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn owner_of_a_claimed_device_may_remove_it() {
+    let api = Api::new().await;
+    let user = api.token(2).await;
+    let code = api.db.get_pairing_code(DeviceID(0x5678), Utc::now()).await.unwrap();
+
+    let claim = json!({ "code": code, "name": "Kitchen" });
+    let device: DeviceInfo = api
+        .json(Method::POST, "/devices/claim", user, Some(claim.clone()))
+        .await;
+    assert_eq!(device.owner, Some(telegram_user(2).to_string()));
+    // The code was used up.
+    assert_eq!(
+        api.status(Method::POST, "/devices/claim", user, Some(claim)).await,
+        StatusCode::NOT_FOUND
+    );
+
+    let other = api.token(3).await;
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/5678", other, None).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/5678", user, None).await,
+        StatusCode::OK
+    );
+}
+
+#[tokio::test]
+async fn status_shows_what_the_device_reported() {
+    let api = Api::new().await;
+    let admin = api.token(ADMIN_ID.0).await;
+
+    let info: DeviceStatusInfo = api.json(Method::GET, "/devices/1234/status", admin, None).await;
+    assert!(!info.connected && info.last_seen.is_none() && info.firmware.is_none() && info.status.is_none());
+
+    let status = DeviceStatus {
+        uptime_sec: 3_600,
+        rssi: None,
+        free_text_slots: 3,
+        free_image_slots: 1,
+        checksum_failures: 0,
+        last_error: None,
+    };
+    // The database stores microseconds.
+    let seen_at = Utc::now().trunc_subsecs(6);
+    api.db.set_firmware(DEVICE_ID, "1.2.3".parse().unwrap()).await.unwrap();
+    api.db.set_status(DEVICE_ID, status, seen_at).await.unwrap();
+
+    let info: DeviceStatusInfo = api.json(Method::GET, "/devices/1234/status", admin, None).await;
+    assert_eq!(info.id, DEVICE_ID);
+    assert_eq!(info.last_seen, Some(seen_at));
+    assert_eq!(info.firmware, Some("1.2.3".parse().unwrap()));
+    assert_eq!(info.status, Some(status));
+    assert_eq!(
+        api.status(Method::GET, "/devices/5678/status", admin, None).await,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn scheduled_messages_are_listed_and_cancelled() {
+    let api = Api::new().await;
+    let admin = api.token(ADMIN_ID.0).await;
+    let user = api.token(2).await;
+    // The database stores microseconds.
+    let now = Utc::now().trunc_subsecs(6);
+    let meta = MessageMeta {
+        receiver_id: DEVICE_ID,
+        duration: TimeDelta::hours(1),
+        urgent: false,
+        not_before: Some(now + TimeDelta::hours(2)),
+    };
+    let content = MessageContent::new_text("Happy birthday").unwrap();
+    let id = api
+        .db
+        .add_message(InsertMessage::new(meta, SenderID::Web, now, content))
+        .await
+        .unwrap();
+    api.add_text(DEVICE_ID, SenderID::Web, "Shown now", now).await;
+
+    let scheduled: Vec<MessageInfo> = api.json(Method::GET, "/messages/scheduled", admin, None).await;
+    let [message] = &scheduled[..] else {
+        panic!("expected the scheduled message, got {scheduled:?}");
+    };
+    assert_eq!(message.id, id);
+    assert!(message.scheduled);
+    assert_eq!(message.not_before, meta.not_before);
+    // Users only see the messages to devices they may send to.
+    let scheduled: Vec<MessageInfo> = api.json(Method::GET, "/messages/scheduled", user, None).await;
+    assert!(scheduled.is_empty());
+
+    let uri = format!("/messages/{id}");
+    assert_eq!(
+        api.status(Method::DELETE, &uri, user, None).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::DELETE, &uri, admin, None).await,
+        StatusCode::NO_CONTENT
+    );
+    let scheduled: Vec<MessageInfo> = api.json(Method::GET, "/messages/scheduled", admin, None).await;
+    assert!(scheduled.is_empty());
+    // No device got the message yet, so there is nothing to recall.
+    assert!(api.db.get_next_recall(DEVICE_ID, None, now).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn scheduled_text_is_cancelled_by_the_returned_id() {
+    let api = Api::new().await;
+    let admin = api.token(ADMIN_ID.0).await;
+    let meta = MessageMeta {
+        receiver_id: DEVICE_ID,
+        duration: TimeDelta::hours(1),
+        urgent: false,
+        not_before: Some(Utc::now() + TimeDelta::hours(2)),
+    };
+    let new_message = NewTextMessage {
+        meta,
+        text: "Happy birthday".to_string(),
+    };
+    let user = User::new_telegram(ADMIN_ID).authorize();
+    let Json(created) = new_text_message(State(api.db.clone()), WebUser(user), Form(new_message))
+        .await
+        .unwrap();
+
+    let scheduled = api.db.get_scheduled_messages().await.unwrap();
+    assert_eq!(
+        scheduled.iter().map(|message| message.id).collect::<Vec<_>>(),
+        [created.id]
+    );
+    let uri = format!("/messages/{}", created.id);
+    assert_eq!(
+        api.status(Method::DELETE, &uri, admin, None).await,
+        StatusCode::NO_CONTENT
+    );
+    assert!(api.db.get_scheduled_messages().await.unwrap().is_empty());
+}
+
+fn message_ids(history: &MessageHistory) -> Vec<MessageID> {
+    history.messages.iter().map(|message| message.id).collect()
+}
+
+#[tokio::test]
+async fn history_pages_follow_the_cursor() {
+    let api = Api::new().await;
+    let admin = api.token(ADMIN_ID.0).await;
+    let now = Utc::now();
+    let mut ids = Vec::new();
+    for i in 0..5 {
+        ids.push(
+            api.add_text(DEVICE_ID, SenderID::Web, &format!("Message {i}"), now)
+                .await,
+        );
+    }
+
+    let page: MessageHistory = api.json(Method::GET, "/messages?limit=2", admin, None).await;
+    assert_eq!(message_ids(&page), [ids[4], ids[3]]);
+    assert_eq!(page.next_cursor, Some(ids[3]));
+    let uri = format!("/messages?limit=2&before={}", ids[3]);
+    let page: MessageHistory = api.json(Method::GET, &uri, admin, None).await;
+    assert_eq!(message_ids(&page), [ids[2], ids[1]]);
+    let uri = format!("/messages?limit=2&before={}", ids[1]);
+    let page: MessageHistory = api.json(Method::GET, &uri, admin, None).await;
+    assert_eq!(message_ids(&page), [ids[0]]);
+    assert_eq!(page.next_cursor, None);
+
+    // A page that exactly holds the remaining messages has no next page either.
+    let page: MessageHistory = api.json(Method::GET, "/messages?limit=5", admin, None).await;
+    assert_eq!(page.messages.len(), 5);
+    assert_eq!(page.next_cursor, None);
+}
+
+#[tokio::test]
+async fn history_limit_must_be_between_1_and_200() {
+    let api = Api::new().await;
+    let admin = api.token(ADMIN_ID.0).await;
+    for limit in [0, 201] {
+        let uri = format!("/messages?limit={limit}");
+        assert_eq!(
+            api.status(Method::GET, &uri, admin, None).await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+    for limit in [1, 200] {
+        let uri = format!("/messages?limit={limit}");
+        assert_eq!(api.status(Method::GET, &uri, admin, None).await, StatusCode::OK);
+    }
+}
+
+#[tokio::test]
+async fn history_is_filtered() {
+    let api = Api::new().await;
+    let admin = api.token(ADMIN_ID.0).await;
+    let other_device = DeviceID(0x5678);
+    api.db
+        .add_device(Device::new(other_device, "Other device".to_string()))
+        .await
+        .unwrap();
+    let now = Utc::now();
+    let hello = api
+        .add_text(DEVICE_ID, SenderID::Web, "Hello", now - TimeDelta::hours(2))
+        .await;
+    let bye = api
+        .add_text(DEVICE_ID, SenderID::Telegram, "Bye", now - TimeDelta::hours(1))
+        .await;
+    let other = api.add_text(other_device, SenderID::Web, "Hello there", now).await;
+
+    assert_eq!(api.history("receiver=1234", admin).await, [bye, hello]);
+    assert_eq!(api.history("sender=telegram", admin).await, [bye]);
+    assert_eq!(api.history("kind=image", admin).await, []);
+    assert_eq!(api.history("q=hello", admin).await, [other, hello]);
+    let from = (now - TimeDelta::minutes(90)).to_rfc3339_opts(SecondsFormat::Micros, true);
+    let to = now.to_rfc3339_opts(SecondsFormat::Micros, true);
+    assert_eq!(api.history(&format!("from={from}&to={to}"), admin).await, [bye]);
+    // Empty parameters, as sent by HTML forms, are ignored.
+    assert_eq!(api.history("receiver=&q=&before=", admin).await, [other, bye, hello]);
+    assert_eq!(
+        api.status(Method::GET, "/messages?sender=fax", admin, None).await,
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[tokio::test]
+async fn history_only_shows_devices_the_user_may_send_to() {
+    let api = Api::new().await;
+    let user = api.token(2).await;
+    let other_device = DeviceID(0x5678);
+    api.db
+        .add_device(Device::new(other_device, "Other device".to_string()))
+        .await
+        .unwrap();
+    let now = Utc::now();
+    api.add_text(DEVICE_ID, SenderID::Web, "Hidden", now).await;
+    let visible = api.add_text(other_device, SenderID::Web, "Visible", now).await;
+
+    assert_eq!(api.history("", user).await, []);
+
+    let grant = Grant {
+        device_id: other_device,
+        user: telegram_user(2),
+        right: DeviceRight::Send,
+    };
+    api.db.set_grant(grant).await.unwrap();
+    assert_eq!(api.history("", user).await, [visible]);
+    // Asking for a device explicitly does not get around the rights.
+    assert_eq!(api.history("receiver=1234", user).await, []);
+}
+
+#[tokio::test]
+async fn latest_message_is_only_shown_to_users_who_may_send_to_the_device() {
+    let api = Api::new().await;
+    let user = api.token(2).await;
+    api.add_text(DEVICE_ID, SenderID::Web, "Hello", Utc::now()).await;
+
+    let response = api.request(Method::GET, "/latest/1234", None, None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        api.status(Method::GET, "/latest/1234", user, None).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::GET, "/latest/5678", user, None).await,
+        StatusCode::NOT_FOUND
+    );
+
+    let grant = Grant {
+        device_id: DEVICE_ID,
+        user: telegram_user(2),
+        right: DeviceRight::Send,
+    };
+    api.db.set_grant(grant).await.unwrap();
+    let response = api.request(Method::GET, "/latest/1234", Some(user), None).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let text = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&text[..], b"Hello");
+}
+
+#[tokio::test]
+async fn grants_need_the_manage_right() {
+    let api = Api::new().await;
+    let user = api.token(2).await;
+    let grantee = api.token(3).await;
+    let send = json!({ "right": "send" });
+
+    // Without any right, a user may neither see nor change the grants.
+    assert_eq!(
+        api.status(Method::GET, "/devices/1234/grants", user, None).await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::PUT, "/devices/1234/grants/telegram:3", user, Some(send.clone()))
+            .await,
+        StatusCode::FORBIDDEN
+    );
+
+    // The send right lets a user see the device, but not manage it.
+    let grant = |right| Grant {
+        device_id: DEVICE_ID,
+        user: telegram_user(2),
+        right,
+    };
+    api.db.set_grant(grant(DeviceRight::Send)).await.unwrap();
+    let devices: Vec<DeviceInfo> = api.json(Method::GET, "/devices", user, None).await;
+    assert_eq!(device_ids(&devices), [DEVICE_ID]);
+    assert_eq!(
+        api.status(Method::PUT, "/devices/1234/grants/telegram:3", user, Some(send.clone()))
+            .await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/1234/grants/telegram:2", user, None)
+            .await,
+        StatusCode::FORBIDDEN
+    );
+    assert_eq!(
+        api.status(Method::GET, "/devices/1234/status", user, None).await,
+        StatusCode::FORBIDDEN
+    );
+
+    // The manage right lets a user pass on rights, but only to users we know.
+    api.db.set_grant(grant(DeviceRight::Manage)).await.unwrap();
+    api.json::<Value>(Method::PUT, "/devices/1234/grants/telegram:3", user, Some(send.clone()))
+        .await;
+    assert_eq!(
+        api.status(Method::PUT, "/devices/1234/grants/telegram:4", user, Some(send))
+            .await,
+        StatusCode::NOT_FOUND
+    );
+    let grants: Vec<Value> = api.json(Method::GET, "/devices/1234/grants", user, None).await;
+    assert_eq!(grants.len(), 2);
+    let devices: Vec<DeviceInfo> = api.json(Method::GET, "/devices", grantee, None).await;
+    assert_eq!(device_ids(&devices), [DEVICE_ID]);
+
+    // Revoking takes the device away again.
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/1234/grants/telegram:3", user, None)
+            .await,
+        StatusCode::NO_CONTENT
+    );
+    assert_eq!(
+        api.status(Method::DELETE, "/devices/1234/grants/telegram:3", user, None)
+            .await,
+        StatusCode::NOT_FOUND
+    );
+    let devices: Vec<DeviceInfo> = api.json(Method::GET, "/devices", grantee, None).await;
+    assert!(devices.is_empty());
+}
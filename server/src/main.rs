@@ -1,14 +1,21 @@
 use std::sync::Arc;
 
+use anyhow::anyhow;
 use dotenvy::dotenv;
 use teloxide::types::UserId;
-use tokio::{runtime::Runtime, signal};
+use tokio::{runtime::Runtime, signal, sync::mpsc};
 
-use crate::db::memory_db::MemoryDb;
+use crate::db::{
+    memory_db::{MemoryDb, MESSAGE_PATH},
+    sqlite_db::SqliteDb,
+    Db,
+};
 
 mod db;
 mod error;
 mod handlers;
+mod retention;
+mod schedule;
 
 fn main() -> error::Result<()> {
     dotenv().expect(".env file not found");
@@ -16,16 +23,22 @@ fn main() -> error::Result<()> {
 
     let body = async {
         // Restore messages from disk.
-        let db = init_db().await;
-        let mut join_handles = Vec::new();
-
-        // spawn task to handle TCP connections from devices
-        join_handles.push(tokio::spawn(handlers::device::run(db.clone())));
-        // spawn task to handle HTTP connections from website
-        join_handles.push(tokio::spawn(handlers::web::run(db.clone())));
-        // spawn task to handle Telegram webhooks
-        // join_handles.push(tokio::spawn(handlers::telegram::run(db.clone())));
-        join_handles.push(tokio::spawn(handlers::telegram::run(db.clone())));
+        let db = init_db()?;
+        let retention_policy = retention::RetentionPolicy::from_env()?;
+        // Messages that reached their device, so that the Telegram bot can tell their senders.
+        let (delivered_tx, delivered_rx) = mpsc::unbounded_channel();
+        let _join_handles = [
+            // spawn task to handle TCP connections from devices
+            tokio::spawn(handlers::device::run(db.clone(), delivered_tx)),
+            // spawn task to handle HTTP connections from website
+            tokio::spawn(handlers::web::run(db.clone())),
+            // spawn task to handle Telegram webhooks
+            tokio::spawn(handlers::telegram::run(db.clone(), delivered_rx)),
+            // spawn task to delete expired messages
+            tokio::spawn(retention::run(db.clone(), retention_policy)),
+            // spawn task to release scheduled messages when they are due
+            tokio::spawn(schedule::run(db.clone())),
+        ];
 
         // for (i, handle) in join_handles.into_iter().enumerate() {
         //     handle.await?;
@@ -33,16 +46,20 @@ fn main() -> error::Result<()> {
         // }
         // log::info!("Joined all tasks.");
         signal::ctrl_c().await.expect("failed to listen for Ctrl-C");
-        Ok(())
+        log::info!("Shutting down.");
+        db.flush().await
     };
 
     let rt = Runtime::new()?;
     rt.block_on(body)
 }
 
-// Messages need to be in an Arc to use axum::debug_handler.
-async fn init_db() -> Arc<MemoryDb> {
-    // let messages = message::Messages::load(&MESSAGE_PATH);
+const DEFAULT_SQLITE_PATH: &str = "./rpi-messages.sqlite";
+
+/// Messages need to be in an Arc to use axum::debug_handler.
+/// The backend is selected with the `DB_BACKEND` environment variable, either "sqlite" (the default) or "memory".
+/// Must be called from within the tokio runtime since the memory backend spawns a task to write snapshots.
+fn init_db() -> error::Result<Arc<dyn Db>> {
     let telegram_admin_id = {
         let id = std::env::var("ADMIN_CHAT_ID")
             .expect("ADMIN_CHAT_ID not set")
@@ -50,6 +67,23 @@ async fn init_db() -> Arc<MemoryDb> {
             .expect("ADMIN_CHAT_ID invalid");
         UserId(id)
     };
-    let messages = MemoryDb::dummy(telegram_admin_id);
-    Arc::new(messages)
+
+    let backend = std::env::var("DB_BACKEND").unwrap_or_else(|_| "sqlite".to_string());
+    match backend.as_str() {
+        "sqlite" => {
+            let path = std::env::var("SQLITE_PATH").unwrap_or_else(|_| DEFAULT_SQLITE_PATH.to_string());
+            log::info!("Using SQLite database at {path}.");
+            Ok(Arc::new(SqliteDb::open(&path, telegram_admin_id)?))
+        }
+        "memory" => {
+            let path = std::env::var("MEMORY_DB_PATH").unwrap_or_else(|_| MESSAGE_PATH.to_string());
+            log::info!("Using in-memory database with snapshots at {path}.");
+            let db = Arc::new(MemoryDb::open(&path, telegram_admin_id)?);
+            tokio::spawn(db.clone().run_snapshots());
+            Ok(db)
+        }
+        _ => Err(anyhow!(
+            "Unknown DB_BACKEND '{backend}', expected 'sqlite' or 'memory'."
+        )),
+    }
 }
@@ -0,0 +1,80 @@
+//! Expired messages are no longer sent to devices, but they stay in the database until they are reaped here.
+
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::{anyhow, Context};
+use chrono::{DateTime, Utc};
+
+use crate::{db::Db, error::Result};
+
+#[cfg(test)]
+mod tests;
+
+const REAP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// What happens to messages after they expired.
+#[derive(Debug, Clone, Copy)]
+pub enum RetentionPolicy {
+    /// Delete messages as soon as they expire.
+    Delete,
+    /// Keep expired messages as history, either forever or for `max_age` after they expired.
+    Keep { max_age: Option<chrono::Duration> },
+}
+
+impl RetentionPolicy {
+    /// Read the policy from the `MESSAGE_RETENTION` ("delete" or "keep") and `MESSAGE_HISTORY_DAYS` environment variables.
+    pub fn from_env() -> Result<Self> {
+        let policy = std::env::var("MESSAGE_RETENTION").unwrap_or_else(|_| "delete".to_string());
+        let history_days = std::env::var("MESSAGE_HISTORY_DAYS").ok();
+        Self::parse(&policy, history_days.as_deref())
+    }
+
+    fn parse(policy: &str, history_days: Option<&str>) -> Result<Self> {
+        match (policy, history_days) {
+            ("delete", None) => Ok(RetentionPolicy::Delete),
+            ("delete", Some(_)) => Err(anyhow!("MESSAGE_HISTORY_DAYS requires MESSAGE_RETENTION=keep")),
+            ("keep", None) => Ok(RetentionPolicy::Keep { max_age: None }),
+            ("keep", Some(days)) => {
+                let days = u32::from_str(days).context("MESSAGE_HISTORY_DAYS must be a number of days")?;
+                Ok(RetentionPolicy::Keep {
+                    max_age: Some(chrono::Duration::days(days.into())),
+                })
+            }
+            _ => Err(anyhow!(
+                "Unknown MESSAGE_RETENTION '{policy}', expected 'delete' or 'keep'."
+            )),
+        }
+    }
+
+    /// Messages that expired before the returned time can be deleted.
+    fn cutoff(self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match self {
+            RetentionPolicy::Delete => Some(now),
+            // Ages beyond the range of dates keep messages forever.
+            RetentionPolicy::Keep { max_age } => max_age.and_then(|max_age| now.checked_sub_signed(max_age)),
+        }
+    }
+}
+
+/// Periodically delete expired messages according to `policy`.
+pub async fn run(db: Arc<dyn Db>, policy: RetentionPolicy) {
+    log::info!("Reaping expired messages with policy {policy:?}.");
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        match reap(&*db, policy, Utc::now()).await {
+            Ok(0) => {}
+            Ok(deleted) => log::info!("Deleted {deleted} expired messages."),
+            Err(e) => log::error!("Deleting expired messages failed: {e:#}"),
+        }
+    }
+}
+
+/// Delete the messages that `policy` does not keep at `now`. Returns how many were deleted.
+async fn reap(db: &dyn Db, policy: RetentionPolicy, now: DateTime<Utc>) -> Result<usize> {
+    match policy.cutoff(now) {
+        Some(cutoff) => db.delete_expired_messages(cutoff).await,
+        None => Ok(0),
+    }
+}
@@ -0,0 +1,104 @@
+use chrono::Duration;
+use common::{
+    protocols::web::MessageMeta,
+    types::{DeviceID, MessageID},
+};
+use teloxide::types::UserId;
+
+use super::*;
+use crate::db::{
+    message::{InsertMessage, MessageContent, MessageFilter, SenderID},
+    sqlite_db::SqliteDb,
+};
+
+const DEVICE_ID: DeviceID = DeviceID(0x1234);
+
+/// Adds a message that expired `expired_ago` before `now`, or is still shown if that is negative.
+async fn add_message(db: &dyn Db, now: DateTime<Utc>, expired_ago: Duration) -> MessageID {
+    let meta = MessageMeta {
+        receiver_id: DEVICE_ID,
+        duration: Duration::hours(1),
+        urgent: false,
+        not_before: None,
+    };
+    let created_at = now - expired_ago - meta.duration;
+    let content = MessageContent::new_text("Hello").unwrap();
+    db.add_message(InsertMessage::new(meta, SenderID::Web, created_at, content))
+        .await
+        .unwrap()
+}
+
+async fn history(db: &dyn Db) -> Vec<MessageID> {
+    let messages = db
+        .get_message_history(&MessageFilter::default(), None, 100)
+        .await
+        .unwrap();
+    messages.into_iter().map(|message| message.id).collect()
+}
+
+#[test]
+fn policy_is_parsed_from_its_variables() {
+    assert!(matches!(
+        RetentionPolicy::parse("delete", None).unwrap(),
+        RetentionPolicy::Delete
+    ));
+    assert!(matches!(
+        RetentionPolicy::parse("keep", None).unwrap(),
+        RetentionPolicy::Keep { max_age: None }
+    ));
+    let RetentionPolicy::Keep { max_age } = RetentionPolicy::parse("keep", Some("7")).unwrap() else {
+        panic!("expected the keep policy");
+    };
+    assert_eq!(max_age, Some(Duration::days(7)));
+
+    assert!(RetentionPolicy::parse("delete", Some("7")).is_err());
+    assert!(RetentionPolicy::parse("keep", Some("a week")).is_err());
+    // A negative age would delete messages before they expire.
+    assert!(RetentionPolicy::parse("keep", Some("-5")).is_err());
+    assert!(RetentionPolicy::parse("archive", None).is_err());
+}
+
+#[test]
+fn cutoff_follows_the_policy() {
+    let now = Utc::now();
+    assert_eq!(RetentionPolicy::Delete.cutoff(now), Some(now));
+    assert_eq!(RetentionPolicy::Keep { max_age: None }.cutoff(now), None);
+    let keep_a_week = RetentionPolicy::Keep {
+        max_age: Some(Duration::days(7)),
+    };
+    assert_eq!(keep_a_week.cutoff(now), Some(now - Duration::days(7)));
+    let keep_longer_than_dates_go = RetentionPolicy::Keep {
+        max_age: Some(Duration::days(u32::MAX.into())),
+    };
+    assert_eq!(keep_longer_than_dates_go.cutoff(now), None);
+}
+
+#[tokio::test]
+async fn delete_policy_reaps_expired_messages_only() {
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    let now = Utc::now();
+    add_message(&db, now, Duration::minutes(1)).await;
+    let active = add_message(&db, now, Duration::minutes(-1)).await;
+
+    assert_eq!(reap(&db, RetentionPolicy::Delete, now).await.unwrap(), 1);
+    assert_eq!(history(&db).await, [active]);
+}
+
+#[tokio::test]
+async fn keep_policy_reaps_messages_older_than_the_history() {
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    let now = Utc::now();
+    add_message(&db, now, Duration::days(8)).await;
+    let recent = add_message(&db, now, Duration::days(6)).await;
+    let active = add_message(&db, now, Duration::minutes(-1)).await;
+
+    let keep_forever = RetentionPolicy::Keep { max_age: None };
+    assert_eq!(reap(&db, keep_forever, now).await.unwrap(), 0);
+    assert_eq!(history(&db).await.len(), 3);
+
+    let keep_a_week = RetentionPolicy::Keep {
+        max_age: Some(Duration::days(7)),
+    };
+    assert_eq!(reap(&db, keep_a_week, now).await.unwrap(), 1);
+    assert_eq!(history(&db).await, [active, recent]);
+}
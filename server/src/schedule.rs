@@ -0,0 +1,128 @@
+//! Scheduled messages are held back until their `not_before` time, when they are released to their devices here.
+//! Recurring messages are turned into normal messages here each time they are due.
+
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use common::protocols::web::DeviceRight;
+use tokio::sync::Notify;
+
+use crate::{
+    db::{access, recurring, recurring::RecurringMessage, Db},
+    error::Result,
+    handlers,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// How long to wait before trying again after the database failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+static SCHEDULE_CHANGED: Notify = Notify::const_new();
+
+/// Look again for the next scheduled or recurring message.
+/// Must be called whenever one was added, since we might be sleeping until a later one is due.
+pub fn wake() {
+    SCHEDULE_CHANGED.notify_one();
+}
+
+/// Release scheduled messages and send recurring messages when they are due and push them to their devices.
+pub async fn run(db: Arc<dyn Db>) {
+    log::info!("Releasing scheduled messages.");
+
+    loop {
+        let wait = match release_due(db.as_ref(), Utc::now()).await {
+            Ok(Some(next)) => (next - Utc::now()).to_std().unwrap_or_default(),
+            Ok(None) => Duration::MAX,
+            Err(e) => {
+                log::error!("Releasing scheduled messages failed: {e:#}");
+                RETRY_INTERVAL
+            }
+        };
+
+        // `notify_one` keeps a permit while we are not waiting, so a message added in the meantime is not missed.
+        tokio::select! {
+            _ = SCHEDULE_CHANGED.notified() => {}
+            _ = sleep(wait) => {}
+        }
+    }
+}
+
+/// Release the messages that are due at time `now` and return when the next one is due.
+pub(crate) async fn release_due(db: &dyn Db, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+    for message in db.release_due_messages(now).await? {
+        log::info!(
+            "Released scheduled message {} to device {}.",
+            message.id,
+            message.meta.receiver_id
+        );
+        handlers::device::wake(message.meta.receiver_id);
+    }
+    let mut next_due = Vec::new();
+    if let Some(message) = db.get_scheduled_messages().await?.first() {
+        next_due.push(message.shown_from());
+    }
+    // Sending a recurring message moves it on, maybe even before ones that were due later.
+    for message in db.get_recurring_messages().await? {
+        if message.next_at > now {
+            next_due.push(message.next_at);
+            break;
+        }
+        next_due.push(send_recurring(db, &message, now).await?);
+    }
+    Ok(next_due.into_iter().min())
+}
+
+/// Send the message that is due for the recurring message and return when it is due next.
+/// Occurrences that were missed while the server was down are only sent if they have not expired yet.
+async fn send_recurring(db: &dyn Db, message: &RecurringMessage, now: DateTime<Utc>) -> Result<DateTime<Utc>> {
+    // Move on first, so that a failure below does not send the message over and over again.
+    let next_at = recurring::next_occurrence(message.recurrence, now);
+    db.set_recurring_next_at(message.id, next_at).await?;
+
+    let occurrence = message.occurrence()?;
+    if occurrence.created_at + occurrence.meta.duration <= now {
+        log::info!("Skipped recurring message {} that was missed.", message.id);
+        return Ok(next_at);
+    }
+    if !author_may_send(db, message).await? {
+        log::info!(
+            "Skipped recurring message {} since its author may no longer send to device {}.",
+            message.id,
+            message.receiver_id
+        );
+        return Ok(next_at);
+    }
+
+    let id = db.add_message(occurrence).await?;
+    log::info!(
+        "Sent message {id} of recurring message {} to device {}.",
+        message.id,
+        message.receiver_id
+    );
+    handlers::device::wake(message.receiver_id);
+    Ok(next_at)
+}
+
+/// Whether the author of the recurring message is still authorized and may send to its device.
+async fn author_may_send(db: &dyn Db, message: &RecurringMessage) -> Result<bool> {
+    let Some(author) = message.author else {
+        return Ok(true);
+    };
+    let (Some(user), Some(device)) = (
+        db.is_user_authorized(author).await?,
+        db.get_device(message.receiver_id).await?,
+    ) else {
+        return Ok(false);
+    };
+    Ok(access::device_right(db, &user, &device).await? >= Some(DeviceRight::Send))
+}
+
+/// Like [`tokio::time::sleep`], but waits forever for durations that are too long for a deadline.
+async fn sleep(duration: Duration) {
+    match tokio::time::Instant::now().checked_add(duration) {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
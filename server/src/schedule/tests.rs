@@ -0,0 +1,193 @@
+use chrono::{NaiveTime, SubsecRound, TimeDelta};
+use common::{
+    protocols::web::{MessageMeta, NewRecurringMessage, Recurrence},
+    types::{DeviceID, MessageID, RecurringID},
+};
+use teloxide::types::UserId;
+
+use super::*;
+use crate::db::{
+    access::Grant,
+    device::Device,
+    message::{InsertMessage, MessageContent, MessageFilter, SenderID},
+    recurring::InsertRecurringMessage,
+    sqlite_db::SqliteDb,
+    user::User,
+};
+
+const DEVICE_ID: DeviceID = DeviceID(0x1234);
+const DAILY: Recurrence = Recurrence::Day {
+    time: NaiveTime::from_hms_opt(8, 0, 0).unwrap(),
+};
+
+async fn add_scheduled(db: &dyn Db, text: &str, now: DateTime<Utc>, not_before: DateTime<Utc>) -> MessageID {
+    let meta = MessageMeta {
+        receiver_id: DEVICE_ID,
+        duration: TimeDelta::hours(1),
+        urgent: false,
+        not_before: Some(not_before),
+    };
+    let content = MessageContent::new_text(text).unwrap();
+    db.add_message(InsertMessage::new(meta, SenderID::Web, now, content))
+        .await
+        .unwrap()
+}
+
+/// The texts of the messages that were released, the newest first.
+async fn released(db: &dyn Db) -> Vec<String> {
+    let messages = db
+        .get_message_history(&MessageFilter::default(), None, 100)
+        .await
+        .unwrap();
+    messages
+        .iter()
+        .filter(|message| !message.scheduled)
+        .map(|message| message.preview(100))
+        .collect()
+}
+
+#[tokio::test]
+async fn nothing_is_due_without_scheduled_messages() {
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    assert_eq!(release_due(&db, Utc::now()).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn scheduled_messages_are_released_when_due() {
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    // The database stores microseconds.
+    let now = Utc::now().trunc_subsecs(6);
+    let (soon, later) = (now + TimeDelta::hours(1), now + TimeDelta::hours(2));
+    add_scheduled(&db, "later", now, later).await;
+    add_scheduled(&db, "soon", now, soon).await;
+
+    assert_eq!(release_due(&db, now).await.unwrap(), Some(soon));
+    assert!(released(&db).await.is_empty());
+
+    assert_eq!(release_due(&db, soon).await.unwrap(), Some(later));
+    assert_eq!(released(&db).await, ["soon"]);
+    assert_eq!(db.get_scheduled_messages().await.unwrap().len(), 1);
+
+    // Messages that are overdue, e.g. after the server was down, are released at once.
+    assert_eq!(release_due(&db, later + TimeDelta::hours(1)).await.unwrap(), None);
+    assert_eq!(released(&db).await, ["soon", "later"]);
+    assert!(db.get_scheduled_messages().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn released_message_is_deleted_by_its_original_id() {
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    let now = Utc::now().trunc_subsecs(6);
+    let id = add_scheduled(&db, "soon", now, now + TimeDelta::hours(1)).await;
+    assert_eq!(release_due(&db, now + TimeDelta::hours(1)).await.unwrap(), None);
+
+    let message = db.get_message(id).await.unwrap().unwrap();
+    assert!(!message.scheduled);
+    assert_eq!(db.delete_message(id).await.unwrap().id, id);
+    assert!(db.get_message(id).await.unwrap().is_none());
+
+    // Devices that might already have it are told to remove it by the sequence they know it by.
+    let recall = db.get_next_recall(DEVICE_ID, None, now).await.unwrap().unwrap();
+    assert_eq!(recall.message_id, message.sequence);
+}
+
+async fn add_recurring(db: &dyn Db, text: &str, author: Option<UserId>, next_at: DateTime<Utc>) -> RecurringID {
+    let message = NewRecurringMessage {
+        receiver_id: DEVICE_ID,
+        text: text.to_string(),
+        recurrence: DAILY,
+        duration: TimeDelta::hours(1),
+        urgent: false,
+    };
+    let author = author.map(|id| User::new_telegram(id).raw());
+    let message = InsertRecurringMessage::new(message, SenderID::Web, next_at)
+        .unwrap()
+        .with_author(author);
+    let id = db.add_recurring_message(message).await.unwrap();
+    db.set_recurring_next_at(id, next_at).await.unwrap();
+    id
+}
+
+async fn next_at(db: &dyn Db, id: RecurringID) -> DateTime<Utc> {
+    db.get_recurring_message(id).await.unwrap().unwrap().next_at
+}
+
+#[tokio::test]
+async fn recurring_message_is_sent_when_due_and_moves_on() {
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    let now = Utc::now().trunc_subsecs(6);
+    let id = add_recurring(&db, "daily", None, now - TimeDelta::minutes(10)).await;
+
+    let next = recurring::next_occurrence(DAILY, now);
+    assert_eq!(release_due(&db, now).await.unwrap(), Some(next));
+    assert_eq!(released(&db).await, ["daily"]);
+    assert_eq!(next_at(&db, id).await, next);
+
+    // Nothing is sent again until the next occurrence is due.
+    assert_eq!(release_due(&db, now).await.unwrap(), Some(next));
+    assert_eq!(released(&db).await, ["daily"]);
+}
+
+#[tokio::test]
+async fn missed_occurrence_is_skipped_once_it_has_expired() {
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    let now = Utc::now().trunc_subsecs(6);
+    let id = add_recurring(&db, "missed", None, now - TimeDelta::hours(2)).await;
+
+    let next = recurring::next_occurrence(DAILY, now);
+    assert_eq!(release_due(&db, now).await.unwrap(), Some(next));
+    assert!(released(&db).await.is_empty());
+    assert_eq!(next_at(&db, id).await, next);
+}
+
+#[tokio::test]
+async fn recurring_message_is_only_sent_while_its_author_may_send() {
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    db.add_device(Device::new(DEVICE_ID, "Test device".to_string()))
+        .await
+        .unwrap();
+    for id in [UserId(2), UserId(3)] {
+        db.add_authorized_user(User::new_telegram(id).authorize())
+            .await
+            .unwrap();
+    }
+    let grant = Grant {
+        device_id: DEVICE_ID,
+        user: User::new_telegram(UserId(2)).raw(),
+        right: DeviceRight::Send,
+    };
+    db.set_grant(grant).await.unwrap();
+
+    let now = Utc::now().trunc_subsecs(6);
+    let due = now - TimeDelta::minutes(10);
+    add_recurring(&db, "admin", Some(UserId(1)), due).await;
+    add_recurring(&db, "granted", Some(UserId(2)), due).await;
+    let without_right = add_recurring(&db, "without right", Some(UserId(3)), due).await;
+    add_recurring(&db, "unauthorized", Some(UserId(4)), due).await;
+
+    release_due(&db, now).await.unwrap();
+    let mut sent = released(&db).await;
+    sent.sort();
+    assert_eq!(sent, ["admin", "granted"]);
+    // Skipped messages still move on to their next occurrence.
+    assert_eq!(
+        next_at(&db, without_right).await,
+        recurring::next_occurrence(DAILY, now)
+    );
+}
+
+#[tokio::test]
+async fn next_due_is_the_earliest_scheduled_or_recurring_message() {
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    let now = Utc::now().trunc_subsecs(6);
+    let (soon, later) = (now + TimeDelta::minutes(10), now + TimeDelta::minutes(20));
+
+    add_recurring(&db, "recurring", None, later).await;
+    add_scheduled(&db, "scheduled", now, soon).await;
+    assert_eq!(release_due(&db, now).await.unwrap(), Some(soon));
+
+    let db = SqliteDb::open(":memory:", UserId(1)).unwrap();
+    add_recurring(&db, "recurring", None, soon).await;
+    add_scheduled(&db, "scheduled", now, later).await;
+    assert_eq!(release_due(&db, now).await.unwrap(), Some(soon));
+}
@@ -2,6 +2,15 @@ function error(msg) {
   alert(`Error: ${msg}`);
 }
 
+/// The token for the web API, which the Telegram bot hands out with the /token command.
+function apiToken() {
+  const token = document.getElementById("api-token").value.trim();
+  if (!token) {
+    error("No API token. Use the /token command of the Telegram bot to get one.");
+  }
+  return token;
+}
+
 function submitMultipart() {
   console.log("Calling event listener of mp submit");
   // TODO is possible to fill directly from form but then we need to attach a "formdata" event listener to the form to change the hours to seconds
@@ -10,8 +19,15 @@ function submitMultipart() {
   const fileIpt = document.getElementById("mp-file");
   const receiverIpt = document.getElementById("mp-receiver");
   const durationIpt = document.getElementById("mp-duration");
+  const urgentIpt = document.getElementById("mp-urgent");
+  const notBeforeIpt = document.getElementById("mp-not-before");
 
-  // const formData = new FormData();
+  const token = apiToken();
+  if (!token) {
+    return;
+  }
+
+  const formData = new FormData();
   if (!fileIpt.files || !fileIpt.files[0]) {
     return error("No input file.");
   }
@@ -25,9 +41,15 @@ function submitMultipart() {
     return error("No duration.");
   }
   formData.append("duration", durationIpt.value * 60 * 60);
+  formData.append("urgent", urgentIpt.checked);
+  if (notBeforeIpt.value) {
+    // The input is in local time, the server expects an RFC 3339 timestamp.
+    formData.append("not_before", new Date(notBeforeIpt.value).toISOString());
+  }
 
   const xhr = new XMLHttpRequest();
-  xhr.open("POST", "new_image_message", true);
+  xhr.open("POST", "/api/new_image_message", true);
+  xhr.setRequestHeader("Authorization", `Bearer ${token}`);
 
   xhr.onload = () => {
     console.log("Finished sending form data.");
@@ -38,6 +60,159 @@ function submitMultipart() {
   xhr.send(formData);
 }
 
+async function submitClaim() {
+  const token = apiToken();
+  const code = document.getElementById("claim-code").value.trim();
+  const name = document.getElementById("claim-name").value;
+
+  if (!token) {
+    return;
+  }
+  if (!/^[0-9]{6}$/.test(code)) {
+    return error("The pairing code consists of 6 digits.");
+  }
+
+  const response = await fetch("/api/devices/claim", {
+    method: "POST",
+    headers: {
+      "Authorization": `Bearer ${token}`,
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({ code: Number(code), name }),
+  });
+  if (!response.ok) {
+    return error(await response.text());
+  }
+  const device = await response.json();
+  alert(`Claimed device ${device.name}.`);
+}
+
+/// List the messages that are not shown yet, each with a button to cancel it.
+async function loadScheduled() {
+  const token = apiToken();
+  if (!token) {
+    return;
+  }
+
+  const response = await fetch("/api/messages/scheduled", {
+    headers: { "Authorization": `Bearer ${token}` },
+  });
+  if (!response.ok) {
+    return error(await response.text());
+  }
+  const messages = await response.json();
+
+  const list = document.getElementById("scheduled-list");
+  list.replaceChildren();
+  for (const message of messages) {
+    const item = document.createElement("li");
+    const content = message.text ?? message.kind;
+    item.textContent = `${new Date(message.not_before).toLocaleString()} to ${message.receiver_id}: ${content} `;
+
+    const cancelBtn = document.createElement("input");
+    cancelBtn.type = "button";
+    cancelBtn.value = "cancel";
+    cancelBtn.addEventListener("click", async () => {
+      const response = await fetch(`/api/messages/${message.id}`, {
+        method: "DELETE",
+        headers: { "Authorization": `Bearer ${token}` },
+      });
+      if (!response.ok) {
+        return error(await response.text());
+      }
+      item.remove();
+    });
+    item.appendChild(cancelBtn);
+    list.appendChild(item);
+  }
+}
+
+async function submitRecurring() {
+  const token = apiToken();
+  const receiver = document.getElementById("recurring-receiver").value.trim();
+  const text = document.getElementById("recurring-text").value;
+  const every = document.getElementById("recurring-every").value;
+  const time = document.getElementById("recurring-time").value;
+  const duration = document.getElementById("recurring-duration").value;
+
+  if (!token) {
+    return;
+  }
+  if (!receiver) {
+    return error("No receiver id.");
+  }
+  if (!time) {
+    return error("No time.");
+  }
+  if (!duration) {
+    return error("No duration.");
+  }
+
+  // The time is in the local time of the server.
+  const recurrence = every === "day"
+    ? { every: "day", time: `${time}:00` }
+    : { every: "week", weekday: every, time: `${time}:00` };
+  const response = await fetch("/api/recurring", {
+    method: "POST",
+    headers: {
+      "Authorization": `Bearer ${token}`,
+      "Content-Type": "application/json",
+    },
+    body: JSON.stringify({
+      // Device IDs are written in hex.
+      receiver_id: parseInt(receiver.replace(/^0x/, ""), 16),
+      text,
+      recurrence,
+      // Durations are serialized as seconds and nanoseconds.
+      duration: [duration * 60 * 60, 0],
+    }),
+  });
+  if (!response.ok) {
+    return error(await response.text());
+  }
+  const message = await response.json();
+  alert(`Added recurring message, next on ${new Date(message.next_at).toLocaleString()}.`);
+}
+
+/// List the recurring messages, each with a button to stop it.
+async function loadRecurring() {
+  const token = apiToken();
+  if (!token) {
+    return;
+  }
+
+  const response = await fetch("/api/recurring", {
+    headers: { "Authorization": `Bearer ${token}` },
+  });
+  if (!response.ok) {
+    return error(await response.text());
+  }
+  const messages = await response.json();
+
+  const list = document.getElementById("recurring-list");
+  list.replaceChildren();
+  for (const message of messages) {
+    const item = document.createElement("li");
+    const every = message.recurrence.every === "day" ? "day" : message.recurrence.weekday;
+    item.textContent = `Every ${every} at ${message.recurrence.time} to ${message.receiver_id}: ${message.text} `;
+
+    const stopBtn = document.createElement("input");
+    stopBtn.type = "button";
+    stopBtn.value = "stop";
+    stopBtn.addEventListener("click", async () => {
+      const response = await fetch(`/api/recurring/${message.id}`, {
+        method: "DELETE",
+        headers: { "Authorization": `Bearer ${token}` },
+      });
+      if (!response.ok) {
+        return error(await response.text());
+      }
+      item.remove();
+    });
+    item.appendChild(stopBtn);
+    list.appendChild(item);
+  }
+}
 
 window.addEventListener("load", () => {
   console.log("Loaded document");
@@ -46,4 +221,20 @@ window.addEventListener("load", () => {
     console.log("Adding event listener to mp submit");
     submitBtn.addEventListener("click", submitMultipart);
   }
+  const claimBtn = document.getElementById("claim-submit");
+  if (claimBtn) {
+    claimBtn.addEventListener("click", submitClaim);
+  }
+  const scheduledBtn = document.getElementById("scheduled-load");
+  if (scheduledBtn) {
+    scheduledBtn.addEventListener("click", loadScheduled);
+  }
+  const recurringBtn = document.getElementById("recurring-submit");
+  if (recurringBtn) {
+    recurringBtn.addEventListener("click", submitRecurring);
+  }
+  const recurringLoadBtn = document.getElementById("recurring-load");
+  if (recurringLoadBtn) {
+    recurringLoadBtn.addEventListener("click", loadRecurring);
+  }
 });